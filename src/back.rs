@@ -1,10 +1,15 @@
 //! The back-end of the compiler.
 
 pub mod asm;
+pub mod bytecode;
+pub mod c;
 pub mod codegen;
+pub mod llvm;
+pub mod regalloc;
 
 pub use asm::*;
 pub use codegen::*;
+pub use regalloc::{allocate_registers, spilled, Reg};
 
 #[cfg(test)]
 mod tests;
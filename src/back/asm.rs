@@ -130,7 +130,9 @@
 //!
 //! # Register allocation
 //!
-//! There is no register allocator, all variables are saved on the stack.
+//! The code generator does not use a register allocator, all variables are
+//! saved on the stack.  See [crate::back::regalloc] for a linear-scan
+//! allocator over the tiny IR.
 #![allow(dead_code)]
 
 use derive_more::Display;
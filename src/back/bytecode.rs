@@ -0,0 +1,563 @@
+//! A bytecode back-end: tiny IR is compiled to the code of a stack machine,
+//! and a small VM runs it.
+//!
+//! The code is a flat sequence of operations, and jumps go to indices in this
+//! sequence.  Each function call gets a frame of local variables (its slots),
+//! and the operands of the operations are on a shared stack.  The code starts
+//! by calling the main program, so the main program has a frame too.
+
+use std::io::{BufReader, Read, Write};
+
+use crate::common::*;
+use crate::front::ast::{BOp, Number};
+use crate::middle::dom::reverse_postorder;
+use crate::middle::interp::{read_num, Location, RunResult, RuntimeError};
+use crate::middle::tir::{self, Instruction, Terminator};
+
+/// An operation of the stack machine.  The binary operators pop the right
+/// operand, then the left operand, and push the result.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// Push a constant.
+    PushConst(Number),
+    /// Push the value of a slot of the current frame.
+    Load(usize),
+    /// Pop a value into a slot of the current frame.
+    Store(usize),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Lt,
+    Gt,
+    Eq,
+    Le,
+    Ge,
+    Ne,
+    And,
+    Or,
+    Min,
+    Max,
+    Shl,
+    Shr,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Neg,
+    /// Pop a value and print it.
+    Print,
+    /// Print a string.
+    PrintStr(String),
+    /// Like [Op::Print] and [Op::PrintStr], but without a newline.
+    PrintNoNl,
+    PrintStrNoNl(String),
+    /// Read a number and push it.
+    Read,
+    Jump(usize),
+    /// Pop a value, and jump if it is false.
+    JumpIfZero(usize),
+    /// Pop `args` values into the first slots of a new frame with `slots`
+    /// slots, and jump to `addr`.
+    Call {
+        addr: usize,
+        args: usize,
+        slots: usize,
+    },
+    /// Drop the current frame and go back to the caller.  The return value
+    /// stays on the stack.
+    Ret,
+    /// Stop the program.
+    Halt,
+    /// Pop a value, and stop the program if it is false because the given
+    /// assertion failed.
+    Assert(String),
+}
+
+impl Op {
+    // The operator of a binary operation
+    fn bop(&self) -> Option<BOp> {
+        Some(match self {
+            Op::Add => BOp::Add,
+            Op::Sub => BOp::Sub,
+            Op::Mul => BOp::Mul,
+            Op::Div => BOp::Div,
+            Op::Mod => BOp::Mod,
+            Op::Pow => BOp::Pow,
+            Op::Lt => BOp::Lt,
+            Op::Gt => BOp::Gt,
+            Op::Eq => BOp::Eq,
+            Op::Le => BOp::Le,
+            Op::Ge => BOp::Ge,
+            Op::Ne => BOp::Ne,
+            Op::And => BOp::And,
+            Op::Or => BOp::Or,
+            Op::Min => BOp::Min,
+            Op::Max => BOp::Max,
+            Op::Shl => BOp::Shl,
+            Op::Shr => BOp::Shr,
+            Op::BitAnd => BOp::BitAnd,
+            Op::BitOr => BOp::BitOr,
+            Op::BitXor => BOp::BitXor,
+            _ => return None,
+        })
+    }
+
+    fn from_bop(op: BOp) -> Self {
+        match op {
+            BOp::Add => Op::Add,
+            BOp::Sub => Op::Sub,
+            BOp::Mul => Op::Mul,
+            BOp::Div => Op::Div,
+            BOp::Mod => Op::Mod,
+            BOp::Pow => Op::Pow,
+            BOp::Lt => Op::Lt,
+            BOp::Gt => Op::Gt,
+            BOp::Eq => Op::Eq,
+            BOp::Le => Op::Le,
+            BOp::Ge => Op::Ge,
+            BOp::Ne => Op::Ne,
+            BOp::And => Op::And,
+            BOp::Or => Op::Or,
+            BOp::Min => Op::Min,
+            BOp::Max => Op::Max,
+            BOp::Shl => Op::Shl,
+            BOp::Shr => Op::Shr,
+            BOp::BitAnd => Op::BitAnd,
+            BOp::BitOr => Op::BitOr,
+            BOp::BitXor => Op::BitXor,
+        }
+    }
+}
+
+/// Compile a well-formed program (see [crate::middle::verify]) to bytecode.
+/// The blocks that cannot be reached from `entry` are left out.
+pub fn compile(program: &tir::Program) -> Vec<Op> {
+    let mut compiler = Compiler {
+        code: vec![],
+        block_fixups: vec![],
+        call_fixups: vec![],
+    };
+    // call the main program, and stop when it stops
+    compiler.code.push(Op::Call {
+        addr: 2,
+        args: 0,
+        slots: program.decl.len(),
+    });
+    compiler.code.push(Op::Halt);
+    compiler.compile_body(program, &[]);
+
+    let mut funcs = Map::new();
+    for (name, func) in &program.funcs {
+        funcs.insert(*name, (compiler.code.len(), func.body.decl.len()));
+        compiler.compile_body(&func.body, &func.params);
+    }
+
+    for (pos, func) in compiler.call_fixups {
+        let Op::Call { addr, slots, .. } = &mut compiler.code[pos] else {
+            unreachable!("the fixup should be a call");
+        };
+        (*addr, *slots) = funcs[&func];
+    }
+    compiler.code
+}
+
+// The state of the compiler
+struct Compiler {
+    code: Vec<Op>,
+    // the jumps to patch with the addresses of the blocks of the current body
+    block_fixups: Vec<(usize, Id)>,
+    // the calls to patch with the addresses of the functions
+    call_fixups: Vec<(usize, Id)>,
+}
+
+impl Compiler {
+    // Compile the CFG of the main program or a function.  The parameters come
+    // first in the slots.
+    fn compile_body(&mut self, body: &tir::Program, params: &[Id]) {
+        let mut slots: Map<Id, usize> = Map::new();
+        for x in params.iter().chain(&body.decl) {
+            let next = slots.len();
+            slots.entry(*x).or_insert(next);
+        }
+
+        let order = reverse_postorder(body);
+        let mut addrs = Map::new();
+        for (i, lbl) in order.iter().enumerate() {
+            addrs.insert(*lbl, self.code.len());
+            let block = &body.block[lbl];
+            for insn in &block.insn {
+                if !matches!(insn, Instruction::Phi { .. }) {
+                    self.compile_insn(insn, &slots);
+                }
+            }
+
+            let next = order.get(i + 1).copied();
+            match block.term {
+                Terminator::Exit => self.code.push(Op::Halt),
+                Terminator::Return(x) => {
+                    self.code.push(Op::Load(slots[&x]));
+                    self.code.push(Op::Ret);
+                }
+                Terminator::Jump(target) => self.compile_edge(body, &slots, *lbl, target, next),
+                Terminator::Branch { guard, tt, ff } => {
+                    self.code.push(Op::Load(slots[&guard]));
+                    let jump_ff = self.code.len();
+                    self.code.push(Op::JumpIfZero(0));
+                    self.compile_edge(body, &slots, *lbl, tt, None);
+                    self.code[jump_ff] = Op::JumpIfZero(self.code.len());
+                    self.compile_edge(body, &slots, *lbl, ff, next);
+                }
+                Terminator::Switch {
+                    scrutinee,
+                    ref cases,
+                    default,
+                } => {
+                    // test the cases in order, each one skipping its edge if
+                    // it does not match
+                    for &(n, target) in cases {
+                        self.code.push(Op::Load(slots[&scrutinee]));
+                        self.code.push(Op::PushConst(Number::Int(n)));
+                        self.code.push(Op::Eq);
+                        let jump_next = self.code.len();
+                        self.code.push(Op::JumpIfZero(0));
+                        self.compile_edge(body, &slots, *lbl, target, None);
+                        self.code[jump_next] = Op::JumpIfZero(self.code.len());
+                    }
+                    self.compile_edge(body, &slots, *lbl, default, next);
+                }
+            }
+        }
+
+        for (pos, lbl) in std::mem::take(&mut self.block_fixups) {
+            if let Op::Jump(addr) = &mut self.code[pos] {
+                *addr = addrs[&lbl];
+            }
+        }
+    }
+
+    // Compile the edge from `pred` to `succ`: the phi nodes of `succ` take
+    // their values from `pred`, then control goes to `succ` unless it is the
+    // next block
+    fn compile_edge(
+        &mut self,
+        body: &tir::Program,
+        slots: &Map<Id, usize>,
+        pred: Id,
+        succ: Id,
+        next: Option<Id>,
+    ) {
+        // load all the sources before storing any of them
+        let mut dsts = vec![];
+        for insn in &body.block[&succ].insn {
+            let Instruction::Phi { dst, srcs } = insn else {
+                break;
+            };
+            let (_, src) = srcs
+                .iter()
+                .find(|(p, _)| *p == pred)
+                .expect("a phi node should have a value for each predecessor");
+            self.code.push(Op::Load(slots[src]));
+            dsts.push(*dst);
+        }
+        for dst in dsts.into_iter().rev() {
+            self.code.push(Op::Store(slots[&dst]));
+        }
+
+        if next != Some(succ) {
+            self.block_fixups.push((self.code.len(), succ));
+            self.code.push(Op::Jump(0));
+        }
+    }
+
+    fn compile_insn(&mut self, insn: &Instruction, slots: &Map<Id, usize>) {
+        use Instruction::*;
+        match insn {
+            Copy { dst, src } => {
+                self.code.push(Op::Load(slots[src]));
+                self.code.push(Op::Store(slots[dst]));
+            }
+            Const { dst, src } => {
+                self.code.push(Op::PushConst(*src));
+                self.code.push(Op::Store(slots[dst]));
+            }
+            Arith { op, dst, lhs, rhs } => {
+                self.code.push(Op::Load(slots[lhs]));
+                self.code.push(Op::Load(slots[rhs]));
+                self.code.push(Op::from_bop(*op));
+                self.code.push(Op::Store(slots[dst]));
+            }
+            Neg { dst, src } => {
+                self.code.push(Op::Load(slots[src]));
+                self.code.push(Op::Neg);
+                self.code.push(Op::Store(slots[dst]));
+            }
+            Read(dst) => {
+                self.code.push(Op::Read);
+                self.code.push(Op::Store(slots[dst]));
+            }
+            Print(src) => {
+                self.code.push(Op::Load(slots[src]));
+                self.code.push(Op::Print);
+            }
+            PrintStr(s) => self.code.push(Op::PrintStr(s.clone())),
+            PrintNoNl(src) => {
+                self.code.push(Op::Load(slots[src]));
+                self.code.push(Op::PrintNoNl);
+            }
+            PrintStrNoNl(s) => self.code.push(Op::PrintStrNoNl(s.clone())),
+            Assert(src, assertion) => {
+                self.code.push(Op::Load(slots[src]));
+                self.code.push(Op::Assert(assertion.clone()));
+            }
+            Nop => {}
+            Call { dst, func, args } => {
+                for arg in args {
+                    self.code.push(Op::Load(slots[arg]));
+                }
+                self.call_fixups.push((self.code.len(), *func));
+                self.code.push(Op::Call {
+                    addr: 0,
+                    args: args.len(),
+                    slots: 0,
+                });
+                self.code.push(Op::Store(slots[dst]));
+            }
+            Phi { .. } => unreachable!("phi nodes are compiled on the edges"),
+        }
+    }
+}
+
+// A function call in progress
+struct Frame {
+    slots: Vec<Number>,
+    // where to continue after the call
+    ret: usize,
+}
+
+/// Run the bytecode, reading numbers from `input` (one per line) and printing
+/// numbers to `output` (one per line).
+pub fn exec(code: &[Op], input: impl Read, mut output: impl Write) -> RunResult<()> {
+    let mut input = BufReader::new(input);
+    let mut stack: Vec<Number> = vec![];
+    let mut frames: Vec<Frame> = vec![];
+    let mut pc = 0;
+
+    while let Some(op) = code.get(pc) {
+        pc += 1;
+        let mut pop = || stack.pop().expect("the stack should not underflow");
+        match op {
+            Op::PushConst(n) => stack.push(*n),
+            Op::Load(slot) => {
+                let frame = frames.last().expect("there should be a frame");
+                stack.push(frame.slots[*slot]);
+            }
+            Op::Store(slot) => {
+                let v = pop();
+                frames.last_mut().expect("there should be a frame").slots[*slot] = v;
+            }
+            Op::Neg => {
+                let v = pop();
+                stack.push(-v);
+            }
+            Op::Print => {
+                let v = pop();
+                writeln!(output, "{v}")?;
+            }
+            Op::PrintStr(s) => writeln!(output, "{s}")?,
+            Op::PrintNoNl => {
+                let v = pop();
+                write!(output, "{v}")?;
+            }
+            Op::PrintStrNoNl(s) => write!(output, "{s}")?,
+            Op::Read => stack.push(read_num(&mut input)?),
+            Op::Jump(addr) => pc = *addr,
+            Op::JumpIfZero(addr) => {
+                if !pop().is_true() {
+                    pc = *addr;
+                }
+            }
+            Op::Call { addr, args, slots } => {
+                let mut frame = Frame {
+                    slots: vec![Number::Int(0); *slots],
+                    ret: pc,
+                };
+                let args = stack.split_off(stack.len() - args);
+                frame.slots[..args.len()].copy_from_slice(&args);
+                frames.push(frame);
+                pc = *addr;
+            }
+            Op::Ret => {
+                let frame = frames.pop().expect("there should be a frame");
+                pc = frame.ret;
+            }
+            Op::Halt => break,
+            Op::Assert(assertion) => {
+                if !pop().is_true() {
+                    return Err(RuntimeError::AssertionFailed(assertion.clone()));
+                }
+            }
+            op => {
+                let bop = op.bop().expect("all other operations are binary");
+                let rhs = pop();
+                let lhs = pop();
+                let v = bop.eval(lhs, rhs).ok_or_else(|| {
+                    let at = Location::Address {
+                        op: format!("{op:?}"),
+                        addr: pc - 1,
+                    };
+                    RuntimeError::undefined(bop, rhs, at)
+                })?;
+                stack.push(v);
+            }
+        }
+    }
+
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{lower, parse};
+    use crate::middle::{interp, optimize, to_ssa};
+
+    // SECTION: helpers
+
+    // Run the program with the TIR interpreter and as bytecode, check that
+    // they agree, and return the output
+    fn run_both(program: &tir::Program, input: &str) -> RunResult<String> {
+        let mut tir_output = vec![];
+        let tir_result = interp::run(program, input.as_bytes(), &mut tir_output);
+
+        let code = compile(program);
+        let mut output = vec![];
+        let result = exec(&code, input.as_bytes(), &mut output);
+
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(&tir_output),
+            "the outputs differ for\n{program}\ncompiled to {code:?}"
+        );
+        // the same kind of error, though the locations in the messages differ
+        assert_eq!(
+            result.as_ref().map_err(std::mem::discriminant),
+            tir_result.as_ref().map_err(std::mem::discriminant),
+        );
+        result.map(|()| String::from_utf8(output).unwrap())
+    }
+
+    // Parse, lower and run the program both ways
+    fn run_src(src: &str, input: &str) -> RunResult<String> {
+        run_both(&lower(parse(src).unwrap()), input)
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn straight_line() {
+        assert_eq!(run_src("", "").unwrap(), "");
+        assert_eq!(
+            run_src("$read a := b * + a 1 ~ 2 $print b $print \"done\"", "4\n").unwrap(),
+            "-10\ndone\n"
+        );
+        assert_eq!(
+            run_src("$printc \"x = \" $printc 1.5 $print \"\" $printc 2", "").unwrap(),
+            "x = 1.5\n2"
+        );
+    }
+
+    #[test]
+    fn operators() {
+        let src = ":= x 7 := y 2.5 $print + x y $print - x y $print * x y $print / x 2 \
+                   $print % x 2 $print < x y $print > x y $print = x 7 $print <= x y \
+                   $print >= x y $print != x y $print & x 0 $print | x 0 $print ~ y \
+                   $print $min x 9 $print $max x y $print $band x 3 $print $bor x 8 \
+                   $print $bxor x y $print << x 2 $print >> x 1";
+        assert_eq!(
+            run_src(src, "").unwrap(),
+            "9.5\n4.5\n17.5\n3\n1\n0\n1\n1\n0\n1\n1\n0\n1\n-2.5\n7\n7.0\n3\n15\n5\n28\n3\n"
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        let src = "$read n := i 0 := sum 0 $while < i n {:= i + i 1 \
+                   $if % i 2 {:= sum + sum i} $else {:= sum - sum 1}} $print sum";
+        assert_eq!(run_src(src, "10\n").unwrap(), "20\n");
+        assert_eq!(run_src(src, "0\n").unwrap(), "0\n");
+
+        let src = "$read x $if & > x 0 < / 10 x 5 {$print 1} {$print 0} $return $print 2";
+        assert_eq!(run_src(src, "3\n").unwrap(), "1\n");
+        assert_eq!(run_src(src, "0\n").unwrap(), "0\n");
+    }
+
+    #[test]
+    fn switch() {
+        // the phi node of `b` gets a different value on each edge
+        let src = "
+            let x, y, z;
+            entry: $read x y = $const 10 z = $const 20 $switch x (1: a, 2: b, 3: entry) b
+            a: $print y $jump b
+            b: y = $phi(entry: z, a: x) $print y $exit
+        ";
+        let program = crate::middle::parse_tir(src).unwrap();
+        assert_eq!(run_both(&program, "1\n").unwrap(), "10\n1\n");
+        assert_eq!(run_both(&program, "2\n").unwrap(), "20\n");
+        assert_eq!(run_both(&program, "2.0\n").unwrap(), "20\n");
+        assert_eq!(run_both(&program, "4\n").unwrap(), "20\n");
+    }
+
+    #[test]
+    fn functions() {
+        let src = "$fn fact(n) {$if < n 2 {:= fact 1} {:= fact * n fact(- n 1)}} \
+                   $fn sub(a, b) {:= sub - a b} $read n $print fact(n) $print sub(n, 1)";
+        assert_eq!(run_src(src, "10\n").unwrap(), "3628800\n9\n");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            run_src("$print 1 $read x $print / 1 x", "0\n"),
+            Err(RuntimeError::DivisionByZero(Location::Address { op, .. })) if op == "Div"
+        ));
+        assert!(matches!(
+            run_src("$read x", "x\n"),
+            Err(RuntimeError::InvalidInput(_))
+        ));
+        assert_eq!(
+            run_src("$read x $assert < x 10 $print x", "12\n")
+                .unwrap_err()
+                .to_string(),
+            "Runtime error: assertion failed at 1:9: `$assert < x 10`."
+        );
+        // the same message in a function
+        assert_eq!(
+            run_src("$fn f(x) {$assert x := f x} $print f(0)", "")
+                .unwrap_err()
+                .to_string(),
+            "Runtime error: assertion failed at 1:11: `$assert x`."
+        );
+    }
+
+    #[test]
+    fn ssa_and_optimized() {
+        let src = ":= a 1 := b 2 := n 3 $while n {:= t a := a b := b t := n - n 1} \
+                   $print a $print b $fn f(x) {$if x {:= f 1} {:= f 2}} $print f(a)";
+        let mut program = lower(parse(src).unwrap());
+        to_ssa(&mut program);
+        assert_eq!(run_both(&program, "").unwrap(), "2\n1\n1\n");
+        assert_eq!(run_both(&optimize(program), "").unwrap(), "2\n1\n1\n");
+    }
+
+    #[test]
+    fn fallthrough() {
+        // the entry block falls through to the loop header, which jumps to
+        // the body, and the body jumps back to the header
+        let code = compile(&lower(parse(":= x 1 $while x {$print x}").unwrap()));
+        let jumps = code.iter().filter(|op| matches!(op, Op::Jump(_))).count();
+        assert_eq!(jumps, 2, "unexpected code: {code:?}");
+    }
+}
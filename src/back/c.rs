@@ -0,0 +1,619 @@
+//! A C back-end: tiny IR is translated to a C program, which any C99 compiler
+//! can then turn into an executable.
+//!
+//! smol values are integers or floats depending on what the program computes
+//! and reads, so every variable is a tagged `num` rather than a plain `long`,
+//! and a small runtime at the top of the output implements the arithmetic, the
+//! input, and the output the way the TIR interpreter does.  Each block becomes
+//! a C label and the terminators become `goto`s.
+
+use std::fmt::Write;
+
+use crate::common::*;
+use crate::front::ast::{BOp, Number};
+use crate::middle::dom::reverse_postorder;
+use crate::middle::tir::{self, Instruction, Terminator};
+
+// The runtime that the generated code calls
+const RUNTIME: &str = r#"#include <errno.h>
+#include <math.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef struct {
+    int is_float;
+    int64_t i;
+    double f;
+} num;
+
+enum smol_op {
+    SMOL_MUL, SMOL_DIV, SMOL_MOD, SMOL_POW, SMOL_ADD, SMOL_SUB, SMOL_LT,
+    SMOL_GT, SMOL_EQ, SMOL_LE, SMOL_GE, SMOL_NE, SMOL_AND, SMOL_OR, SMOL_MIN,
+    SMOL_MAX, SMOL_SHL, SMOL_SHR, SMOL_BAND, SMOL_BOR, SMOL_BXOR
+};
+
+static num smol_int(int64_t i) {
+    num n = {0, i, 0.0};
+    return n;
+}
+
+static num smol_float(double f) {
+    num n = {1, 0, f};
+    return n;
+}
+
+static int smol_is_true(num n) {
+    return n.is_float ? n.f != 0.0 : n.i != 0;
+}
+
+static void smol_error(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "%s\n", msg);
+    exit(1);
+}
+
+static void smol_assert(num n, const char *msg) {
+    if (!smol_is_true(n)) smol_error(msg);
+}
+
+/* integer arithmetic wraps around, so it is done on unsigned integers */
+static int64_t smol_wrap(uint64_t u) {
+    return u > INT64_MAX ? -(int64_t)(~u) - 1 : (int64_t)u;
+}
+
+/* raise to a non-negative power by repeated squaring, squaring the base only
+   if a higher bit of the exponent needs it, so that it overflows only if the
+   result does */
+static int64_t smol_pow(int64_t base, int64_t exp) {
+    int64_t result = 1;
+    for (; exp > 0; exp >>= 1) {
+        if ((exp & 1) && __builtin_mul_overflow(result, base, &result))
+            smol_error("Runtime error: integer overflow.");
+        if (exp > 1 && __builtin_mul_overflow(base, base, &base))
+            smol_error("Runtime error: integer overflow.");
+    }
+    return result;
+}
+
+/* the integer part of a float, saturating like a cast in Rust */
+static int64_t smol_trunc(double f) {
+    if (f != f) return 0;
+    if (f >= 9223372036854775807.0) return INT64_MAX;
+    if (f <= -9223372036854775808.0) return INT64_MIN;
+    return (int64_t)f;
+}
+
+static num smol_arith(enum smol_op op, num l, num r) {
+    if (!l.is_float && !r.is_float) {
+        int64_t a = l.i, b = r.i;
+        switch (op) {
+        case SMOL_MUL: return smol_int(smol_wrap((uint64_t)a * (uint64_t)b));
+        case SMOL_DIV:
+        case SMOL_MOD:
+            if (b == 0) smol_error("Runtime error: division by zero.");
+            if (a == INT64_MIN && b == -1) return smol_int(op == SMOL_DIV ? a : 0);
+            return smol_int(op == SMOL_DIV ? a / b : a % b);
+        case SMOL_POW:
+            if (b < 0) smol_error("Runtime error: negative exponent.");
+            return smol_int(smol_pow(a, b));
+        case SMOL_ADD: return smol_int(smol_wrap((uint64_t)a + (uint64_t)b));
+        case SMOL_SUB: return smol_int(smol_wrap((uint64_t)a - (uint64_t)b));
+        case SMOL_LT: return smol_int(a < b);
+        case SMOL_GT: return smol_int(a > b);
+        case SMOL_EQ: return smol_int(a == b);
+        case SMOL_LE: return smol_int(a <= b);
+        case SMOL_GE: return smol_int(a >= b);
+        case SMOL_NE: return smol_int(a != b);
+        case SMOL_AND: return smol_int(a != 0 && b != 0);
+        case SMOL_OR: return smol_int(a != 0 || b != 0);
+        case SMOL_MIN: return smol_int(a < b ? a : b);
+        case SMOL_MAX: return smol_int(a > b ? a : b);
+        /* only the low 6 bits of the shift amount count, and shifting right
+           keeps the sign */
+        case SMOL_SHL: return smol_int(smol_wrap((uint64_t)a << (b & 63)));
+        case SMOL_SHR: return smol_int(a < 0 ? ~(~a >> (b & 63)) : a >> (b & 63));
+        case SMOL_BAND: return smol_int(a & b);
+        case SMOL_BOR: return smol_int(a | b);
+        case SMOL_BXOR: return smol_int(a ^ b);
+        }
+    } else {
+        double a = l.is_float ? l.f : (double)l.i;
+        double b = r.is_float ? r.f : (double)r.i;
+        switch (op) {
+        case SMOL_MUL: return smol_float(a * b);
+        case SMOL_DIV: return smol_float(a / b);
+        case SMOL_MOD: return smol_float(fmod(a, b));
+        case SMOL_POW: return smol_float(pow(a, b));
+        case SMOL_ADD: return smol_float(a + b);
+        case SMOL_SUB: return smol_float(a - b);
+        case SMOL_LT: return smol_int(a < b);
+        case SMOL_GT: return smol_int(a > b);
+        case SMOL_EQ: return smol_int(a == b);
+        case SMOL_LE: return smol_int(a <= b);
+        case SMOL_GE: return smol_int(a >= b);
+        case SMOL_NE: return smol_int(a != b);
+        case SMOL_AND: return smol_int(a != 0.0 && b != 0.0);
+        case SMOL_OR: return smol_int(a != 0.0 || b != 0.0);
+        case SMOL_MIN: return smol_float(fmin(a, b));
+        case SMOL_MAX: return smol_float(fmax(a, b));
+        case SMOL_SHL: return smol_float(a * exp2(b));
+        case SMOL_SHR: return smol_float(a / exp2(b));
+        /* the bitwise operators work on the integer parts */
+        case SMOL_BAND:
+        case SMOL_BOR:
+        case SMOL_BXOR:
+            return smol_arith(op, smol_int(smol_trunc(a)), smol_int(smol_trunc(b)));
+        }
+    }
+    abort();
+}
+
+static num smol_neg(num n) {
+    return n.is_float ? smol_float(-n.f) : smol_int(smol_wrap(-(uint64_t)n.i));
+}
+
+/* print floats like the interpreter: without an exponent, with the fewest
+   decimals that read back as the same float, and at least one decimal */
+static void smol_printc(num n) {
+    static char buf[1024];
+    int decimals;
+    if (!n.is_float) {
+        printf("%lld", (long long)n.i);
+    } else if (isnan(n.f)) {
+        printf("NaN");
+    } else if (isinf(n.f)) {
+        printf(n.f < 0 ? "-inf" : "inf");
+    } else {
+        for (decimals = 1; decimals < 400; decimals++) {
+            snprintf(buf, sizeof buf, "%.*f", decimals, n.f);
+            if (strtod(buf, NULL) == n.f) break;
+        }
+        printf("%s", buf);
+    }
+}
+
+static void smol_print(num n) {
+    smol_printc(n);
+    putchar('\n');
+}
+
+/* read a whitespace-separated word, and parse it as an integer or a float */
+static num smol_read(void) {
+    static char word[1024];
+    char *end;
+    long long i;
+    double f;
+    if (scanf("%1023s", word) != 1)
+        smol_error("Runtime error: reached the end of the input while reading a number.");
+    errno = 0;
+    i = strtoll(word, &end, 10);
+    if (*end == '\0' && errno == 0) return smol_int(i);
+    f = strtod(word, &end);
+    if (*end == '\0') return smol_float(f);
+    fflush(stdout);
+    fprintf(stderr, "Runtime error: expected a number in the input, found `%s`.\n", word);
+    exit(1);
+}
+"#;
+
+/// Translate a well-formed program (see [crate::middle::verify]) to C.  The
+/// blocks that cannot be reached from `entry` are left out.
+///
+/// The variables, the functions, and the labels get the prefixes `v_`, `f_`,
+/// and `l_` so that they cannot clash with C keywords or with the runtime.
+pub fn emit_c(program: &tir::Program) -> String {
+    let mut c = String::from(RUNTIME);
+
+    writeln!(c).unwrap();
+    for (name, func) in &program.funcs {
+        writeln!(c, "{};", signature(*name, &func.params)).unwrap();
+    }
+
+    for (name, func) in &program.funcs {
+        writeln!(c).unwrap();
+        writeln!(c, "{} {{", signature(*name, &func.params)).unwrap();
+        emit_body(&mut c, &func.body, &func.params);
+        writeln!(c, "}}").unwrap();
+    }
+
+    writeln!(c).unwrap();
+    writeln!(c, "int main(void) {{").unwrap();
+    emit_body(&mut c, program, &[]);
+    writeln!(c, "}}").unwrap();
+    c
+}
+
+// The C declaration of a function
+fn signature(name: Id, params: &[Id]) -> String {
+    let params: Vec<_> = params.iter().map(|x| format!("num v_{x}")).collect();
+    let params = if params.is_empty() {
+        "void".to_string()
+    } else {
+        params.join(", ")
+    };
+    format!("static num f_{name}({params})")
+}
+
+// Write the variables and the blocks of the main program or a function
+fn emit_body(c: &mut String, body: &tir::Program, params: &[Id]) {
+    for x in &body.decl {
+        if !params.contains(x) {
+            writeln!(c, "    num v_{x} = smol_int(0);").unwrap();
+        }
+    }
+
+    for lbl in reverse_postorder(body) {
+        let block = &body.block[&lbl];
+        // a label must be followed by a statement, so give it an empty one
+        writeln!(c, "l_{lbl}:;").unwrap();
+        for insn in &block.insn {
+            if !matches!(insn, Instruction::Phi { .. } | Instruction::Nop) {
+                writeln!(c, "    {}", insn_to_c(insn)).unwrap();
+            }
+        }
+        match block.term {
+            Terminator::Exit => writeln!(c, "    exit(0);").unwrap(),
+            Terminator::Return(x) => writeln!(c, "    return v_{x};").unwrap(),
+            Terminator::Jump(target) => {
+                writeln!(c, "    {}", edge(body, lbl, target)).unwrap();
+            }
+            Terminator::Branch { guard, tt, ff } => {
+                writeln!(
+                    c,
+                    "    if (smol_is_true(v_{guard})) {}",
+                    edge(body, lbl, tt)
+                )
+                .unwrap();
+                writeln!(c, "    else {}", edge(body, lbl, ff)).unwrap();
+            }
+            Terminator::Switch {
+                scrutinee,
+                ref cases,
+                default,
+            } => {
+                // the cases compare like `eq`, so that a float can match too
+                for &(n, target) in cases {
+                    writeln!(
+                        c,
+                        "    if (smol_is_true(smol_arith(SMOL_EQ, v_{scrutinee}, {}))) {}",
+                        const_to_c(Number::Int(n)),
+                        edge(body, lbl, target)
+                    )
+                    .unwrap();
+                }
+                writeln!(c, "    {}", edge(body, lbl, default)).unwrap();
+            }
+        }
+    }
+}
+
+// The C statement that goes from `pred` to `succ`.  The phi nodes of `succ`
+// take their values from `pred` at the same time, so the values are copied to
+// temporaries first.
+fn edge(body: &tir::Program, pred: Id, succ: Id) -> String {
+    let mut loads = vec![];
+    let mut stores = vec![];
+    for insn in &body.block[&succ].insn {
+        let Instruction::Phi { dst, srcs } = insn else {
+            break;
+        };
+        let (_, src) = srcs
+            .iter()
+            .find(|(p, _)| *p == pred)
+            .expect("a phi node should have a value for each predecessor");
+        let tmp = loads.len();
+        loads.push(format!("num t{tmp} = v_{src};"));
+        stores.push(format!("v_{dst} = t{tmp};"));
+    }
+
+    if loads.is_empty() {
+        format!("goto l_{succ};")
+    } else {
+        format!(
+            "{{ {} {} goto l_{succ}; }}",
+            loads.join(" "),
+            stores.join(" ")
+        )
+    }
+}
+
+fn insn_to_c(insn: &Instruction) -> String {
+    use Instruction::*;
+    match insn {
+        Copy { dst, src } => format!("v_{dst} = v_{src};"),
+        Const { dst, src } => format!("v_{dst} = {};", const_to_c(*src)),
+        Arith { op, dst, lhs, rhs } => {
+            format!("v_{dst} = smol_arith({}, v_{lhs}, v_{rhs});", op_to_c(*op))
+        }
+        Neg { dst, src } => format!("v_{dst} = smol_neg(v_{src});"),
+        Read(dst) => format!("v_{dst} = smol_read();"),
+        Print(src) => format!("smol_print(v_{src});"),
+        PrintStr(s) => format!("puts({});", string_to_c(s)),
+        PrintNoNl(src) => format!("smol_printc(v_{src});"),
+        PrintStrNoNl(s) => format!("fputs({}, stdout);", string_to_c(s)),
+        Assert(src, assertion) => {
+            let msg = format!("Runtime error: assertion failed at {assertion}.");
+            format!("smol_assert(v_{src}, {});", string_to_c(&msg))
+        }
+        Call { dst, func, args } => {
+            let args: Vec<_> = args.iter().map(|x| format!("v_{x}")).collect();
+            format!("v_{dst} = f_{func}({});", args.join(", "))
+        }
+        Phi { .. } => unreachable!("phi nodes are translated on the edges"),
+        Nop => unreachable!("no-ops are skipped"),
+    }
+}
+
+fn const_to_c(n: Number) -> String {
+    match n {
+        // the most negative integer has no literal in C
+        Number::Int(i64::MIN) => "smol_int(INT64_MIN)".to_string(),
+        Number::Int(n) => format!("smol_int(INT64_C({n}))"),
+        Number::Float(x) if x.is_nan() => "smol_float(NAN)".to_string(),
+        Number::Float(x) if x.is_infinite() && x > 0.0 => "smol_float(INFINITY)".to_string(),
+        Number::Float(x) if x.is_infinite() => "smol_float(-INFINITY)".to_string(),
+        // the exponent form is the shortest one that reads back exactly
+        Number::Float(x) => format!("smol_float({x:e})"),
+    }
+}
+
+fn op_to_c(op: BOp) -> &'static str {
+    use BOp::*;
+    match op {
+        Mul => "SMOL_MUL",
+        Div => "SMOL_DIV",
+        Mod => "SMOL_MOD",
+        Pow => "SMOL_POW",
+        Add => "SMOL_ADD",
+        Sub => "SMOL_SUB",
+        Lt => "SMOL_LT",
+        Gt => "SMOL_GT",
+        Eq => "SMOL_EQ",
+        Le => "SMOL_LE",
+        Ge => "SMOL_GE",
+        Ne => "SMOL_NE",
+        And => "SMOL_AND",
+        Or => "SMOL_OR",
+        Min => "SMOL_MIN",
+        Max => "SMOL_MAX",
+        Shl => "SMOL_SHL",
+        Shr => "SMOL_SHR",
+        BitAnd => "SMOL_BAND",
+        BitOr => "SMOL_BOR",
+        BitXor => "SMOL_BXOR",
+    }
+}
+
+// A C string literal.  Everything other than printable ASCII is written as
+// octal escapes of its UTF-8 bytes, and so is `?` to avoid trigraphs.
+fn string_to_c(s: &str) -> String {
+    let mut lit = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' => lit.push_str("\\\""),
+            b'\\' => lit.push_str("\\\\"),
+            b'?' => lit.push_str("\\?"),
+            b' '..=b'~' => lit.push(b as char),
+            b => write!(lit, "\\{b:03o}").unwrap(),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    use super::*;
+    use crate::front::{lower, parse};
+    use crate::middle::{interp, optimize, to_ssa};
+
+    // SECTION: helpers
+
+    // Parse and lower a program, and translate it to C
+    fn emit_src(src: &str) -> String {
+        emit_c(&lower(parse(src).unwrap()))
+    }
+
+    // Check that the braces and the parentheses of the C code are balanced
+    fn assert_balanced(c: &str) {
+        let mut stack = vec![];
+        let mut in_string = false;
+        let mut escaped = false;
+        for ch in c.chars() {
+            if in_string {
+                match ch {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match ch {
+                '"' => in_string = true,
+                '(' | '{' => stack.push(ch),
+                ')' => assert_eq!(stack.pop(), Some('('), "unbalanced C code:\n{c}"),
+                '}' => assert_eq!(stack.pop(), Some('{'), "unbalanced C code:\n{c}"),
+                _ => {}
+            }
+        }
+        assert!(stack.is_empty() && !in_string, "unbalanced C code:\n{c}");
+    }
+
+    // Compile the C code with the system C compiler and run it, or return
+    // `None` if there is no C compiler.  Returns the output and whether the
+    // program succeeded.
+    fn run_c(c: &str, input: &str) -> Option<(String, bool)> {
+        let dir = std::env::temp_dir().join(format!(
+            "smol-c-test-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (src, exe) = (dir.join("main.c"), dir.join("main"));
+        std::fs::write(&src, c).unwrap();
+
+        let compiled = Command::new("cc")
+            .arg("-std=c99")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&src)
+            .arg("-lm")
+            .status()
+            .ok()?;
+        assert!(compiled.success(), "the C code does not compile:\n{c}");
+
+        let mut child = Command::new(&exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut child.stdin.take().unwrap(), input.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        Some((
+            String::from_utf8(output.stdout).unwrap(),
+            output.status.success(),
+        ))
+    }
+
+    // Check that the C code of the program behaves like the TIR interpreter
+    fn assert_same_behavior(program: &tir::Program, input: &str) {
+        let mut expected = vec![];
+        let ok = interp::run(program, input.as_bytes(), &mut expected).is_ok();
+        let expected = (String::from_utf8(expected).unwrap(), ok);
+        if let Some(found) = run_c(&emit_c(program), input) {
+            assert_eq!(found, expected, "the C code differs for\n{program}");
+        }
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn labels_and_gotos() {
+        let c = emit_src("$read n $while n {$print n := n - n 1} $print \"done\"");
+        assert_balanced(&c);
+        for line in [
+            "int main(void) {",
+            "    num v_n = smol_int(0);",
+            "l_entry:;",
+            "    v_n = smol_read();",
+            "l_lbl1:;",
+            "    if (smol_is_true(v_n)) goto l_lbl2;",
+            "    else goto l_lbl3;",
+            "l_lbl2:;",
+            "    smol_print(v_n);",
+            "    goto l_lbl1;",
+            "l_lbl3:;",
+            "    puts(\"done\");",
+            "    exit(0);",
+        ] {
+            assert!(c.lines().any(|l| l == line), "{line:?} is missing in\n{c}");
+        }
+    }
+
+    #[test]
+    fn functions() {
+        let c =
+            emit_src("$fn add(a, b) {:= add + a b} $fn zero() {:= zero 0} $print add(1, zero())");
+        assert_balanced(&c);
+        assert!(c.contains("static num f_add(num v_a, num v_b);"));
+        assert!(c.contains("static num f_zero(void) {"));
+        assert!(c.contains("= f_add(v_"));
+        assert!(c.contains("    return v_add;"));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(const_to_c(Number::Int(-5)), "smol_int(INT64_C(-5))");
+        assert_eq!(const_to_c(Number::Int(i64::MIN)), "smol_int(INT64_MIN)");
+        assert_eq!(const_to_c(Number::Float(0.25)), "smol_float(2.5e-1)");
+        assert_eq!(const_to_c(Number::Float(f64::NAN)), "smol_float(NAN)");
+        assert_eq!(
+            string_to_c("say \"hi\"?\n\\ é"),
+            "\"say \\\"hi\\\"\\?\\012\\\\ \\303\\251\""
+        );
+    }
+
+    #[test]
+    fn compiled_behavior() {
+        let programs = [
+            (
+                "$read a $read b $print + a b $print / a b $print % a b $print ~ a",
+                "7\n2\n",
+            ),
+            (
+                "$read a $read b $print / a b $print * a b $print < a b $print - 0.5 0.25",
+                "1\n3.5\n",
+            ),
+            ("$read a $print / 1 a $print \"unreachable\"", "0\n"),
+            ("$read a $assert < a 10 $print a", "3\n"),
+            ("$read a $assert < a 10 $print a", "12\n"),
+            ("$read a $read b $print ^ a b $print ^ a 0.5", "3\n41\n"),
+            ("$read a $read b $print ^ a b $print ^ a 0.5", "2.0\n-2\n"),
+            ("$read a $read b $print ^ a b", "2\n-2\n"),
+            ("$read a $read b $print ^ a b", "-2\n63\n"),
+            ("$read a $read b $print $min a b $print $max a b", "3\n-7\n"),
+            (
+                "$read a $read b $print $min a b $print $max a b",
+                "0.5\n2\n",
+            ),
+            (
+                "$read a $read b $print $band a b $print $bor a b $print $bxor a b",
+                "-12\n10\n",
+            ),
+            (
+                "$read a $read b $print $band a b $print $bor a b $print $bxor a b",
+                "12.9\n-1e300\n",
+            ),
+            ("$read a $read b $print << a b $print >> a b", "-5\n66\n"),
+            ("$read a", "oops\n"),
+            (
+                "$read a $printc \"a = \" $printc a $printc \" \" $print / a 2",
+                "3\n",
+            ),
+            ("$read a", ""),
+            (
+                "$print * 9223372036854775807 2 $print / 1.0 0 $print 0.1",
+                "",
+            ),
+            (
+                "$fn fact(n) {$if < n 2 {:= fact 1} {:= fact * n fact(- n 1)}} $print fact(20)",
+                "",
+            ),
+        ];
+        for (src, input) in programs {
+            assert_same_behavior(&lower(parse(src).unwrap()), input);
+        }
+    }
+
+    #[test]
+    fn compiled_ssa() {
+        let src = ":= a 1 := b 2 $read n $while n {:= t a := a b := b t := n - n 1} \
+                   $print a $print b";
+        let mut program = lower(parse(src).unwrap());
+        to_ssa(&mut program);
+        assert_balanced(&emit_c(&program));
+        assert_same_behavior(&program, "3\n");
+        assert_same_behavior(&optimize(program), "4\n");
+    }
+
+    #[test]
+    fn compiled_shifts() {
+        // the optimizer turns these into shifts
+        let src = "$read x := c - < x 0 < x 10 $print * c 8 $print / $max * c 3 0 4";
+        let program = optimize(lower(parse(src).unwrap()));
+        let c = emit_c(&program);
+        for op in ["SMOL_SHL", "SMOL_SHR"] {
+            assert!(c.contains(&format!("smol_arith({op}, ")), "no {op} in\n{c}");
+        }
+        for input in ["-3\n", "5\n", "20\n"] {
+            assert_same_behavior(&program, input);
+        }
+    }
+}
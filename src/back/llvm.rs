@@ -0,0 +1,813 @@
+//! An LLVM back-end: tiny IR is translated to textual LLVM IR, so that LLVM
+//! can optimize it and generate machine code for it.
+//!
+//! Every variable lives in an `i64` stack slot (LLVM's `mem2reg` pass turns
+//! these into SSA values), and each block becomes an LLVM basic block.  The
+//! values are integers only: float constants are rejected, and the input is
+//! read with `scanf("%lld")`, so that a float in the input is a runtime error.
+
+use std::fmt::Write;
+
+use crate::common::*;
+use crate::front::ast::{BOp, Number};
+use crate::middle::dom::reverse_postorder;
+use crate::middle::tir::{self, Instruction, Terminator, ENTRY_LABEL};
+
+// The declarations and the helper functions that the generated code calls.
+// The helpers check for the runtime errors and report them like the TIR
+// interpreter does.
+const RUNTIME: &str = r#"@fmt.print = private constant [6 x i8] c"%lld\0A\00"
+@fmt.printc = private constant [5 x i8] c"%lld\00"
+@fmt.str = private constant [3 x i8] c"%s\00"
+@fmt.read = private constant [5 x i8] c"%lld\00"
+@msg.div = private constant [33 x i8] c"Runtime error: division by zero.\0A"
+@msg.pow = private constant [34 x i8] c"Runtime error: negative exponent.\0A"
+@msg.overflow = private constant [33 x i8] c"Runtime error: integer overflow.\0A"
+@msg.read = private constant [47 x i8] c"Runtime error: expected a number in the input.\0A"
+@msg.int = private constant [49 x i8] c"Runtime error: expected an integer in the input.\0A"
+
+declare i32 @printf(ptr, ...)
+declare i32 @scanf(ptr, ...)
+declare i32 @puts(ptr)
+declare i32 @getchar()
+declare i32 @isspace(i32)
+declare i32 @fflush(ptr)
+declare i64 @write(i32, ptr, i64)
+declare void @exit(i32)
+declare { i64, i1 } @llvm.smul.with.overflow.i64(i64, i64)
+
+define private void @smol.error(ptr %msg, i64 %len) {
+  call i32 @fflush(ptr null)
+  call i64 @write(i32 2, ptr %msg, i64 %len)
+  call void @exit(i32 1)
+  unreachable
+}
+
+define private void @smol.assert(i64 %v, ptr %msg, i64 %len) {
+  %false = icmp eq i64 %v, 0
+  br i1 %false, label %error, label %done
+error:
+  call void @smol.error(ptr %msg, i64 %len)
+  unreachable
+done:
+  ret void
+}
+
+define private i64 @smol.div(i64 %a, i64 %b) {
+  %zero = icmp eq i64 %b, 0
+  br i1 %zero, label %error, label %check
+error:
+  call void @smol.error(ptr @msg.div, i64 33)
+  unreachable
+check:
+  %minus_one = icmp eq i64 %b, -1
+  br i1 %minus_one, label %negate, label %divide
+negate:
+  %neg = sub i64 0, %a
+  ret i64 %neg
+divide:
+  %quot = sdiv i64 %a, %b
+  ret i64 %quot
+}
+
+define private i64 @smol.rem(i64 %a, i64 %b) {
+  %zero = icmp eq i64 %b, 0
+  br i1 %zero, label %error, label %check
+error:
+  call void @smol.error(ptr @msg.div, i64 33)
+  unreachable
+check:
+  %minus_one = icmp eq i64 %b, -1
+  br i1 %minus_one, label %done, label %divide
+done:
+  ret i64 0
+divide:
+  %rem = srem i64 %a, %b
+  ret i64 %rem
+}
+
+define private i64 @smol.pow(i64 %base, i64 %exp) {
+entry:
+  %negative = icmp slt i64 %exp, 0
+  br i1 %negative, label %error, label %loop
+error:
+  call void @smol.error(ptr @msg.pow, i64 34)
+  unreachable
+overflow:
+  call void @smol.error(ptr @msg.overflow, i64 33)
+  unreachable
+loop:
+  %b = phi i64 [ %base, %entry ], [ %b.next, %square ]
+  %e = phi i64 [ %exp, %entry ], [ %e.next, %square ]
+  %r = phi i64 [ 1, %entry ], [ %r.next, %square ]
+  %more = icmp sgt i64 %e, 0
+  br i1 %more, label %step, label %done
+step:
+  %odd = trunc i64 %e to i1
+  br i1 %odd, label %mul, label %next
+mul:
+  %r.pair = call { i64, i1 } @llvm.smul.with.overflow.i64(i64 %r, i64 %b)
+  %r.mul = extractvalue { i64, i1 } %r.pair, 0
+  %r.over = extractvalue { i64, i1 } %r.pair, 1
+  br i1 %r.over, label %overflow, label %next
+next:
+  %r.next = phi i64 [ %r, %step ], [ %r.mul, %mul ]
+  %e.next = ashr i64 %e, 1
+  %last = icmp eq i64 %e.next, 0
+  br i1 %last, label %done, label %square
+square:
+  %b.pair = call { i64, i1 } @llvm.smul.with.overflow.i64(i64 %b, i64 %b)
+  %b.next = extractvalue { i64, i1 } %b.pair, 0
+  %b.over = extractvalue { i64, i1 } %b.pair, 1
+  br i1 %b.over, label %overflow, label %loop
+done:
+  %result = phi i64 [ %r, %loop ], [ %r.next, %next ]
+  ret i64 %result
+}
+
+define private i64 @smol.read() {
+  %slot = alloca i64
+  %count = call i32 (ptr, ...) @scanf(ptr @fmt.read, ptr %slot)
+  %ok = icmp eq i32 %count, 1
+  br i1 %ok, label %end, label %error
+error:
+  call void @smol.error(ptr @msg.read, i64 47)
+  unreachable
+end:
+  %c = call i32 @getchar()
+  %eof = icmp eq i32 %c, -1
+  %space = call i32 @isspace(i32 %c)
+  %is.space = icmp ne i32 %space, 0
+  %int = or i1 %eof, %is.space
+  br i1 %int, label %done, label %float
+float:
+  call void @smol.error(ptr @msg.int, i64 49)
+  unreachable
+done:
+  %n = load i64, ptr %slot
+  ret i64 %n
+}
+
+define private void @smol.print(i64 %n) {
+  call i32 (ptr, ...) @printf(ptr @fmt.print, i64 %n)
+  ret void
+}
+
+define private void @smol.printc(i64 %n) {
+  call i32 (ptr, ...) @printf(ptr @fmt.printc, i64 %n)
+  ret void
+}
+"#;
+
+/// Translate a well-formed program (see [crate::middle::verify]) that
+/// computes only with integers to LLVM IR.  The blocks that cannot be reached
+/// from `entry` are left out.
+///
+/// The variables, the functions, and the blocks are named `%v.x`, `@f.name`,
+/// and `%l.lbl`, so that they cannot clash with each other, with the C
+/// library, or with the helpers.
+///
+/// Fails if the program has a float constant.
+pub fn emit_llvm(program: &tir::Program) -> Result<String, String> {
+    if let Some(insn) = float_const(program) {
+        return Err(format!(
+            "the LLVM back-end does not support floats, found `{insn}`."
+        ));
+    }
+
+    let mut emitter = Emitter {
+        globals: String::new(),
+        code: String::new(),
+        strings: 0,
+        temps: 0,
+    };
+
+    for (name, func) in &program.funcs {
+        let params: Vec<_> = func.params.iter().map(|x| format!("i64 %p.{x}")).collect();
+        writeln!(emitter.code).unwrap();
+        writeln!(
+            emitter.code,
+            "define i64 @f.{name}({}) {{",
+            params.join(", ")
+        )
+        .unwrap();
+        emitter.emit_body(&func.body, &func.params);
+        writeln!(emitter.code, "}}").unwrap();
+    }
+
+    writeln!(emitter.code).unwrap();
+    writeln!(emitter.code, "define i32 @main() {{").unwrap();
+    emitter.emit_body(program, &[]);
+    writeln!(emitter.code, "}}").unwrap();
+
+    Ok(format!("{RUNTIME}{}{}", emitter.globals, emitter.code))
+}
+
+// The first instruction with a float constant in the program or its functions
+fn float_const(program: &tir::Program) -> Option<&Instruction> {
+    std::iter::once(program)
+        .chain(program.funcs.values().map(|func| &func.body))
+        .flat_map(|body| body.block.values())
+        .flat_map(|block| &block.insn)
+        .find(|insn| {
+            matches!(
+                insn,
+                Instruction::Const {
+                    src: Number::Float(_),
+                    ..
+                }
+            )
+        })
+}
+
+// The state of the translation
+struct Emitter {
+    // the string constants
+    globals: String,
+    // the functions
+    code: String,
+    // the number of string constants so far
+    strings: usize,
+    // the number of temporaries so far in the current function
+    temps: usize,
+}
+
+impl Emitter {
+    // Write the blocks of the main program or a function.  The first block
+    // allocates the variables and stores the parameters, because the entry
+    // block of an LLVM function cannot be a jump target.
+    fn emit_body(&mut self, body: &tir::Program, params: &[Id]) {
+        self.temps = 0;
+        writeln!(self.code, "start:").unwrap();
+        for x in params
+            .iter()
+            .chain(body.decl.iter().filter(|x| !params.contains(x)))
+        {
+            writeln!(self.code, "  %v.{x} = alloca i64").unwrap();
+        }
+        for x in &body.decl {
+            let init = if params.contains(x) {
+                format!("%p.{x}")
+            } else {
+                "0".to_string()
+            };
+            writeln!(self.code, "  store i64 {init}, ptr %v.{x}").unwrap();
+        }
+        for x in params.iter().filter(|x| !body.decl.contains(x)) {
+            writeln!(self.code, "  store i64 %p.{x}, ptr %v.{x}").unwrap();
+        }
+        writeln!(self.code, "  br label %l.{ENTRY_LABEL}").unwrap();
+
+        for lbl in reverse_postorder(body) {
+            let block = &body.block[&lbl];
+            writeln!(self.code, "l.{lbl}:").unwrap();
+            for insn in &block.insn {
+                if !matches!(insn, Instruction::Phi { .. }) {
+                    self.emit_insn(insn);
+                }
+            }
+
+            // the edges that copy values for phi nodes get blocks of their
+            // own
+            let mut edges = vec![];
+            match block.term {
+                Terminator::Exit => {
+                    writeln!(self.code, "  call void @exit(i32 0)").unwrap();
+                    writeln!(self.code, "  unreachable").unwrap();
+                }
+                Terminator::Return(x) => {
+                    let v = self.load(x);
+                    writeln!(self.code, "  ret i64 {v}").unwrap();
+                }
+                Terminator::Jump(target) => {
+                    let target = edge_target(body, lbl, target, &mut edges);
+                    writeln!(self.code, "  br label %{target}").unwrap();
+                }
+                Terminator::Branch { guard, tt, ff } => {
+                    let v = self.load(guard);
+                    let cond = self.temp();
+                    writeln!(self.code, "  {cond} = icmp ne i64 {v}, 0").unwrap();
+                    let tt = edge_target(body, lbl, tt, &mut edges);
+                    let ff = edge_target(body, lbl, ff, &mut edges);
+                    writeln!(self.code, "  br i1 {cond}, label %{tt}, label %{ff}").unwrap();
+                }
+                Terminator::Switch {
+                    scrutinee,
+                    ref cases,
+                    default,
+                } => {
+                    let v = self.load(scrutinee);
+                    let default = edge_target(body, lbl, default, &mut edges);
+                    let mut arms = vec![];
+                    for &(n, target) in cases {
+                        let target = edge_target(body, lbl, target, &mut edges);
+                        arms.push(format!("i64 {n}, label %{target}"));
+                    }
+                    writeln!(
+                        self.code,
+                        "  switch i64 {v}, label %{default} [{}]",
+                        arms.join(" ")
+                    )
+                    .unwrap();
+                }
+            }
+
+            for Edge { name, moves, succ } in edges {
+                // load all the sources before storing any of them
+                writeln!(self.code, "{name}:").unwrap();
+                let vals: Vec<_> = moves.iter().map(|(_, src)| self.load(*src)).collect();
+                for ((dst, _), v) in moves.iter().zip(vals) {
+                    writeln!(self.code, "  store i64 {v}, ptr %v.{dst}").unwrap();
+                }
+                writeln!(self.code, "  br label %l.{succ}").unwrap();
+            }
+        }
+    }
+
+    fn emit_insn(&mut self, insn: &Instruction) {
+        use Instruction::*;
+        match insn {
+            Copy { dst, src } => {
+                let v = self.load(*src);
+                self.store(*dst, &v);
+            }
+            Const { dst, src } => {
+                let Number::Int(n) = src else {
+                    unreachable!("`emit_llvm` rejects floats");
+                };
+                self.store(*dst, &n.to_string());
+            }
+            Arith { op, dst, lhs, rhs } => {
+                let (l, r) = (self.load(*lhs), self.load(*rhs));
+                let v = self.arith(*op, &l, &r);
+                self.store(*dst, &v);
+            }
+            Neg { dst, src } => {
+                let v = self.load(*src);
+                let neg = self.temp();
+                writeln!(self.code, "  {neg} = sub i64 0, {v}").unwrap();
+                self.store(*dst, &neg);
+            }
+            Read(dst) => {
+                let v = self.temp();
+                writeln!(self.code, "  {v} = call i64 @smol.read()").unwrap();
+                self.store(*dst, &v);
+            }
+            Print(src) => {
+                let v = self.load(*src);
+                writeln!(self.code, "  call void @smol.print(i64 {v})").unwrap();
+            }
+            PrintStr(s) => {
+                let name = self.string(s);
+                writeln!(self.code, "  call i32 @puts(ptr {name})").unwrap();
+            }
+            PrintNoNl(src) => {
+                let v = self.load(*src);
+                writeln!(self.code, "  call void @smol.printc(i64 {v})").unwrap();
+            }
+            PrintStrNoNl(s) => {
+                let name = self.string(s);
+                writeln!(
+                    self.code,
+                    "  call i32 (ptr, ...) @printf(ptr @fmt.str, ptr {name})"
+                )
+                .unwrap();
+            }
+            Assert(src, assertion) => {
+                let v = self.load(*src);
+                let msg = format!("Runtime error: assertion failed at {assertion}.\n");
+                let name = self.string(&msg);
+                writeln!(
+                    self.code,
+                    "  call void @smol.assert(i64 {v}, ptr {name}, i64 {})",
+                    msg.len()
+                )
+                .unwrap();
+            }
+            Nop => {}
+            Call { dst, func, args } => {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|x| format!("i64 {}", self.load(*x)))
+                    .collect();
+                let v = self.temp();
+                writeln!(self.code, "  {v} = call i64 @f.{func}({})", args.join(", ")).unwrap();
+                self.store(*dst, &v);
+            }
+            Phi { .. } => unreachable!("phi nodes are translated on the edges"),
+        }
+    }
+
+    // Compute a binary operation, and return the temporary with the result
+    fn arith(&mut self, op: BOp, l: &str, r: &str) -> String {
+        use BOp::*;
+        let v = self.temp();
+        let cmp = match op {
+            Mul | Add | Sub | BitAnd | BitOr | BitXor => {
+                // without `nsw`, these wrap around on overflow
+                let insn = match op {
+                    Mul => "mul",
+                    Add => "add",
+                    Sub => "sub",
+                    BitAnd => "and",
+                    BitOr => "or",
+                    _ => "xor",
+                };
+                writeln!(self.code, "  {v} = {insn} i64 {l}, {r}").unwrap();
+                return v;
+            }
+            Div | Mod | Pow => {
+                let helper = match op {
+                    Div => "div",
+                    Mod => "rem",
+                    _ => "pow",
+                };
+                writeln!(
+                    self.code,
+                    "  {v} = call i64 @smol.{helper}(i64 {l}, i64 {r})"
+                )
+                .unwrap();
+                return v;
+            }
+            Shl | Shr => {
+                // only the low 6 bits of the shift amount count
+                let amount = self.temp();
+                writeln!(self.code, "  {amount} = and i64 {r}, 63").unwrap();
+                let insn = if op == Shl { "shl" } else { "ashr" };
+                writeln!(self.code, "  {v} = {insn} i64 {l}, {amount}").unwrap();
+                return v;
+            }
+            Min | Max => {
+                let cmp = self.temp();
+                let cond = if op == Min { "slt" } else { "sgt" };
+                writeln!(self.code, "  {cmp} = icmp {cond} i64 {l}, {r}").unwrap();
+                writeln!(self.code, "  {v} = select i1 {cmp}, i64 {l}, i64 {r}").unwrap();
+                return v;
+            }
+            And | Or => {
+                let (lt, rt) = (self.temp(), self.temp());
+                writeln!(self.code, "  {lt} = icmp ne i64 {l}, 0").unwrap();
+                writeln!(self.code, "  {rt} = icmp ne i64 {r}, 0").unwrap();
+                let insn = if op == And { "and" } else { "or" };
+                writeln!(self.code, "  {v} = {insn} i1 {lt}, {rt}").unwrap();
+                v
+            }
+            Lt | Gt | Eq | Le | Ge | Ne => {
+                let cond = match op {
+                    Lt => "slt",
+                    Gt => "sgt",
+                    Eq => "eq",
+                    Le => "sle",
+                    Ge => "sge",
+                    _ => "ne",
+                };
+                writeln!(self.code, "  {v} = icmp {cond} i64 {l}, {r}").unwrap();
+                v
+            }
+        };
+        // the truth values are the integers 0 and 1
+        let ext = self.temp();
+        writeln!(self.code, "  {ext} = zext i1 {cmp} to i64").unwrap();
+        ext
+    }
+
+    // Load a variable into a new temporary
+    fn load(&mut self, x: Id) -> String {
+        let v = self.temp();
+        writeln!(self.code, "  {v} = load i64, ptr %v.{x}").unwrap();
+        v
+    }
+
+    fn store(&mut self, x: Id, v: &str) {
+        writeln!(self.code, "  store i64 {v}, ptr %v.{x}").unwrap();
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps)
+    }
+
+    // Add a global for a string literal, and return its name
+    fn string(&mut self, s: &str) -> String {
+        let name = format!("@str.{}", self.strings);
+        self.strings += 1;
+        writeln!(
+            self.globals,
+            "{name} = private constant [{} x i8] {}",
+            s.len() + 1,
+            string_to_llvm(s)
+        )
+        .unwrap();
+        name
+    }
+}
+
+// A block on the edge to a block with phi nodes
+struct Edge {
+    name: String,
+    // the copies for the phi nodes, as (destination, source) pairs
+    moves: Vec<(Id, Id)>,
+    succ: Id,
+}
+
+// The block to go to from `pred` to `succ`.  If `succ` has phi nodes, this is
+// a new block that copies their sources from `pred`, which is added to
+// `edges`.
+fn edge_target(body: &tir::Program, pred: Id, succ: Id, edges: &mut Vec<Edge>) -> String {
+    let mut moves = vec![];
+    for insn in &body.block[&succ].insn {
+        let Instruction::Phi { dst, srcs } = insn else {
+            break;
+        };
+        let (_, src) = srcs
+            .iter()
+            .find(|(p, _)| *p == pred)
+            .expect("a phi node should have a value for each predecessor");
+        moves.push((*dst, *src));
+    }
+
+    if moves.is_empty() {
+        return format!("l.{succ}");
+    }
+    let name = format!("e.{pred}.{succ}");
+    // both arms of a branch may go to the same block
+    if !edges.iter().any(|e| e.name == name) {
+        edges.push(Edge {
+            name: name.clone(),
+            moves,
+            succ,
+        });
+    }
+    name
+}
+
+// An LLVM string constant with a terminating NUL.  Everything other than
+// printable ASCII is written as hex escapes of its UTF-8 bytes.
+fn string_to_llvm(s: &str) -> String {
+    let mut lit = String::from("c\"");
+    for b in s.bytes().chain([0]) {
+        match b {
+            b'"' | b'\\' => write!(lit, "\\{b:02X}").unwrap(),
+            b' '..=b'~' => lit.push(b as char),
+            b => write!(lit, "\\{b:02X}").unwrap(),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Output, Stdio};
+
+    use super::*;
+    use crate::front::{lower, parse};
+    use crate::middle::{interp, to_ssa};
+
+    // SECTION: helpers
+
+    // Parse and lower a program, and translate it to LLVM IR
+    fn emit_src(src: &str) -> String {
+        emit_llvm(&lower(parse(src).unwrap())).unwrap()
+    }
+
+    // The basic blocks of the function with the given name, as the label and
+    // the instructions of each block
+    fn blocks(ir: &str, func: &str) -> Vec<(String, Vec<String>)> {
+        let mut lines = ir
+            .lines()
+            .skip_while(|l| !l.starts_with(&format!("define {func}")))
+            .skip(1)
+            .take_while(|l| *l != "}");
+        let mut blocks = vec![];
+        for line in lines.by_ref() {
+            match line.strip_suffix(':') {
+                Some(label) => blocks.push((label.to_string(), vec![])),
+                None => blocks
+                    .last_mut()
+                    .expect("a function should start with a label")
+                    .1
+                    .push(line.trim().to_string()),
+            }
+        }
+        blocks
+    }
+
+    // Check that each block ends with a terminator and has no other
+    // terminators, and that each branch goes to a block of the function
+    fn assert_well_formed(ir: &str, func: &str) {
+        let blocks = blocks(ir, func);
+        let labels: Set<_> = blocks.iter().map(|(l, _)| format!("%{l}")).collect();
+        for (label, insns) in &blocks {
+            let is_term =
+                |i: &String| i.starts_with("br ") || i.starts_with("ret ") || i == "unreachable";
+            let last = insns.last().unwrap_or_else(|| panic!("{label} is empty"));
+            assert!(is_term(last), "{label} does not end with a terminator");
+            assert_eq!(insns.iter().filter(|i| is_term(i)).count(), 1);
+            if last.starts_with("br ") {
+                for target in last.split(", ").filter_map(|t| t.split("label ").nth(1)) {
+                    assert!(labels.contains(target), "{label} jumps to missing {target}");
+                }
+            }
+        }
+    }
+
+    // The flags that LLVM needs to read the `ptr` type, or `None` if LLVM is
+    // not installed.  LLVM 14 needs a flag, the later versions do not.
+    fn llvm_flags() -> Option<Vec<&'static str>> {
+        let module = "define void @f(ptr %p) {\n  ret void\n}\n";
+        [vec![], vec!["-opaque-pointers"]]
+            .into_iter()
+            .find(|flags| {
+                run_tool("llvm-as", flags, &["-o", "/dev/null"], module)
+                    .is_some_and(|output| output.status.success())
+            })
+    }
+
+    // Run a tool with the given input, or return `None` if it is not
+    // installed
+    fn run_tool(tool: &str, flags: &[&str], args: &[&str], input: &str) -> Option<Output> {
+        let mut child = Command::new(tool)
+            .args(flags)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .ok()?;
+        std::io::Write::write_all(&mut child.stdin.take().unwrap(), input.as_bytes()).unwrap();
+        Some(child.wait_with_output().unwrap())
+    }
+
+    // Check that LLVM accepts the IR, and run it with the given input, if
+    // LLVM is installed
+    fn run_ir(ir: &str, input: &str) -> Option<Output> {
+        let flags = llvm_flags()?;
+        let checked = run_tool("llvm-as", &flags, &["-o", "/dev/null"], ir).unwrap();
+        assert!(
+            checked.status.success(),
+            "LLVM rejects the IR: {}\n{ir}",
+            String::from_utf8_lossy(&checked.stderr)
+        );
+
+        let path = std::env::temp_dir().join(format!(
+            "smol-llvm-test-{}-{:?}.ll",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, ir).unwrap();
+        let output = run_tool("lli", &flags, &[path.to_str().unwrap()], input);
+        std::fs::remove_file(&path).unwrap();
+        output
+    }
+
+    // Check that LLVM accepts the IR and that running it behaves like the
+    // TIR interpreter, if LLVM is installed
+    fn assert_same_behavior(program: &tir::Program, input: &str) {
+        let Some(output) = run_ir(&emit_llvm(program).unwrap(), input) else {
+            return;
+        };
+        let mut expected = vec![];
+        let ok = interp::run(program, input.as_bytes(), &mut expected).is_ok();
+        assert_eq!(
+            (
+                String::from_utf8(output.stdout).unwrap(),
+                output.status.success()
+            ),
+            (String::from_utf8(expected).unwrap(), ok),
+            "the LLVM IR behaves differently for\n{program}"
+        );
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn blocks_match_the_cfg() {
+        let src = "$read n $while n {$if % n 2 {$print n} $print \"odd\" := n - n 1}";
+        let program = lower(parse(src).unwrap());
+        let ir = emit_llvm(&program).unwrap();
+        let labels: Vec<_> = blocks(&ir, "i32 @main")
+            .into_iter()
+            .map(|(l, _)| l)
+            .collect();
+        let mut expected = vec!["start".to_string()];
+        expected.extend(program.block.keys().map(|l| format!("l.{l}")));
+        let mut sorted = labels.clone();
+        sorted.sort();
+        expected.sort();
+        assert_eq!(sorted, expected);
+        // the entry block comes right after the allocations
+        assert_eq!(labels[1], "l.entry");
+        assert_well_formed(&ir, "i32 @main");
+    }
+
+    #[test]
+    fn instructions() {
+        let ir = emit_src("$read a $read b $print + a b $print < a b $print / a b $print ~ a");
+        for line in [
+            "  %v.a = alloca i64",
+            "  store i64 0, ptr %v.a",
+            "  br label %l.entry",
+            "  call void @exit(i32 0)",
+        ] {
+            assert!(
+                ir.lines().any(|l| l == line),
+                "{line:?} is missing in\n{ir}"
+            );
+        }
+        for insn in [
+            "call i64 @smol.read()",
+            "= add i64 ",
+            "= icmp slt i64 ",
+            "= call i64 @smol.div(i64 ",
+            "= sub i64 0, ",
+            "call void @smol.print(i64 ",
+        ] {
+            assert!(ir.contains(insn), "{insn:?} is missing in\n{ir}");
+        }
+        assert_well_formed(&ir, "i32 @main");
+    }
+
+    #[test]
+    fn functions_and_strings() {
+        let ir =
+            emit_src("$fn f(x) {$if x {:= f * x f(- x 1)} {:= f 1}} $print f(5) $print \"\\\"é\"");
+        assert!(ir.contains("define i64 @f.f(i64 %p.x) {"));
+        assert!(ir.contains("= call i64 @f.f(i64 "));
+        assert!(ir.contains("@str.0 = private constant [4 x i8] c\"\\22\\C3\\A9\\00\""));
+        assert!(ir.contains("call i32 @puts(ptr @str.0)"));
+        assert_well_formed(&ir, "i64 @f.f");
+        assert_well_formed(&ir, "i32 @main");
+    }
+
+    #[test]
+    fn phi_edges() {
+        let src = ":= a 1 := b 2 $read n $while n {:= t a := a b := b t := n - n 1} $print a";
+        let mut program = lower(parse(src).unwrap());
+        to_ssa(&mut program);
+        let ir = emit_llvm(&program).unwrap();
+        assert!(ir.contains("e.entry.lbl1:"), "no edge block in\n{ir}");
+        assert_well_formed(&ir, "i32 @main");
+    }
+
+    #[test]
+    fn floats() {
+        let err = emit_llvm(&lower(parse(":= x 1.5 $print x").unwrap())).unwrap_err();
+        assert_eq!(
+            err,
+            "the LLVM back-end does not support floats, found `_const_1 = $const 1.5`."
+        );
+        let program = lower(parse("$fn f() {:= f 0.5} $print f()").unwrap());
+        assert!(emit_llvm(&program).is_err());
+
+        // a float in the input is an error rather than read as its integer part
+        let ir = emit_src("$read a $print a");
+        if let Some(output) = run_ir(&ir, "2.5\n") {
+            assert!(!output.status.success());
+            assert!(output.stdout.is_empty());
+        }
+        if let Some(output) = run_ir(&ir, "7") {
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"7\n");
+        }
+    }
+
+    #[test]
+    fn runs() {
+        let programs = [
+            (
+                "$read a $read b $print + a b $print % a b $print | a b $print \"done\"",
+                "7\n3\n",
+            ),
+            (
+                "$read a $print * a 9223372036854775807 $print / a 0 $print 1",
+                "2\n",
+            ),
+            ("$read a", "x\n"),
+            ("$read a $print a $assert a $print \"unreachable\"", "0\n"),
+            (
+                "$read a $printc \"a = \" $printc a $printc \";\" $print a",
+                "3\n",
+            ),
+            ("$read a $read b $print ^ a b $print ^ b a", "3\n41\n"),
+            ("$read a $print ^ 2 a", "-1\n"),
+            ("$read a $read b $print ^ a b", "-2\n63\n"),
+            ("$read a $read b $print $min a b $print $max a b", "-3\n4\n"),
+            (
+                "$read a $read b $print $band a b $print $bor a b $print $bxor a b",
+                "-12\n10\n",
+            ),
+            ("$read a $read b $print << a b $print >> a b", "-5\n66\n"),
+            (
+                "$fn f(x) {$if x {:= f * x f(- x 1)} {:= f 1}} $print f(10)",
+                "",
+            ),
+        ];
+        for (src, input) in programs {
+            let mut program = lower(parse(src).unwrap());
+            assert_same_behavior(&program, input);
+            to_ssa(&mut program);
+            assert_same_behavior(&program, input);
+        }
+    }
+}
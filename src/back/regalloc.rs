@@ -0,0 +1,237 @@
+//! A linear-scan register allocator over the tiny IR, after "Linear Scan
+//! Register Allocation" by Poletto and Sarkar.
+//!
+//! The blocks are laid out one after the other in the order of their labels,
+//! and each variable gets a live interval: the range of positions from its
+//! first to its last definition, use, or block boundary it is live at.  The
+//! intervals are approximate because they have no holes, so two variables get
+//! different registers if their intervals overlap even when they are never
+//! live at the same time.
+
+use crate::common::*;
+use crate::middle::*;
+
+/// Where a variable is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reg {
+    /// One of the `k` physical registers, numbered from 0.
+    Phys(usize),
+    /// A stack slot, numbered from 0.  Each spilled variable gets its own.
+    Spill(usize),
+}
+
+/// Assign each variable that appears in the instructions or the terminators
+/// of the program one of `k` physical registers or a spill slot.  Two
+/// variables that may be live at the same time never share a register.
+///
+/// The functions are not allocated, call this on their bodies separately.
+pub fn allocate_registers(program: &Program, k: usize) -> Map<Id, Reg> {
+    let mut intervals: Vec<(usize, usize, Id)> = live_intervals(program)
+        .into_iter()
+        .map(|(x, (start, end))| (start, end, x))
+        .collect();
+    intervals.sort();
+
+    let mut allocation = Map::new();
+    let mut free: Set<usize> = (0..k).collect();
+    // the intervals that hold a register, with the register
+    let mut active: Vec<(usize, Id, usize)> = vec![];
+    let mut spills = 0;
+    for (start, end, x) in intervals {
+        // free the registers of the intervals that ended
+        active.retain(|&(active_end, _, reg)| {
+            if active_end < start {
+                free.insert(reg);
+            }
+            active_end >= start
+        });
+
+        if let Some(&reg) = free.first() {
+            free.remove(&reg);
+            allocation.insert(x, Reg::Phys(reg));
+            active.push((end, x, reg));
+            continue;
+        }
+
+        // spill the interval that ends last, which is either `x` or the active
+        // interval that ends last
+        let last = active.iter().enumerate().max_by_key(|(_, a)| a.0);
+        match last {
+            Some((i, &(last_end, y, reg))) if last_end > end => {
+                allocation.insert(y, Reg::Spill(spills));
+                allocation.insert(x, Reg::Phys(reg));
+                active[i] = (end, x, reg);
+            }
+            _ => {
+                allocation.insert(x, Reg::Spill(spills));
+            }
+        }
+        spills += 1;
+    }
+    allocation
+}
+
+/// The variables that did not get a physical register.
+pub fn spilled(allocation: &Map<Id, Reg>) -> Set<Id> {
+    allocation
+        .iter()
+        .filter(|(_, reg)| matches!(reg, Reg::Spill(_)))
+        .map(|(x, _)| *x)
+        .collect()
+}
+
+// The first and the last position at which each variable is live.  A block
+// takes a position for its start, one for each instruction, one for its
+// terminator, and one for its end.
+fn live_intervals(program: &Program) -> Map<Id, (usize, usize)> {
+    let live = liveness(program);
+
+    let mut intervals: Map<Id, (usize, usize)> = Map::new();
+    let mut extend = |x: Id, pos: usize| {
+        let interval = intervals.entry(x).or_insert((pos, pos));
+        interval.0 = interval.0.min(pos);
+        interval.1 = interval.1.max(pos);
+    };
+
+    let mut pos = 0;
+    for (lbl, block) in &program.block {
+        for &x in &live[lbl].live_in {
+            extend(x, pos);
+        }
+        for i in &block.insn {
+            pos += 1;
+            for x in i.uses().into_iter().chain(i.def()) {
+                extend(x, pos);
+            }
+        }
+        pos += 1;
+        for x in block.term.uses() {
+            extend(x, pos);
+        }
+        pos += 1;
+        for &x in &live[lbl].live_out {
+            extend(x, pos);
+        }
+        pos += 1;
+    }
+    intervals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{lower, parse};
+
+    // SECTION: helpers
+
+    // Check that every variable got a location, and that the variables that
+    // are live at the same point are in different registers
+    fn assert_no_conflicts(program: &Program, allocation: &Map<Id, Reg>) {
+        let live = liveness(program);
+        let check = |live: &Set<Id>| {
+            let mut regs = Map::new();
+            for x in live {
+                let reg = allocation
+                    .get(x)
+                    .unwrap_or_else(|| panic!("{x} is not allocated"));
+                if let Reg::Phys(r) = reg {
+                    if let Some(y) = regs.insert(*r, *x) {
+                        panic!("{x} and {y} are both in register {r}");
+                    }
+                }
+            }
+        };
+
+        for (lbl, block) in &program.block {
+            // walk the block backwards
+            let mut now = live[lbl].live_out.clone();
+            now.extend(block.term.uses());
+            check(&now);
+            for i in block.insn.iter().rev() {
+                if let Some(dst) = i.def() {
+                    // the destination is written while the others are live
+                    now.insert(dst);
+                    check(&now);
+                    now.remove(&dst);
+                }
+                now.extend(i.uses());
+                check(&now);
+            }
+        }
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn disjoint_ranges_share() {
+        let program = parse_tir(
+            "let a, b;
+             entry: a = $const 1 $print a b = $const 2 $print b $exit",
+        )
+        .unwrap();
+        let allocation = allocate_registers(&program, 1);
+        assert_eq!(
+            allocation,
+            Map::from([(id("a"), Reg::Phys(0)), (id("b"), Reg::Phys(0))])
+        );
+        assert!(spilled(&allocation).is_empty());
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let program = parse_tir(
+            "let a, b;
+             entry: a = $const 1 b = $const 2 $print a $print b $exit",
+        )
+        .unwrap();
+        let allocation = allocate_registers(&program, 2);
+        assert_ne!(allocation[&id("a")], allocation[&id("b")]);
+        assert!(spilled(&allocation).is_empty());
+        assert_no_conflicts(&program, &allocation);
+
+        // `b` ends after `a`, so it is the one that is spilled
+        let allocation = allocate_registers(&program, 1);
+        assert_eq!(allocation[&id("a")], Reg::Phys(0));
+        assert_eq!(allocation[&id("b")], Reg::Spill(0));
+        assert_eq!(spilled(&allocation), Set::from([id("b")]));
+    }
+
+    #[test]
+    fn spill_longest() {
+        // `x` lives the longest, so it is spilled instead of `b`
+        let program = parse_tir(
+            "let a, b, x;
+             entry:
+                x = $const 0
+                a = $const 1
+                b = $const 2
+                $print a
+                $print b
+                $print x
+                $exit",
+        )
+        .unwrap();
+        let allocation = allocate_registers(&program, 2);
+        assert_eq!(spilled(&allocation), Set::from([id("x")]));
+        assert_no_conflicts(&program, &allocation);
+    }
+
+    #[test]
+    fn loops() {
+        let src = "$read n := s 0 := i 0 $while < i n {:= s + s * i i := i + i 1} $print s";
+        let program = lower(parse(src).unwrap());
+        for k in 0..6 {
+            let allocation = allocate_registers(&program, k);
+            assert_no_conflicts(&program, &allocation);
+            let spills: Vec<&Reg> = allocation
+                .values()
+                .filter(|reg| matches!(reg, Reg::Spill(_)))
+                .collect();
+            let slots: Set<&Reg> = spills.iter().copied().collect();
+            assert_eq!(slots.len(), spills.len(), "spill slots are shared");
+            assert!(allocation
+                .values()
+                .all(|reg| !matches!(reg, Reg::Phys(r) if *r >= k)));
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! the smol driver. parses, lowers, or runs a smol program, depending on the
+//! subcommand.
+//!
+//! run with `--help` for more info.
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::process::ExitCode;
+
+use smol::{
+    front::{
+        check_strings, check_uses, lex::has_comments, lower, parse::ParseErrorKind, parse_all,
+        render_error, Program, Session,
+    },
+    middle::{interp, optimize, tir, unused_variables},
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+#[derive(Debug, Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// print the ast data structure
+    Parse {
+        /// the input file, `-` to read from stdin
+        #[arg(default_value = "-")]
+        file: String,
+        /// the output format
+        #[arg(value_enum, long, default_value_t = Emit::Text)]
+        emit: Emit,
+    },
+    /// print the tiny IR
+    Lower {
+        /// the input file, `-` to read from stdin
+        #[arg(default_value = "-")]
+        file: String,
+        /// the output format
+        #[arg(value_enum, long, default_value_t = Emit::Text)]
+        emit: Emit,
+        /// optimize the tiny IR
+        #[arg(long)]
+        optimize: bool,
+    },
+    /// run the program, reading from stdin and writing to stdout.  if the
+    /// program itself is read from stdin, it cannot read any input.
+    Run {
+        /// the input file, `-` to read from stdin
+        #[arg(default_value = "-")]
+        file: String,
+        /// optimize the tiny IR before running it
+        #[arg(long)]
+        optimize: bool,
+        /// write each executed block and instruction to stderr
+        #[arg(long)]
+        trace: bool,
+    },
+    /// print the program formatted canonically.  comments are not kept.
+    Fmt {
+        /// the input file, `-` to read from stdin
+        #[arg(default_value = "-")]
+        file: String,
+        /// overwrite the input file instead of printing the result, unless it
+        /// has comments
+        #[arg(long)]
+        write: bool,
+    },
+    /// read statements from stdin one line at a time, and run them as soon as
+    /// they are complete.  the variables and the functions persist between
+    /// lines.
+    Repl,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// the human-readable format
+    Text,
+    /// JSON, for other tools (needs the `serde` feature)
+    Json,
+}
+
+// Read the input file (or stdin if the file is `-`), reporting the errors to
+// stderr
+fn read(file: &str) -> Result<String, ExitCode> {
+    let input = if file == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(file)
+    };
+    input.map_err(|e| {
+        eprintln!("Cannot read {file}: {e}");
+        ExitCode::FAILURE
+    })
+}
+
+// Parse the input, reporting the errors to stderr
+fn parse_input(input: &str) -> Result<Program, ExitCode> {
+    parse_all(input).map_err(|errors| {
+        for e in errors {
+            eprintln!("{}", render_error(input, &e));
+        }
+        ExitCode::FAILURE
+    })
+}
+
+// Read and parse the input file (or stdin if the file is `-`), reporting the
+// errors to stderr
+fn load(file: &str) -> Result<Program, ExitCode> {
+    parse_input(&read(file)?)
+}
+
+// Check the program before lowering it, reporting the errors to stderr
+fn check(ast: Program) -> Result<Program, ExitCode> {
+    match check_uses(&ast).and_then(|()| check_strings(&ast)) {
+        Ok(()) => Ok(ast),
+        Err(errors) => {
+            for e in errors {
+                eprintln!("Error: {e}");
+            }
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+// Print the value as JSON
+#[cfg(feature = "serde")]
+fn print_json(value: &impl serde::Serialize) -> Result<(), ExitCode> {
+    let json = serde_json::to_string_pretty(value).expect("the value should be serializable");
+    println!("{json}");
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_json<T>(_: &T) -> Result<(), ExitCode> {
+    eprintln!("JSON output needs smol to be built with the `serde` feature.");
+    Err(ExitCode::FAILURE)
+}
+
+// Run the lines of stdin with the AST interpreter, as soon as they make up
+// complete statements.  `$read` reads from the following lines.  Errors are
+// reported, and the session goes on.
+fn repl() -> Result<(), ExitCode> {
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut input = stdin.lock();
+    let mut stdout = std::io::stdout().lock();
+    let mut session = Session::new();
+
+    loop {
+        // read lines until they parse, or until they fail to parse before the
+        // end of the input they make up
+        let mut src = String::new();
+        let parsed = loop {
+            if interactive {
+                print!("{}", if src.is_empty() { "> " } else { ". " });
+                stdout.flush().map_err(|_| ExitCode::FAILURE)?;
+            }
+            let read = match input.read_line(&mut src) {
+                Ok(read) => read,
+                Err(e) => {
+                    eprintln!("Cannot read stdin: {e}");
+                    return Err(ExitCode::FAILURE);
+                }
+            };
+            if read == 0 && src.trim().is_empty() {
+                return Ok(());
+            }
+            match parse_all(&src) {
+                Err(errors) if read > 0 && errors.iter().any(|e| incomplete(&e.kind)) => {}
+                parsed => break parsed,
+            }
+        };
+
+        match parsed {
+            Ok(program) => {
+                if let Err(e) = session.run(program, &mut input, &mut stdout) {
+                    eprintln!("{e}");
+                }
+            }
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("{}", render_error(&src, &e));
+                }
+            }
+        }
+        // the output before an error goes out too
+        stdout.flush().map_err(|_| ExitCode::FAILURE)?;
+    }
+}
+
+// Whether the parse error is only there because the input ends too early, so
+// that more lines may complete the statement
+fn incomplete(kind: &ParseErrorKind) -> bool {
+    matches!(
+        kind,
+        ParseErrorKind::UnexpectedEof | ParseErrorKind::MissingToken(_)
+    )
+}
+
+// Print the program read from `file` formatted canonically, or write it back
+// to the file
+fn format(input: &str, file: &str, write: bool) -> Result<(), ExitCode> {
+    let formatted = parse_input(input)?.to_string();
+    if !write {
+        print!("{formatted}");
+        return Ok(());
+    }
+    if file == "-" {
+        eprintln!("Cannot write the formatted program back to stdin.");
+        return Err(ExitCode::FAILURE);
+    }
+    // the formatted program has no comments, so writing it would delete them
+    if has_comments(input) {
+        eprintln!("Cannot write the formatted program back to {file}, since it has comments.");
+        return Err(ExitCode::FAILURE);
+    }
+    std::fs::write(file, formatted).map_err(|e| {
+        eprintln!("Cannot write {file}: {e}");
+        ExitCode::FAILURE
+    })
+}
+
+// Lower the program, warn about the variables it never reads, and optimize
+// it if asked to
+fn to_tir(ast: Program, opt: bool) -> tir::Program {
+    let tir = lower(ast);
+    warn_unused(&tir);
+    if opt {
+        optimize(tir)
+    } else {
+        tir
+    }
+}
+
+// Warn on stderr about the variables whose values are never read
+fn warn_unused(tir: &tir::Program) {
+    for x in unused_variables(tir) {
+        eprintln!("Warning: The variable `{x}` is never read.");
+    }
+    for (name, func) in &tir.funcs {
+        for x in unused_variables(&func.body) {
+            eprintln!("Warning: In the function `{name}`: The variable `{x}` is never read.");
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let result = match args.command {
+        Command::Parse { file, emit } => load(&file).and_then(|ast| match emit {
+            Emit::Text => {
+                println!("{ast:?}");
+                Ok(())
+            }
+            Emit::Json => print_json(&ast),
+        }),
+        Command::Lower {
+            file,
+            emit,
+            optimize,
+        } => load(&file).and_then(check).and_then(|ast| {
+            let tir = to_tir(ast, optimize);
+            match emit {
+                Emit::Text => {
+                    print!("{tir}");
+                    Ok(())
+                }
+                Emit::Json => print_json(&tir),
+            }
+        }),
+        Command::Run {
+            file,
+            optimize,
+            trace,
+        } => load(&file).and_then(check).and_then(|ast| {
+            let (stdin, stdout) = (std::io::stdin().lock(), std::io::stdout().lock());
+            let tir = to_tir(ast, optimize);
+            let result = if trace {
+                interp::run_traced(&tir, stdin, stdout, std::io::stderr().lock())
+            } else {
+                interp::run(&tir, stdin, stdout)
+            };
+            result.map_err(|e| {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            })
+        }),
+        Command::Fmt { file, write } => read(&file).and_then(|input| format(&input, &file, write)),
+        Command::Repl => repl(),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => code,
+    }
+}
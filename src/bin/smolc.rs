@@ -3,6 +3,8 @@
 //!
 //! run with `--help` for more info.
 
+use std::process::ExitCode;
+
 use smol::{back::*, front::*, middle::*};
 
 use clap::{Parser, ValueEnum};
@@ -28,42 +30,87 @@ enum Output {
     Ast,
     /// tiny IR in JSON format, after optimizations
     Tir,
+    /// the control-flow graph of tiny IR in Graphviz DOT format
+    Dot,
+    /// statistics about the control-flow graph: its size, cyclomatic
+    /// complexity, and loops
+    CfgStats,
     /// the resulting assembly code
     Asm,
+    /// C source code
+    C,
+    /// LLVM IR, for programs that use only integers
+    Llvm,
 }
 
-fn get_ir(input: &str, opt: bool) -> tir::Program {
-    let ast = parse(input).unwrap();
-    let ir = lower(ast);
-    if opt {
-        optimize(ir)
-    } else {
-        ir
+// Parse the input, reporting the errors to stderr
+fn get_ast(input: &str) -> Result<ast::Program, ExitCode> {
+    parse_all(input).map_err(|errors| {
+        for e in errors {
+            eprintln!("{}", render_error(input, &e));
+        }
+        ExitCode::FAILURE
+    })
+}
+
+// Parse, check, and lower the input, reporting the errors to stderr
+fn get_ir(input: &str, opt: bool) -> Result<tir::Program, ExitCode> {
+    let ast = get_ast(input)?;
+    if let Err(errors) = check_uses(&ast).and_then(|()| check_strings(&ast)) {
+        for e in errors {
+            eprintln!("Error: {e}");
+        }
+        return Err(ExitCode::FAILURE);
     }
+    let ir = lower(ast);
+    Ok(if opt { optimize(ir) } else { ir })
 }
 
-fn main() {
+fn main() -> ExitCode {
     use Output::*;
     let args = Args::parse();
 
-    let input = String::from_utf8(std::fs::read(&args.file).expect("file should be readable"))
-        .expect("input characters should be utf8");
+    let input = match std::fs::read_to_string(&args.file) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Cannot read {}: {e}", args.file);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    match args.out {
-        Tokens => {
-            let mut lexer = lex::Lexer::new(&input);
-            while let Some(token) = lexer.next() {
-                println!("{token}");
+    let result = match args.out {
+        Tokens => match lex::try_get_tokens(&input) {
+            Ok(tokens) => {
+                for token in tokens {
+                    println!("{token}");
+                }
+                Ok(())
             }
-        }
-        Ast => {
-            println!("{:?}", parse(&input).unwrap());
-        }
-        Tir => {
-            println!("{}", get_ir(&input, args.optimize))
-        }
-        Asm => {
-            println!("{}", code_gen(get_ir(&input, args.optimize)).asm_code())
-        }
+            Err(err) => {
+                eprintln!("{err}");
+                Err(ExitCode::FAILURE)
+            }
+        },
+        Ast => get_ast(&input).map(|ast| println!("{ast:?}")),
+        Tir => get_ir(&input, args.optimize).map(|ir| println!("{ir}")),
+        Dot => get_ir(&input, args.optimize).map(|ir| print!("{}", to_dot(&ir))),
+        CfgStats => get_ir(&input, args.optimize).map(|ir| print!("{}", cfg_stats(&ir))),
+        Asm => get_ir(&input, args.optimize).map(|ir| println!("{}", code_gen(ir).asm_code())),
+        C => get_ir(&input, args.optimize).map(|ir| print!("{}", c::emit_c(&ir))),
+        Llvm => get_ir(&input, args.optimize).and_then(|ir| match llvm::emit_llvm(&ir) {
+            Ok(ir) => {
+                print!("{ir}");
+                Ok(())
+            }
+            Err(err) => {
+                eprintln!("Error: {err}");
+                Err(ExitCode::FAILURE)
+            }
+        }),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => code,
     }
 }
@@ -1 +1,51 @@
-fn main() {}
+//! the virtual machine for tiny IR. takes a tiny IR program in its textual
+//! form, and runs it using the standard input and output.
+//!
+//! run with `--help` for more info.
+
+use std::process::ExitCode;
+
+use smol::middle::{interp, parse_tir, verify};
+
+use clap::Parser;
+
+#[derive(Debug, Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// the tiny IR program to run
+    file: String,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let input = match std::fs::read_to_string(&args.file) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Cannot read {}: {e}", args.file);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let program = match parse_tir(&input) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Parse error: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(errors) = verify(&program) {
+        for e in errors {
+            eprintln!("Ill-formed program: {e}");
+        }
+        return ExitCode::FAILURE;
+    }
+
+    match interp::run(&program, std::io::stdin().lock(), std::io::stdout().lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -3,10 +3,71 @@
 // Use sorted sets and maps for consistent output
 pub use std::collections::{BTreeMap as Map, BTreeSet as Set};
 
-/// Identifiers.
+/// Identifiers.  They are interned in a global table, so each name is stored
+/// once, and comparing or hashing two identifiers only compares pointers.
+/// Ordering still compares the names, to keep the output deterministic.
 pub type Id = internment::Intern<String>;
 
-/// Identifier factory
+/// Identifier factory.  Calling it twice with the same name gives the same
+/// handle, and `Display` shows the name.
 pub fn id(name: &str) -> Id {
     Id::from_ref(name)
 }
+
+/// Write a string as a double-quoted literal, escaping the characters that
+/// cannot appear in it literally.  This is the inverse of [unquote].
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Read a double-quoted string literal, resolving the escape sequences `\n`,
+/// `\"`, and `\\`.  The error message says what is wrong with the literal.
+pub fn unquote(literal: &str) -> Result<String, String> {
+    let mut chars = literal.strip_prefix('"').unwrap_or(literal).chars();
+    let mut s = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if chars.as_str().is_empty() => return Ok(s),
+            '"' => return Err("Unexpected text after the end of the string.".to_string()),
+            '\\' => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some(c) => return Err(format!("Unknown escape sequence `\\{c}` in a string.")),
+                None => break,
+            },
+            c => s.push(c),
+        }
+    }
+    Err("Unterminated string literal.".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SECTION: tests
+
+    #[test]
+    fn interned_ids() {
+        let x = id("x");
+        let y = id(&String::from("x"));
+        assert_eq!(x, y);
+        // both handles point at the same interned string
+        assert!(std::ptr::eq(x.as_ref(), y.as_ref()));
+        assert_ne!(x, id("y"));
+        assert_eq!(x.to_string(), "x");
+        // the order is by name, not by address
+        assert!(id("a") < id("b") && id("b") < id("c"));
+    }
+}
@@ -1,10 +1,16 @@
 //! The front-end of the compiler.
 
 pub mod ast;
+pub mod check;
+pub mod eval;
 pub mod lex;
 pub mod lower;
 pub mod parse;
 
 pub use ast::*;
-pub use lower::lower;
-pub use parse::parse;
+pub use check::{check_strings, check_uses};
+pub use eval::{eval, Session};
+pub use lower::{lower, lower_keeping_names, lower_per_statement};
+pub use parse::{
+    parse, parse_all, parse_infix, parse_iterative, parse_with_max_depth, render_error, MAX_DEPTH,
+};
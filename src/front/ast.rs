@@ -1,48 +1,1138 @@
 //! The abstract syntax tree.
 
-use derive_more::derive::Display;
+use std::fmt;
 
-use crate::common::Id;
+use crate::common::{quote, Id};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "UncheckedProgram"))]
 pub struct Program {
     pub stmts: Vec<Stmt>,
+    pub funcs: Vec<Func>,
+}
+
+// A program read from JSON, which may have strings where the parser never puts
+// them, so it becomes a [Program] only if `check_strings` accepts it
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct UncheckedProgram {
+    stmts: Vec<Stmt>,
+    funcs: Vec<Func>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<UncheckedProgram> for Program {
+    type Error = String;
+
+    fn try_from(UncheckedProgram { stmts, funcs }: UncheckedProgram) -> Result<Self, String> {
+        let program = Program { stmts, funcs };
+        super::check::check_strings(&program).map_err(|errors| errors.join("\n"))?;
+        Ok(program)
+    }
+}
+
+/// A function definition.  The function returns the value of the variable
+/// with the same name as the function.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Func {
+    pub name: Id,
+    pub params: Vec<Id>,
+    pub body: Vec<Stmt>,
+}
+
+/// A part of the source code, given by byte offsets into the input, along
+/// with the line and the column it starts at.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    /// Where the span starts (inclusive).
+    pub start: usize,
+    /// Where the span ends (exclusive).
+    pub end: usize,
+    /// The line the span starts at (1-based), or 0 for an empty span.
+    pub line: usize,
+    /// The column the span starts at, in characters (1-based).
+    pub col: usize,
+}
+
+impl Span {
+    /// The smallest span covering both spans.
+    pub fn to(self, other: Span) -> Span {
+        let first = if self.start <= other.start {
+            self
+        } else {
+            other
+        };
+        Span {
+            end: self.end.max(other.end),
+            ..first
+        }
+    }
+}
+
+/// Shows where the span starts as `line:col`, like parse errors do.
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// How runtime errors show the assertion `$assert e` spanning `span`: in
+/// backticks, after the line and the column it is at if it was parsed from
+/// source code.
+pub fn describe_assertion(e: &Expr, span: Span) -> String {
+    if span.line == 0 {
+        format!("`$assert {e}`")
+    } else {
+        format!("{span}: `$assert {e}`")
+    }
+}
+
+/// A statement, and where it is in the source code.
+///
+/// The span is empty for statements that were not parsed from source code.
+/// It is ignored when comparing, debug-printing, or serializing statements.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Stmt {
+    pub kind: StmtKind,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub span: Span,
 }
 
 #[derive(Debug, PartialEq, Eq)]
-pub enum Stmt {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StmtKind {
     Assign(Id, Expr),
-    Print(Expr),
-    Read(Id),
+    /// Print the values on one line, separated by spaces.  There is at least
+    /// one value.
+    Print(Vec<Expr>),
+    /// Like [StmtKind::Print], but without a newline after the value.
+    PrintNoNl(Expr),
+    /// Read a number into each variable, in order.
+    Read(Vec<Id>),
+    /// Exchange the values of two variables.
+    Swap(Id, Id),
+    /// Stop the program with an error if the value is zero.
+    Assert(Expr),
     If {
         guard: Expr,
         tt: Vec<Stmt>,
         ff: Vec<Stmt>,
     },
+    While {
+        guard: Expr,
+        body: Vec<Stmt>,
+    },
+    /// Count `var` up from `start` for as long as it is less than `end`,
+    /// running the body for each value.  `end` is evaluated before each
+    /// iteration, like the guard of a `$while`.  The bounds are boxed to keep
+    /// statements small.
+    For {
+        var: Id,
+        start: Box<Expr>,
+        end: Box<Expr>,
+        body: Vec<Stmt>,
+    },
+    /// Run the arm whose integer is equal to the value of `scrutinee`, or
+    /// `default` if there is no such arm.  The integers of the arms are
+    /// different.
+    Case {
+        scrutinee: Expr,
+        arms: Vec<(i64, Vec<Stmt>)>,
+        default: Vec<Stmt>,
+    },
+    /// Return from the current function, or stop the program outside
+    /// functions.
+    Return,
+}
+
+/// An expression, and where it is in the source code.
+///
+/// Like for [Stmt], the span is empty for expressions that were not parsed
+/// from source code, and it is ignored when comparing, debug-printing, or
+/// serializing expressions.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Expr {
+    pub kind: ExprKind,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub span: Span,
 }
 
 #[derive(Debug, PartialEq, Eq)]
-pub enum Expr {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExprKind {
     Var(Id),
-    Const(i64),
+    Const(Number),
     BinOp {
         op: BOp,
         lhs: Box<Expr>,
         rhs: Box<Expr>,
     },
     Negate(Box<Expr>),
+    /// `tt` if `guard` is true, and `ff` otherwise.  Only the chosen operand
+    /// is evaluated.
+    Select {
+        guard: Box<Expr>,
+        tt: Box<Expr>,
+        ff: Box<Expr>,
+    },
+    Call {
+        func: Id,
+        args: Vec<Expr>,
+    },
+    /// A string literal.  These can only be printed, so they appear only as
+    /// the argument of [StmtKind::Print].
+    Str(String),
+}
+
+impl Stmt {
+    /// A statement with given kind, spanning given part of the source code.
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
+}
+
+impl Expr {
+    /// An expression with given kind, spanning given part of the source code.
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+
+    /// The kind of the expression.  It cannot be moved out of the expression
+    /// directly, since expressions have a [Drop] implementation.
+    pub fn into_kind(mut self) -> ExprKind {
+        std::mem::replace(&mut self.kind, ExprKind::Const(Number::Int(0)))
+    }
+}
+
+// Dropping the operands recursively would overflow the stack on expressions
+// that the iterative parser nests arbitrarily deeply, so move them to a stack
+// of their own instead.  Each operand on the stack is dropped after its own
+// operands are moved off it, so no drop recurses.
+impl Drop for Expr {
+    fn drop(&mut self) {
+        use ExprKind::*;
+        let mut operands = vec![];
+        let mut kind = std::mem::replace(&mut self.kind, Const(Number::Int(0)));
+        loop {
+            match kind {
+                Var(_) | Const(_) | Str(_) => {}
+                BinOp { lhs, rhs, .. } => operands.extend([*lhs, *rhs]),
+                Negate(e) => operands.push(*e),
+                Select { guard, tt, ff } => operands.extend([*guard, *tt, *ff]),
+                Call { args, .. } => operands.extend(args),
+            }
+            match operands.pop() {
+                Some(e) => kind = e.into_kind(),
+                None => break,
+            }
+        }
+    }
+}
+
+impl From<StmtKind> for Stmt {
+    fn from(kind: StmtKind) -> Self {
+        Stmt::new(kind, Span::default())
+    }
+}
+
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Expr::new(kind, Span::default())
+    }
+}
+
+// Like comparisons, debug output leaves out the spans, so that it stays readable.
+impl fmt::Debug for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl PartialEq for Stmt {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Stmt {}
+
+impl PartialEq<StmtKind> for Stmt {
+    fn eq(&self, other: &StmtKind) -> bool {
+        self.kind == *other
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Expr {}
+
+impl PartialEq<ExprKind> for Expr {
+    fn eq(&self, other: &ExprKind) -> bool {
+        self.kind == *other
+    }
+}
+
+/// The values smol programs compute with.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Number {
+    Int(i64),
+    #[cfg_attr(feature = "serde", serde(with = "json_float"))]
+    Float(f64),
+}
+
+impl Number {
+    /// Is this a true value when used as a condition, i.e. is it non-zero?
+    pub fn is_true(self) -> bool {
+        match self {
+            Number::Int(n) => n != 0,
+            Number::Float(x) => x != 0.0,
+        }
+    }
+
+    /// The negation, or `None` if it overflows, which only the smallest
+    /// integer does.
+    pub fn checked_neg(self) -> Option<Number> {
+        match self {
+            Number::Int(n) => n.checked_neg().map(Number::Int),
+            Number::Float(x) => Some(Number::Float(-x)),
+        }
+    }
+
+    // The value converted to a float, for mixed arithmetic
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(n) => n as f64,
+            Number::Float(x) => x,
+        }
+    }
+}
+
+// Numbers are equal only if they have the same type and the same bits, so that
+// a float is never equal to an integer, and NaN is equal to itself.  This is
+// the equality of the literals rather than the equality of `BOp::Eq`.
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Number::Int(l), Number::Int(r)) => l == r,
+            (Number::Float(l), Number::Float(r)) => l.to_bits() == r.to_bits(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Number {}
+
+// Hash the same bits that equality compares.
+impl std::hash::Hash for Number {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        match self {
+            Number::Int(n) => (0u8, *n).hash(state),
+            Number::Float(x) => (1u8, x.to_bits()).hash(state),
+        }
+    }
+}
+
+impl std::ops::Neg for Number {
+    type Output = Number;
+
+    /// Negation wraps around on overflow like the rest of integer arithmetic.
+    fn neg(self) -> Number {
+        match self {
+            Number::Int(n) => Number::Int(n.wrapping_neg()),
+            Number::Float(x) => Number::Float(-x),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Int(n) => write!(f, "{n}"),
+            // always print a fractional part so that floats stay floats when
+            // they are read back
+            Number::Float(x) if x.fract() == 0.0 => write!(f, "{x:.1}"),
+            Number::Float(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Number {
+    type Err = std::num::ParseFloatError;
+
+    /// Read an integer, or a float if the input is not an integer.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(n) => Ok(Number::Int(n)),
+            Err(_) => s.parse().map(Number::Float),
+        }
+    }
+}
+
+// JSON has no infinities or NaN, and serde_json writes them as `null`, which
+// cannot be read back, so they are written as the strings `inf`, `-inf`, and
+// `NaN`, the way the tiny IR writes them
+#[cfg(feature = "serde")]
+mod json_float {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(x: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if x.is_finite() {
+            serializer.serialize_f64(*x)
+        } else {
+            serializer.serialize_str(&x.to_string())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Float {
+            Finite(f64),
+            NonFinite(String),
+        }
+
+        match Float::deserialize(deserializer)? {
+            Float::Finite(x) => Ok(x),
+            Float::NonFinite(s) => match s.as_str() {
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                "NaN" => Ok(f64::NAN),
+                _ => Err(de::Error::invalid_value(
+                    de::Unexpected::Str(&s),
+                    &"a number, `inf`, `-inf`, or `NaN`",
+                )),
+            },
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Display)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BOp {
-    #[display("mul")]
     Mul,
-    #[display("div")]
     Div,
-    #[display("add")]
+    Mod,
+    Pow,
     Add,
-    #[display("sub")]
     Sub,
-    #[display("lt")]
     Lt,
+    Gt,
+    Eq,
+    Le,
+    Ge,
+    Ne,
+    And,
+    Or,
+    Min,
+    Max,
+    Shl,
+    Shr,
+    /// The bitwise operators.  `And` and `Or` are the logical ones.
+    BitAnd,
+    BitOr,
+    BitXor,
+}
+
+impl BOp {
+    /// The operator as it is written in the source code.  This is also how it
+    /// is displayed.
+    pub fn symbol(self) -> &'static str {
+        use BOp::*;
+        match self {
+            Mul => "*",
+            Div => "/",
+            Mod => "%",
+            Pow => "^",
+            Add => "+",
+            Sub => "-",
+            Lt => "<",
+            Gt => ">",
+            Eq => "=",
+            Le => "<=",
+            Ge => ">=",
+            Ne => "!=",
+            And => "&",
+            Or => "|",
+            Min => "$min",
+            Max => "$max",
+            Shl => "<<",
+            Shr => ">>",
+            BitAnd => "$band",
+            BitOr => "$bor",
+            BitXor => "$bxor",
+        }
+    }
+
+    /// The name of the operator in tiny IR, e.g. `add` in `$arith add x y`.
+    pub fn mnemonic(self) -> &'static str {
+        use BOp::*;
+        match self {
+            Mul => "mul",
+            Div => "div",
+            Mod => "mod",
+            Pow => "pow",
+            Add => "add",
+            Sub => "sub",
+            Lt => "lt",
+            Gt => "gt",
+            Eq => "eq",
+            Le => "le",
+            Ge => "ge",
+            Ne => "ne",
+            And => "and",
+            Or => "or",
+            Min => "min",
+            Max => "max",
+            Shl => "shl",
+            Shr => "shr",
+            BitAnd => "band",
+            BitOr => "bor",
+            BitXor => "bxor",
+        }
+    }
+
+    /// Evaluate this operator on given operands.  If either operand is a
+    /// float, both are converted to floats.  Integer arithmetic wraps around on
+    /// overflow, and comparisons and logical operators produce the integers 1
+    /// for true and 0 for false.  The bitwise operators work on the integer
+    /// parts of floats, and always produce integers.
+    ///
+    /// Both operands have already been evaluated here, so this does not
+    /// short-circuit `And` and `Or`; that is up to the caller.
+    ///
+    /// Returns `None` if the result is undefined (integer division or remainder
+    /// by zero, or an integer power with a negative exponent or whose result
+    /// overflows).  Float division by zero and float powers follow IEEE 754
+    /// instead.
+    pub fn eval(self, lhs: Number, rhs: Number) -> Option<Number> {
+        match (lhs, rhs) {
+            (Number::Int(lhs), Number::Int(rhs)) => self.eval_int(lhs, rhs).map(Number::Int),
+            _ => Some(self.eval_float(lhs.as_f64(), rhs.as_f64())),
+        }
+    }
+
+    /// Whether the operation overflows on given operands, so that its result
+    /// wraps around.  This can only happen to integer additions, subtractions,
+    /// multiplications, divisions, remainders, and left shifts; integer powers
+    /// that overflow are undefined instead (see [BOp::eval]).
+    pub fn overflows(self, lhs: Number, rhs: Number) -> bool {
+        let (Number::Int(lhs), Number::Int(rhs)) = (lhs, rhs) else {
+            return false;
+        };
+        match self {
+            BOp::Add => lhs.checked_add(rhs).is_none(),
+            BOp::Sub => lhs.checked_sub(rhs).is_none(),
+            BOp::Mul => lhs.checked_mul(rhs).is_none(),
+            // dividing the smallest integer by -1; division by zero is an
+            // error of its own
+            BOp::Div => rhs != 0 && lhs.checked_div(rhs).is_none(),
+            BOp::Mod => rhs != 0 && lhs.checked_rem(rhs).is_none(),
+            // the bits shifted out are not all copies of the sign bit
+            BOp::Shl => lhs.wrapping_shl(rhs as u32).wrapping_shr(rhs as u32) != lhs,
+            _ => false,
+        }
+    }
+
+    fn eval_int(self, lhs: i64, rhs: i64) -> Option<i64> {
+        use BOp::*;
+        Some(match self {
+            Div | Mod if rhs == 0 => return None,
+            Pow if rhs < 0 => return None,
+            Mul => lhs.wrapping_mul(rhs),
+            Div => lhs.wrapping_div(rhs),
+            Mod => lhs.wrapping_rem(rhs),
+            Pow => return checked_pow(lhs, rhs),
+            Add => lhs.wrapping_add(rhs),
+            Sub => lhs.wrapping_sub(rhs),
+            Lt => (lhs < rhs) as i64,
+            Gt => (lhs > rhs) as i64,
+            Eq => (lhs == rhs) as i64,
+            Le => (lhs <= rhs) as i64,
+            Ge => (lhs >= rhs) as i64,
+            Ne => (lhs != rhs) as i64,
+            And => (lhs != 0 && rhs != 0) as i64,
+            Or => (lhs != 0 || rhs != 0) as i64,
+            Min => lhs.min(rhs),
+            Max => lhs.max(rhs),
+            // like on RISC-V, only the low 6 bits of the shift amount count,
+            // and `Shr` is an arithmetic shift
+            Shl => lhs.wrapping_shl(rhs as u32),
+            Shr => lhs.wrapping_shr(rhs as u32),
+            BitAnd => lhs & rhs,
+            BitOr => lhs | rhs,
+            BitXor => lhs ^ rhs,
+        })
+    }
+
+    fn eval_float(self, lhs: f64, rhs: f64) -> Number {
+        use BOp::*;
+        let truth = |b: bool| Number::Int(b as i64);
+        match self {
+            Mul => Number::Float(lhs * rhs),
+            Div => Number::Float(lhs / rhs),
+            Mod => Number::Float(lhs % rhs),
+            Pow => Number::Float(lhs.powf(rhs)),
+            Add => Number::Float(lhs + rhs),
+            Sub => Number::Float(lhs - rhs),
+            Lt => truth(lhs < rhs),
+            Gt => truth(lhs > rhs),
+            Eq => truth(lhs == rhs),
+            Le => truth(lhs <= rhs),
+            Ge => truth(lhs >= rhs),
+            Ne => truth(lhs != rhs),
+            And => truth(lhs != 0.0 && rhs != 0.0),
+            Or => truth(lhs != 0.0 || rhs != 0.0),
+            Min => Number::Float(lhs.min(rhs)),
+            Max => Number::Float(lhs.max(rhs)),
+            // shifting a float scales it by a power of two
+            Shl => Number::Float(lhs * rhs.exp2()),
+            Shr => Number::Float(lhs / rhs.exp2()),
+            // the bitwise operators work on the integer parts
+            BitAnd | BitOr | BitXor => Number::Int(
+                self.eval_int(lhs as i64, rhs as i64)
+                    .expect("bitwise operators are always defined"),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for BOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+// Raise `base` to a non-negative power by repeated squaring, or `None` if the
+// result overflows.  The base is only squared if a higher bit of the exponent
+// needs it, so the squares overflow only if the result does.
+fn checked_pow(mut base: i64, mut exp: i64) -> Option<i64> {
+    let mut result: i64 = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result.checked_mul(base)?;
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = base.checked_mul(base)?;
+        }
+    }
+    Some(result)
+}
+
+// SECTION: unparsing the AST back to the source code
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for func in &self.funcs {
+            writeln!(f, "{func}")?;
+        }
+        for stmt in &self.stmts {
+            writeln!(f, "{stmt}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Func {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "$fn {}(", self.name)?;
+        write_list(f, &self.params)?;
+        write!(f, ") ")?;
+        write_block(f, &self.body)
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl fmt::Display for StmtKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use StmtKind::*;
+        match self {
+            Assign(x, e) => write!(f, ":= {x} {e}"),
+            Print(es) => {
+                write!(f, "$print")?;
+                for e in es {
+                    write!(f, " {e}")?;
+                }
+                Ok(())
+            }
+            PrintNoNl(e) => write!(f, "$printc {e}"),
+            Read(xs) => {
+                write!(f, "$read")?;
+                for x in xs {
+                    write!(f, " {x}")?;
+                }
+                Ok(())
+            }
+            Swap(x, y) => write!(f, "$swap {x} {y}"),
+            Assert(e) => write!(f, "$assert {e}"),
+            If { guard, tt, ff } => {
+                write!(f, "$if {guard} ")?;
+                write_block(f, tt)?;
+                write!(f, " ")?;
+                write_block(f, ff)
+            }
+            While { guard, body } => {
+                write!(f, "$while {guard} ")?;
+                write_block(f, body)
+            }
+            For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                write!(f, "$for {var} {start} {end} ")?;
+                write_block(f, body)
+            }
+            Case {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                writeln!(f, "$case {scrutinee} {{")?;
+                for (n, body) in arms {
+                    write_arm(f, &n.to_string(), body)?;
+                }
+                write_arm(f, "$default", default)?;
+                write!(f, "}}")
+            }
+            Return => write!(f, "$return"),
+        }
+    }
+}
+
+// Write a block of statements, with each statement indented on its own line
+fn write_block(f: &mut fmt::Formatter<'_>, stmts: &[Stmt]) -> fmt::Result {
+    if stmts.is_empty() {
+        return write!(f, "{{}}");
+    }
+
+    writeln!(f, "{{")?;
+    for stmt in stmts {
+        for line in stmt.to_string().lines() {
+            writeln!(f, "    {line}")?;
+        }
+    }
+    write!(f, "}}")
+}
+
+// Write an arm of a `$case` on its own lines, indented
+fn write_arm(f: &mut fmt::Formatter<'_>, label: &str, stmts: &[Stmt]) -> fmt::Result {
+    struct Block<'a>(&'a [Stmt]);
+    impl fmt::Display for Block<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_block(f, self.0)
+        }
+    }
+
+    for line in format!("{label} {}", Block(stmts)).lines() {
+        writeln!(f, "    {line}")?;
+    }
+    Ok(())
+}
+
+// Write a comma-separated list
+fn write_list(f: &mut fmt::Formatter<'_>, items: &[impl fmt::Display]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        let sep = if i == 0 { "" } else { ", " };
+        write!(f, "{sep}{item}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl fmt::Display for ExprKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ExprKind::*;
+        match self {
+            Var(x) => write!(f, "{x}"),
+            // there are no literals for infinities and NaN, and `inf` or `NaN`
+            // would read back as variables, so write them as divisions
+            Const(Number::Float(x)) if x.is_nan() => write!(f, "/ 0.0 0.0"),
+            Const(Number::Float(x)) if x.is_infinite() => {
+                write!(f, "/ {} 0.0", Number::Float(x.signum()))
+            }
+            Const(n) => write!(f, "{n}"),
+            BinOp { op, lhs, rhs } => write!(f, "{op} {lhs} {rhs}"),
+            Negate(e) => write!(f, "~ {e}"),
+            Select { guard, tt, ff } => write!(f, "? {guard} {tt} {ff}"),
+            Call { func, args } => {
+                write!(f, "{func}(")?;
+                write_list(f, args)?;
+                write!(f, ")")
+            }
+            Str(s) => write!(f, "{}", quote(s)),
+        }
+    }
+}
+
+/// A rewrite of the AST.  Each method takes a node and returns the node that
+/// replaces it.  By default, the methods rebuild the node from its rewritten
+/// children (see [walk_program] and the other `walk_` functions), so an
+/// implementation only overrides the methods for the nodes it changes.
+pub trait Folder {
+    fn fold_program(&mut self, program: Program) -> Program {
+        walk_program(self, program)
+    }
+
+    fn fold_func(&mut self, func: Func) -> Func {
+        walk_func(self, func)
+    }
+
+    fn fold_stmt(&mut self, stmt: Stmt) -> Stmt {
+        walk_stmt(self, stmt)
+    }
+
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        walk_expr(self, e)
+    }
+}
+
+/// Fold the functions and the statements of the program.
+pub fn walk_program<F: Folder + ?Sized>(folder: &mut F, program: Program) -> Program {
+    Program {
+        funcs: program
+            .funcs
+            .into_iter()
+            .map(|func| folder.fold_func(func))
+            .collect(),
+        stmts: fold_stmts(folder, program.stmts),
+    }
+}
+
+/// Fold the body of the function.
+pub fn walk_func<F: Folder + ?Sized>(folder: &mut F, func: Func) -> Func {
+    Func {
+        body: fold_stmts(folder, func.body),
+        ..func
+    }
+}
+
+/// Fold the expressions and the blocks of the statement.
+pub fn walk_stmt<F: Folder + ?Sized>(folder: &mut F, stmt: Stmt) -> Stmt {
+    use StmtKind::*;
+    let kind = match stmt.kind {
+        Assign(x, e) => Assign(x, folder.fold_expr(e)),
+        Print(es) => Print(es.into_iter().map(|e| folder.fold_expr(e)).collect()),
+        PrintNoNl(e) => PrintNoNl(folder.fold_expr(e)),
+        Assert(e) => Assert(folder.fold_expr(e)),
+        kind @ (Read(_) | Swap(..) | Return) => kind,
+        If { guard, tt, ff } => If {
+            guard: folder.fold_expr(guard),
+            tt: fold_stmts(folder, tt),
+            ff: fold_stmts(folder, ff),
+        },
+        While { guard, body } => While {
+            guard: folder.fold_expr(guard),
+            body: fold_stmts(folder, body),
+        },
+        For {
+            var,
+            start,
+            end,
+            body,
+        } => For {
+            var,
+            start: Box::new(folder.fold_expr(*start)),
+            end: Box::new(folder.fold_expr(*end)),
+            body: fold_stmts(folder, body),
+        },
+        Case {
+            scrutinee,
+            arms,
+            default,
+        } => Case {
+            scrutinee: folder.fold_expr(scrutinee),
+            arms: arms
+                .into_iter()
+                .map(|(n, body)| (n, fold_stmts(folder, body)))
+                .collect(),
+            default: fold_stmts(folder, default),
+        },
+    };
+    Stmt::new(kind, stmt.span)
+}
+
+/// Fold the operands of the expression.
+pub fn walk_expr<F: Folder + ?Sized>(folder: &mut F, e: Expr) -> Expr {
+    use ExprKind::*;
+    let mut fold = |e: Box<Expr>| Box::new(folder.fold_expr(*e));
+    let span = e.span;
+    let kind = match e.into_kind() {
+        kind @ (Var(_) | Const(_) | Str(_)) => kind,
+        BinOp { op, lhs, rhs } => BinOp {
+            op,
+            lhs: fold(lhs),
+            rhs: fold(rhs),
+        },
+        Negate(e) => Negate(fold(e)),
+        Select { guard, tt, ff } => Select {
+            guard: fold(guard),
+            tt: fold(tt),
+            ff: fold(ff),
+        },
+        Call { func, args } => Call {
+            func,
+            args: args.into_iter().map(|e| folder.fold_expr(e)).collect(),
+        },
+    };
+    Expr::new(kind, span)
+}
+
+fn fold_stmts<F: Folder + ?Sized>(folder: &mut F, stmts: Vec<Stmt>) -> Vec<Stmt> {
+    stmts
+        .into_iter()
+        .map(|stmt| folder.fold_stmt(stmt))
+        .collect()
+}
+
+/// A [Folder] that replaces the operations on constants with their results,
+/// e.g. `+ 3 4` with `7`.  Operations whose result is undefined, such as
+/// division by zero, stay in place so that they fail at run time, and so do
+/// the ones that overflow, so that they fail in checked mode.
+pub struct ConstFolder;
+
+impl Folder for ConstFolder {
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        // fold the operands first, so that nested operations fold too
+        let e = walk_expr(self, e);
+        let value = match &e.kind {
+            ExprKind::BinOp { op, lhs, rhs } => match (&lhs.kind, &rhs.kind) {
+                (ExprKind::Const(l), ExprKind::Const(r)) if !op.overflows(*l, *r) => {
+                    op.eval(*l, *r)
+                }
+                _ => None,
+            },
+            ExprKind::Negate(operand) => match operand.kind {
+                ExprKind::Const(n) => n.checked_neg(),
+                _ => None,
+            },
+            _ => None,
+        };
+        match value {
+            Some(n) => Expr::new(ExprKind::Const(n), e.span),
+            None => e,
+        }
+    }
+}
+
+/// The number of statements in the program, counting the ones nested in
+/// blocks and the ones in function bodies too.
+pub fn stmt_count(program: &Program) -> usize {
+    all_bodies(program).map(count_stmts).sum()
+}
+
+/// The number of expression nodes in the program: each variable, constant,
+/// string, operation, and call counts as one, along with its operands.
+pub fn expr_node_count(program: &Program) -> usize {
+    all_bodies(program).map(count_expr_nodes).sum()
+}
+
+// The main program and the function bodies
+fn all_bodies(program: &Program) -> impl Iterator<Item = &[Stmt]> {
+    std::iter::once(&program.stmts[..]).chain(program.funcs.iter().map(|f| &f.body[..]))
+}
+
+// The expressions and the blocks right inside the statement
+fn children(stmt: &Stmt) -> (Vec<&Expr>, Vec<&[Stmt]>) {
+    use StmtKind::*;
+    match &stmt.kind {
+        Assign(_, e) | PrintNoNl(e) | Assert(e) => (vec![e], vec![]),
+        Print(es) => (es.iter().collect(), vec![]),
+        Read(_) | Swap(..) | Return => (vec![], vec![]),
+        If { guard, tt, ff } => (vec![guard], vec![tt, ff]),
+        While { guard, body } => (vec![guard], vec![body]),
+        For {
+            start, end, body, ..
+        } => (vec![&**start, &**end], vec![body]),
+        Case {
+            scrutinee,
+            arms,
+            default,
+        } => {
+            let mut blocks: Vec<&[Stmt]> = arms.iter().map(|(_, body)| &body[..]).collect();
+            blocks.push(default);
+            (vec![scrutinee], blocks)
+        }
+    }
+}
+
+fn count_stmts(stmts: &[Stmt]) -> usize {
+    stmts
+        .iter()
+        .map(|stmt| 1 + children(stmt).1.into_iter().map(count_stmts).sum::<usize>())
+        .sum()
+}
+
+fn count_expr_nodes(stmts: &[Stmt]) -> usize {
+    stmts
+        .iter()
+        .map(|stmt| {
+            let (exprs, blocks) = children(stmt);
+            exprs.into_iter().map(count_nodes).sum::<usize>()
+                + blocks.into_iter().map(count_expr_nodes).sum::<usize>()
+        })
+        .sum()
+}
+
+fn count_nodes(e: &Expr) -> usize {
+    use ExprKind::*;
+    1 + match &e.kind {
+        Var(_) | Const(_) | Str(_) => 0,
+        BinOp { lhs, rhs, .. } => count_nodes(lhs) + count_nodes(rhs),
+        Negate(e) => count_nodes(e),
+        Select { guard, tt, ff } => count_nodes(guard) + count_nodes(tt) + count_nodes(ff),
+        Call { args, .. } => args.iter().map(count_nodes).sum(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::parse;
+
+    // SECTION: helpers
+
+    // Parse the program, and fold its constants
+    fn fold_src(src: &str) -> Program {
+        ConstFolder.fold_program(parse(src).unwrap())
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn const_folder() {
+        assert_eq!(
+            fold_src("$print + 3 4").stmts,
+            vec![StmtKind::Print(
+                vec![ExprKind::Const(Number::Int(7)).into()]
+            )]
+        );
+        assert_eq!(
+            fold_src(":= x * ~ 2 + 1 0.5 $print - x ~ + 1 1").to_string(),
+            ":= x -3.0\n$print - x -2\n"
+        );
+        // everywhere in the program, down to the function bodies
+        assert_eq!(
+            fold_src("$fn f(a) {$if < 1 2 {:= f a(+ 1 1)}} $while & 1 0 {$print ? x 1 = 2 2}")
+                .to_string(),
+            "$fn f(a) {\n    $if 1 {\n        := f a(2)\n    } {}\n}\n$while 0 {\n    $print ? x 1 1\n}\n"
+        );
+        // the spans stay the same
+        let program = fold_src(":= x + 3 4");
+        let StmtKind::Assign(_, e) = &program.stmts[0].kind else {
+            unreachable!();
+        };
+        assert_eq!((e.span.start, e.span.end), (5, 10));
+    }
+
+    #[test]
+    fn non_finite_constants() {
+        let program = fold_src("$print / 1.0 0 / ~ 1 0.0 - / 1.0 0 / 1.0 0");
+        assert_eq!(
+            program.to_string(),
+            "$print / 1.0 0.0 / -1.0 0.0 / 0.0 0.0\n"
+        );
+        // they read back as divisions, which are constants again once folded
+        let all = |program: &Program, pred: fn(&ExprKind) -> bool| match &program.stmts[0].kind {
+            StmtKind::Print(es) => es.iter().all(|e| pred(&e.kind)),
+            _ => unreachable!(),
+        };
+        let reparsed = parse(&program.to_string()).unwrap();
+        assert!(all(&reparsed, |e| matches!(
+            e,
+            ExprKind::BinOp { op: BOp::Div, .. }
+        )));
+        let refolded = ConstFolder.fold_program(reparsed);
+        assert_eq!(refolded.to_string(), program.to_string());
+        assert!(all(&refolded, |e| matches!(
+            e,
+            ExprKind::Const(Number::Float(_))
+        )));
+    }
+
+    #[test]
+    fn const_folder_keeps_errors() {
+        let src = ":= x / 1 0 $print % + 1 1 0 $print ^ 2 ~ 1";
+        assert_eq!(
+            fold_src(src).to_string(),
+            ":= x / 1 0\n$print % 2 0\n$print ^ 2 -1\n"
+        );
+    }
+
+    #[test]
+    fn folder_default() {
+        // a folder that renames every variable
+        struct Rename;
+        impl Folder for Rename {
+            fn fold_expr(&mut self, e: Expr) -> Expr {
+                match e.kind {
+                    ExprKind::Var(_) => Expr::new(ExprKind::Var(crate::common::id("y")), e.span),
+                    _ => walk_expr(self, e),
+                }
+            }
+        }
+        let program = parse("$read x $case + x 1 {1 {$print x} $default {:= z x}}").unwrap();
+        assert_eq!(
+            Rename.fold_program(program).to_string(),
+            "$read x\n$case + y 1 {\n    1 {\n        $print y\n    }\n    $default {\n        := z y\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn counts() {
+        let cases = [
+            ("", 0, 0),
+            ("$read x y $swap x y $return", 3, 0),
+            ("$print 1 \"two\" + x 3", 1, 5),
+            ("$read x $while x {$if < x 0 {:= x 0} {:= x - x 1}}", 5, 8),
+            (
+                "$read x $case x {1 {$print x} $default {$print ~ x $print 0}}",
+                5,
+                5,
+            ),
+            (
+                "$fn f(a, b) {:= f ? a b f(a, 1)} $print f(f(1, 2), 3)",
+                2,
+                11,
+            ),
+        ];
+        for (src, stmts, exprs) in cases {
+            let program = parse(src).unwrap();
+            assert_eq!(stmt_count(&program), stmts, "{src}");
+            assert_eq!(expr_node_count(&program), exprs, "{src}");
+        }
+
+        // folding constants shrinks the expressions, but not the statements
+        let program = parse(":= x * 2 + 3 4 $print - x ~ 1").unwrap();
+        assert_eq!((stmt_count(&program), expr_node_count(&program)), (2, 9));
+        let folded = ConstFolder.fold_program(program);
+        assert_eq!((stmt_count(&folded), expr_node_count(&folded)), (2, 4));
+    }
 }
@@ -0,0 +1,406 @@
+//! Semantic checks on the abstract syntax tree, which run between parsing and
+//! lowering.
+
+use super::ast::*;
+use crate::common::{quote, Id, Set};
+
+/// Check that every variable is defined (by `:=` or `$read`) before it is
+/// read, and return all the violations if there are any.
+///
+/// The check is conservative: a variable is defined after a `$if` only if
+/// both branches define it, and the definitions in a `$while` body do not
+/// count after the loop because the body may not run at all.  In a function,
+/// the parameters are defined from the start.
+pub fn check_uses(program: &Program) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    check_block(&program.stmts, &mut Set::new(), &mut errors);
+
+    for func in &program.funcs {
+        let mut func_errors = vec![];
+        let mut defined = func.params.iter().copied().collect();
+        check_block(&func.body, &mut defined, &mut func_errors);
+        errors.extend(
+            func_errors
+                .into_iter()
+                .map(|e| format!("In the function `{}`: {e}", func.name)),
+        );
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Check that string literals appear only as the values of `$print` and
+/// `$printc`, and return all the violations if there are any.  The parser only
+/// produces such programs, and reading a program from JSON runs this check,
+/// but ASTs built in other ways (e.g. by a [Folder]) may have strings anywhere.
+/// Lowering needs this to hold.
+pub fn check_strings(program: &Program) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    strings_in_block(&program.stmts, &mut errors);
+
+    for func in &program.funcs {
+        let mut func_errors = vec![];
+        strings_in_block(&func.body, &mut func_errors);
+        errors.extend(
+            func_errors
+                .into_iter()
+                .map(|e| format!("In the function `{}`: {e}", func.name)),
+        );
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// Report the string literals that the statements use as numbers
+fn strings_in_block(stmts: &[Stmt], errors: &mut Vec<String>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::Print(es) => {
+                for e in es {
+                    if !matches!(e.kind, ExprKind::Str(_)) {
+                        strings_in_expr(e, errors);
+                    }
+                }
+            }
+            StmtKind::PrintNoNl(e) => {
+                if !matches!(e.kind, ExprKind::Str(_)) {
+                    strings_in_expr(e, errors);
+                }
+            }
+            StmtKind::Assign(_, e) | StmtKind::Assert(e) => strings_in_expr(e, errors),
+            StmtKind::Read(_) | StmtKind::Swap(..) | StmtKind::Return => {}
+            StmtKind::If { guard, tt, ff } => {
+                strings_in_expr(guard, errors);
+                strings_in_block(tt, errors);
+                strings_in_block(ff, errors);
+            }
+            StmtKind::While { guard, body } => {
+                strings_in_expr(guard, errors);
+                strings_in_block(body, errors);
+            }
+            StmtKind::For {
+                start, end, body, ..
+            } => {
+                strings_in_expr(start, errors);
+                strings_in_expr(end, errors);
+                strings_in_block(body, errors);
+            }
+            StmtKind::Case {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                strings_in_expr(scrutinee, errors);
+                for (_, body) in arms {
+                    strings_in_block(body, errors);
+                }
+                strings_in_block(default, errors);
+            }
+        }
+    }
+}
+
+fn strings_in_expr(e: &Expr, errors: &mut Vec<String>) {
+    match &e.kind {
+        ExprKind::Str(s) => errors.push(format!(
+            "The string literal {} is used as a number, but strings can only be printed.",
+            quote(s)
+        )),
+        ExprKind::Var(_) | ExprKind::Const(_) => {}
+        ExprKind::BinOp { lhs, rhs, .. } => {
+            strings_in_expr(lhs, errors);
+            strings_in_expr(rhs, errors);
+        }
+        ExprKind::Negate(e) => strings_in_expr(e, errors),
+        ExprKind::Select { guard, tt, ff } => {
+            strings_in_expr(guard, errors);
+            strings_in_expr(tt, errors);
+            strings_in_expr(ff, errors);
+        }
+        ExprKind::Call { args, .. } => {
+            for arg in args {
+                strings_in_expr(arg, errors);
+            }
+        }
+    }
+}
+
+// Check the statements given the variables defined before them, and add the
+// variables they define.  Return whether the block always returns, in which
+// case the code after it is unreachable.
+fn check_block(stmts: &[Stmt], defined: &mut Set<Id>, errors: &mut Vec<String>) -> bool {
+    for stmt in stmts {
+        if check_stmt(stmt, defined, errors) {
+            // the rest of the block is unreachable, so it cannot read any
+            // undefined variables
+            return true;
+        }
+    }
+    false
+}
+
+fn check_stmt(stmt: &Stmt, defined: &mut Set<Id>, errors: &mut Vec<String>) -> bool {
+    match &stmt.kind {
+        StmtKind::Assign(x, e) => {
+            check_expr(e, defined, errors);
+            defined.insert(*x);
+        }
+        StmtKind::Print(es) => {
+            for e in es {
+                check_expr(e, defined, errors);
+            }
+        }
+        StmtKind::PrintNoNl(e) | StmtKind::Assert(e) => check_expr(e, defined, errors),
+        StmtKind::Read(xs) => defined.extend(xs),
+        StmtKind::Swap(x, y) => {
+            for v in [x, y] {
+                if !defined.contains(v) {
+                    errors.push(format!(
+                        "The variable `{v}` may be used before it is defined."
+                    ));
+                    defined.insert(*v);
+                }
+            }
+        }
+        StmtKind::If { guard, tt, ff } => {
+            check_expr(guard, defined, errors);
+            let mut tt_defined = defined.clone();
+            let tt_returns = check_block(tt, &mut tt_defined, errors);
+            let mut ff_defined = defined.clone();
+            let ff_returns = check_block(ff, &mut ff_defined, errors);
+            // a branch that returns does not reach the code after the `$if`
+            *defined = match (tt_returns, ff_returns) {
+                (true, _) => ff_defined,
+                (false, true) => tt_defined,
+                (false, false) => &tt_defined & &ff_defined,
+            };
+            return tt_returns && ff_returns;
+        }
+        StmtKind::While { guard, body } => {
+            check_expr(guard, defined, errors);
+            check_block(body, &mut defined.clone(), errors);
+        }
+        StmtKind::For {
+            var,
+            start,
+            end,
+            body,
+        } => {
+            // the variable is set before `end` is first evaluated
+            check_expr(start, defined, errors);
+            defined.insert(*var);
+            check_expr(end, defined, errors);
+            check_block(body, &mut defined.clone(), errors);
+        }
+        StmtKind::Case {
+            scrutinee,
+            arms,
+            default,
+        } => {
+            check_expr(scrutinee, defined, errors);
+            // like for `$if`, only the arms that do not return reach the code
+            // after the `$case`
+            let mut after: Option<Set<Id>> = None;
+            for body in arms.iter().map(|(_, body)| body).chain([default]) {
+                let mut body_defined = defined.clone();
+                if !check_block(body, &mut body_defined, errors) {
+                    after = Some(match after {
+                        Some(after) => &after & &body_defined,
+                        None => body_defined,
+                    });
+                }
+            }
+            match after {
+                Some(after) => *defined = after,
+                None => return true,
+            }
+        }
+        StmtKind::Return => return true,
+    }
+    false
+}
+
+fn check_expr(e: &Expr, defined: &mut Set<Id>, errors: &mut Vec<String>) {
+    match &e.kind {
+        ExprKind::Var(x) => {
+            if !defined.contains(x) {
+                errors.push(format!(
+                    "The variable `{x}` may be used before it is defined."
+                ));
+                // report each variable only once
+                defined.insert(*x);
+            }
+        }
+        ExprKind::Const(_) | ExprKind::Str(_) => {}
+        ExprKind::BinOp { lhs, rhs, .. } => {
+            check_expr(lhs, defined, errors);
+            check_expr(rhs, defined, errors);
+        }
+        ExprKind::Negate(e) => check_expr(e, defined, errors),
+        ExprKind::Select { guard, tt, ff } => {
+            check_expr(guard, defined, errors);
+            check_expr(tt, defined, errors);
+            check_expr(ff, defined, errors);
+        }
+        ExprKind::Call { args, .. } => {
+            for arg in args {
+                check_expr(arg, defined, errors);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::parse;
+
+    // SECTION: helpers
+
+    // Parse and check a program
+    fn check_src(src: &str) -> Result<(), Vec<String>> {
+        check_uses(&parse(src).unwrap())
+    }
+
+    // The error message for an undefined variable
+    fn undefined(x: &str) -> String {
+        format!("The variable `{x}` may be used before it is defined.")
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn defined() {
+        let programs = [
+            "",
+            "$print 1 $print \"x\"",
+            "$read x $print x",
+            "$read x y $swap x y $print y",
+            ":= x 1 := y + x x $print y",
+            "$read x $if x {:= y 1} {$read y} $print y",
+            "$read x $if x {:= y 1} {$return} $print y",
+            "$read x $case x {1 {:= y 1} 2 {$return} $default {$read y}} $print y",
+            "$read x $case x {1 {$return} $default {$return}} $print y",
+            ":= i 0 $while < i 10 {:= j i $print j := i + i 1}",
+            "$read n $for i 0 + i n {$print i} $print i",
+            "$fn f(a, b) {:= f + a b} $print f(1, 2)",
+        ];
+        for src in programs {
+            assert_eq!(check_src(src), Ok(()), "{src:?} is rejected");
+        }
+    }
+
+    #[test]
+    fn undefined_variables() {
+        assert_eq!(check_src("$print x"), Err(vec![undefined("x")]));
+        assert_eq!(check_src(":= x + x 1"), Err(vec![undefined("x")]));
+        assert_eq!(
+            check_src("$print + x y $print x"),
+            Err(vec![undefined("x"), undefined("y")])
+        );
+        assert_eq!(check_src("$if x {} {}"), Err(vec![undefined("x")]));
+        assert_eq!(check_src("$print f(x)"), Err(vec![undefined("x")]));
+        // the use comes before the definition
+        assert_eq!(check_src("$print x $read x"), Err(vec![undefined("x")]));
+        assert_eq!(check_src(":= x 1 $swap x y"), Err(vec![undefined("y")]));
+        assert_eq!(check_src(":= x 1 $print x y"), Err(vec![undefined("y")]));
+        assert_eq!(check_src("$assert < x 1"), Err(vec![undefined("x")]));
+        assert_eq!(check_src("$for i i 10 {}"), Err(vec![undefined("i")]));
+        assert_eq!(
+            check_src("$for i 0 10 {:= j i} $print j"),
+            Err(vec![undefined("j")])
+        );
+    }
+
+    #[test]
+    fn branches() {
+        // defined only in one arm
+        assert_eq!(
+            check_src("$read c $if c {:= x 1} {} $print x"),
+            Err(vec![undefined("x")])
+        );
+        assert_eq!(
+            check_src("$read c $if c {} $elif - c 1 {:= x 1} $else {:= x 2} $print x"),
+            Err(vec![undefined("x")])
+        );
+        // each arm sees only its own definitions
+        assert_eq!(
+            check_src("$read c $if c {:= x 1} {$print x}"),
+            Err(vec![undefined("x")])
+        );
+        assert_eq!(
+            check_src("$read c $case c {1 {:= x 1} 2 {:= y 1}} $print x"),
+            Err(vec![undefined("x")])
+        );
+        assert_eq!(
+            check_src("$read c $case c {1 {:= x 1} $default {:= x 2}} $print x"),
+            Ok(())
+        );
+        // the loop body may not run
+        assert_eq!(
+            check_src("$read n $while n {:= x n := n 0} $print x"),
+            Err(vec![undefined("x")])
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            check_strings(&parse(r#"$print "a" 1 $printc "b" $fn f() {$print "c"}"#).unwrap()),
+            Ok(())
+        );
+
+        // the parser does not allow strings anywhere else, so build the ASTs
+        let string = || Expr::from(ExprKind::Str("a\"b".to_string()));
+        let sum = || {
+            Expr::from(ExprKind::BinOp {
+                op: BOp::Add,
+                lhs: Box::new(string()),
+                rhs: Box::new(ExprKind::Const(Number::Int(1)).into()),
+            })
+        };
+        let error =
+            r#"The string literal "a\"b" is used as a number, but strings can only be printed."#;
+        let program = Program {
+            stmts: vec![
+                StmtKind::Assign(crate::common::id("x"), string()).into(),
+                StmtKind::Print(vec![string(), sum()]).into(),
+            ],
+            funcs: vec![Func {
+                name: crate::common::id("f"),
+                params: vec![],
+                body: vec![StmtKind::While {
+                    guard: sum(),
+                    body: vec![],
+                }
+                .into()],
+            }],
+        };
+        assert_eq!(
+            check_strings(&program),
+            Err(vec![
+                error.to_string(),
+                error.to_string(),
+                format!("In the function `f`: {error}")
+            ])
+        );
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(check_src("$fn f(x) {:= f x} $print f(1)"), Ok(()));
+        // the variables of the main program are not visible in functions
+        assert_eq!(
+            check_src("$read y $fn f(x) {:= f + x y} $print f(y)"),
+            Err(vec![format!("In the function `f`: {}", undefined("y"))])
+        );
+    }
+}
@@ -0,0 +1,591 @@
+//! An interpreter that runs the abstract syntax tree directly.
+//!
+//! This is the reference semantics for smol programs: running a program with
+//! this interpreter and running its lowered form with the tiny IR interpreter
+//! should produce the same results.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use super::ast::*;
+use crate::common::{quote, Id};
+use crate::middle::interp::{
+    grow_stack, read_num, Location, RunResult, RuntimeError, MAX_CALL_DEPTH,
+};
+
+/// Run the program, reading numbers from `input` (one per line) and printing
+/// numbers to `output` (one per line).
+pub fn eval(program: &Program, input: impl Read, output: impl Write) -> RunResult<()> {
+    let mut evaluator = Evaluator {
+        funcs: &program.funcs,
+        env: HashMap::new(),
+        input: BufReader::new(input),
+        output,
+        depth: 0,
+    };
+    // returning from the main program just stops it
+    evaluator.eval_stmts(&program.stmts)?;
+    evaluator.output.flush()?;
+    Ok(())
+}
+
+/// The state of the interpreter that persists between runs, so that a program
+/// can be run piece by piece (e.g., in a REPL).
+#[derive(Debug, Default)]
+pub struct Session {
+    funcs: Vec<Func>,
+    env: HashMap<Id, Number>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define the functions of the program, replacing the ones with the same
+    /// names, then run its statements.  The variables keep their values
+    /// between runs, even if a run stops with an error.
+    pub fn run(
+        &mut self,
+        program: Program,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> RunResult<()> {
+        for func in program.funcs {
+            self.funcs.retain(|f| f.name != func.name);
+            self.funcs.push(func);
+        }
+
+        let mut evaluator = Evaluator {
+            funcs: &self.funcs,
+            env: std::mem::take(&mut self.env),
+            input,
+            output,
+            depth: 0,
+        };
+        let result = evaluator.eval_stmts(&program.stmts);
+        self.env = evaluator.env;
+        // the output before an error goes out too
+        let flushed = evaluator.output.flush();
+        result?;
+        flushed?;
+        Ok(())
+    }
+
+    /// The value of the variable, if it is set.
+    pub fn get(&self, x: Id) -> Option<Number> {
+        self.env.get(&x).copied()
+    }
+}
+
+// The state of the interpreter
+struct Evaluator<'a, R, W> {
+    funcs: &'a [Func],
+    // values of the variables in the current call, the ones that are missing
+    // are 0
+    env: HashMap<Id, Number>,
+    input: R,
+    output: W,
+    // the number of calls being run
+    depth: usize,
+}
+
+// Whether to keep running the statements after a statement
+#[derive(PartialEq, Eq)]
+enum Flow {
+    Next,
+    Return,
+}
+
+impl<R: BufRead, W: Write> Evaluator<'_, R, W> {
+    fn eval_stmts(&mut self, stmts: &[Stmt]) -> RunResult<Flow> {
+        for stmt in stmts {
+            if self.eval_stmt(stmt)? == Flow::Return {
+                return Ok(Flow::Return);
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn eval_stmt(&mut self, stmt: &Stmt) -> RunResult<Flow> {
+        match &stmt.kind {
+            StmtKind::Assign(x, e) => {
+                let v = self.eval_expr(e)?;
+                self.env.insert(*x, v);
+            }
+            StmtKind::Print(es) => {
+                for (i, e) in es.iter().enumerate() {
+                    if i > 0 {
+                        write!(self.output, " ")?;
+                    }
+                    match &e.kind {
+                        ExprKind::Str(s) => write!(self.output, "{s}")?,
+                        _ => {
+                            let v = self.eval_expr(e)?;
+                            write!(self.output, "{v}")?;
+                        }
+                    }
+                }
+                writeln!(self.output)?;
+            }
+            StmtKind::PrintNoNl(Expr {
+                kind: ExprKind::Str(s),
+                ..
+            }) => write!(self.output, "{s}")?,
+            StmtKind::PrintNoNl(e) => {
+                let v = self.eval_expr(e)?;
+                write!(self.output, "{v}")?;
+            }
+            StmtKind::Read(xs) => {
+                for x in xs {
+                    let v = read_num(&mut self.input)?;
+                    self.env.insert(*x, v);
+                }
+            }
+            StmtKind::Swap(x, y) => {
+                let vx = self.env.get(x).copied().unwrap_or(Number::Int(0));
+                let vy = self.env.get(y).copied().unwrap_or(Number::Int(0));
+                self.env.insert(*x, vy);
+                self.env.insert(*y, vx);
+            }
+            StmtKind::If { guard, tt, ff } => {
+                return if self.eval_expr(guard)?.is_true() {
+                    self.eval_stmts(tt)
+                } else {
+                    self.eval_stmts(ff)
+                };
+            }
+            StmtKind::Assert(e) => {
+                if !self.eval_expr(e)?.is_true() {
+                    return Err(RuntimeError::AssertionFailed(describe_assertion(
+                        e, stmt.span,
+                    )));
+                }
+            }
+            StmtKind::Case {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                let v = self.eval_expr(scrutinee)?;
+                let body = arms
+                    .iter()
+                    .find(|(n, _)| {
+                        BOp::Eq
+                            .eval(v, Number::Int(*n))
+                            .is_some_and(Number::is_true)
+                    })
+                    .map_or(default, |(_, body)| body);
+                return self.eval_stmts(body);
+            }
+            StmtKind::While { guard, body } => {
+                while self.eval_expr(guard)?.is_true() {
+                    if self.eval_stmts(body)? == Flow::Return {
+                        return Ok(Flow::Return);
+                    }
+                }
+            }
+            StmtKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                let start = self.eval_expr(start)?;
+                self.env.insert(*var, start);
+                loop {
+                    let i = self.env[var];
+                    let end = self.eval_expr(end)?;
+                    if !BOp::Lt.eval(i, end).is_some_and(Number::is_true) {
+                        break;
+                    }
+                    if self.eval_stmts(body)? == Flow::Return {
+                        return Ok(Flow::Return);
+                    }
+                    // the body may change the variable too
+                    let i = self.env.get(var).copied().unwrap_or(Number::Int(0));
+                    let next = BOp::Add
+                        .eval(i, Number::Int(1))
+                        .expect("`+` is always defined");
+                    self.env.insert(*var, next);
+                }
+            }
+            StmtKind::Return => return Ok(Flow::Return),
+        }
+        Ok(Flow::Next)
+    }
+
+    fn eval_expr(&mut self, e: &Expr) -> RunResult<Number> {
+        match &e.kind {
+            ExprKind::Var(x) => Ok(self.env.get(x).copied().unwrap_or(Number::Int(0))),
+            ExprKind::Const(n) => Ok(*n),
+            ExprKind::BinOp {
+                op: op @ (BOp::And | BOp::Or),
+                lhs,
+                rhs,
+            } => {
+                // only evaluate the right operand if the left one does not
+                // determine the result
+                let lhs = self.eval_expr(lhs)?.is_true();
+                if lhs == (*op == BOp::Or) {
+                    Ok(Number::Int(lhs as i64))
+                } else {
+                    Ok(Number::Int(self.eval_expr(rhs)?.is_true() as i64))
+                }
+            }
+            ExprKind::BinOp { op, lhs, rhs } => {
+                let l = self.eval_expr(lhs)?;
+                let r = self.eval_expr(rhs)?;
+                op.eval(l, r)
+                    .ok_or_else(|| RuntimeError::undefined(*op, r, Location::Expr(e.to_string())))
+            }
+            ExprKind::Negate(e) => Ok(-self.eval_expr(e)?),
+            ExprKind::Select { guard, tt, ff } => {
+                if self.eval_expr(guard)?.is_true() {
+                    self.eval_expr(tt)
+                } else {
+                    self.eval_expr(ff)
+                }
+            }
+            ExprKind::Call { func, args } => {
+                let args = args
+                    .iter()
+                    .map(|e| self.eval_expr(e))
+                    .collect::<RunResult<Vec<_>>>()?;
+                self.call(*func, args)
+            }
+            ExprKind::Str(s) => Err(RuntimeError::StringOperand(quote(s))),
+        }
+    }
+
+    // Run the function with a fresh set of variables, and get its result
+    fn call(&mut self, name: Id, args: Vec<Number>) -> RunResult<Number> {
+        let func = self
+            .funcs
+            .iter()
+            .find(|f| f.name == name)
+            .ok_or(RuntimeError::MissingFunction(name))?;
+        if func.params.len() != args.len() {
+            return Err(RuntimeError::WrongArgumentCount {
+                func: name,
+                expected: func.params.len(),
+                found: args.len(),
+            });
+        }
+
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(RuntimeError::CallTooDeep(name));
+        }
+
+        let env = func.params.iter().copied().zip(args).collect();
+        let caller_env = std::mem::replace(&mut self.env, env);
+        self.depth += 1;
+        let result = grow_stack(|| self.eval_stmts(&func.body));
+        self.depth -= 1;
+        let env = std::mem::replace(&mut self.env, caller_env);
+        result?;
+        Ok(env.get(&name).copied().unwrap_or(Number::Int(0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::id;
+    use crate::front::{lower, parse};
+    use crate::middle::interp::run;
+
+    // SECTION: helpers
+
+    // Run the given program with both interpreters, check that they agree,
+    // and return the output.
+    fn eval_src(src: &str, input: &str) -> RunResult<String> {
+        let program = parse(src).unwrap();
+
+        let mut ast_output = vec![];
+        let ast_result = eval(&program, input.as_bytes(), &mut ast_output);
+
+        let mut tir_output = vec![];
+        let tir_result = run(&lower(program), input.as_bytes(), &mut tir_output);
+
+        assert_eq!(
+            ast_output, tir_output,
+            "the interpreters produced different outputs for {src:?}"
+        );
+        assert_eq!(
+            ast_result.as_ref().map_err(without_location),
+            tir_result.as_ref().map_err(without_location),
+            "the interpreters produced different results for {src:?}"
+        );
+        ast_result.map(|()| String::from_utf8(ast_output).unwrap())
+    }
+
+    // The message of the error, with the location of an arithmetic error left
+    // out: the AST interpreter points at the expression and the TIR one at the
+    // instruction.
+    fn without_location(e: &RuntimeError) -> String {
+        match e.location() {
+            Some(at) => e.to_string().replace(&at.to_string(), ""),
+            None => e.to_string(),
+        }
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn straight_line() {
+        assert_eq!(eval_src("", "").unwrap(), "");
+        assert_eq!(eval_src("$print 42 $print x", "").unwrap(), "42\n0\n");
+        assert_eq!(
+            eval_src(
+                "$read a $read b := c * + a b ~ 2 $print c $print % c 5",
+                "3\n4\n"
+            )
+            .unwrap(),
+            "-14\n-4\n"
+        );
+        assert_eq!(
+            eval_src(r#":= x 7 $print "x =" x ~ x $print x"#, "").unwrap(),
+            "x = 7 -7\n7\n"
+        );
+        assert_eq!(
+            eval_src(":= a 1 := b 2 $swap a b $print a $print b", "").unwrap(),
+            "2\n1\n"
+        );
+        let src = "$read x $case x {1 {$print 10} 2 {$print 20} $default {$print 0}}";
+        assert_eq!(eval_src(src, "2\n").unwrap(), "20\n");
+        assert_eq!(eval_src(src, "1.0\n").unwrap(), "10\n");
+        assert_eq!(eval_src(src, "3\n").unwrap(), "0\n");
+        let src = "$read x $assert < x 10 $print x";
+        assert_eq!(eval_src(src, "3\n").unwrap(), "3\n");
+        assert_eq!(
+            eval_src(src, "12\n").unwrap_err().to_string(),
+            "Runtime error: assertion failed at 1:9: `$assert < x 10`."
+        );
+        // identical assertions are told apart by where they are
+        let src = "$read x $assert x\n$read x $assert x";
+        assert_eq!(
+            eval_src(src, "1\n0\n").unwrap_err().to_string(),
+            "Runtime error: assertion failed at 2:9: `$assert x`."
+        );
+        // the interpreters agree on failures in functions too
+        assert_eq!(
+            eval_src("$fn f(x) {$assert x := f x} $print f(0)", "")
+                .unwrap_err()
+                .to_string(),
+            "Runtime error: assertion failed at 1:11: `$assert x`."
+        );
+    }
+
+    #[test]
+    fn agreement() {
+        let programs = [
+            ("$read a $read b $if < a b {$print a} {$print b}", "3\n5\n"),
+            ("$read a $read b $if < a b {$print a} {$print b}", "5\n3\n"),
+            (
+                "$read a $if a {} {$read b $read c $read x $print + * a * x x + * b x c}",
+                "0\n1\n2\n3\n",
+            ),
+            (
+                "$read a $if a {} {$read b $read c $read x $print + * a * x x + * b x c}",
+                "2\n1\n2\n3\n",
+            ),
+            (
+                "$read n := i 0 := sum 0 $while < i n {:= i + i 1 := sum + sum * i i} $print sum",
+                "10\n",
+            ),
+            (
+                ":= a 0 := b 1 := n 10 $while > n 0 {$print a := t b := b + a b := a t := n - n 1}",
+                "",
+            ),
+            (
+                "$read n $for i 0 n {$for j i n {$print * i j} := n - n 1}",
+                "5\n",
+            ),
+            (
+                "$print + 9223372036854775807 1 $print / ~ 9223372036854775807 ~ 1",
+                "",
+            ),
+            (
+                r#"$read x $print "x is\n" $print x $print "\"done\"""#,
+                "7\n",
+            ),
+        ];
+        for (src, input) in programs {
+            assert!(eval_src(src, input).is_ok(), "{src:?} failed");
+        }
+    }
+
+    #[test]
+    fn floats() {
+        let src = "$read r $print * 3.5 * r r $print + 1 2 $print / 7 2 $print / 7 2.0 \
+                   $print % 7.5 2 $print < 1.5 2 $print ~ r";
+        assert_eq!(
+            eval_src(src, "2\n").unwrap(),
+            "14.0\n3\n3\n3.5\n1.5\n1\n-2\n"
+        );
+        assert_eq!(
+            eval_src(src, "0.5\n").unwrap(),
+            "0.875\n3\n3\n3.5\n1.5\n1\n-0.5\n"
+        );
+
+        let src = "$read x $if x {$print 1} {$print 0} $print / 1 x $print / ~ 1 x";
+        assert_eq!(eval_src(src, "0.0\n").unwrap(), "0\ninf\n-inf\n");
+        assert_eq!(eval_src(src, "0.1\n").unwrap(), "1\n10.0\n-10.0\n");
+        assert!(matches!(
+            eval_src(src, "0\n"),
+            Err(RuntimeError::DivisionByZero(_))
+        ));
+
+        let src = ":= x 0.0 $while < x 1 {:= x + x 0.25} $print x";
+        assert_eq!(eval_src(src, "").unwrap(), "1.0\n");
+    }
+
+    #[test]
+    fn short_circuit() {
+        // the division by zero on the right is never evaluated
+        let src = "$read x $print & x / 10 x $print | = x 0 / 10 x";
+        assert_eq!(eval_src(src, "0\n").unwrap(), "0\n1\n");
+        assert_eq!(eval_src(src, "5\n").unwrap(), "1\n1\n");
+        assert!(matches!(
+            eval_src("$read x $print | x / 10 x", "0\n"),
+            Err(RuntimeError::DivisionByZero(_))
+        ));
+
+        let src = "$read x $read y $if & < 0 x < x 10 {$print 1} {$print 0} $print | x y";
+        assert_eq!(eval_src(src, "3\n0\n").unwrap(), "1\n1\n");
+        assert_eq!(eval_src(src, "10\n0\n").unwrap(), "0\n1\n");
+        assert_eq!(eval_src(src, "0\n0\n").unwrap(), "0\n0\n");
+        assert_eq!(eval_src(src, "0\n-4\n").unwrap(), "0\n1\n");
+    }
+
+    #[test]
+    fn select() {
+        let src = "$read x $print ? < x 0 0 x $print ? x / 10 x 42";
+        assert_eq!(eval_src(src, "-5\n").unwrap(), "0\n-2\n");
+        assert_eq!(eval_src(src, "0\n").unwrap(), "0\n42\n");
+        assert_eq!(eval_src(src, "7\n").unwrap(), "7\n1\n");
+    }
+
+    #[test]
+    fn functions() {
+        let src =
+            "$fn square(x) {:= square * x x} $read n $print square(n) $print square(square(2))";
+        assert_eq!(eval_src(src, "5\n").unwrap(), "25\n16\n");
+
+        // variables are local to each call, and the result defaults to 0
+        let src = "$fn f(x) {:= y + y 1 $print y} := y 7 $print f(f(1)) $print y";
+        assert_eq!(eval_src(src, "").unwrap(), "1\n1\n0\n7\n");
+
+        let src = "$fn fact(n) {$if < n 2 {:= fact 1} {:= fact * n fact(- n 1)}} \
+                   $fn fib(n) {:= fib n $if > n 1 {:= fib + fib(- n 1) fib(- n 2)}} \
+                   $read n $print fact(n) $print fib(n)";
+        assert_eq!(eval_src(src, "10\n").unwrap(), "3628800\n55\n");
+        assert_eq!(eval_src(src, "1\n").unwrap(), "1\n1\n");
+    }
+
+    #[test]
+    fn deep_recursion() {
+        // far deeper than a test thread's stack holds, which grows as needed
+        let src = "$read n $fn f(n) {$if n {:= f + 1 f(- n 1)} {:= f 0}} $print f(n)";
+        let max = MAX_CALL_DEPTH - 1;
+        assert_eq!(eval_src(src, &max.to_string()).unwrap(), format!("{max}\n"));
+        // the interpreters stop rather than running out of memory
+        for n in [MAX_CALL_DEPTH, 1_000_000] {
+            assert!(matches!(
+                eval_src(src, &n.to_string()),
+                Err(RuntimeError::CallTooDeep(f)) if f == id("f")
+            ));
+        }
+    }
+
+    #[test]
+    fn early_return() {
+        let src = "$read x $if < x 0 {$print \"negative\" $return} $print x $return $print 0";
+        assert_eq!(eval_src(src, "-3\n").unwrap(), "negative\n");
+        assert_eq!(eval_src(src, "3\n").unwrap(), "3\n");
+
+        let src = "$fn find(n) {:= i 1 $while i {$if = * i i n {:= find i $return} := i + i 1 \
+                   $if > i n {:= i 0}}} $read n $print find(n) $print 1";
+        assert_eq!(eval_src(src, "49\n").unwrap(), "7\n1\n");
+        assert_eq!(eval_src(src, "50\n").unwrap(), "0\n1\n");
+    }
+
+    #[test]
+    fn function_errors() {
+        assert!(matches!(
+            eval_src("$print 1 $print f(1)", ""),
+            Err(RuntimeError::MissingFunction(f)) if f == id("f")
+        ));
+        assert!(matches!(
+            eval_src("$fn f(x, y) {} $print f(1)", ""),
+            Err(RuntimeError::WrongArgumentCount {
+                expected: 2,
+                found: 1,
+                ..
+            })
+        ));
+        // the arguments are evaluated before the call
+        assert!(matches!(
+            eval_src("$fn f(x) {} $print f(/ 1 0)", ""),
+            Err(RuntimeError::DivisionByZero(_))
+        ));
+        assert!(matches!(
+            eval_src("$fn f(x) {:= f / 1 x} $print f(2) $print f(0)", ""),
+            Err(RuntimeError::DivisionByZero(_))
+        ));
+    }
+
+    #[test]
+    fn session() {
+        let mut session = Session::new();
+        let mut input = &b"5\n"[..];
+        let mut output = vec![];
+        let mut run = |src: &str| session.run(parse(src).unwrap(), &mut input, &mut output);
+
+        run(":= x 2 $fn f(a) {:= f * a 2}").unwrap();
+        run("$read y $print + x y").unwrap();
+        run("$print f(y)").unwrap();
+        assert!(matches!(
+            run(":= x 3 $print / 1 0 := x 4"),
+            Err(RuntimeError::DivisionByZero(_))
+        ));
+        // redefining a function replaces it
+        run("$fn f(a) {:= f ~ a} $print f(x) $return $print 1").unwrap();
+        assert_eq!(session.get(id("x")), Some(Number::Int(3)));
+        assert_eq!(session.get(id("z")), None);
+        assert_eq!(output, b"7\n10\n-3\n");
+    }
+
+    #[test]
+    fn errors() {
+        // the parser only allows strings to be printed, but other ASTs may
+        // have them anywhere
+        let program = Program {
+            stmts: vec![StmtKind::Print(vec![ExprKind::Negate(Box::new(
+                ExprKind::Str("s".to_string()).into(),
+            ))
+            .into()])
+            .into()],
+            funcs: vec![],
+        };
+        let err = eval(&program, "".as_bytes(), vec![]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Runtime error: the string literal "s" is used as a number."#
+        );
+
+        assert!(matches!(
+            eval_src("$print 1 $read x $print / 1 x $print 2", "0\n"),
+            Err(RuntimeError::DivisionByZero(Location::Expr(e))) if e == "/ 1 x"
+        ));
+        assert!(matches!(
+            eval_src("$read x", ""),
+            Err(RuntimeError::EndOfInput)
+        ));
+        assert!(matches!(
+            eval_src("$read x", "x\n"),
+            Err(RuntimeError::InvalidInput(_))
+        ));
+    }
+}
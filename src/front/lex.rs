@@ -12,6 +12,47 @@ pub struct Token<'src> {
     pub kind: TokenKind,
     /// What part of the input this token carries.
     pub text: &'src str,
+    /// The line this token starts at (1-based).
+    pub line: usize,
+    /// The column this token starts at, in characters (1-based).
+    pub col: usize,
+    /// The byte offset this token starts at (0-based).
+    pub offset: usize,
+}
+
+impl Token<'_> {
+    /// Is this an error token that looks like a number?
+    pub fn is_malformed_number(&self) -> bool {
+        let digits = self.text.strip_prefix('-').unwrap_or(self.text);
+        self.kind == Error && digits.starts_with(|c: char| c.is_ascii_digit())
+    }
+}
+
+/// A character that does not start any token.
+#[derive(Clone, Copy, PartialEq, Eq, Display, Debug)]
+#[display("Lex error at {line}:{col}: Unexpected character `{ch}`.")]
+pub struct LexError {
+    /// The line the character is at (1-based).
+    pub line: usize,
+    /// The column the character is at (1-based).
+    pub col: usize,
+    pub ch: char,
+}
+
+impl LexError {
+    /// The error for an error token, if the token is a character that does
+    /// not start any token.  Malformed numbers are error tokens too, but they
+    /// are left for the parser to report.
+    pub fn from_token(token: &Token) -> Option<LexError> {
+        if token.kind != Error || token.is_malformed_number() {
+            return None;
+        }
+        Some(LexError {
+            line: token.line,
+            col: token.col,
+            ch: token.text.chars().next()?,
+        })
+    }
 }
 
 /// Token classes.
@@ -21,18 +62,56 @@ pub enum TokenKind {
     Id,
     #[display("num")]
     Num,
+    #[display("float")]
+    Float,
     #[display(":=")]
     Assign,
+    #[display("+=")]
+    AddAssign,
+    #[display("-=")]
+    SubAssign,
+    #[display("*=")]
+    MulAssign,
+    #[display("/=")]
+    DivAssign,
     #[display("$print")]
     Print,
+    #[display("$printc")]
+    PrintC,
     #[display("$read")]
     Read,
+    #[display("$swap")]
+    Swap,
+    #[display("$assert")]
+    Assert,
     #[display("$if")]
     If,
+    #[display("$while")]
+    While,
+    #[display("$for")]
+    For,
+    #[display("$elif")]
+    Elif,
+    #[display("$else")]
+    Else,
+    #[display("$case")]
+    Case,
+    #[display("$default")]
+    Default,
+    #[display("$fn")]
+    Fn,
+    #[display("$return")]
+    Return,
     #[display("{{")]
     LBrace,
     #[display("}}")]
     RBrace,
+    #[display("(")]
+    LParen,
+    #[display(")")]
+    RParen,
+    #[display(",")]
+    Comma,
     #[display("+")]
     Plus,
     #[display("-")]
@@ -41,10 +120,48 @@ pub enum TokenKind {
     Mul,
     #[display("/")]
     Div,
+    #[display("%")]
+    Percent,
+    #[display("^")]
+    Caret,
+    #[display("?")]
+    Question,
+    #[display("$min")]
+    Min,
+    #[display("$max")]
+    Max,
+    #[display("$band")]
+    BitAnd,
+    #[display("$bor")]
+    BitOr,
+    #[display("$bxor")]
+    BitXor,
     #[display("<")]
     Lt,
+    #[display(">")]
+    Gt,
+    #[display("=")]
+    Eq,
+    #[display("<=")]
+    Le,
+    #[display(">=")]
+    Ge,
+    #[display("!=")]
+    Ne,
+    #[display("<<")]
+    Shl,
+    #[display(">>")]
+    Shr,
     #[display("~")]
     Tilde,
+    #[display("&")]
+    And,
+    #[display("|")]
+    Or,
+    #[display("str")]
+    /// String literals, including the quotes.  The escape sequences are left
+    /// for the parser to resolve.
+    Str,
     #[display("error")]
     /// For unrecognized characters.
     Error,
@@ -53,6 +170,9 @@ pub enum TokenKind {
 pub struct Lexer<'input> {
     input: &'input str,
     pos: usize,
+    // line and column of `pos`
+    line: usize,
+    col: usize,
     whitespace: Regex,
     matchers: Vec<(Regex, TokenKind)>,
 }
@@ -60,20 +180,75 @@ pub struct Lexer<'input> {
 impl<'input> Lexer<'input> {
     pub fn new(input: &'input str) -> Self {
         let matchers = [
+            // `$printc` has to be tried before its prefix `$print`
+            (r"\$printc", PrintC),
             (r"\$print", Print),
             (r"\$read", Read),
+            (r"\$swap", Swap),
+            (r"\$assert", Assert),
             (r"\$if", If),
+            (r"\$while", While),
+            (r"\$for", For),
+            (r"\$elif", Elif),
+            (r"\$else", Else),
+            (r"\$case", Case),
+            (r"\$default", Default),
+            (r"\$fn", Fn),
+            (r"\$return", Return),
+            (r"\$min", Min),
+            (r"\$max", Max),
+            (r"\$band", BitAnd),
+            (r"\$bor", BitOr),
+            (r"\$bxor", BitXor),
             (r"\{", LBrace),
             (r"\}", RBrace),
+            (r"\(", LParen),
+            (r"\)", RParen),
+            (r",", Comma),
+            // `:=` has to be tried before `=` so assignments are not split up
             (r":=", Assign),
+            // so do the compound assignments before their operators, and `-=`
+            // before negative numbers
+            (r"\+=", AddAssign),
+            (r"-=", SubAssign),
+            (r"\*=", MulAssign),
+            (r"/=", DivAssign),
+            (r"=", Eq),
             (r"\+", Plus),
+            // a `-` right before a digit is the sign of a negative number,
+            // which has to be tried before `-` on its own.  The numbers are
+            // the same as the ones below
+            (r"-0[xb][0-9a-zA-Z_]*", Num),
+            (r"-[0-9][0-9_]*(?:\.[0-9_]*)*", Num),
             (r"-", Minus),
             (r"\*", Mul),
             (r"/", Div),
+            (r"%", Percent),
+            (r"\^", Caret),
+            (r"\?", Question),
+            // the two-character operators have to be tried before their
+            // one-character prefixes to get the longest match
+            (r"<=", Le),
+            (r">=", Ge),
+            (r"!=", Ne),
+            (r"<<", Shl),
+            (r">>", Shr),
             (r"<", Lt),
+            (r">", Gt),
             (r"[a-zA-Z_][a-zA-Z0-9_]*", Id),
-            (r"[0-9]+", Num),
+            // anything that looks like a number is read as a single token,
+            // which `number_kind` then classifies.  This way, a malformed
+            // number is a single error token rather than a number followed by
+            // some dots or underscores.  Hexadecimal and binary numbers start
+            // with `0x` and `0b`.
+            (r"0[xb][0-9a-zA-Z_]*", Num),
+            (r"[0-9][0-9_]*(?:\.[0-9_]*)*", Num),
             (r"~", Tilde),
+            (r"&", And),
+            (r"\|", Or),
+            // an unterminated string runs until the end of the line, so that
+            // the parser can report it as a single token
+            (r#""(?:[^"\\\n]|\\.)*"?"#, Str),
         ]
         .into_iter()
         .map(|(regex, kind)| (Regex::new(&format!(r"\A{regex}")).unwrap(), kind))
@@ -82,7 +257,9 @@ impl<'input> Lexer<'input> {
         Lexer {
             input,
             pos: 0,
-            whitespace: Regex::new(r"\A(?:[ \t\f\r\n\v]|(?://.*))*").unwrap(),
+            line: 1,
+            col: 1,
+            whitespace: Regex::new(r"\A(?:[ \t\f\r\n\v]|(?://.*)|(?:#.*))*").unwrap(),
             matchers,
         }
     }
@@ -92,10 +269,28 @@ impl<'input> Lexer<'input> {
         self.pos == self.input.len()
     }
 
+    /// The line and the column the lexer is at.
+    pub fn position(&self) -> (usize, usize) {
+        (self.line, self.col)
+    }
+
+    // Move forward by given number of bytes, keeping track of lines and columns
+    fn advance(&mut self, len: usize) {
+        for c in self.input[self.pos..(self.pos + len)].chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
+        self.pos += len;
+    }
+
     // Skip comments and whitespace
     fn skip_whitespace(&mut self) {
         if let Some(m) = self.whitespace.find(&self.input[self.pos..]) {
-            self.pos += m.end()
+            self.advance(m.end())
         }
     }
 
@@ -118,7 +313,11 @@ impl<'input> Lexer<'input> {
             re.find(&self.input[self.pos..]).map(|m| {
                 (*kind, m.len())
             })
-        }).unwrap_or((Error, 1));
+        }).unwrap_or_else(|| {
+            // skip the whole character, however many bytes it takes
+            let c = self.input[self.pos..].chars().next().unwrap();
+            (Error, c.len_utf8())
+        });
 
         /*
          * Iterative Approach
@@ -135,19 +334,49 @@ impl<'input> Lexer<'input> {
          * }
          */
 
+        let text = &self.input[self.pos..(self.pos + len)];
+        let kind = if kind == Num { number_kind(text) } else { kind };
         let token = Token {
             kind,
-            text: &self.input[self.pos..(self.pos + len)]
+            text,
+            line: self.line,
+            col: self.col,
+            offset: self.pos,
         };
 
-        self.pos += len;
+        self.advance(len);
 
         Some(token)
     }
 }
 
+// Classify a token that looks like a number.  Numbers are made up of groups of
+// digits separated by single underscores, and floats have a dot between two
+// such decimal numbers.  Everything else is malformed, e.g. `3.`, `1.2.3`, `5_`,
+// `5__0`, `0x`, or `0b2`.
+fn number_kind(text: &str) -> TokenKind {
+    let text = text.strip_prefix('-').unwrap_or(text);
+    let is_digits_in = |s: &str, radix| {
+        s.split('_')
+            .all(|group| !group.is_empty() && group.chars().all(|c| c.is_digit(radix)))
+    };
+    if let Some(digits) = text.strip_prefix("0x") {
+        return if is_digits_in(digits, 16) { Num } else { Error };
+    }
+    if let Some(digits) = text.strip_prefix("0b") {
+        return if is_digits_in(digits, 2) { Num } else { Error };
+    }
+
+    let is_digits = |s: &str| is_digits_in(s, 10);
+    match text.split('.').collect::<Vec<_>>()[..] {
+        [int] if is_digits(int) => Num,
+        [int, frac] if is_digits(int) && is_digits(frac) => Float,
+        _ => Error,
+    }
+}
+
 /// Read all the tokens from input
-pub fn get_tokens(input: &str) -> Vec<Token> {
+pub fn get_tokens(input: &str) -> Vec<Token<'_>> {
     let mut lexer = Lexer::new(input);
 
     let mut tokens = vec![];
@@ -158,47 +387,143 @@ pub fn get_tokens(input: &str) -> Vec<Token> {
     tokens
 }
 
+/// Read all the tokens from input, failing at the first character that does
+/// not start any token.
+pub fn try_get_tokens(input: &str) -> Result<Vec<Token<'_>>, LexError> {
+    let tokens = get_tokens(input);
+    match tokens.iter().find_map(LexError::from_token) {
+        Some(e) => Err(e),
+        None => Ok(tokens),
+    }
+}
+
+/// Whether the input has any comments.
+pub fn has_comments(input: &str) -> bool {
+    // the lexer skips only whitespace and comments between the tokens, so
+    // anything else it skips is a comment
+    let is_comment = |skipped: &str| !skipped.trim().is_empty();
+    let mut lexer = Lexer::new(input);
+    let mut end = 0;
+    while let Some(token) = lexer.next() {
+        if is_comment(&input[end..token.offset]) {
+            return true;
+        }
+        end = token.offset + token.text.len();
+    }
+    is_comment(&input[end..])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     // SECTION: helpers
 
+    // Read all the tokens, and clear their positions so that they can be
+    // compared to the tokens created by the helpers below.
+    fn lex(input: &str) -> Vec<Token<'_>> {
+        get_tokens(input)
+            .into_iter()
+            .map(|token| Token {
+                line: 0,
+                col: 0,
+                offset: 0,
+                ..token
+            })
+            .collect()
+    }
+
+    // Create a token without position information
+    fn token(kind: TokenKind, text: &str) -> Token<'_> {
+        Token {
+            kind,
+            text,
+            line: 0,
+            col: 0,
+            offset: 0,
+        }
+    }
+
     // Create an id token
-    fn id(text: &str) -> Token {
-        Token { kind: Id, text }
+    fn id(text: &str) -> Token<'_> {
+        token(Id, text)
     }
 
     // Create a num token
-    fn num(text: &str) -> Token {
-        Token { kind: Num, text }
+    fn num(text: &str) -> Token<'_> {
+        token(Num, text)
     }
 
     // Create an error token
-    fn error(text: &str) -> Token {
-        Token { kind: Error, text }
+    fn error(text: &str) -> Token<'_> {
+        token(Error, text)
+    }
+
+    // Create a float token
+    fn float(text: &str) -> Token<'_> {
+        token(Float, text)
+    }
+
+    // Create a string literal token
+    fn str(text: &str) -> Token<'_> {
+        token(Str, text)
     }
 
     // Create a token with only one lexeme (anything except id, num, error).
     fn t(kind: TokenKind) -> Token<'static> {
-        Token {
+        token(
             kind,
-            text: match kind {
-                Id | Num | Error => unreachable!(),
+            match kind {
+                Id | Num | Float | Str | Error => unreachable!(),
                 Assign => ":=",
+                AddAssign => "+=",
+                SubAssign => "-=",
+                MulAssign => "*=",
+                DivAssign => "/=",
                 Print => "$print",
+                PrintC => "$printc",
                 Read => "$read",
+                Swap => "$swap",
+                Assert => "$assert",
                 If => "$if",
+                While => "$while",
+                For => "$for",
+                Elif => "$elif",
+                Else => "$else",
+                Case => "$case",
+                Default => "$default",
+                Fn => "$fn",
+                Return => "$return",
                 LBrace => "{",
                 RBrace => "}",
+                LParen => "(",
+                RParen => ")",
+                Comma => ",",
                 Plus => "+",
                 Minus => "-",
                 Mul => "*",
                 Div => "/",
+                Percent => "%",
+                Caret => "^",
+                Question => "?",
+                Min => "$min",
+                Max => "$max",
+                BitAnd => "$band",
+                BitOr => "$bor",
+                BitXor => "$bxor",
                 Lt => "<",
+                Gt => ">",
+                Eq => "=",
+                Le => "<=",
+                Ge => ">=",
+                Ne => "!=",
+                Shl => "<<",
+                Shr => ">>",
                 Tilde => "~",
+                And => "&",
+                Or => "|",
             },
-        }
+        )
     }
 
     // SECTION: tests
@@ -214,13 +539,37 @@ mod tests {
         let mut lexer = Lexer::new(" // stuff\n\t  foo ");
         lexer.skip_whitespace();
         assert_eq!(lexer.pos, 13);
+        let mut lexer = Lexer::new(" # stuff\n\t  foo ");
+        lexer.skip_whitespace();
+        assert_eq!(lexer.pos, 12);
     }
 
     #[test]
     fn empty() {
-        assert_eq!(get_tokens(""), vec![]);
-        assert_eq!(get_tokens("  \n//hello\n"), vec![]);
-        assert_eq!(get_tokens("  \n//hi"), vec![]);
+        assert_eq!(lex(""), vec![]);
+        assert_eq!(lex("  \n//hello\n"), vec![]);
+        assert_eq!(lex("  \n//hi"), vec![]);
+        assert_eq!(lex("  \n#hello\n"), vec![]);
+        assert_eq!(lex("  \n# hi $print 0"), vec![]);
+        assert_eq!(lex("#"), vec![]);
+    }
+
+    #[test]
+    fn hash_comments() {
+        assert_eq!(lex("x # y\nz"), vec![id("x"), id("z")]);
+        assert_eq!(lex("x#y"), vec![id("x")]);
+        assert_eq!(lex("# a // b\n// c # d\nx"), vec![id("x")]);
+        assert_eq!(lex("x\n# last line"), vec![id("x")]);
+    }
+
+    #[test]
+    fn comments() {
+        for src in ["# hi", "x // y", "$print x\n# z\n$print y", ":= x 1 #"] {
+            assert!(has_comments(src), "{src:?}");
+        }
+        for src in ["", " \n\t", "$print x", r##"$print "# not a comment""##] {
+            assert!(!has_comments(src), "{src:?}");
+        }
     }
 
     #[test]
@@ -232,33 +581,287 @@ mod tests {
             ("yolo", vec![id("yolo")]),
             ("3", vec![num("3")]),
             ("0345678910", vec![num("0345678910")]),
-            ("%", vec![error("%")]),
+            ("@", vec![error("@")]),
             (":=", vec![t(Assign)]),
+            ("+=", vec![t(AddAssign)]),
+            ("-=", vec![t(SubAssign)]),
+            ("-=1", vec![t(SubAssign), num("1")]),
+            ("*=", vec![t(MulAssign)]),
+            ("/=", vec![t(DivAssign)]),
+            ("+ =", vec![t(Plus), t(Eq)]),
             ("$print", vec![t(Print)]),
+            ("$printc", vec![t(PrintC)]),
+            ("$print c", vec![t(Print), id("c")]),
             ("$read", vec![t(Read)]),
+            ("$swap", vec![t(Swap)]),
+            ("$assert", vec![t(Assert)]),
             ("$if", vec![t(If)]),
+            ("$while", vec![t(While)]),
+            ("$for", vec![t(For)]),
+            ("$fn $for", vec![t(Fn), t(For)]),
+            ("$case", vec![t(Case)]),
+            ("$default", vec![t(Default)]),
+            ("while", vec![id("while")]),
+            ("$return", vec![t(Return)]),
+            ("return", vec![id("return")]),
             ("{", vec![t(LBrace)]),
             ("}", vec![t(RBrace)]),
             ("+", vec![t(Plus)]),
             ("-", vec![t(Minus)]),
             ("*", vec![t(Mul)]),
             ("/", vec![t(Div)]),
+            ("%", vec![t(Percent)]),
+            ("^", vec![t(Caret)]),
+            ("?", vec![t(Question)]),
+            ("$min", vec![t(Min)]),
+            ("$max x", vec![t(Max), id("x")]),
+            ("max", vec![id("max")]),
+            ("$band", vec![t(BitAnd)]),
+            ("$bor", vec![t(BitOr)]),
+            ("$bxor", vec![t(BitXor)]),
             ("<", vec![t(Lt)]),
+            (">", vec![t(Gt)]),
+            ("=", vec![t(Eq)]),
+            ("<=", vec![t(Le)]),
+            (">=", vec![t(Ge)]),
+            ("!=", vec![t(Ne)]),
+            ("<<", vec![t(Shl)]),
+            (">>", vec![t(Shr)]),
+            // the longest operator wins, so this is no longer `<` `<=`
+            ("<<=", vec![t(Shl), t(Eq)]),
+            ("< <", vec![t(Lt), t(Lt)]),
+            ("!", vec![error("!")]),
         ];
 
         for (input, expected) in tests {
             assert_eq!(
-                get_tokens(input),
+                lex(input),
                 expected,
                 "the lexer produced the wrong results for the input {input:?}"
             )
         }
     }
 
+    #[test]
+    fn positions() {
+        let positions = |input| {
+            get_tokens(input)
+                .into_iter()
+                .map(|t| (t.text, t.line, t.col))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(positions("x"), vec![("x", 1, 1)]);
+        assert_eq!(
+            positions(":= x 3\n$print   x\n\n  // comment\n  $if\n{}"),
+            vec![
+                (":=", 1, 1),
+                ("x", 1, 4),
+                ("3", 1, 6),
+                ("$print", 2, 1),
+                ("x", 2, 10),
+                ("$if", 5, 3),
+                ("{", 6, 1),
+                ("}", 6, 2),
+            ]
+        );
+        // columns count characters, not bytes
+        assert_eq!(
+            positions("// π\n\tfoo //é\n bar"),
+            vec![("foo", 2, 2), ("bar", 3, 2)]
+        );
+        // offsets count bytes
+        let offsets = get_tokens("// π\n\tfoo //é\n bar")
+            .into_iter()
+            .map(|t| t.offset)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![7, 17]);
+
+        let mut lexer = Lexer::new("x\n  ");
+        while lexer.next().is_some() {}
+        assert_eq!(lexer.position(), (2, 3));
+    }
+
+    #[test]
+    fn assign_and_eq() {
+        assert_eq!(lex(":="), vec![t(Assign)]);
+        assert_eq!(lex(":=="), vec![t(Assign), t(Eq)]);
+        assert_eq!(lex("= :="), vec![t(Eq), t(Assign)]);
+        assert_eq!(lex(": ="), vec![error(":"), t(Eq)]);
+        assert_eq!(
+            lex(":= x = y z"),
+            vec![t(Assign), id("x"), t(Eq), id("y"), id("z")]
+        );
+    }
+
+    #[test]
+    fn longest_comparison() {
+        assert_eq!(lex("<=<"), vec![t(Le), t(Lt)]);
+        assert_eq!(lex("< ="), vec![t(Lt), t(Eq)]);
+        assert_eq!(lex(">=>="), vec![t(Ge), t(Ge)]);
+        assert_eq!(lex("!==!"), vec![t(Ne), t(Eq), error("!")]);
+        assert_eq!(lex("<>"), vec![t(Lt), t(Gt)]);
+    }
+
+    #[test]
+    fn floats() {
+        let tests = [
+            ("3", vec![num("3")]),
+            ("3.14", vec![float("3.14")]),
+            ("0.5 10", vec![float("0.5"), num("10")]),
+            ("007.700", vec![float("007.700")]),
+            ("+ 1.5 x", vec![t(Plus), float("1.5"), id("x")]),
+            ("3.", vec![error("3.")]),
+            ("3. 4", vec![error("3."), num("4")]),
+            ("3.x", vec![error("3."), id("x")]),
+            ("3.4.5", vec![error("3.4.5")]),
+            ("3..4", vec![error("3..4")]),
+            ("3.4.", vec![error("3.4.")]),
+            (".5", vec![error("."), num("5")]),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(lex(input), expected, "wrong tokens for {input:?}");
+        }
+    }
+
+    #[test]
+    fn digit_separators() {
+        let tests = [
+            ("1_000_000", vec![num("1_000_000")]),
+            ("1_2_3 4", vec![num("1_2_3"), num("4")]),
+            ("0_0", vec![num("0_0")]),
+            ("1_000.000_1", vec![float("1_000.000_1")]),
+            // a leading underscore makes an identifier
+            ("_5", vec![id("_5")]),
+            ("5_", vec![error("5_")]),
+            ("5__0", vec![error("5__0")]),
+            ("5_ 0", vec![error("5_"), num("0")]),
+            ("1_.5", vec![error("1_.5")]),
+            ("1._5", vec![error("1._5")]),
+            ("1.5_", vec![error("1.5_")]),
+            ("1_x", vec![error("1_"), id("x")]),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(lex(input), expected, "wrong tokens for {input:?}");
+        }
+    }
+
+    #[test]
+    fn radixes() {
+        let tests = [
+            ("0xFF", vec![num("0xFF")]),
+            ("0xdead_BEEF 1", vec![num("0xdead_BEEF"), num("1")]),
+            ("0b1010", vec![num("0b1010")]),
+            ("0b1_0 0b0", vec![num("0b1_0"), num("0b0")]),
+            ("0x0+", vec![num("0x0"), t(Plus)]),
+            ("0x", vec![error("0x")]),
+            ("0b", vec![error("0b")]),
+            ("0b2", vec![error("0b2")]),
+            ("0b102", vec![error("0b102")]),
+            ("0xFG", vec![error("0xFG")]),
+            ("0x_F", vec![error("0x_F")]),
+            ("0xF_", vec![error("0xF_")]),
+            ("0x1.5", vec![num("0x1"), error("."), num("5")]),
+            // only lowercase prefixes, and only after a single 0
+            ("0XFF", vec![num("0"), id("XFF")]),
+            ("00xF", vec![num("00"), id("xF")]),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(lex(input), expected, "wrong tokens for {input:?}");
+        }
+    }
+
+    #[test]
+    fn negative_numbers() {
+        let tests = [
+            ("-5", vec![num("-5")]),
+            ("- 5", vec![t(Minus), num("5")]),
+            ("-1.5", vec![token(Float, "-1.5")]),
+            ("-0xF -0b1", vec![num("-0xF"), num("-0b1")]),
+            ("--5", vec![t(Minus), num("-5")]),
+            ("x-5", vec![id("x"), num("-5")]),
+            ("-x", vec![t(Minus), id("x")]),
+            ("-1_000", vec![num("-1_000")]),
+            ("-1.", vec![error("-1.")]),
+            ("-0x", vec![error("-0x")]),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(lex(input), expected, "wrong tokens for {input:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_characters() {
+        assert_eq!(
+            try_get_tokens("$print @"),
+            Err(LexError {
+                line: 1,
+                col: 8,
+                ch: '@'
+            })
+        );
+        assert_eq!(
+            try_get_tokens("$read x\n:= x é;").unwrap_err().to_string(),
+            "Lex error at 2:6: Unexpected character `é`."
+        );
+        // a multibyte character is a single error token
+        assert_eq!(lex("é;"), vec![error("é"), error(";")]);
+        // malformed numbers are left for the parser
+        assert_eq!(try_get_tokens("1.").unwrap(), get_tokens("1."));
+        assert_eq!(try_get_tokens("$print x").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn strings() {
+        let tests = [
+            (r#""""#, vec![str(r#""""#)]),
+            (r#""hello world""#, vec![str(r#""hello world""#)]),
+            (r#""a" "b""#, vec![str(r#""a""#), str(r#""b""#)]),
+            (r#""a\nb""#, vec![str(r#""a\nb""#)]),
+            (r#""say \"hi\"" x"#, vec![str(r#""say \"hi\"""#), id("x")]),
+            (r#""back\\" x"#, vec![str(r#""back\\""#), id("x")]),
+            (
+                r##""# not a comment""##,
+                vec![str(r##""# not a comment""##)],
+            ),
+            (r#"$print "x"#, vec![t(Print), str(r#""x"#)]),
+            ("\"open\nx", vec![str(r#""open"#), id("x")]),
+            (r#""escaped end\""#, vec![str(r#""escaped end\""#)]),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(lex(input), expected, "wrong tokens for {input:?}");
+        }
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            lex("$fn f(a, b) {} $print f(1,x)"),
+            vec![
+                t(Fn),
+                id("f"),
+                t(LParen),
+                id("a"),
+                t(Comma),
+                id("b"),
+                t(RParen),
+                t(LBrace),
+                t(RBrace),
+                t(Print),
+                id("f"),
+                t(LParen),
+                num("1"),
+                t(Comma),
+                id("x"),
+                t(RParen),
+            ]
+        );
+        assert_eq!(lex("$fn fn $fnx"), vec![t(Fn), id("fn"), t(Fn), id("x")]);
+    }
+
     #[test]
     fn multi_token() {
         assert_eq!(
-            get_tokens("x$print$read$if{}+0-*$/<"),
+            lex("x$print$read$if{}+0-*$/<"),
             vec![
                 id("x"),
                 t(Print),
@@ -276,7 +879,7 @@ mod tests {
             ]
         );
         assert_eq!(
-            get_tokens("x yz $print $read $if { } +  0   -  //hi\n * $ read / < ~"),
+            lex("x yz $print $read $if { } +  0   -  //hi\n * $ read / < ~"),
             vec![
                 id("x"),
                 id("yz"),
@@ -1,20 +1,86 @@
 //! Lowering
 
 use super::ast;
-use super::BOp;
-use ast::Stmt;
-use ast::Expr;
+use ast::{describe_assertion, Stmt, StmtKind};
+use ast::{Expr, ExprKind};
+use ast::{BOp, Number};
 use crate::{
     common::{id, Id},
     middle::tir,
 };
 use std::collections::{BTreeMap as Map, BTreeSet as Set};
-use tir::{Block, Instruction, Terminator};
+use tir::{Block, Instruction, Terminator, ENTRY_LABEL};
 use TvEntry::*;
 
+/// Lower the program to tiny IR.
+///
+/// The program must pass [super::check_strings], since string literals that
+/// are not printed have no value to lower to.
 pub fn lower(program: ast::Program) -> tir::Program {
-    let lower = Lower::new();
-    lower.lower_program(program)
+    lower_program(program, LowerOptions::default())
+}
+
+/// Lower the program like [lower], but compute the value of an assignment
+/// directly into the assigned variable when a single instruction computes it,
+/// so `:= x + y 1` becomes one `$arith` into `x` instead of an `$arith` into a
+/// temporary followed by a `$copy`.
+pub fn lower_keeping_names(program: ast::Program) -> tir::Program {
+    let options = LowerOptions {
+        keep_names: true,
+        ..LowerOptions::default()
+    };
+    lower_program(program, options)
+}
+
+/// Lower the program like [lower], but number the temporaries and labels of
+/// each top-level statement separately, so the second statement creates
+/// `_t_s2_1`, `lbl_s2_1`, and so on.  Editing one statement then leaves the
+/// names in the others alone, which keeps diffs of the IR small.
+pub fn lower_per_statement(program: ast::Program) -> tir::Program {
+    let options = LowerOptions {
+        per_stmt: true,
+        ..LowerOptions::default()
+    };
+    lower_program(program, options)
+}
+
+// How the lowering names what it computes
+#[derive(Debug, Default, Clone, Copy)]
+struct LowerOptions {
+    // whether assignments write their value directly into the variable
+    keep_names: bool,
+    // whether the counters start over at each top-level statement, whose
+    // number then goes into the fresh names
+    per_stmt: bool,
+}
+
+fn lower_program(program: ast::Program, options: LowerOptions) -> tir::Program {
+    // the temporaries must not clash with any name in the source
+    let used = source_ids(&program);
+    let funcs = program
+        .funcs
+        .into_iter()
+        .map(|func| (func.name, lower_func(func, &used, options)))
+        .collect();
+    let lower = Lower::new(&used, options);
+    let mut main = lower.lower_body(program.stmts, Terminator::Exit);
+    main.funcs = funcs;
+    main
+}
+
+// Lower a function into its own CFG, which returns the variable named after
+// the function at the end
+fn lower_func(func: ast::Func, used: &Set<Id>, options: LowerOptions) -> tir::Function {
+    let mut lower = Lower::new(used, options);
+    // the parameters and the result exist even if the body does not use them
+    lower.add_decl(func.name);
+    for &x in &func.params {
+        lower.add_decl(x);
+    }
+    tir::Function {
+        params: func.params,
+        body: lower.lower_body(func.body, Terminator::Return(func.name)),
+    }
 }
 
 // Entries in the translation vector
@@ -45,17 +111,32 @@ struct Lower {
     tv: Vec<TvEntry>,
     // for creating fresh locals
     fresh_ctr: i64,
+    // the identifiers in the source program, which fresh locals avoid
+    used: Set<Id>,
     // for creating fresh block labels
     bb_ctr: i64,
+    // the terminator `$return` lowers to
+    ret: Terminator,
+    // the temporaries holding the integer constants materialized so far in the
+    // current block of the current statement
+    consts: Map<i64, Id>,
+    options: LowerOptions,
+    // the number of the current top-level statement
+    stmt_ctr: i64,
 }
 
 impl Lower {
-    fn new() -> Self {
+    fn new(used: &Set<Id>, options: LowerOptions) -> Self {
         Lower {
             decl: Set::new(),
             tv: vec![],
             fresh_ctr: 0,
+            used: used.clone(),
             bb_ctr: 0,
+            ret: Terminator::Exit,
+            consts: Map::new(),
+            options,
+            stmt_ctr: 0,
         }
     }
 
@@ -64,120 +145,536 @@ impl Lower {
         self.decl.insert(var);
     }
 
-    fn lower_program(mut self, program: ast::Program) -> tir::Program {
-        self.tv.push(Label(id("entry")));
+    // Lower the statements into a CFG that ends with given terminator
+    fn lower_body(mut self, stmts: Vec<Stmt>, term: Terminator) -> tir::Program {
+        self.ret = term.clone();
+        self.start_block(id(ENTRY_LABEL));
 
-        for stmt in program.stmts {
+        for stmt in stmts {
+            if self.options.per_stmt {
+                self.stmt_ctr += 1;
+                self.fresh_ctr = 0;
+                self.bb_ctr = 0;
+            }
             self.lower_stmt(stmt);
         }
         // Close the last basic block
-        self.tv.push(Term(Terminator::Exit));
+        self.tv.push(Term(term));
 
         tir::Program {
             decl: self.decl,
             block: construct_cfg(self.tv),
+            funcs: Map::new(),
         }
     }
 
     fn lower_stmt(&mut self, stmt: Stmt) {
-        match stmt {
-            Stmt::Assign(dst, e) => {
+        self.consts.clear();
+        match stmt.kind {
+            StmtKind::Assign(dst, e) => {
                 self.add_decl(dst);
                 let src = self.lower_expr(e);
-                self.tv.push(Inner(Instruction::Copy { dst, src }));
+                if !(self.options.keep_names && self.retarget(src, dst)) {
+                    self.tv.push(Inner(Instruction::Copy { dst, src }));
+                }
+            }
+            StmtKind::Print(mut es) if es.len() == 1 => match es.pop().unwrap() {
+                Expr {
+                    kind: ExprKind::Str(ref s),
+                    ..
+                } => self.tv.push(Inner(Instruction::PrintStr(s.clone()))),
+                e => {
+                    let x = self.lower_expr(e);
+                    self.tv.push(Inner(Instruction::Print(x)));
+                }
+            },
+            // print the values one by one with spaces in between, and end the
+            // line with an empty string
+            StmtKind::Print(es) => {
+                for (i, e) in es.into_iter().enumerate() {
+                    if i > 0 {
+                        self.tv
+                            .push(Inner(Instruction::PrintStrNoNl(" ".to_string())));
+                    }
+                    match e {
+                        Expr {
+                            kind: ExprKind::Str(ref s),
+                            ..
+                        } => self.tv.push(Inner(Instruction::PrintStrNoNl(s.clone()))),
+                        e => {
+                            let x = self.lower_expr(e);
+                            self.tv.push(Inner(Instruction::PrintNoNl(x)));
+                        }
+                    }
+                }
+                self.tv.push(Inner(Instruction::PrintStr(String::new())));
             }
-            Stmt::Print(e) => {
+            StmtKind::PrintNoNl(Expr {
+                kind: ExprKind::Str(ref s),
+                ..
+            }) => self.tv.push(Inner(Instruction::PrintStrNoNl(s.clone()))),
+            StmtKind::PrintNoNl(e) => {
                 let x = self.lower_expr(e);
-                self.tv.push(Inner(Instruction::Print(x)));
+                self.tv.push(Inner(Instruction::PrintNoNl(x)));
             }
-            Stmt::Read(x) => {
+            StmtKind::Read(xs) => {
+                for x in xs {
+                    self.add_decl(x);
+                    self.tv.push(Inner(Instruction::Read(x)));
+                }
+            }
+            StmtKind::Swap(x, y) => {
                 self.add_decl(x);
-                self.tv.push(Inner(Instruction::Read(x)));
+                self.add_decl(y);
+                let tmp = self.mk_var("_t");
+                self.tv.push(Inner(Instruction::Copy { dst: tmp, src: x }));
+                self.tv.push(Inner(Instruction::Copy { dst: x, src: y }));
+                self.tv.push(Inner(Instruction::Copy { dst: y, src: tmp }));
+            }
+            StmtKind::Assert(e) => {
+                let text = describe_assertion(&e, stmt.span);
+                let x = self.lower_expr(e);
+                self.tv.push(Inner(Instruction::Assert(x, text)));
             }
-            Stmt::If { guard, tt, ff } => {
+            StmtKind::If { guard, tt, ff } => {
                 let lbl_tt = self.mk_label();
                 let lbl_ff = self.mk_label();
                 let lbl_join = self.mk_label();
                 let guard = self.lower_expr(guard);
                 self.tv.push(Term(Terminator::Branch { guard, tt: lbl_tt, ff: lbl_ff }));
 
-                self.tv.push(Label(lbl_tt));
+                self.start_block(lbl_tt);
                 for stmt in tt {
                     self.lower_stmt(stmt);
                 }
                 self.tv.push(Term(Terminator::Jump(lbl_join)));
-                self.tv.push(Label(lbl_ff));
+                self.start_block(lbl_ff);
                 for stmt in ff {
                     self.lower_stmt(stmt);
                 }
                 self.tv.push(Term(Terminator::Jump(lbl_join)));
-                self.tv.push(Label(lbl_join));
+                self.start_block(lbl_join);
             },
+            StmtKind::While { guard, body } => self.lower_while(guard, body),
+            StmtKind::For {
+                var,
+                start,
+                end,
+                mut body,
+            } => {
+                // `$for i a b {body}` is `:= i a $while < i b {body := i + i 1}`
+                let var_expr = || Box::new(Expr::from(ExprKind::Var(var)));
+                self.lower_stmt(StmtKind::Assign(var, *start).into());
+                let guard = ExprKind::BinOp {
+                    op: BOp::Lt,
+                    lhs: var_expr(),
+                    rhs: end,
+                };
+                let one = Box::new(ExprKind::Const(Number::Int(1)).into());
+                let step = ExprKind::BinOp {
+                    op: BOp::Add,
+                    lhs: var_expr(),
+                    rhs: one,
+                };
+                body.push(StmtKind::Assign(var, step.into()).into());
+                self.lower_while(guard.into(), body);
+            }
+            StmtKind::Case {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                // each arm gets its own block, and all of them join afterwards
+                let scrutinee = self.lower_expr(scrutinee);
+                let mut cases = vec![];
+                let mut bodies = vec![];
+                for (n, body) in arms {
+                    let lbl = self.mk_label();
+                    cases.push((n, lbl));
+                    bodies.push((lbl, body));
+                }
+                let lbl_default = self.mk_label();
+                let lbl_join = self.mk_label();
+                bodies.push((lbl_default, default));
+                self.tv.push(Term(Terminator::Switch {
+                    scrutinee,
+                    cases,
+                    default: lbl_default,
+                }));
+
+                for (lbl, body) in bodies {
+                    self.start_block(lbl);
+                    for stmt in body {
+                        self.lower_stmt(stmt);
+                    }
+                    self.tv.push(Term(Terminator::Jump(lbl_join)));
+                }
+                self.start_block(lbl_join);
+            }
+            StmtKind::Return => {
+                self.tv.push(Term(self.ret.clone()));
+                // the code after the return goes to an unreachable block
+                let lbl_dead = self.mk_label();
+                self.start_block(lbl_dead);
+            }
         }
     }
 
     fn lower_expr(&mut self, e: Expr) -> Id {
-        match e {
-            Expr::Var(x) => {
+        if let Some(n) = fold(&e) {
+            return self.mk_const(n);
+        }
+
+        match e.into_kind() {
+            ExprKind::Var(x) => {
                 self.add_decl(x);
                 x
             }
-            Expr::Const(n) => {
-                // this is not as good as the IR generation I covered.
-                let dst = self.mk_var("_const");
-                self.tv.push(Inner(Instruction::Const { dst, src: n }));
+            ExprKind::Const(n) => self.mk_const(n),
+            ExprKind::BinOp {
+                op: op @ (BOp::And | BOp::Or),
+                lhs,
+                rhs,
+            } => {
+                // the right operand is evaluated in its own block, which is
+                // skipped if the left operand already determines the result
+                let lbl_rhs = self.mk_label();
+                let lbl_short = self.mk_label();
+                let lbl_join = self.mk_label();
+                let lhs = self.lower_expr(*lhs);
+                let (tt, ff) = match op {
+                    BOp::And => (lbl_rhs, lbl_short),
+                    _ => (lbl_short, lbl_rhs),
+                };
+                self.tv
+                    .push(Term(Terminator::Branch { guard: lhs, tt, ff }));
+
+                self.start_block(lbl_rhs);
+                let rhs = self.lower_expr(*rhs);
+                let zero = self.mk_const(Number::Int(0));
+                let dst = self.mk_var("_t");
+                let ne = Instruction::Arith {
+                    op: BOp::Ne,
+                    dst,
+                    lhs: rhs,
+                    rhs: zero,
+                };
+                self.tv.push(Inner(ne));
+                self.tv.push(Term(Terminator::Jump(lbl_join)));
+
+                self.start_block(lbl_short);
+                let src = Number::Int((op == BOp::Or) as i64);
+                self.tv.push(Inner(Instruction::Const { dst, src }));
+                self.tv.push(Term(Terminator::Jump(lbl_join)));
+                self.start_block(lbl_join);
                 dst
             }
-            Expr::BinOp { op, lhs, rhs } => {
+            ExprKind::BinOp { op, lhs, rhs } => {
                 let lhs = self.lower_expr(*lhs);
                 let rhs = self.lower_expr(*rhs);
                 let dst = self.mk_var("_t");
                 self.tv.push(Inner(Instruction::Arith { op, dst, lhs, rhs }));
                 dst
             }
-            Expr::Negate(e) => {
-                // not the most efficient method, but it works
-                self.lower_expr(Expr::BinOp { op: BOp::Sub, lhs: Box::new(Expr::Const(0)), rhs: e })
+            ExprKind::Negate(e) => {
+                let src = self.lower_expr(*e);
+                let dst = self.mk_var("_t");
+                self.tv.push(Inner(Instruction::Neg { dst, src }));
+                dst
+            }
+            ExprKind::Select { guard, tt, ff } => {
+                // each operand is computed in its own block, which copies it
+                // into the result before joining the other one
+                let lbl_tt = self.mk_label();
+                let lbl_ff = self.mk_label();
+                let lbl_join = self.mk_label();
+                let guard = self.lower_expr(*guard);
+                let dst = self.mk_var("_t");
+                self.tv.push(Term(Terminator::Branch {
+                    guard,
+                    tt: lbl_tt,
+                    ff: lbl_ff,
+                }));
+
+                for (lbl, e) in [(lbl_tt, *tt), (lbl_ff, *ff)] {
+                    self.start_block(lbl);
+                    let src = self.lower_expr(e);
+                    self.tv.push(Inner(Instruction::Copy { dst, src }));
+                    self.tv.push(Term(Terminator::Jump(lbl_join)));
+                }
+                self.start_block(lbl_join);
+                dst
+            }
+            ExprKind::Call { func, args } => {
+                let args = args.into_iter().map(|e| self.lower_expr(e)).collect();
+                let dst = self.mk_var("_t");
+                self.tv.push(Inner(Instruction::Call { dst, func, args }));
+                dst
             }
+            ExprKind::Str(_) => panic!("string literals can only be printed, see `check_strings`"),
         }
     }
 
+    fn lower_while(&mut self, guard: Expr, body: Vec<Stmt>) {
+        let lbl_header = self.mk_label();
+        let lbl_body = self.mk_label();
+        let lbl_exit = self.mk_label();
+        // close the current block so that the header can be a jump target
+        self.tv.push(Term(Terminator::Jump(lbl_header)));
+
+        self.start_block(lbl_header);
+        let guard = self.lower_expr(guard);
+        self.tv.push(Term(Terminator::Branch {
+            guard,
+            tt: lbl_body,
+            ff: lbl_exit,
+        }));
+
+        self.start_block(lbl_body);
+        for stmt in body {
+            self.lower_stmt(stmt);
+        }
+        // the back edge
+        self.tv.push(Term(Terminator::Jump(lbl_header)));
+        self.start_block(lbl_exit);
+    }
+
+    // Create a fresh local, skipping the names the source program uses
     fn mk_var(&mut self, prefix: &str) -> Id {
-        self.fresh_ctr += 1;
-        let x = id(&format!("{prefix}_{}", self.fresh_ctr));
+        let x = loop {
+            self.fresh_ctr += 1;
+            let x = id(&format!("{prefix}_{}{}", self.stmt_tag(), self.fresh_ctr));
+            if !self.used.contains(&x) {
+                break x;
+            }
+        };
         self.decl.insert(x);
         x
     }
 
+    // Make the last instruction write into `dst` instead of the temporary
+    // `src`, if it is the one that computes `src`
+    fn retarget(&mut self, src: Id, dst: Id) -> bool {
+        if self.used.contains(&src) {
+            return false;
+        }
+        let Some(Inner(ins)) = self.tv.last_mut() else {
+            return false;
+        };
+        match ins {
+            Instruction::Const { dst: d, .. }
+            | Instruction::Arith { dst: d, .. }
+            | Instruction::Neg { dst: d, .. }
+            | Instruction::Call { dst: d, .. }
+                if *d == src =>
+            {
+                *d = dst
+            }
+            _ => return false,
+        }
+        // the temporary is not read anywhere else, and a constant cached in it
+        // is gone now
+        self.decl.remove(&src);
+        self.consts.retain(|_, x| *x != src);
+        true
+    }
+
+    // Materialize a constant into a temporary, reusing the temporary of the
+    // same integer if the current block of the statement already has one
+    fn mk_const(&mut self, src: Number) -> Id {
+        if let Number::Int(n) = src {
+            if let Some(&dst) = self.consts.get(&n) {
+                return dst;
+            }
+        }
+        let dst = self.mk_var("_const");
+        self.tv.push(Inner(Instruction::Const { dst, src }));
+        if let Number::Int(n) = src {
+            self.consts.insert(n, dst);
+        }
+        dst
+    }
+
+    // Start a new block.  The constants of the previous block may not be
+    // computed on the way to this one.
+    fn start_block(&mut self, lbl: Id) {
+        self.consts.clear();
+        self.tv.push(Label(lbl));
+    }
+
     fn mk_label(&mut self) -> Id {
         self.bb_ctr += 1;
-        id(&format!("lbl{}", self.bb_ctr))
+        if self.options.per_stmt {
+            id(&format!("lbl_{}{}", self.stmt_tag(), self.bb_ctr))
+        } else {
+            id(&format!("lbl{}", self.bb_ctr))
+        }
+    }
+
+    // The part of fresh names that tells the statements apart
+    fn stmt_tag(&self) -> String {
+        if self.options.per_stmt {
+            format!("s{}_", self.stmt_ctr)
+        } else {
+            String::new()
+        }
     }
 }
 
-fn construct_cfg(tv: Vec<TvEntry>) -> Map<Id, Block> {
-    let mut tv_iter = tv.iter();
+// Collect all identifiers in the program: variables, parameters, and functions
+fn source_ids(program: &ast::Program) -> Set<Id> {
+    let mut ids = Set::new();
+    for func in &program.funcs {
+        ids.insert(func.name);
+        ids.extend(&func.params);
+        stmts_ids(&func.body, &mut ids);
+    }
+    stmts_ids(&program.stmts, &mut ids);
+    ids
+}
 
-    let mut grammar: Map<Id, Block> = Map::new();
+fn stmts_ids(stmts: &[Stmt], ids: &mut Set<Id>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::Assign(x, e) => {
+                ids.insert(*x);
+                expr_ids(e, ids);
+            }
+            StmtKind::Print(es) => {
+                for e in es {
+                    expr_ids(e, ids);
+                }
+            }
+            StmtKind::PrintNoNl(e) | StmtKind::Assert(e) => expr_ids(e, ids),
+            StmtKind::Read(xs) => ids.extend(xs),
+            StmtKind::Swap(x, y) => ids.extend([x, y]),
+            StmtKind::If { guard, tt, ff } => {
+                expr_ids(guard, ids);
+                stmts_ids(tt, ids);
+                stmts_ids(ff, ids);
+            }
+            StmtKind::While { guard, body } => {
+                expr_ids(guard, ids);
+                stmts_ids(body, ids);
+            }
+            StmtKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                ids.insert(*var);
+                expr_ids(start, ids);
+                expr_ids(end, ids);
+                stmts_ids(body, ids);
+            }
+            StmtKind::Case {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                expr_ids(scrutinee, ids);
+                for (_, body) in arms {
+                    stmts_ids(body, ids);
+                }
+                stmts_ids(default, ids);
+            }
+            StmtKind::Return => {}
+        }
+    }
+}
 
-    let mut curr_block = match tv_iter.next() {
-        Some(Label(id)) => id,
-        _ => { return grammar; }
-    };
+fn expr_ids(e: &Expr, ids: &mut Set<Id>) {
+    match &e.kind {
+        ExprKind::Var(x) => {
+            ids.insert(*x);
+        }
+        ExprKind::Const(_) | ExprKind::Str(_) => {}
+        ExprKind::BinOp { lhs, rhs, .. } => {
+            expr_ids(lhs, ids);
+            expr_ids(rhs, ids);
+        }
+        ExprKind::Negate(e) => expr_ids(e, ids),
+        ExprKind::Select { guard, tt, ff } => {
+            expr_ids(guard, ids);
+            expr_ids(tt, ids);
+            expr_ids(ff, ids);
+        }
+        ExprKind::Call { func, args } => {
+            ids.insert(*func);
+            for arg in args {
+                expr_ids(arg, ids);
+            }
+        }
+    }
+}
 
+// Compute the value of given expression if it consists only of constants.  This
+// leaves expressions whose result is undefined (e.g. division by zero) alone,
+// and also the ones that overflow, which are errors in checked mode.
+fn fold(e: &Expr) -> Option<Number> {
+    match &e.kind {
+        ExprKind::Var(_) | ExprKind::Call { .. } | ExprKind::Str(_) => None,
+        // the right operand does not matter if the left one determines the
+        // result, so it does not have to be constant
+        ExprKind::BinOp {
+            op: op @ (BOp::And | BOp::Or),
+            lhs,
+            rhs,
+        } => match fold(lhs)?.is_true() {
+            l if l == (*op == BOp::Or) => Some(Number::Int(l as i64)),
+            _ => Some(Number::Int(fold(rhs)?.is_true() as i64)),
+        },
+        ExprKind::Const(n) => Some(*n),
+        ExprKind::BinOp { op, lhs, rhs } => {
+            let (lhs, rhs) = (fold(lhs)?, fold(rhs)?);
+            if op.overflows(lhs, rhs) {
+                return None;
+            }
+            op.eval(lhs, rhs)
+        }
+        ExprKind::Negate(e) => fold(e)?.checked_neg(),
+        // like above, only the chosen operand has to be constant
+        ExprKind::Select { guard, tt, ff } => {
+            if fold(guard)?.is_true() {
+                fold(tt)
+            } else {
+                fold(ff)
+            }
+        }
+    }
+}
+
+// Split the translation vector into basic blocks.  A label that is not preceded
+// by a terminator falls through to its block, so the block before it is closed
+// with an explicit jump.  Instructions that are not under any label are
+// unreachable, so they are dropped.
+fn construct_cfg(tv: Vec<TvEntry>) -> Map<Id, Block> {
+    let mut grammar: Map<Id, Block> = Map::new();
+
+    // the label of the block we are building, if we are in a block
+    let mut curr_block: Option<Id> = None;
     let mut insn: Vec<Instruction> = vec![];
 
-    for channel in tv_iter {
+    for channel in tv {
         match channel {
             Label(id) => {
-                curr_block = id;
-            },
+                if let Some(prev) = curr_block.replace(id) {
+                    let insn = std::mem::take(&mut insn);
+                    let term = Terminator::Jump(id);
+                    grammar.insert(prev, Block { insn, term });
+                }
+            }
             Inner(ins) => {
-                insn.push(ins.clone());
-            },
+                if curr_block.is_some() {
+                    insn.push(ins);
+                }
+            }
             Term(term) => {
-                grammar.insert(*curr_block, Block { insn, term: term.clone() });
-                insn = vec![];
+                if let Some(id) = curr_block.take() {
+                    let insn = std::mem::take(&mut insn);
+                    grammar.insert(id, Block { insn, term });
+                }
             }
         }
     }
@@ -185,6 +682,609 @@ fn construct_cfg(tv: Vec<TvEntry>) -> Map<Id, Block> {
     grammar
 }
 
-// fn main() {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{parse, BOp};
+
+    // SECTION: helpers
+
+    // Parse and lower a program
+    fn lower_src(src: &str) -> tir::Program {
+        lower(parse(src).unwrap())
+    }
+
+    // Check that all jump targets in the program exist
+    fn assert_targets_exist(program: &tir::Program) {
+        for (lbl, block) in &program.block {
+            for target in block.term.successors() {
+                assert!(
+                    program.block.contains_key(&target),
+                    "block {lbl} jumps to missing block {target}"
+                );
+            }
+        }
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn while_back_edge() {
+        let program = lower_src("$while x {$print x}");
+        assert_targets_exist(&program);
+        let (header, body, exit) = (id("lbl1"), id("lbl2"), id("lbl3"));
+
+        assert_eq!(program.block.len(), 4);
+        assert!(matches!(
+            program.block[&id("entry")].term,
+            Terminator::Jump(l) if l == header
+        ));
+        assert!(matches!(
+            program.block[&header].term,
+            Terminator::Branch { guard, tt, ff } if guard == id("x") && tt == body && ff == exit
+        ));
+        assert_eq!(program.block[&body].insn.len(), 1);
+        assert!(matches!(
+            program.block[&body].term,
+            Terminator::Jump(l) if l == header
+        ));
+        assert!(matches!(program.block[&exit].term, Terminator::Exit));
+    }
+
+    #[test]
+    fn for_loop() {
+        let program = lower_src("$for i 0 n {$print i}");
+        assert_targets_exist(&program);
+        assert!(program.decl.contains(&id("i")));
+        let (header, body) = (id("lbl1"), id("lbl2"));
+        assert_eq!(program.block.len(), 4);
+        assert!(matches!(
+            program.block[&header].insn[..],
+            [Instruction::Arith { op: BOp::Lt, lhs, rhs, .. }] if lhs == id("i") && rhs == id("n")
+        ));
+        // the body ends with the increment and the back edge
+        assert!(matches!(
+            program.block[&body].insn[..],
+            [
+                Instruction::Print(_),
+                Instruction::Const { .. },
+                Instruction::Arith { op: BOp::Add, .. },
+                Instruction::Copy { dst, .. },
+            ] if dst == id("i")
+        ));
+        assert!(matches!(
+            program.block[&body].term,
+            Terminator::Jump(l) if l == header
+        ));
+    }
+
+    #[test]
+    fn cfg_fallthrough_labels() {
+        let x = id("x");
+        let tv = vec![
+            Label(id("a")),
+            Label(id("b")),
+            Inner(Instruction::Print(x)),
+            Label(id("c")),
+            Term(Terminator::Exit),
+        ];
+        let cfg = construct_cfg(tv);
+        assert_eq!(cfg.len(), 3);
+        assert!(cfg[&id("a")].insn.is_empty());
+        assert!(matches!(cfg[&id("a")].term, Terminator::Jump(l) if l == id("b")));
+        assert_eq!(cfg[&id("b")].insn.len(), 1);
+        assert!(matches!(cfg[&id("b")].term, Terminator::Jump(l) if l == id("c")));
+        assert!(matches!(cfg[&id("c")].term, Terminator::Exit));
+    }
+
+    #[test]
+    fn cfg_unreachable_code() {
+        let x = id("x");
+        let tv = vec![
+            Label(id("a")),
+            Term(Terminator::Exit),
+            Inner(Instruction::Print(x)),
+            Term(Terminator::Exit),
+            Label(id("b")),
+            Term(Terminator::Exit),
+        ];
+        let cfg = construct_cfg(tv);
+        assert_eq!(cfg.len(), 2);
+        assert!(cfg[&id("a")].insn.is_empty());
+        assert!(cfg[&id("b")].insn.is_empty());
+    }
+
+    #[test]
+    fn nested_empty_ifs() {
+        let program = lower_src("$if x {$if y {} {}} {}");
+        assert_targets_exist(&program);
+        assert_eq!(program.block.len(), 7);
+
+        let program = lower_src("$if x {$if y {} {}} {$if z {} {}} $if x {$if y {} {}} {}");
+        assert_targets_exist(&program);
+        assert_eq!(program.block.len(), 16);
+    }
+
+    #[test]
+    fn negate() {
+        let program = lower_src("$print ~ x");
+        let insn = &program.block[&id("entry")].insn;
+        assert_eq!(insn.len(), 2);
+        assert!(matches!(insn[0], Instruction::Neg { src, .. } if src == id("x")));
+        assert!(matches!(insn[1], Instruction::Print(_)));
+
+        let program = lower_src("$print ~ ~ y");
+        let insn = &program.block[&id("entry")].insn;
+        assert_eq!(insn.len(), 3);
+        assert!(matches!(insn[0], Instruction::Neg { src, .. } if src == id("y")));
+        assert!(matches!(insn[1], Instruction::Neg { .. }));
+    }
+
+    #[test]
+    fn constant_folding() {
+        let tests = [
+            ("$print + 3 4", 7),
+            ("$print * + 3 4 2", 14),
+            ("$print - 3 10", -7),
+            ("$print / 7 2", 3),
+            ("$print % 7 2", 1),
+            ("$print < 3 4", 1),
+            ("$print < 4 3", 0),
+            ("$print ~ 5", -5),
+            ("$print ~ - 3 10", 7),
+        ];
+        for (src, expected) in tests {
+            let program = lower_src(src);
+            let insn = &program.block[&id("entry")].insn;
+            assert_eq!(insn.len(), 2, "wrong instruction count for {src:?}");
+            assert!(
+                matches!(insn[0], Instruction::Const { src, .. } if src == Number::Int(expected)),
+                "wrong constant for {src:?}: {}",
+                insn[0]
+            );
+            assert!(matches!(insn[1], Instruction::Print(_)));
+        }
+    }
+
+    #[test]
+    fn no_folding_overflow() {
+        // these fail in checked mode, so they are left for the interpreter
+        for src in [
+            "$print + 9223372036854775807 1",
+            "$print * 4294967296 4294967296",
+            "$print / -9223372036854775808 ~ 1",
+            "$print << 1 63",
+        ] {
+            let program = lower_src(src);
+            let insn = &program.block[&id("entry")].insn;
+            assert!(
+                matches!(insn[insn.len() - 2], Instruction::Arith { .. }),
+                "folded {src:?}"
+            );
+        }
+        let program = lower_src("$print ~ -9223372036854775808");
+        let insn = &program.block[&id("entry")].insn;
+        assert!(matches!(insn[1], Instruction::Neg { .. }));
+    }
+
+    #[test]
+    fn float_folding() {
+        let tests = [
+            ("$print + 1 2.5", Number::Float(3.5)),
+            ("$print * 2.0 3", Number::Float(6.0)),
+            ("$print / 1 4.0", Number::Float(0.25)),
+            ("$print ~ 1.5", Number::Float(-1.5)),
+            ("$print < 1 1.5", Number::Int(1)),
+            ("$print / 1.0 0", Number::Float(f64::INFINITY)),
+            ("$print & 0.5 1", Number::Int(1)),
+        ];
+        for (src, expected) in tests {
+            let program = lower_src(src);
+            let insn = &program.block[&id("entry")].insn;
+            assert_eq!(insn.len(), 2, "wrong instruction count for {src:?}");
+            assert!(
+                matches!(insn[0], Instruction::Const { src, .. } if src == expected),
+                "wrong constant for {src:?}: {}",
+                insn[0]
+            );
+        }
+    }
+
+    #[test]
+    fn partial_constant_folding() {
+        let program = lower_src("$print + x * 2 3");
+        let insn = &program.block[&id("entry")].insn;
+        assert_eq!(insn.len(), 3);
+        assert!(matches!(insn[0], Instruction::Const { src, .. } if src == Number::Int(6)));
+        assert!(matches!(
+            insn[1],
+            Instruction::Arith { op: BOp::Add, lhs, .. } if lhs == id("x")
+        ));
+    }
+
+    #[test]
+    fn no_folding_division_by_zero() {
+        for src in ["$print / 1 0", "$print % 1 - 2 2"] {
+            let program = lower_src(src);
+            let insn = &program.block[&id("entry")].insn;
+            assert_eq!(insn.len(), 4, "wrong instruction count for {src:?}");
+            assert!(matches!(insn[2], Instruction::Arith { .. }));
+        }
+    }
+
+    #[test]
+    fn fresh_names() {
+        // the source already uses the names of the first temporaries
+        let program = lower_src(":= _const_1 7 := _t_3 + _const_1 1");
+        let insn = &program.block[&id("entry")].insn;
+        assert!(matches!(
+            insn[..],
+            [
+                Instruction::Const { dst: c, .. },
+                Instruction::Copy { .. },
+                Instruction::Const { dst: one, .. },
+                Instruction::Arith { dst: t, lhs, rhs: one2, .. },
+                Instruction::Copy { .. },
+            ] if c == id("_const_2")
+                && lhs == id("_const_1")
+                && one == one2
+                && one != id("_const_1")
+                && t != id("_t_3")
+        ));
+    }
+
+    #[test]
+    fn print_many() {
+        let program = lower_src(r#"$read x $print "x =" x 1"#);
+        assert_eq!(
+            program.block[&id("entry")].to_string(),
+            "    $read x
+    $printc \"x =\"
+    $printc \" \"
+    $printc x
+    $printc \" \"
+    _const_1 = $const 1
+    $printc _const_1
+    $print \"\"
+    $exit
+"
+        );
+    }
+
+    #[test]
+    fn swap() {
+        let program = lower_src("$read x y $swap x y");
+        assert!(matches!(
+            program.block[&id("entry")].insn[2..],
+            [
+                Instruction::Copy { dst: t1, src: x1 },
+                Instruction::Copy { dst: x2, src: y1 },
+                Instruction::Copy { dst: y2, src: t2 },
+            ] if x1 == id("x") && y1 == id("y") && t1 == t2 && x2 == x1 && y2 == y1
+        ));
+    }
+
+    #[test]
+    fn case() {
+        let program = lower_src("$read x $case x {1 {$print 1} 2 {} $default {$print 0}} $print 2");
+        assert_targets_exist(&program);
+        let [lbl1, lbl2, lbl3, lbl4] = ["lbl1", "lbl2", "lbl3", "lbl4"].map(id);
+        assert_eq!(
+            program.block[&id("entry")].term,
+            Terminator::Switch {
+                scrutinee: id("x"),
+                cases: vec![(1, lbl1), (2, lbl2)],
+                default: lbl3
+            }
+        );
+        // every arm joins afterwards
+        for lbl in [lbl1, lbl2, lbl3] {
+            assert_eq!(program.block[&lbl].term, Terminator::Jump(lbl4));
+        }
+        assert!(program.block[&lbl2].insn.is_empty());
+        assert!(matches!(
+            program.block[&lbl4].insn[1],
+            Instruction::Print(_)
+        ));
+    }
+
+    #[test]
+    fn assert() {
+        // the assertion stays in the block instead of branching
+        let program = lower_src("$read x $assert x $print x");
+        assert_eq!(program.block.len(), 1);
+        assert_eq!(
+            program.block[&id("entry")].insn,
+            [
+                Instruction::Read(id("x")),
+                Instruction::Assert(id("x"), "1:9: `$assert x`".to_string()),
+                Instruction::Print(id("x"))
+            ]
+        );
+        let program = lower_src("$read x $assert < x 10");
+        assert!(matches!(
+            program.block[&id("entry")].insn[..],
+            [_, _, Instruction::Arith { dst, .. }, Instruction::Assert(x, ref text)]
+                if dst == x && text == "1:9: `$assert < x 10`"
+        ));
+    }
+
+    #[test]
+    fn shared_constants() {
+        let program = lower_src("$print + * x 5 5 $print 5");
+        let insn = &program.block[&id("entry")].insn;
+        let five = insn
+            .iter()
+            .filter(|i| {
+                matches!(
+                    i,
+                    Instruction::Const {
+                        src: Number::Int(5),
+                        ..
+                    }
+                )
+            })
+            .count();
+        // one for the first statement, and one for the second
+        assert_eq!(five, 2);
+        assert!(matches!(
+            insn[..4],
+            [
+                Instruction::Const { dst, .. },
+                Instruction::Arith { op: BOp::Mul, rhs: a, .. },
+                Instruction::Arith { op: BOp::Add, rhs: b, .. },
+                Instruction::Print(_),
+            ] if a == dst && b == dst
+        ));
+    }
+
+    #[test]
+    fn shared_constants_per_block() {
+        // the `5` in the right operand of `&` is not computed if `x` is false,
+        // so the join block computes its own
+        let program = lower_src("$print + & x 5 5");
+        assert!(matches!(
+            program.block[&id("lbl3")].insn[..],
+            [
+                Instruction::Const {
+                    src: Number::Int(5),
+                    ..
+                },
+                Instruction::Arith { .. },
+                Instruction::Print(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn short_circuit() {
+        let (rhs, short, join) = (id("lbl1"), id("lbl2"), id("lbl3"));
+        for (src, tt, ff, value) in [
+            ("$print & x y", rhs, short, Number::Int(0)),
+            ("$print | x y", short, rhs, Number::Int(1)),
+        ] {
+            let program = lower_src(src);
+            assert_targets_exist(&program);
+            assert_eq!(program.block.len(), 4, "wrong block count for {src:?}");
+
+            // the left operand decides whether the right one is evaluated
+            let entry = &program.block[&id("entry")];
+            assert!(entry.insn.is_empty());
+            assert!(matches!(
+                entry.term,
+                Terminator::Branch { guard, tt: t, ff: f } if guard == id("x") && t == tt && f == ff
+            ));
+
+            // the right operand is only read in its own block
+            let insn = &program.block[&rhs].insn;
+            assert_eq!(insn.len(), 2, "wrong instruction count for {src:?}");
+            assert!(matches!(
+                insn[1],
+                Instruction::Arith { op: BOp::Ne, lhs, .. } if lhs == id("y")
+            ));
+            assert!(matches!(program.block[&rhs].term, Terminator::Jump(l) if l == join));
+
+            let insn = &program.block[&short].insn;
+            assert!(matches!(insn[..], [Instruction::Const { src, .. }] if src == value));
+            assert!(matches!(program.block[&short].term, Terminator::Jump(l) if l == join));
+
+            assert!(matches!(
+                program.block[&join].insn[..],
+                [Instruction::Print(_)]
+            ));
+        }
+    }
 
-// }
+    #[test]
+    fn short_circuit_folding() {
+        for (src, expected) in [
+            ("$print & 0 x", Number::Int(0)),
+            ("$print | 1 / x 0", Number::Int(1)),
+            ("$print & 3 2", Number::Int(1)),
+            ("$print | 0 0", Number::Int(0)),
+        ] {
+            let program = lower_src(src);
+            assert_eq!(program.block.len(), 1, "{src:?} is not folded");
+            let insn = &program.block[&id("entry")].insn;
+            assert!(matches!(insn[0], Instruction::Const { src, .. } if src == expected));
+        }
+
+        // the right operand decides the result, but it is not constant
+        assert_eq!(lower_src("$print & 1 x").block.len(), 4);
+    }
+
+    #[test]
+    fn select() {
+        let program = lower_src("$print ? x y 3");
+        assert_targets_exist(&program);
+        assert_eq!(program.block.len(), 4);
+        let (tt, ff, join) = (id("lbl1"), id("lbl2"), id("lbl3"));
+        assert!(matches!(
+            program.block[&id("entry")].term,
+            Terminator::Branch { guard, tt: t, ff: f } if guard == id("x") && t == tt && f == ff
+        ));
+
+        // both operands are copied into the same result, which is printed
+        let dst = match program.block[&tt].insn[..] {
+            [Instruction::Copy { dst, src }] if src == id("y") => dst,
+            ref insn => panic!("unexpected instructions {insn:?}"),
+        };
+        assert!(matches!(
+            program.block[&ff].insn[..],
+            [Instruction::Const { .. }, Instruction::Copy { dst: d, .. }] if d == dst
+        ));
+        for lbl in [tt, ff] {
+            assert!(matches!(program.block[&lbl].term, Terminator::Jump(l) if l == join));
+        }
+        assert!(matches!(program.block[&join].insn[..], [Instruction::Print(x)] if x == dst));
+
+        // a constant guard folds away the select if the chosen operand is
+        // constant too
+        assert_eq!(lower_src("$print ? 1 2 x").block.len(), 1);
+        assert_eq!(lower_src("$print ? 0 2 x").block.len(), 4);
+    }
+
+    #[test]
+    fn functions() {
+        let program = lower_src("$fn f(x, y) {$if x {:= z y}} $print f(1, g)");
+        let f = &program.funcs[&id("f")];
+        assert_targets_exist(&f.body);
+        assert_eq!(f.params, vec![id("x"), id("y")]);
+        assert_eq!(f.body.decl, Set::from([id("f"), id("x"), id("y"), id("z")]));
+        let exit = f
+            .body
+            .block
+            .values()
+            .find(|b| b.term.successors().is_empty());
+        assert!(matches!(exit.unwrap().term, Terminator::Return(x) if x == id("f")));
+
+        // the caller does not see the variables of the function
+        assert!(!program.decl.contains(&id("z")));
+        let insn = &program.block[&id("entry")].insn;
+        assert!(matches!(
+            &insn[1],
+            Instruction::Call { func, args, .. } if *func == id("f") && args[1] == id("g")
+        ));
+        assert!(matches!(program.block[&id("entry")].term, Terminator::Exit));
+    }
+
+    #[test]
+    fn entry_label() {
+        let program = lower_src("$read x $while x {:= x - x 1} $fn f() {$if 1 {} {}} $print f()");
+        // the main program and the functions start at the entry block, which
+        // nothing jumps back to
+        for body in [&program, &program.funcs[&id("f")].body] {
+            assert_eq!(body.block_order()[0], id(ENTRY_LABEL));
+            assert!(body.predecessors(id(ENTRY_LABEL)).is_empty());
+        }
+    }
+
+    #[test]
+    fn early_return() {
+        let program = lower_src("$read x $if x {$return} $print x");
+        assert_targets_exist(&program);
+        // the true branch exits, and the rest of it is an unreachable block
+        // that jumps to the join block
+        assert!(matches!(program.block[&id("lbl1")].term, Terminator::Exit));
+        assert!(matches!(
+            program.block[&id("lbl4")].term,
+            Terminator::Jump(l) if l == id("lbl3")
+        ));
+        assert!(program
+            .block
+            .values()
+            .all(|b| !b.term.successors().contains(&id("lbl4"))));
+
+        // inside a function, `$return` returns the result variable
+        let program = lower_src("$fn f() {:= f 1 $return := f 2} $print f()");
+        let f = &program.funcs[&id("f")].body;
+        assert_targets_exist(f);
+        assert!(matches!(f.block[&id("entry")].term, Terminator::Return(x) if x == id("f")));
+        assert!(matches!(f.block[&id("lbl1")].term, Terminator::Return(x) if x == id("f")));
+    }
+
+    #[test]
+    fn binop() {
+        for (src, expected) in [
+            ("$print % x y", BOp::Mod),
+            ("$print < x y", BOp::Lt),
+            ("$print > x y", BOp::Gt),
+            ("$print = x y", BOp::Eq),
+            ("$print <= x y", BOp::Le),
+            ("$print >= x y", BOp::Ge),
+            ("$print != x y", BOp::Ne),
+        ] {
+            let program = lower_src(src);
+            let insn = &program.block[&id("entry")].insn;
+            assert_eq!(insn.len(), 2, "wrong instruction count for {src:?}");
+            assert!(matches!(
+                insn[0],
+                Instruction::Arith { op, lhs, rhs, .. }
+                    if op == expected && lhs == id("x") && rhs == id("y")
+            ));
+            assert!(matches!(insn[1], Instruction::Print(_)));
+        }
+    }
+
+    #[test]
+    fn per_statement_names() {
+        let src = "$print + x 1 $print * y + z 2 $if < x y {$print - x 1} {:= y 0}";
+        let edited = "$print + * x 3 x $print * y + z 2 $if < x y {$print - x 1} {:= y 0}";
+        // the program without the lines of the first statement
+        let rest = |program: tir::Program| {
+            let text = program.to_string();
+            text.lines()
+                .filter(|l| !l.contains("_s1_"))
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+        let program = lower_per_statement(parse(src).unwrap());
+        assert!(program.decl.contains(&id("_t_s2_3")));
+        assert!(program.block.contains_key(&id("lbl_s3_1")));
+        assert_eq!(
+            rest(program),
+            rest(lower_per_statement(parse(edited).unwrap()))
+        );
+        // the global numbering shifts everything after the edit
+        assert_ne!(
+            rest(lower(parse(src).unwrap())),
+            rest(lower(parse(edited).unwrap()))
+        );
+        // functions are numbered the same way
+        let program = lower_per_statement(parse("$fn f(a) {:= f + a 1}").unwrap());
+        assert!(program.funcs[&id("f")].body.decl.contains(&id("_t_s1_2")));
+    }
+
+    #[test]
+    fn keeping_names() {
+        let src = ":= y 3 := x + y 1 := z x := w ? x y z";
+        let insns = |program: tir::Program| {
+            let entry = &program.block[&id("entry")];
+            entry.insn.iter().map(|i| i.to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            insns(lower_src(src)),
+            [
+                "_const_1 = $const 3",
+                "y = $copy _const_1",
+                "_const_2 = $const 1",
+                "_t_3 = $arith add y _const_2",
+                "x = $copy _t_3",
+                "z = $copy x",
+            ]
+        );
+        // a plain copy stays a copy, and so does the result of a select,
+        // which several blocks compute
+        let program = lower_keeping_names(parse(src).unwrap());
+        assert!(!program.decl.contains(&id("_t_3")));
+        assert_eq!(
+            insns(program),
+            [
+                "y = $const 3",
+                "_const_2 = $const 1",
+                "x = $arith add y _const_2",
+                "z = $copy x"
+            ]
+        );
+    }
+}
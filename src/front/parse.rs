@@ -2,16 +2,102 @@
 
 use std::fmt::Debug;
 
-use clap::Id;
 use derive_more::derive::Display;
 
 use super::ast::*;
 use super::lex::*;
-use crate::common::id;
+use crate::common::{id, unquote, Id};
 
 #[derive(Display)]
-#[display("Parse error: {}", self.0)]
-pub struct ParseError(String);
+#[display("Parse error at {line}:{col}: {kind}")]
+pub struct ParseError {
+    /// The line the error is at (1-based).
+    pub line: usize,
+    /// The column the error is at (1-based).
+    pub col: usize,
+    /// How many characters the offending part of the input spans (at least 1).
+    pub len: usize,
+    /// What went wrong.
+    pub kind: ParseErrorKind,
+}
+
+/// The kinds of parse errors.  Their `Display` is the message that is shown
+/// after the position of the error.
+#[derive(Clone, PartialEq, Eq, Display, Debug)]
+pub enum ParseErrorKind {
+    #[display("Unexpected end of input.")]
+    UnexpectedEof,
+    /// The input ended where a token of the given kind should be.
+    #[display("Expected a token with kind {_0} but reached the end of input.")]
+    MissingToken(TokenKind),
+    #[display(
+        "Expected a token with kind {expected}, found a token with kind {found} and text `{text}`."
+    )]
+    UnexpectedToken {
+        expected: TokenKind,
+        found: TokenKind,
+        text: String,
+    },
+    /// A character that does not start any token.
+    #[display("Unexpected character `{_0}`.")]
+    UnexpectedChar(char),
+    /// A token that cannot start a statement where a statement should be.
+    #[display("Expected start of a statement, found {_0}")]
+    ExpectedStatement(String),
+    /// A token that cannot start an expression where an expression should be.
+    /// The message is the same as for [ParseErrorKind::ExpectedStatement].
+    #[display("Expected start of a statement, found {_0}")]
+    ExpectedExpression(String),
+    /// A binary operator where an operand should be, in infix notation.
+    #[display("Expected an operand, found the operator `{_0}`.")]
+    ExpectedOperand(String),
+    #[display("There are still leftover tokens after reading a whole program.")]
+    LeftoverTokens,
+    #[display("The function `{_0}` is defined twice.")]
+    DuplicateFunction(Id),
+    #[display("Functions can only be defined at the top level.")]
+    NestedFunction,
+    #[display("This `(` is never closed.")]
+    UnclosedParen,
+    #[display("This `)` does not close any `(`.")]
+    UnopenedParen,
+    #[display("The numeric literal `{_0}` is malformed.")]
+    MalformedNumber(String),
+    #[display("The numeric literal `{_0}` does not fit in a 64-bit integer.")]
+    NumberTooLarge(String),
+    /// A malformed string literal, with the message saying what is wrong.
+    #[display("{_0}")]
+    MalformedString(String),
+    #[display("String literals can only be printed.")]
+    StringOutsidePrint,
+    #[display("Expression nesting too deep.")]
+    ExpressionTooDeep,
+    #[display("Block nesting too deep.")]
+    BlockTooDeep,
+    /// Two arms of a `$case` with the same integer.
+    #[display("The case `{_0}` is handled twice.")]
+    DuplicateCase(i64),
+}
+
+impl ParseError {
+    fn at(token: &Token, kind: ParseErrorKind) -> Self {
+        ParseError {
+            line: token.line,
+            col: token.col,
+            len: token.text.chars().count().max(1),
+            kind,
+        }
+    }
+
+    // An error for a token that cannot appear where it is, unless the token
+    // is a character that does not start any token, which is reported as such
+    fn unexpected(token: &Token, kind: ParseErrorKind) -> Self {
+        match LexError::from_token(token) {
+            Some(e) => ParseError::at(token, ParseErrorKind::UnexpectedChar(e.ch)),
+            None => ParseError::at(token, kind),
+        }
+    }
+}
 
 impl Debug for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -21,151 +107,994 @@ impl Debug for ParseError {
 
 type ParseResult<T> = Result<T, ParseError>;
 
+/// Render the error along with the line of the source code it is on, and
+/// underline the offending part of the line, e.g.
+///
+/// ```text
+/// Parse error at 2:4: Expected a token with kind id, ...
+///   |
+/// 2 | := 3 a
+///   |    ^
+/// ```
+pub fn render_error(src: &str, err: &ParseError) -> String {
+    let line = src.lines().nth(err.line - 1).unwrap_or("");
+    // keep the tabs before the error so that the underline lines up with the
+    // source code however wide the tabs are
+    let indent: String = line
+        .chars()
+        .take(err.col - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let margin = " ".repeat(err.line.to_string().len());
+    let source = format!("{} | {line}", err.line);
+    format!(
+        "{err}\n{margin} |\n{}\n{margin} | {indent}{}",
+        source.trim_end(),
+        "^".repeat(err.len)
+    )
+}
+
+/// How deeply expressions and blocks may be nested by default.  Anything
+/// deeper is an error rather than a stack overflow.
+pub const MAX_DEPTH: usize = 200;
+
 pub fn parse(input: &str) -> Result<Program, ParseError> {
+    Parser::new(input).parse_whole()
+}
+
+/// Parse the whole input like [parse], but allow expressions and blocks to be
+/// nested at most `max_depth` levels deep instead of [MAX_DEPTH].
+pub fn parse_with_max_depth(input: &str, max_depth: usize) -> Result<Program, ParseError> {
     let mut parser = Parser::new(input);
-    let program = parser.parse_program()?;
-    if !parser.tokens.is_empty() {
-        Err(ParseError(
-            "There are still leftover tokens after reading a whole program.".to_string(),
-        ))
-    } else {
+    parser.max_depth = max_depth;
+    parser.parse_whole()
+}
+
+/// Parse the whole input like [parse], but without recursing into the operands
+/// of expressions, so that expressions can be nested arbitrarily deeply
+/// without overflowing the stack.  The result is the same as that of [parse].
+/// Blocks are still nested at most [MAX_DEPTH] levels deep.
+pub fn parse_iterative(input: &str) -> Result<Program, ParseError> {
+    let mut parser = Parser::new(input);
+    parser.iterative = true;
+    parser.parse_whole()
+}
+
+/// Parse the whole input, reading expressions in infix notation.
+///
+/// The statements are the same as in [parse], but expressions are written the
+/// usual way, e.g. `:= z x + 3 * y` instead of `:= z + x * 3 y`.  `^` binds
+/// tightest, then the multiplicative operators, then the additive ones, then
+/// comparisons, then `&`, then `|`.  All binary operators except `^` are
+/// left-associative, and parentheses group as usual.
+pub fn parse_infix(input: &str) -> Result<Program, ParseError> {
+    let mut parser = Parser::new(input);
+    parser.infix = true;
+    parser.parse_whole()
+}
+
+/// Parse the whole input, recovering from errors at statement boundaries.
+///
+/// Unlike [parse], this does not stop at the first error: after a malformed
+/// statement, the parser skips to the start of the next statement and carries
+/// on, so that all the errors in the input are reported at once.  Inside a
+/// block, it skips to the next statement or the end of the block, so an error
+/// in one branch of a `$if` does not hide the other branch.
+pub fn parse_all(input: &str) -> Result<Program, Vec<ParseError>> {
+    let mut parser = Parser::new(input);
+    parser.recover = true;
+    let mut program = Program {
+        stmts: vec![],
+        funcs: vec![],
+    };
+
+    while !parser.tokens.is_empty() {
+        if let Err(e) = parser.parse_item(&mut program) {
+            parser.errors.push(e);
+            parser.synchronize();
+        }
+    }
+
+    if parser.errors.is_empty() {
         Ok(program)
+    } else {
+        Err(parser.errors)
     }
 }
 
 struct Parser<'input> {
     /// Rest of the input, ordered in reverse.
     tokens: Vec<Token<'input>>,
+    /// Line and column of the end of input.
+    end: (usize, usize),
+    /// Whether expressions are in infix rather than prefix notation.
+    infix: bool,
+    /// Whether prefix expressions are parsed with an explicit stack.
+    iterative: bool,
+    /// The byte offset right after the last token read.
+    prev_end: usize,
+    /// How many nested expressions and blocks are being parsed.
+    depth: usize,
+    /// The maximum of `depth`.
+    max_depth: usize,
+    /// Whether blocks recover from errors in their statements.
+    recover: bool,
+    /// The errors recovered from so far, in the order of the input.
+    errors: Vec<ParseError>,
+    /// How many `{` have been read without their `}`.
+    open_braces: usize,
 }
 
 impl<'a> Parser<'a> {
     fn new(input: &'a str) -> Self {
-        let mut tokens = get_tokens(input);
+        let mut lexer = Lexer::new(input);
+        let mut tokens = vec![];
+        while let Some(token) = lexer.next() {
+            tokens.push(token);
+        }
         tokens.reverse();
-        Parser { tokens }
+        Parser {
+            tokens,
+            end: lexer.position(),
+            infix: false,
+            iterative: false,
+            prev_end: 0,
+            depth: 0,
+            max_depth: MAX_DEPTH,
+            recover: false,
+            errors: vec![],
+            open_braces: 0,
+        }
     }
 
-    fn peek(&self) -> Option<Token> {
+    // Create an error located at the end of input.
+    fn error_at_end(&self, kind: ParseErrorKind) -> ParseError {
+        let (line, col) = self.end;
+        ParseError {
+            line,
+            col,
+            len: 1,
+            kind,
+        }
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
         self.tokens.last().copied()
     }
 
-    fn next(&mut self) -> ParseResult<Token> {
-        self.tokens
+    fn next(&mut self) -> ParseResult<Token<'a>> {
+        let token = self
+            .tokens
             .pop()
-            .ok_or(ParseError("Unexpected end of input.".to_owned()))
+            .ok_or_else(|| self.error_at_end(ParseErrorKind::UnexpectedEof))?;
+        self.prev_end = token.offset + token.text.len();
+        match token.kind {
+            TokenKind::LBrace => self.open_braces += 1,
+            TokenKind::RBrace => self.open_braces = self.open_braces.saturating_sub(1),
+            _ => {}
+        }
+        Ok(token)
+    }
+
+    // Put back a token that was just read.
+    fn unread(&mut self, token: Token<'a>) {
+        match token.kind {
+            TokenKind::LBrace => self.open_braces -= 1,
+            TokenKind::RBrace => self.open_braces += 1,
+            _ => {}
+        }
+        self.tokens.push(token);
     }
 
     fn next_is(&self, kind: TokenKind) -> bool {
         self.peek().map(|t| t.kind == kind).unwrap_or(false)
     }
 
+    // Is the next token of the given kind, and does it start right where
+    // `tok` ends, with no whitespace in between?
+    fn next_is_adjacent(&self, tok: &Token, kind: TokenKind) -> bool {
+        self.peek()
+            .is_some_and(|t| t.kind == kind && t.offset == tok.offset + tok.text.len())
+    }
+
     fn eat(&mut self, kind: TokenKind) -> bool {
         if self.next_is(kind) {
-            self.tokens.pop();
-            true
+            self.next().is_ok()
         } else {
             false
         }
     }
 
-    fn expect(&mut self, kind: TokenKind) -> ParseResult<Token> {
+    fn expect(&mut self, kind: TokenKind) -> ParseResult<Token<'a>> {
         if self.next_is(kind) {
             self.next()
         } else if let Some(actual) = self.peek() {
-            Err(ParseError(format!(
-                "Expected a token with kind {kind}, found a token with kind {} and text `{}`.",
-                actual.kind, actual.text
-            )))
+            Err(ParseError::unexpected(
+                &actual,
+                ParseErrorKind::UnexpectedToken {
+                    expected: kind,
+                    found: actual.kind,
+                    text: actual.text.to_string(),
+                },
+            ))
+        } else {
+            Err(self.error_at_end(ParseErrorKind::MissingToken(kind)))
+        }
+    }
+
+    // Run given parser one level deeper, or fail with the error `too_deep` if
+    // that is nested too deeply.
+    fn nested<T>(
+        &mut self,
+        too_deep: ParseErrorKind,
+        parse: impl FnOnce(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<T> {
+        if self.depth >= self.max_depth {
+            return Err(match self.peek() {
+                Some(token) => ParseError::at(&token, too_deep),
+                None => self.error_at_end(too_deep),
+            });
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    // Run given parser, and find the part of the input it has read.
+    fn spanned<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<(T, Span)> {
+        let start = match self.peek() {
+            Some(tok) => start_of(&tok),
+            None => Span {
+                start: self.prev_end,
+                end: self.prev_end,
+                line: self.end.0,
+                col: self.end.1,
+            },
+        };
+        let result = parse(self)?;
+        Ok((result, self.span_from(start)))
+    }
+
+    // Skip tokens until the start of the next statement or the end of input.
+    fn synchronize(&mut self) {
+        while let Some(token) = self.peek() {
+            if starts_stmt(token.kind) {
+                break;
+            }
+            self.tokens.pop();
+        }
+    }
+
+    // Skip tokens until the start of the next statement or the `}` that closes
+    // the current block, given how many braces were open when the failed
+    // statement started.  The blocks the statement left open are closed first,
+    // and nested blocks are passed over as a whole.
+    fn synchronize_block(&mut self, open_braces: usize) {
+        while let Some(token) = self.peek() {
+            if self.open_braces == open_braces
+                && (token.kind == TokenKind::RBrace || starts_stmt(token.kind))
+            {
+                break;
+            }
+            let _ = self.next();
+        }
+    }
+
+    // Parse a program, and make sure it spans the whole input.
+    fn parse_whole(&mut self) -> ParseResult<Program> {
+        let program = self.parse_program()?;
+        if let Some(token) = self.peek() {
+            Err(ParseError::at(&token, ParseErrorKind::LeftoverTokens))
         } else {
-            Err(ParseError(format!(
-                "Expected a token with kind {kind} but reached the end of input."
-            )))
+            Ok(program)
         }
     }
 
     fn parse_program(&mut self) -> ParseResult<Program> {
-        let mut stmts = vec![];
+        let mut program = Program {
+            stmts: vec![],
+            funcs: vec![],
+        };
 
         while !self.tokens.is_empty() {
-            stmts.push(self.parse_stmt()?);
+            self.parse_item(&mut program)?;
+        }
+
+        Ok(program)
+    }
+
+    // Parse a function definition or a statement at the top level, and add it
+    // to the program
+    fn parse_item(&mut self, program: &mut Program) -> ParseResult<()> {
+        let Some(tok) = self.peek().filter(|t| t.kind == TokenKind::Fn) else {
+            program.stmts.push(self.parse_stmt()?);
+            return Ok(());
+        };
+
+        let func = self.parse_func()?;
+        if program.funcs.iter().any(|f| f.name == func.name) {
+            return Err(ParseError::at(
+                &tok,
+                ParseErrorKind::DuplicateFunction(func.name),
+            ));
         }
+        program.funcs.push(func);
+        Ok(())
+    }
+
+    fn parse_func(&mut self) -> ParseResult<Func> {
+        self.expect(TokenKind::Fn)?;
+        let name = self.parse_id()?;
+        let params = self.parse_list(Self::parse_id)?;
+        let body = self.parse_block()?;
+        Ok(Func { name, params, body })
+    }
 
-        Ok(Program { stmts })
+    // Parse a parenthesized, comma-separated list
+    fn parse_list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<Vec<T>> {
+        let mut items = vec![];
+        self.expect(TokenKind::LParen)?;
+        if self.eat(TokenKind::RParen) {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.eat(TokenKind::RParen) {
+                return Ok(items);
+            }
+            self.expect(TokenKind::Comma)?;
+        }
     }
 
     fn parse_stmt(&mut self) -> ParseResult<Stmt> {
+        let (kind, span) = self.spanned(Self::parse_stmt_kind)?;
+        Ok(Stmt::new(kind, span))
+    }
+
+    fn parse_stmt_kind(&mut self) -> ParseResult<StmtKind> {
         let tok = self.next()?;
         match tok.kind {
             TokenKind::Assign => {
                 let lhs = id(self.expect(TokenKind::Id)?.text);
                 let rhs = self.parse_expr()?;
-                Ok(Stmt::Assign(lhs, rhs))
+                Ok(StmtKind::Assign(lhs, rhs))
             }
-            TokenKind::Print => Ok(Stmt::Print(self.parse_expr()?)),
-            TokenKind::Read => Ok(Stmt::Read(id(self.expect(TokenKind::Id)?.text))),
-            TokenKind::If => {
-                let guard = self.parse_expr()?;
-                let tt = self.parse_block()?;
-                let ff = self.parse_block()?;
-                Ok(Stmt::If { guard, tt, ff })
+            TokenKind::AddAssign => self.parse_compound_assign(BOp::Add),
+            TokenKind::SubAssign => self.parse_compound_assign(BOp::Sub),
+            TokenKind::MulAssign => self.parse_compound_assign(BOp::Mul),
+            TokenKind::DivAssign => self.parse_compound_assign(BOp::Div),
+            // the values go on until the next token that cannot start one
+            TokenKind::Print => {
+                let mut es = vec![self.parse_print_arg()?];
+                while self.peek().is_some_and(|t| starts_expr(t.kind)) {
+                    es.push(self.parse_print_arg()?);
+                }
+                Ok(StmtKind::Print(es))
             }
-            _ => Err(ParseError(format!(
-                "Expected start of a statement, found {}",
-                tok.text
-            ))),
+            TokenKind::PrintC if self.next_is(TokenKind::Str) => {
+                Ok(StmtKind::PrintNoNl(self.parse_str()?))
+            }
+            TokenKind::PrintC => Ok(StmtKind::PrintNoNl(self.parse_expr()?)),
+            TokenKind::Read => {
+                let mut xs = vec![self.parse_id()?];
+                while self.next_is(TokenKind::Id) {
+                    xs.push(self.parse_id()?);
+                }
+                Ok(StmtKind::Read(xs))
+            }
+            TokenKind::Swap => {
+                let x = self.parse_id()?;
+                let y = self.parse_id()?;
+                Ok(StmtKind::Swap(x, y))
+            }
+            TokenKind::If => self.parse_if(),
+            TokenKind::While => self.parse_while(),
+            TokenKind::For => self.parse_for(),
+            TokenKind::Case => self.parse_case(),
+            TokenKind::Assert => Ok(StmtKind::Assert(self.parse_expr()?)),
+            TokenKind::Return => Ok(StmtKind::Return),
+            TokenKind::Fn => Err(ParseError::at(&tok, ParseErrorKind::NestedFunction)),
+            TokenKind::RParen => Err(ParseError::at(&tok, ParseErrorKind::UnopenedParen)),
+            _ => Err(ParseError::unexpected(
+                &tok,
+                ParseErrorKind::ExpectedStatement(tok.text.to_string()),
+            )),
         }
     }
 
-    fn parse_id(&mut self) -> ParseResult<crate::common::Id> {
+    fn parse_id(&mut self) -> ParseResult<Id> {
         Ok(id(self.expect(TokenKind::Id)?.text))
     }
 
     fn parse_block(&mut self) -> ParseResult<Vec<Stmt>> {
-        let mut stmts = vec![];
+        self.nested(ParseErrorKind::BlockTooDeep, |parser| {
+            let mut stmts = vec![];
+
+            parser.expect(TokenKind::LBrace)?;
+            while !parser.eat(TokenKind::RBrace) {
+                let open_braces = parser.open_braces;
+                match parser.parse_stmt() {
+                    Ok(stmt) => stmts.push(stmt),
+                    // at the end of input, the block is missing its `}`
+                    Err(e) if parser.recover && !parser.tokens.is_empty() => {
+                        parser.errors.push(e);
+                        parser.synchronize_block(open_braces);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
 
+            Ok(stmts)
+        })
+    }
+
+    // Parse the rest of a `$if`: the guard and the true branch, and then the
+    // false branch, which is either a plain block or given by `$elif` and
+    // `$else`
+    fn parse_if(&mut self) -> ParseResult<StmtKind> {
+        let guard = self.parse_expr()?;
+        let tt = self.parse_block()?;
+        let ff = if self.next_is(TokenKind::LBrace) {
+            self.parse_block()?
+        } else {
+            self.parse_else()?
+        };
+        Ok(StmtKind::If { guard, tt, ff })
+    }
+
+    // Parse the rest of a `$while`: the guard and then the body
+    fn parse_while(&mut self) -> ParseResult<StmtKind> {
+        let guard = self.parse_expr()?;
+        let body = self.parse_block()?;
+        Ok(StmtKind::While { guard, body })
+    }
+
+    // Parse the `$elif` branches and the `$else` branch following a `$if` into
+    // the false branch of the `$if`.  Each `$elif` becomes a nested `$if`, and
+    // a missing `$else` is an empty block.
+    fn parse_else(&mut self) -> ParseResult<Vec<Stmt>> {
+        if self.next_is(TokenKind::Elif) {
+            // each `$elif` is nested in the previous one
+            let (kind, span) = self.nested(ParseErrorKind::BlockTooDeep, |parser| {
+                parser.spanned(|parser| {
+                    parser.next()?;
+                    let guard = parser.parse_expr()?;
+                    let tt = parser.parse_block()?;
+                    let ff = parser.parse_else()?;
+                    Ok(StmtKind::If { guard, tt, ff })
+                })
+            })?;
+            Ok(vec![Stmt::new(kind, span)])
+        } else if self.eat(TokenKind::Else) {
+            self.parse_block()
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    // Parse the rest of a `$for`: the variable, the start and the end, and then
+    // the body
+    fn parse_for(&mut self) -> ParseResult<StmtKind> {
+        let var = self.parse_id()?;
+        let start = Box::new(self.parse_expr()?);
+        let end = Box::new(self.parse_expr()?);
+        let body = self.parse_block()?;
+        Ok(StmtKind::For {
+            var,
+            start,
+            end,
+            body,
+        })
+    }
+
+    // Parse the rest of a `$case`: the scrutinee, then the arms in braces, each
+    // an integer followed by a block, and then the `$default` block, which is
+    // empty if it is missing
+    fn parse_case(&mut self) -> ParseResult<StmtKind> {
+        let scrutinee = self.parse_expr()?;
+        let mut arms: Vec<(i64, Vec<Stmt>)> = vec![];
+        let mut default = vec![];
         self.expect(TokenKind::LBrace)?;
         while !self.eat(TokenKind::RBrace) {
-            stmts.push(self.parse_stmt()?);
+            if self.eat(TokenKind::Default) {
+                default = self.parse_block()?;
+                self.expect(TokenKind::RBrace)?;
+                break;
+            }
+            let tok = self.expect(TokenKind::Num)?;
+            let n = parse_int(tok.text).map_err(|_| {
+                ParseError::at(&tok, ParseErrorKind::NumberTooLarge(tok.text.to_string()))
+            })?;
+            if arms.iter().any(|(m, _)| *m == n) {
+                return Err(ParseError::at(&tok, ParseErrorKind::DuplicateCase(n)));
+            }
+            arms.push((n, self.parse_block()?));
         }
-
-        Ok(stmts)
+        Ok(StmtKind::Case {
+            scrutinee,
+            arms,
+            default,
+        })
     }
 
     fn parse_expr(&mut self) -> ParseResult<Expr> {
-        use Expr::*;
+        if self.iterative && !self.infix {
+            return self.parse_prefix_iterative();
+        }
+        self.nested(ParseErrorKind::ExpressionTooDeep, |parser| {
+            if parser.infix {
+                parser.parse_infix_expr(0)
+            } else {
+                parser.parse_prefix_expr()
+            }
+        })
+    }
+
+    // Parse an infix expression whose operators all have at least the given
+    // precedence, by precedence climbing.
+    fn parse_infix_expr(&mut self, min_prec: u8) -> ParseResult<Expr> {
+        let mut lhs = self.parse_operand()?;
+        self.split_sign();
+        while let Some((op, prec)) = self.peek().and_then(|t| infix_op(t.kind)) {
+            if prec < min_prec {
+                break;
+            }
+            self.next()?;
+            // the right operand binds tighter, so operators are left-associative,
+            // except for `^` which is right-associative like in math
+            let rhs_prec = if op == BOp::Pow { prec } else { prec + 1 };
+            let rhs = self.nested(ParseErrorKind::ExpressionTooDeep, |parser| {
+                parser.parse_infix_expr(rhs_prec)
+            })?;
+            let span = lhs.span.to(rhs.span);
+            let kind = ExprKind::BinOp {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+            lhs = Expr::new(kind, span);
+            self.split_sign();
+        }
+        Ok(lhs)
+    }
+
+    // The lexer reads `-` right before a digit as the sign of a number, but a
+    // number after an operand is not another operand in infix notation, so
+    // `x -1` is a subtraction.  Split the sign off the next token if it is
+    // such a number.
+    fn split_sign(&mut self) {
+        let Some(tok) = self.peek() else {
+            return;
+        };
+        if !matches!(tok.kind, TokenKind::Num | TokenKind::Float) || !tok.text.starts_with('-') {
+            return;
+        }
+        self.tokens.pop();
+        self.tokens.push(Token {
+            text: &tok.text[1..],
+            col: tok.col + 1,
+            offset: tok.offset + 1,
+            ..tok
+        });
+        self.tokens.push(Token {
+            kind: TokenKind::Minus,
+            text: &tok.text[..1],
+            ..tok
+        });
+    }
+
+    // Parse an operand of an infix operator.  Apart from the binary operators
+    // and `~` (which applies only to the operand right after it), these are
+    // the same as in prefix notation.
+    fn parse_operand(&mut self) -> ParseResult<Expr> {
+        match self.peek() {
+            Some(tok) if tok.kind == TokenKind::Tilde => {
+                let (kind, span) = self.spanned(|parser| {
+                    parser.next()?;
+                    let inner =
+                        parser.nested(ParseErrorKind::ExpressionTooDeep, Self::parse_operand)?;
+                    Ok(ExprKind::Negate(Box::new(inner)))
+                })?;
+                Ok(Expr::new(kind, span))
+            }
+            Some(tok) if infix_op(tok.kind).is_some() => Err(ParseError::at(
+                &tok,
+                ParseErrorKind::ExpectedOperand(tok.text.to_string()),
+            )),
+            _ => self.parse_prefix_expr(),
+        }
+    }
+
+    // Parse a prefix expression like [Parser::parse_prefix_expr], but keep the
+    // operations whose operands are not read yet on an explicit stack instead
+    // of the call stack.
+    fn parse_prefix_iterative(&mut self) -> ParseResult<Expr> {
+        let mut pending = vec![];
+        loop {
+            // read operators until an operand, which is parsed the usual way
+            // because it does not recurse
+            let tok = self.next()?;
+            let start = start_of(&tok);
+            let mut e = match tok.kind {
+                TokenKind::Id if self.next_is_adjacent(&tok, TokenKind::LParen) => {
+                    self.next()?;
+                    let func = id(tok.text);
+                    if !self.eat(TokenKind::RParen) {
+                        pending.push(Pending::Call {
+                            start,
+                            func,
+                            args: vec![],
+                        });
+                        continue;
+                    }
+                    let kind = ExprKind::Call { func, args: vec![] };
+                    Expr::new(kind, self.span_from(start))
+                }
+                TokenKind::Tilde => {
+                    pending.push(Pending::Negate { start });
+                    continue;
+                }
+                TokenKind::Question => {
+                    pending.push(Pending::Select {
+                        start,
+                        operands: vec![],
+                    });
+                    continue;
+                }
+                TokenKind::LParen => {
+                    pending.push(Pending::Group { open: tok });
+                    continue;
+                }
+                kind => match prefix_op(kind) {
+                    Some(op) => {
+                        pending.push(Pending::BinOp {
+                            op,
+                            start,
+                            lhs: None,
+                        });
+                        continue;
+                    }
+                    None => {
+                        self.unread(tok);
+                        self.parse_prefix_expr()?
+                    }
+                },
+            };
+
+            // pass the operand to the pending operations, completing them until
+            // one needs another operand
+            loop {
+                let Some(op) = pending.pop() else {
+                    return Ok(e);
+                };
+                let (kind, start) = match op {
+                    Pending::BinOp {
+                        op,
+                        start,
+                        lhs: None,
+                    } => {
+                        pending.push(Pending::BinOp {
+                            op,
+                            start,
+                            lhs: Some(e),
+                        });
+                        break;
+                    }
+                    Pending::BinOp {
+                        op,
+                        start,
+                        lhs: Some(lhs),
+                    } => {
+                        let (lhs, rhs) = (Box::new(lhs), Box::new(e));
+                        (ExprKind::BinOp { op, lhs, rhs }, start)
+                    }
+                    Pending::Negate { start } => (ExprKind::Negate(Box::new(e)), start),
+                    Pending::Select {
+                        start,
+                        mut operands,
+                    } if operands.len() < 2 => {
+                        operands.push(e);
+                        pending.push(Pending::Select { start, operands });
+                        break;
+                    }
+                    Pending::Select {
+                        start,
+                        mut operands,
+                    } => {
+                        let ff = Box::new(e);
+                        let tt = Box::new(operands.pop().unwrap());
+                        let guard = Box::new(operands.pop().unwrap());
+                        (ExprKind::Select { guard, tt, ff }, start)
+                    }
+                    Pending::Group { open } => {
+                        if !self.eat(TokenKind::RParen) {
+                            return Err(ParseError::at(&open, ParseErrorKind::UnclosedParen));
+                        }
+                        (e.into_kind(), start_of(&open))
+                    }
+                    Pending::Call {
+                        start,
+                        func,
+                        mut args,
+                    } => {
+                        args.push(e);
+                        if !self.eat(TokenKind::RParen) {
+                            self.expect(TokenKind::Comma)?;
+                            pending.push(Pending::Call { start, func, args });
+                            break;
+                        }
+                        (ExprKind::Call { func, args }, start)
+                    }
+                };
+                e = Expr::new(kind, self.span_from(start));
+            }
+        }
+    }
+
+    // The span from where given span starts to the end of the last token read
+    fn span_from(&self, start: Span) -> Span {
+        Span {
+            end: self.prev_end,
+            ..start
+        }
+    }
+
+    fn parse_prefix_expr(&mut self) -> ParseResult<Expr> {
+        let (kind, span) = self.spanned(Self::parse_prefix_kind)?;
+        Ok(Expr::new(kind, span))
+    }
+
+    fn parse_prefix_kind(&mut self) -> ParseResult<ExprKind> {
+        use ExprKind::*;
 
         let tok = self.next()?;
 
         match tok.kind {
+            // a call needs its `(` right after the function name, so that
+            // `f (x)` is a variable followed by a parenthesized expression
+            TokenKind::Id if self.next_is_adjacent(&tok, TokenKind::LParen) => {
+                let args = self.parse_list(Self::parse_expr)?;
+                Ok(Call {
+                    func: id(tok.text),
+                    args,
+                })
+            }
             TokenKind::Id => Ok(Var(id(tok.text))),
-            TokenKind::Num => Ok(Const(tok.text.parse().unwrap())),
+            // the digit separators are only there for the reader
+            TokenKind::Float => Ok(Const(Number::Float(
+                tok.text.replace('_', "").parse().unwrap(),
+            ))),
+            TokenKind::Error if tok.is_malformed_number() => Err(ParseError::at(
+                &tok,
+                ParseErrorKind::MalformedNumber(tok.text.to_string()),
+            )),
+            TokenKind::Num => parse_int(tok.text)
+                .map(|n| Const(Number::Int(n)))
+                .map_err(|_| {
+                    ParseError::at(&tok, ParseErrorKind::NumberTooLarge(tok.text.to_string()))
+                }),
             TokenKind::Plus => self.parse_binop(BOp::Add),
             TokenKind::Minus => self.parse_binop(BOp::Sub),
             TokenKind::Mul => self.parse_binop(BOp::Mul),
             TokenKind::Div => self.parse_binop(BOp::Div),
+            TokenKind::Percent => self.parse_binop(BOp::Mod),
+            TokenKind::Caret => self.parse_binop(BOp::Pow),
             TokenKind::Lt => self.parse_binop(BOp::Lt),
+            TokenKind::Gt => self.parse_binop(BOp::Gt),
+            TokenKind::Eq => self.parse_binop(BOp::Eq),
+            TokenKind::Le => self.parse_binop(BOp::Le),
+            TokenKind::Ge => self.parse_binop(BOp::Ge),
+            TokenKind::Ne => self.parse_binop(BOp::Ne),
+            TokenKind::And => self.parse_binop(BOp::And),
+            TokenKind::Or => self.parse_binop(BOp::Or),
+            TokenKind::Min => self.parse_binop(BOp::Min),
+            TokenKind::Max => self.parse_binop(BOp::Max),
+            TokenKind::Shl => self.parse_binop(BOp::Shl),
+            TokenKind::Shr => self.parse_binop(BOp::Shr),
+            TokenKind::BitAnd => self.parse_binop(BOp::BitAnd),
+            TokenKind::BitOr => self.parse_binop(BOp::BitOr),
+            TokenKind::BitXor => self.parse_binop(BOp::BitXor),
             TokenKind::Tilde => Ok(Negate(Box::new(self.parse_expr()?))),
-            _ => Err(ParseError(format!(
-                "Expected start of a statement, found {}",
-                tok.text
-            ))),
+            TokenKind::Question => {
+                let guard = Box::new(self.parse_expr()?);
+                let tt = Box::new(self.parse_expr()?);
+                let ff = Box::new(self.parse_expr()?);
+                Ok(Select { guard, tt, ff })
+            }
+            TokenKind::LParen => {
+                let inner = self.parse_expr()?;
+                if self.eat(TokenKind::RParen) {
+                    Ok(inner.into_kind())
+                } else {
+                    Err(ParseError::at(&tok, ParseErrorKind::UnclosedParen))
+                }
+            }
+            TokenKind::RParen => Err(ParseError::at(&tok, ParseErrorKind::UnopenedParen)),
+            TokenKind::Str => Err(ParseError::at(&tok, ParseErrorKind::StringOutsidePrint)),
+            _ => Err(ParseError::unexpected(
+                &tok,
+                ParseErrorKind::ExpectedExpression(tok.text.to_string()),
+            )),
+        }
+    }
+
+    // Parse a value to print, which may be a string
+    fn parse_print_arg(&mut self) -> ParseResult<Expr> {
+        if self.next_is(TokenKind::Str) {
+            self.parse_str()
+        } else {
+            self.parse_expr()
         }
     }
 
+    // Parse a string literal, which may only appear as the argument of `$print`
+    fn parse_str(&mut self) -> ParseResult<Expr> {
+        let tok = self.expect(TokenKind::Str)?;
+        let span = self.span_from(start_of(&tok));
+        unquote(tok.text)
+            .map(|s| Expr::new(ExprKind::Str(s), span))
+            .map_err(|msg| ParseError::at(&tok, ParseErrorKind::MalformedString(msg)))
+    }
+
     // helper: read and parse both sides of given binary operation
-    fn parse_binop(&mut self, op: BOp) -> ParseResult<Expr> {
+    fn parse_binop(&mut self, op: BOp) -> ParseResult<ExprKind> {
         let lhs = Box::new(self.parse_expr()?);
         let rhs = Box::new(self.parse_expr()?);
-        Ok(Expr::BinOp { op, lhs, rhs })
+        Ok(ExprKind::BinOp { op, lhs, rhs })
+    }
+
+    // helper: desugar a compound assignment like `+= x e` into `:= x + x e`
+    fn parse_compound_assign(&mut self, op: BOp) -> ParseResult<StmtKind> {
+        let (x, x_span) = self.spanned(Self::parse_id)?;
+        let lhs = Expr::new(ExprKind::Var(x), x_span);
+        let rhs = self.parse_expr()?;
+        let span = lhs.span.to(rhs.span);
+        let kind = ExprKind::BinOp {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        };
+        Ok(StmtKind::Assign(x, Expr::new(kind, span)))
+    }
+}
+
+// An operation in a prefix expression that still needs some of its operands,
+// for the iterative parser
+enum Pending<'a> {
+    BinOp {
+        op: BOp,
+        start: Span,
+        lhs: Option<Expr>,
+    },
+    Negate {
+        start: Span,
+    },
+    Select {
+        start: Span,
+        operands: Vec<Expr>,
+    },
+    // a parenthesized expression, which needs its `)` after the operand
+    Group {
+        open: Token<'a>,
+    },
+    Call {
+        start: Span,
+        func: Id,
+        args: Vec<Expr>,
+    },
+}
+
+// The empty span where the token starts
+fn start_of(tok: &Token) -> Span {
+    Span {
+        start: tok.offset,
+        end: tok.offset,
+        line: tok.line,
+        col: tok.col,
+    }
+}
+
+// The value of an integer literal, which may be hexadecimal or binary
+fn parse_int(text: &str) -> Result<i64, std::num::ParseIntError> {
+    // the digit separators are only there for the reader
+    let text = text.replace('_', "");
+    let (sign, text) = match text.strip_prefix('-') {
+        Some(text) => ("-", text),
+        None => ("", text.as_str()),
+    };
+    if let Some(digits) = text.strip_prefix("0x") {
+        i64::from_str_radix(&format!("{sign}{digits}"), 16)
+    } else if let Some(digits) = text.strip_prefix("0b") {
+        i64::from_str_radix(&format!("{sign}{digits}"), 2)
+    } else {
+        format!("{sign}{text}").parse()
     }
 }
 
+// The binary operator a token of given kind stands for in infix notation, and
+// its precedence.  Higher precedence binds tighter.
+fn infix_op(kind: TokenKind) -> Option<(BOp, u8)> {
+    use TokenKind::*;
+    Some(match kind {
+        Or => (BOp::Or, 0),
+        And => (BOp::And, 1),
+        Lt => (BOp::Lt, 2),
+        Gt => (BOp::Gt, 2),
+        Eq => (BOp::Eq, 2),
+        Le => (BOp::Le, 2),
+        Ge => (BOp::Ge, 2),
+        Ne => (BOp::Ne, 2),
+        Shl => (BOp::Shl, 3),
+        Shr => (BOp::Shr, 3),
+        Plus => (BOp::Add, 4),
+        Minus => (BOp::Sub, 4),
+        Mul => (BOp::Mul, 5),
+        Div => (BOp::Div, 5),
+        Percent => (BOp::Mod, 5),
+        Caret => (BOp::Pow, 6),
+        _ => return None,
+    })
+}
+
+// The binary operator a token of given kind stands for in prefix notation.
+// These are the infix operators, and the ones like `$max` and `$band` that are
+// only written in front of their operands.
+fn prefix_op(kind: TokenKind) -> Option<BOp> {
+    match kind {
+        TokenKind::Min => Some(BOp::Min),
+        TokenKind::Max => Some(BOp::Max),
+        TokenKind::BitAnd => Some(BOp::BitAnd),
+        TokenKind::BitOr => Some(BOp::BitOr),
+        TokenKind::BitXor => Some(BOp::BitXor),
+        _ => infix_op(kind).map(|(op, _)| op),
+    }
+}
+
+// Can an expression (or a string to print) start with a token of given kind?
+// None of these start a statement.
+fn starts_expr(kind: TokenKind) -> bool {
+    use TokenKind::*;
+    matches!(kind, Id | Num | Float | Str | LParen | Tilde | Question) || prefix_op(kind).is_some()
+}
+
+// Can a statement start with a token of given kind?
+fn starts_stmt(kind: TokenKind) -> bool {
+    use TokenKind::*;
+    matches!(
+        kind,
+        Assign
+            | AddAssign
+            | SubAssign
+            | MulAssign
+            | DivAssign
+            | Print
+            | PrintC
+            | Read
+            | Swap
+            | Assert
+            | Case
+            | If
+            | While
+            | For
+            | Return
+            | Fn
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::front::lower;
     use BOp::*;
-    use Expr::*;
-    use Stmt::*;
+    use ExprKind::*;
+    use StmtKind::*;
 
     // SECTION: helpers
 
@@ -174,6 +1103,16 @@ mod tests {
         Box::new(x)
     }
 
+    // A span by its offsets, line and column
+    fn span(start: usize, end: usize, line: usize, col: usize) -> Span {
+        Span {
+            start,
+            end,
+            line,
+            col,
+        }
+    }
+
     // Build a binary operation expression
     fn bop(op: BOp, lhs: Expr, rhs: Expr) -> Expr {
         BinOp {
@@ -181,172 +1120,1423 @@ mod tests {
             lhs: b(lhs),
             rhs: b(rhs),
         }
+        .into()
     }
 
     // Build a negation expression
     fn negate(inner: Expr) -> Expr {
-        Negate(b(inner))
+        Negate(b(inner)).into()
+    }
+
+    // Build an integer constant node
+    fn int(n: i64) -> Expr {
+        Const(Number::Int(n)).into()
     }
 
     // Build a variable node
     fn var(name: &str) -> Expr {
-        Var(id(name))
+        Var(id(name)).into()
     }
 
     // SECTION: tests
 
     #[test]
     fn empty() {
-        assert_eq!(parse("").unwrap().stmts, vec![]);
+        assert!(parse("").unwrap().stmts.is_empty());
+        // only whitespace and comments is empty too
+        for src in ["", " \n\t", "// nothing\n# here", "# no newline"] {
+            for program in [parse(src), parse_infix(src), parse_iterative(src)] {
+                let program = program.unwrap();
+                assert_eq!(
+                    program,
+                    Program {
+                        stmts: vec![],
+                        funcs: vec![]
+                    }
+                );
+                assert_eq!(program.to_string(), "");
+            }
+        }
     }
 
     #[test]
     fn print() {
-        assert_eq!(parse("$print 0").unwrap().stmts, vec![Print(Const(0))]);
+        assert_eq!(parse("$print 0").unwrap().stmts, vec![Print(vec![int(0)])]);
     }
 
     #[test]
-    fn read() {
-        assert_eq!(parse("$read x").unwrap().stmts, vec![Read(id("x"))]);
+    fn comments() {
+        assert_eq!(
+            parse("# hello\n$print 0").unwrap().stmts,
+            vec![Print(vec![int(0)])]
+        );
+        assert_eq!(
+            parse("$print 0 # hello\n# $print 1").unwrap().stmts,
+            vec![Print(vec![int(0)])]
+        );
     }
 
     #[test]
-    fn var_test() {
-        assert_eq!(parse("$print x").unwrap().stmts, vec![Print(var("x"))]);
+    fn read() {
+        assert_eq!(parse("$read x").unwrap().stmts, vec![Read(vec![id("x")])]);
+        assert_eq!(
+            parse("$read x y").unwrap().stmts,
+            vec![Read(vec![id("x"), id("y")])]
+        );
+        assert_eq!(
+            parse("$read x y z $print x").unwrap().stmts,
+            vec![Read(vec![id("x"), id("y"), id("z")]), Print(vec![var("x")])]
+        );
+        assert_eq!(parse("$read x y z").unwrap().to_string(), "$read x y z\n");
     }
 
     #[test]
-    fn binop() {
+    fn print_many() {
         assert_eq!(
-            parse("$print + x x").unwrap().stmts,
-            vec![Print(bop(Add, var("x"), var("x")))]
+            parse("$print x 1 y").unwrap().stmts,
+            vec![Print(vec![var("x"), int(1), var("y")])]
         );
+        // each value is a whole expression, and the values end at the next
+        // statement
         assert_eq!(
-            parse("$print * x x").unwrap().stmts,
-            vec![Print(bop(Mul, var("x"), var("x")))]
+            parse("$print + x 1 ~ y := x 2").unwrap().stmts,
+            vec![
+                Print(vec![bop(Add, var("x"), int(1)), negate(var("y"))]),
+                Assign(id("x"), int(2))
+            ]
         );
+        // or at the end of the block
         assert_eq!(
-            parse("$print / x x").unwrap().stmts,
-            vec![Print(bop(Div, var("x"), var("x")))]
+            parse("$while x {$print x -1}").unwrap().stmts,
+            vec![While {
+                guard: var("x"),
+                body: vec![Print(vec![var("x"), int(-1)]).into()],
+            }]
         );
         assert_eq!(
-            parse("$print - x x").unwrap().stmts,
-            vec![Print(bop(Sub, var("x"), var("x")))]
+            parse(r#"$print "x =" x"#).unwrap().stmts,
+            vec![Print(vec![Str("x =".to_string()).into(), var("x")])]
         );
         assert_eq!(
-            parse("$print < x x").unwrap().stmts,
-            vec![Print(bop(Lt, var("x"), var("x")))]
+            parse("$print x y $print z").unwrap().to_string(),
+            "$print x y\n$print z\n"
         );
-    }
 
-    #[test]
-    fn negate_test() {
+        // in infix notation, a value ends where the next one cannot continue
+        // it, and a signed number after a value is a subtraction
         assert_eq!(
-            parse("$print ~ x").unwrap().stmts,
-            vec![Print(negate(var("x")))]
+            parse_infix("$print x + 1 y").unwrap().stmts,
+            vec![Print(vec![bop(Add, var("x"), int(1)), var("y")])]
+        );
+        assert_eq!(
+            parse_infix("$print x ~y (z)").unwrap().stmts,
+            vec![Print(vec![var("x"), negate(var("y")), var("z")])]
+        );
+        assert_eq!(
+            parse_infix("$print x -1").unwrap().stmts,
+            vec![Print(vec![bop(Sub, var("x"), int(1))])]
         );
     }
 
     #[test]
-    fn complex_expr() {
+    fn compound_assign() {
         assert_eq!(
-            parse("$print * + x 3 / ~ 7 y").unwrap().stmts,
-            vec![Print(bop(
-                Mul,
-                bop(Add, var("x"), Const(3)),
-                bop(Div, negate(Const(7)), var("y"))
-            ))]
+            parse("+= x 1").unwrap().stmts,
+            vec![Assign(id("x"), bop(Add, var("x"), int(1)))]
+        );
+        assert_eq!(
+            parse("-= x * y 2").unwrap().stmts,
+            vec![Assign(
+                id("x"),
+                bop(Sub, var("x"), bop(Mul, var("y"), int(2)))
+            )]
         );
+        assert_eq!(
+            parse("*= x y /= x 2").unwrap().stmts,
+            vec![
+                Assign(id("x"), bop(Mul, var("x"), var("y"))),
+                Assign(id("x"), bop(Div, var("x"), int(2))),
+            ]
+        );
+        assert_eq!(
+            parse_infix("+= x 2 * y").unwrap().stmts,
+            vec![Assign(
+                id("x"),
+                bop(Add, var("x"), bop(Mul, int(2), var("y")))
+            )]
+        );
+        // the desugared expression spans the variable and the operand
+        let program = parse("+= x 10").unwrap();
+        let StmtKind::Assign(_, e) = &program.stmts[0].kind else {
+            panic!("not an assignment");
+        };
+        assert_eq!(e.span, span(3, 7, 1, 4));
+        assert_eq!(program.to_string(), ":= x + x 10\n");
     }
 
     #[test]
-    fn assign() {
-        assert_eq!(
-            parse(":= x 3").unwrap().stmts,
-            vec![Assign(id("x"), Const(3))]
-        );
+    fn death_test_compound_assign() {
+        assert!(parse("+= x").is_err());
+        assert!(parse("+= 3 x").is_err());
+        assert!(parse("-= x $print x").is_err());
+    }
+
+    #[test]
+    fn swap() {
         assert_eq!(
-            parse(":= x + x 3").unwrap().stmts,
-            vec![Assign(id("x"), bop(Add, var("x"), Const(3)))]
+            parse("$swap x y").unwrap().stmts,
+            vec![Swap(id("x"), id("y"))]
         );
+        assert_eq!(parse("$swap x y").unwrap().to_string(), "$swap x y\n");
     }
 
     #[test]
-    fn if_test() {
+    fn case() {
         assert_eq!(
-            parse("$if x {} {}").unwrap().stmts,
-            vec![If {
-                guard: var("x"),
-                tt: vec![],
-                ff: vec![]
+            parse("$case x {}").unwrap().stmts,
+            vec![Case {
+                scrutinee: var("x"),
+                arms: vec![],
+                default: vec![]
             }]
         );
+        let src = "$case + x 1 {1 {$print 1} -2 {} $default {$print 0 $return}}";
         assert_eq!(
-            parse("$if x {$print 0} {:= x 3}").unwrap().stmts,
-            vec![If {
-                guard: var("x"),
-                tt: vec![Print(Const(0))],
-                ff: vec![Assign(id("x"), Const(3))]
+            parse(src).unwrap().stmts,
+            vec![Case {
+                scrutinee: bop(Add, var("x"), int(1)),
+                arms: vec![(1, vec![Print(vec![int(1)]).into()]), (-2, vec![])],
+                default: vec![Print(vec![int(0)]).into(), Return.into()]
             }]
         );
         assert_eq!(
-            parse("$if x {$print 0 $read x} {:= x 3 := y x}")
-                .unwrap()
-                .stmts,
-            vec![If {
-                guard: var("x"),
-                tt: vec![Print(Const(0)), Read(id("x"))],
-                ff: vec![Assign(id("x"), Const(3)), Assign(id("y"), var("x"))]
-            }]
+            parse(src).unwrap().to_string(),
+            "$case + x 1 {
+    1 {
+        $print 1
+    }
+    -2 {}
+    $default {
+        $print 0
+        $return
+    }
+}
+"
         );
         assert_eq!(
-            parse("$if < x y {$print 0} {:= x 3}").unwrap().stmts,
-            vec![If {
-                guard: bop(Lt, var("x"), var("y")),
-                tt: vec![Print(Const(0))],
-                ff: vec![Assign(id("x"), Const(3))]
-            }]
+            parse(&parse(src).unwrap().to_string()).unwrap(),
+            parse(src).unwrap()
+        );
+        assert_eq!(
+            parse_infix("$case x + 1 {0x10 {}}").unwrap().to_string(),
+            "$case + x 1 {\n    16 {}\n    $default {}\n}\n"
         );
     }
 
     #[test]
-    fn death_test1() {
-        // illegal tokens to start a program
-        assert!(parse("x").is_err());
-        assert!(parse("0").is_err());
-        assert!(parse("<").is_err());
-
-        // extra lexemes after a statement
-        assert!(parse(":= x y + z").is_err());
-        assert!(parse(":= x y + z t").is_err());
+    fn assert() {
+        assert_eq!(
+            parse("$assert < x 10 $print x").unwrap().stmts,
+            vec![Assert(bop(Lt, var("x"), int(10))), Print(vec![var("x")])]
+        );
+        assert_eq!(
+            parse("$assert < x 10").unwrap().to_string(),
+            "$assert < x 10\n"
+        );
+        assert_eq!(
+            parse_infix("$assert x < 10").unwrap(),
+            parse("$assert < x 10").unwrap()
+        );
     }
 
     #[test]
-    fn death_test_print() {
-        assert!(parse("$print").is_err());
+    fn bop_display() {
+        let ops = [
+            (Mul, "*", "mul"),
+            (Div, "/", "div"),
+            (Mod, "%", "mod"),
+            (Pow, "^", "pow"),
+            (Add, "+", "add"),
+            (Sub, "-", "sub"),
+            (Lt, "<", "lt"),
+            (Gt, ">", "gt"),
+            (Eq, "=", "eq"),
+            (Le, "<=", "le"),
+            (Ge, ">=", "ge"),
+            (Ne, "!=", "ne"),
+            (And, "&", "and"),
+            (Or, "|", "or"),
+            (Shl, "<<", "shl"),
+            (Shr, ">>", "shr"),
+        ];
+        for (op, symbol, mnemonic) in ops {
+            assert_eq!(op.to_string(), symbol);
+            assert_eq!(op.mnemonic(), mnemonic);
+            // the symbol is the token the operator is parsed from
+            assert_eq!(
+                parse(&format!("$print {op} x y")).unwrap().stmts,
+                vec![Print(vec![bop(op, var("x"), var("y"))])]
+            );
+            assert_eq!(
+                parse_infix(&format!("$print x {op} y")).unwrap().stmts,
+                vec![Print(vec![bop(op, var("x"), var("y"))])]
+            );
+        }
     }
 
     #[test]
-    fn death_test_read() {
-        assert!(parse("$read").is_err());
+    fn var_test() {
+        assert_eq!(
+            parse("$print x").unwrap().stmts,
+            vec![Print(vec![var("x")])]
+        );
     }
 
     #[test]
-    fn death_test_assign() {
-        assert!(parse(":=").is_err());
-        assert!(parse(":= x").is_err());
-        assert!(parse(":= 3 x").is_err());
-    }
+    fn binop() {
+        assert_eq!(
+            parse("$print + x x").unwrap().stmts,
+            vec![Print(vec![bop(Add, var("x"), var("x"))])]
+        );
+        assert_eq!(
+            parse("$print * x x").unwrap().stmts,
+            vec![Print(vec![bop(Mul, var("x"), var("x"))])]
+        );
+        assert_eq!(
+            parse("$print / x x").unwrap().stmts,
+            vec![Print(vec![bop(Div, var("x"), var("x"))])]
+        );
+        assert_eq!(
+            parse("$print % x 2").unwrap().stmts,
+            vec![Print(vec![bop(Mod, var("x"), int(2))])]
+        );
+        assert_eq!(
+            parse("$print - x x").unwrap().stmts,
+            vec![Print(vec![bop(Sub, var("x"), var("x"))])]
+        );
+        assert_eq!(
+            parse("$print < x x").unwrap().stmts,
+            vec![Print(vec![bop(Lt, var("x"), var("x"))])]
+        );
+        assert_eq!(
+            parse("$print > x y").unwrap().stmts,
+            vec![Print(vec![bop(Gt, var("x"), var("y"))])]
+        );
+        assert_eq!(
+            parse("$print = x y").unwrap().stmts,
+            vec![Print(vec![bop(Eq, var("x"), var("y"))])]
+        );
+        assert_eq!(
+            parse("$print <= x y").unwrap().stmts,
+            vec![Print(vec![bop(Le, var("x"), var("y"))])]
+        );
+        assert_eq!(
+            parse("$print >= x y").unwrap().stmts,
+            vec![Print(vec![bop(Ge, var("x"), var("y"))])]
+        );
+        assert_eq!(
+            parse("$print != x y").unwrap().stmts,
+            vec![Print(vec![bop(Ne, var("x"), var("y"))])]
+        );
+        assert_eq!(
+            parse("$print <= < x y z").unwrap().stmts,
+            vec![Print(vec![bop(Le, bop(Lt, var("x"), var("y")), var("z"))])]
+        );
+    }
+
+    #[test]
+    fn negate_test() {
+        assert_eq!(
+            parse("$print ~ x").unwrap().stmts,
+            vec![Print(vec![negate(var("x"))])]
+        );
+    }
+
+    #[test]
+    fn negative_literals() {
+        // a `-` right before a digit is a sign, and a `-` on its own subtracts
+        assert_eq!(
+            parse("$print -5").unwrap().stmts,
+            vec![Print(vec![int(-5)])]
+        );
+        assert_eq!(
+            parse("$print - 5 3").unwrap().stmts,
+            vec![Print(vec![bop(Sub, int(5), int(3))])]
+        );
+        assert_eq!(
+            parse("$print - -5 -3").unwrap().stmts,
+            vec![Print(vec![bop(Sub, int(-5), int(-3))])]
+        );
+        assert_eq!(
+            parse("$print * x -2.5").unwrap().stmts,
+            vec![Print(vec![bop(
+                Mul,
+                var("x"),
+                Const(Number::Float(-2.5)).into()
+            )])]
+        );
+        assert_eq!(
+            parse("$print -0x10 $print -9223372036854775808")
+                .unwrap()
+                .stmts,
+            vec![Print(vec![int(-16)]), Print(vec![int(i64::MIN)])]
+        );
+        // negative constants are printed so that they read back the same
+        let program = parse("$print + x -1").unwrap();
+        assert_eq!(parse(&program.to_string()).unwrap(), program);
+
+        assert!(parse("$print -").is_err());
+        assert!(parse("$print - 5").is_err());
+        assert!(parse("$print -9223372036854775809").is_err());
+        let err = parse("$print -1.").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::MalformedNumber("-1.".to_string()));
+    }
+
+    #[test]
+    fn negative_literals_infix() {
+        // after an operand, the sign is a subtraction
+        for (infix, prefix) in [
+            ("$print x -1", "$print - x 1"),
+            ("$print x-1", "$print - x 1"),
+            ("$print -1 - -2", "$print - -1 -2"),
+            ("$print x * -2", "$print * x -2"),
+            ("$print f(-1)-1", "$print - f(-1) 1"),
+        ] {
+            assert_eq!(
+                parse_infix(infix).unwrap(),
+                parse(prefix).unwrap(),
+                "{infix:?}"
+            );
+        }
+        let Print(es) = &parse_infix("$print x-1").unwrap().stmts[0].kind else {
+            panic!("expected a print");
+        };
+        let ExprKind::BinOp { rhs, .. } = &es[0].kind else {
+            panic!("expected a subtraction");
+        };
+        assert_eq!(rhs.span, span(9, 10, 1, 10));
+    }
+
+    #[test]
+    fn complex_expr() {
+        assert_eq!(
+            parse("$print * + x 3 / ~ 7 y").unwrap().stmts,
+            vec![Print(vec![bop(
+                Mul,
+                bop(Add, var("x"), int(3)),
+                bop(Div, negate(int(7)), var("y"))
+            )])]
+        );
+    }
+
+    #[test]
+    fn assign() {
+        assert_eq!(
+            parse(":= x 3").unwrap().stmts,
+            vec![Assign(id("x"), int(3))]
+        );
+        assert_eq!(
+            parse(":= x + x 3").unwrap().stmts,
+            vec![Assign(id("x"), bop(Add, var("x"), int(3)))]
+        );
+        assert_eq!(
+            parse(":= x = y z").unwrap().stmts,
+            vec![Assign(id("x"), bop(Eq, var("y"), var("z")))]
+        );
+    }
+
+    #[test]
+    fn if_test() {
+        assert_eq!(
+            parse("$if x {} {}").unwrap().stmts,
+            vec![If {
+                guard: var("x"),
+                tt: vec![],
+                ff: vec![]
+            }]
+        );
+        assert_eq!(
+            parse("$if x {$print 0} {:= x 3}").unwrap().stmts,
+            vec![If {
+                guard: var("x"),
+                tt: vec![Print(vec![int(0)]).into()],
+                ff: vec![Assign(id("x"), int(3)).into()]
+            }]
+        );
+        assert_eq!(
+            parse("$if x {$print 0 $read x} {:= x 3 := y x}")
+                .unwrap()
+                .stmts,
+            vec![If {
+                guard: var("x"),
+                tt: vec![Print(vec![int(0)]).into(), Read(vec![id("x")]).into()],
+                ff: vec![
+                    Assign(id("x"), int(3)).into(),
+                    Assign(id("y"), var("x")).into()
+                ]
+            }]
+        );
+        assert_eq!(
+            parse("$if < x y {$print 0} {:= x 3}").unwrap().stmts,
+            vec![If {
+                guard: bop(Lt, var("x"), var("y")),
+                tt: vec![Print(vec![int(0)]).into()],
+                ff: vec![Assign(id("x"), int(3)).into()]
+            }]
+        );
+    }
+
+    #[test]
+    fn while_test() {
+        assert_eq!(
+            parse("$while x {}").unwrap().stmts,
+            vec![While {
+                guard: var("x"),
+                body: vec![]
+            }]
+        );
+        assert_eq!(
+            parse("$while < x 10 {:= x + x 1 $print x}").unwrap().stmts,
+            vec![While {
+                guard: bop(Lt, var("x"), int(10)),
+                body: vec![
+                    Assign(id("x"), bop(Add, var("x"), int(1))).into(),
+                    Print(vec![var("x")]).into()
+                ]
+            }]
+        );
+        assert_eq!(
+            parse("$while x {$if y {} {}}").unwrap().stmts,
+            vec![While {
+                guard: var("x"),
+                body: vec![If {
+                    guard: var("y"),
+                    tt: vec![],
+                    ff: vec![]
+                }
+                .into()]
+            }]
+        );
+    }
+
+    #[test]
+    fn for_loop() {
+        assert_eq!(
+            parse("$for i 0 + n 1 {$print i}").unwrap().stmts,
+            vec![For {
+                var: id("i"),
+                start: Box::new(int(0)),
+                end: Box::new(bop(Add, var("n"), int(1))),
+                body: vec![Print(vec![var("i")]).into()],
+            }]
+        );
+        assert_eq!(
+            parse("$for i 0 10 {$for j i 10 {}}").unwrap().stmts,
+            vec![For {
+                var: id("i"),
+                start: Box::new(int(0)),
+                end: Box::new(int(10)),
+                body: vec![For {
+                    var: id("j"),
+                    start: Box::new(var("i")),
+                    end: Box::new(int(10)),
+                    body: vec![],
+                }
+                .into()],
+            }]
+        );
+        assert_eq!(
+            parse_infix("$for i x + 1 n * 2 {}").unwrap(),
+            parse("$for i + x 1 * n 2 {}").unwrap()
+        );
+        assert!(parse("$for 0 1 2 {}").is_err());
+        assert!(parse("$for i 0 {}").is_err());
+        assert!(parse("$for i 0 10 $print i").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let programs = [
+            "",
+            "$print 0",
+            "$read x",
+            "$print + x x $print * x x $print / x x $print - x x $print < x x",
+            "$print > x y $print = x y $print <= x y $print >= x y $print != x y $print % x 2",
+            "$print ~ x",
+            "$print * + x 3 / ~ 7 y",
+            ":= x 3 := x + x 3 := x = y z",
+            "$if x {} {}",
+            "$if x {$print 0} {:= x 3}",
+            "$if x {$print 0 $read x} {:= x 3 := y x}",
+            "$if < x y {$print 0} {:= x 3}",
+            "$while < x 10 {:= x + x 1 $print x}",
+            "$while x {$if y {$while z {}} {$if w {} {$print 1}}} $print 2",
+            "$for i 0 n {$for j ~ i i {$print * i j}} $print i",
+            "$read a $if a {} {$read b $read c $read x $print + * a * x x + * b x c}",
+            r#"$print "" $print "hello, world" $print "\"\n\\" $print "$if # //""#,
+            "$fn f() {} $fn g(x, y) {:= g f()} $print + g(1, ~ x) f()",
+            "$print ? < x 0 ~ x ? y 1 2",
+        ];
+        for src in programs {
+            let program = parse(src).unwrap();
+            let unparsed = program.to_string();
+            assert_eq!(
+                parse(&unparsed).unwrap(),
+                program,
+                "round trip failed for {src:?}, unparsed as {unparsed:?}"
+            );
+            // unparsing is canonical
+            assert_eq!(parse(&unparsed).unwrap().to_string(), unparsed);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let src = "$fn f(x) {:= f * x 2.5 $return} $read y \
+                   $if & < y 3 ~ y {$print \"small\"} $else {$while y {:= y - y 1}} $print f(y)";
+        let program = parse(src).unwrap();
+        let json = serde_json::to_string(&program).unwrap();
+        assert_eq!(serde_json::from_str::<Program>(&json).unwrap(), program);
+        // identifiers are written as strings
+        assert!(
+            json.contains(r#"{"Read":["y"]}"#),
+            "unexpected JSON: {json}"
+        );
+
+        // infinities and NaN are written as strings, since JSON has no numbers
+        // for them
+        let program = ConstFolder.fold_program(parse("$print / 1.0 0 / -1.0 0 / 0.0 0").unwrap());
+        let json = serde_json::to_string(&program).unwrap();
+        for x in ["inf", "-inf", "NaN"] {
+            let float = format!(r#"{{"Float":"{x}"}}"#);
+            assert!(json.contains(&float), "unexpected JSON: {json}");
+        }
+        let reparsed = serde_json::from_str::<Program>(&json).unwrap();
+        assert_eq!(reparsed.to_string(), program.to_string());
+        assert!(serde_json::from_str::<Program>(&json.replace("NaN", "nan")).is_err());
+
+        // strings are only allowed where the parser would put them
+        let json = serde_json::to_string(&parse(":= x 1").unwrap()).unwrap();
+        let json = json.replace(r#"{"Const":{"Int":1}}"#, r#"{"Str":"one"}"#);
+        let err = serde_json::from_str::<Program>(&json).unwrap_err();
+        assert!(
+            err.to_string().contains("only be printed"),
+            "unexpected error: {err}"
+        );
+    }
+
+    #[test]
+    fn unparse() {
+        assert_eq!(
+            parse(":= x + 1 ~ y $if < x 3 {$print x $while x {}} {}")
+                .unwrap()
+                .to_string(),
+            ":= x + 1 ~ y\n$if < x 3 {\n    $print x\n    $while x {}\n} {}\n"
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            parse(r#"$print "hello" $print x"#).unwrap().stmts,
+            vec![
+                Print(vec![Str("hello".to_string()).into()]),
+                Print(vec![var("x")])
+            ]
+        );
+        assert_eq!(
+            parse(r#"$print "a\nb" $print "say \"hi\"" $print "c:\\""#)
+                .unwrap()
+                .stmts,
+            vec![
+                Print(vec![Str("a\nb".to_string()).into()]),
+                Print(vec![Str("say \"hi\"".to_string()).into()]),
+                Print(vec![Str("c:\\".to_string()).into()]),
+            ]
+        );
+        assert_eq!(
+            parse(r#"$print "tab\\t""#).unwrap().to_string(),
+            "$print \"tab\\\\t\"\n"
+        );
+    }
+
+    #[test]
+    fn print_without_newline() {
+        assert_eq!(
+            parse(r#"$printc "x = " $printc x $print x"#).unwrap().stmts,
+            vec![
+                PrintNoNl(Str("x = ".to_string()).into()),
+                PrintNoNl(var("x")),
+                Print(vec![var("x")]),
+            ]
+        );
+        assert_eq!(
+            parse("$printc + x 1").unwrap().stmts,
+            vec![PrintNoNl(bop(Add, var("x"), int(1)))]
+        );
+        assert_eq!(parse("$printc x").unwrap().to_string(), "$printc x\n");
+    }
+
+    #[test]
+    fn death_test_strings() {
+        let err = parse("$print 0\n  $print \"abc\n$print 1").unwrap_err();
+        assert_eq!((err.line, err.col), (2, 10));
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::MalformedString("Unterminated string literal.".to_string())
+        );
+
+        let err = parse(r#"$print "abc\""#).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::MalformedString("Unterminated string literal.".to_string())
+        );
+
+        let err = parse(r#"$print "a\tb""#).unwrap_err();
+        assert_eq!((err.line, err.col), (1, 8));
+        assert_eq!(
+            err.to_string(),
+            "Parse error at 1:8: Unknown escape sequence `\\t` in a string."
+        );
+
+        let err = parse(r#":= x "abc""#).unwrap_err();
+        assert_eq!((err.line, err.col), (1, 6));
+        assert_eq!(err.kind, ParseErrorKind::StringOutsidePrint);
+        assert!(parse(r#"$print + "a" 1"#).is_err());
+        assert!(parse(r#"$if "a" {} {}"#).is_err());
+    }
+
+    #[test]
+    fn elif_else() {
+        // `$else` is the same as the plain false branch, and it is optional
+        let expected = vec![If {
+            guard: var("x"),
+            tt: vec![Print(vec![int(0)]).into()],
+            ff: vec![Print(vec![int(1)]).into()],
+        }];
+        assert_eq!(
+            parse("$if x {$print 0} $else {$print 1}").unwrap().stmts,
+            expected
+        );
+        assert_eq!(
+            parse("$if x {$print 0} {$print 1}").unwrap().stmts,
+            expected
+        );
+        assert_eq!(
+            parse("$if x {$print 0}").unwrap().stmts,
+            parse("$if x {$print 0} {}").unwrap().stmts
+        );
+
+        // one `$elif`
+        let expected = vec![If {
+            guard: var("x"),
+            tt: vec![Print(vec![int(0)]).into()],
+            ff: vec![If {
+                guard: var("y"),
+                tt: vec![Print(vec![int(1)]).into()],
+                ff: vec![Print(vec![int(2)]).into()],
+            }
+            .into()],
+        }];
+        assert_eq!(
+            parse("$if x {$print 0} $elif y {$print 1} $else {$print 2}")
+                .unwrap()
+                .stmts,
+            expected
+        );
+        assert_eq!(
+            parse("$if x {$print 0} $elif y {$print 1}").unwrap().stmts,
+            parse("$if x {$print 0} {$if y {$print 1} {}}")
+                .unwrap()
+                .stmts
+        );
+
+        // two `$elif`s
+        assert_eq!(
+            parse("$if x {$print 0} $elif y {$print 1} $elif < x y {} $else {$print 3} $print 4")
+                .unwrap()
+                .stmts,
+            parse("$if x {$print 0} {$if y {$print 1} {$if < x y {} {$print 3}}} $print 4")
+                .unwrap()
+                .stmts
+        );
+        assert_eq!(
+            parse("$if x {} $elif y {} $elif z {}").unwrap().stmts,
+            parse("$if x {} {$if y {} {$if z {} {}}}").unwrap().stmts
+        );
+    }
+
+    #[test]
+    fn death_test1() {
+        // illegal tokens to start a program
+        assert!(parse("x").is_err());
+        assert!(parse("0").is_err());
+        assert!(parse("<").is_err());
+
+        // extra lexemes after a statement
+        assert!(parse(":= x y + z").is_err());
+        assert!(parse(":= x y + z t").is_err());
+    }
+
+    #[test]
+    fn death_test_print() {
+        assert!(parse("$print").is_err());
+    }
+
+    #[test]
+    fn death_test_unknown_characters() {
+        let err = parse("$print @").unwrap_err();
+        assert_eq!((err.line, err.col, err.len), (1, 8, 1));
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedChar('@'));
+        let err = parse("$read x;").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 8));
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedChar(';'));
+        let err = parse(":= x\n  ü").unwrap_err();
+        assert_eq!((err.line, err.col, err.len), (2, 3, 1));
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedChar('ü'));
+    }
+
+    #[test]
+    fn death_test_read() {
+        assert!(parse("$read").is_err());
+        assert!(parse("$read 3").is_err());
+        let err = parse("$read $print x").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 7));
+    }
+
+    #[test]
+    fn death_test_swap() {
+        assert!(parse("$swap").is_err());
+        assert!(parse("$swap x").is_err());
+        assert!(parse("$swap x 3").is_err());
+        let err = parse("$swap x $print x").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 9));
+    }
+
+    #[test]
+    fn death_test_case() {
+        assert!(parse("$case").is_err());
+        assert!(parse("$case x").is_err());
+        assert!(parse("$case x {").is_err());
+        assert!(parse("$case x {1}").is_err());
+        assert!(parse("$case x {1.5 {}}").is_err());
+        assert!(parse("$case x {y {}}").is_err());
+        assert!(parse("$case x {$default {} 1 {}}").is_err());
+        assert!(parse("$case x {$default {}").is_err());
+        assert!(parse("$default {}").is_err());
+        let err = parse("$case x {1 {} 2 {} 1 {}}").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::DuplicateCase(1));
+        assert_eq!((err.line, err.col), (1, 20));
+        let err = parse("$case x {99999999999999999999 {}}").unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::NumberTooLarge(_)));
+    }
+
+    #[test]
+    fn death_test_assert() {
+        assert!(parse("$assert").is_err());
+        assert!(parse("$assert $print x").is_err());
+        assert!(parse("$assert {x}").is_err());
+    }
+
+    #[test]
+    fn death_test_nesting() {
+        let too_deep = |src: &str| {
+            let err = parse(src).unwrap_err();
+            assert!(
+                matches!(
+                    err.kind,
+                    ParseErrorKind::ExpressionTooDeep | ParseErrorKind::BlockTooDeep
+                ),
+                "{err}"
+            );
+        };
+        too_deep(&format!("$print {}1", "~ ".repeat(1000)));
+        too_deep(&format!("$print {}1{}", "(".repeat(1000), ")".repeat(1000)));
+        too_deep(&format!(
+            "{}{}",
+            "$while 1 {".repeat(1000),
+            "}".repeat(1000)
+        ));
+        too_deep(&format!("$if 1 {{}} {}", "$elif 1 {} ".repeat(1000)));
+        let err = parse_infix(&format!("$print {}1", "2 ^ ".repeat(1000))).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::ExpressionTooDeep);
+        let err = parse_infix(&format!("$print {}1", "~".repeat(1000))).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::ExpressionTooDeep);
+        // the error is reported once, and parsing carries on after it
+        let src = format!("$print {}1 $print ~", "~ ".repeat(1000));
+        assert_eq!(parse_all(&src).unwrap_err().len(), 2);
+
+        // the limit is configurable
+        assert!(parse(&format!("$print {}1", "~ ".repeat(100))).is_ok());
+        assert!(parse_with_max_depth("$print ~ ~ 1", 3).is_ok());
+        let err = parse_with_max_depth("$print ~ ~ 1", 2).unwrap_err();
+        assert_eq!((err.line, err.col), (1, 12));
+        assert!(parse_with_max_depth("$if 1 { $if 1 {} {} } {}", 1).is_err());
+    }
+
+    #[test]
+    fn iterative() {
+        let programs = [
+            "$print + 1 * x 3",
+            ":= x - ~ ~ y ( / z 2 )",
+            "$print ? < x 1 f(x, + y 1, g()) ~ 0x10",
+            "$if & x | y z { $print (((x))) } $elif h(1) { $read x } $else { $print -2.5 }",
+            "$fn f(a) { := f ^ a 2 } $while f(x) { $print ? x y z }",
+            "$print $max x $min + y 1 0",
+            "$print $bxor << x 2 $band >> y 1 $bor z 7",
+        ];
+        for src in programs {
+            let recursive = parse(src).unwrap();
+            let iterative = parse_iterative(src).unwrap();
+            assert_eq!(recursive.stmts, iterative.stmts, "{src:?}");
+            assert_eq!(recursive.funcs, iterative.funcs, "{src:?}");
+        }
+        // the spans are the same too
+        let StmtKind::Print(es) = &parse_iterative("$print ( + x f( y ) )").unwrap().stmts[0].kind
+        else {
+            panic!("not a print");
+        };
+        let e = &es[0];
+        assert_eq!(e.span, span(7, 21, 1, 8));
+        let ExprKind::BinOp { rhs, .. } = &e.kind else {
+            panic!("not a binary operation");
+        };
+        assert_eq!(rhs.span, span(13, 19, 1, 14));
+
+        // so are the errors
+        let errors = [
+            "$print +",
+            "$print + 1",
+            "$print ( x",
+            "$print f(1 2)",
+            "$print ? x y",
+            "$print )",
+            "$print + 1 \"s\"",
+            "$print 0x",
+        ];
+        for src in errors {
+            assert_eq!(
+                parse(src).unwrap_err().to_string(),
+                parse_iterative(src).unwrap_err().to_string(),
+                "{src:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn iterative_deep() {
+        // 100k nested operations, far deeper than the recursive parser allows
+        let n = 100_000;
+        let src = format!("$print {}1", "+ 1 ".repeat(n));
+        assert!(parse(&src).is_err());
+        let program = parse_iterative(&src).unwrap();
+        let StmtKind::Print(es) = &program.stmts[0].kind else {
+            panic!("not a print");
+        };
+        let e = &es[0];
+        let mut depth = 0;
+        let mut e = e;
+        while let ExprKind::BinOp {
+            op: BOp::Add,
+            lhs,
+            rhs,
+        } = &e.kind
+        {
+            assert_eq!(**lhs, int(1));
+            depth += 1;
+            e = rhs;
+        }
+        assert_eq!((depth, e), (n, &int(1)));
+        // the tree is dropped without recursing
+        drop(program);
+    }
+
+    #[test]
+    fn death_test_assign() {
+        assert!(parse(":=").is_err());
+        assert!(parse("= x 3").is_err());
+        assert!(parse(":= x").is_err());
+        assert!(parse(":= 3 x").is_err());
+    }
 
     #[test]
     fn death_test_if() {
         assert!(parse("$if").is_err());
-        assert!(parse("$if x {}").is_err());
+        assert!(parse("$if x {} $else").is_err());
+        assert!(parse("$if x {} $else $print x").is_err());
+        assert!(parse("$if x {} $elif {}").is_err());
+        assert!(parse("$if x {} $elif y").is_err());
+        assert!(parse("$if x {} {} $else {}").is_err());
+        assert!(parse("$if x {} $else {} {}").is_err());
+        assert!(parse("$if x {} $else {} $elif y {}").is_err());
+        assert!(parse("$elif x {}").is_err());
+        assert!(parse("$else {}").is_err());
         assert!(parse("$if {} {}").is_err());
         assert!(parse("$if x y {}").is_err());
         assert!(parse("$if x $print x {}").is_err());
     }
 
+    #[test]
+    fn death_test_while() {
+        assert!(parse("$while").is_err());
+        assert!(parse("$while x").is_err());
+        assert!(parse("$while {}").is_err());
+        assert!(parse("$while x y").is_err());
+        assert!(parse("$while x {} {}").is_err());
+        assert!(parse("$while x $print x").is_err());
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(
+            parse("$print 2.75").unwrap().stmts,
+            vec![Print(vec![Const(Number::Float(2.75)).into()])]
+        );
+        assert_eq!(
+            parse("$print + 1 2.0").unwrap().stmts,
+            vec![Print(vec![bop(
+                Add,
+                int(1),
+                Const(Number::Float(2.0)).into()
+            )])]
+        );
+        assert_eq!(parse("$print 3").unwrap().stmts, vec![Print(vec![int(3)])]);
+        assert_eq!(
+            parse("$print 2.0 $print 0.25").unwrap().to_string(),
+            "$print 2.0\n$print 0.25\n"
+        );
+
+        for (src, col, literal) in [
+            ("$print 3.", 8, "3."),
+            ("$print + 3.4.5 1", 10, "3.4.5"),
+            (":= x 1..2", 6, "1..2"),
+            ("$print 1__000", 8, "1__000"),
+            ("$print - 5_ 1", 10, "5_"),
+        ] {
+            let err = parse(src).unwrap_err();
+            assert_eq!((err.line, err.col), (1, col), "wrong position for {src:?}");
+            assert_eq!(
+                err.kind,
+                ParseErrorKind::MalformedNumber(literal.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn functions() {
+        let program = parse("$fn square(x) {:= square * x x} $print square(5)").unwrap();
+        assert_eq!(
+            program.funcs,
+            vec![Func {
+                name: id("square"),
+                params: vec![id("x")],
+                body: vec![Assign(id("square"), bop(Mul, var("x"), var("x"))).into()],
+            }]
+        );
+        assert_eq!(
+            program.stmts,
+            vec![Print(vec![Call {
+                func: id("square"),
+                args: vec![int(5)],
+            }
+            .into()])]
+        );
+
+        // functions can be defined after they are used
+        let program = parse("$print + f() g(1, * x 2) $fn f() {} $fn g(a, b) {}").unwrap();
+        assert_eq!(
+            program.stmts,
+            vec![Print(vec![bop(
+                Add,
+                Call {
+                    func: id("f"),
+                    args: vec![],
+                }
+                .into(),
+                Call {
+                    func: id("g"),
+                    args: vec![int(1), bop(Mul, var("x"), int(2))],
+                }
+                .into()
+            )])]
+        );
+        assert_eq!(program.funcs.len(), 2);
+        assert_eq!(
+            program.to_string(),
+            "$fn f() {}\n$fn g(a, b) {}\n$print + f() g(1, * x 2)\n"
+        );
+    }
+
+    #[test]
+    fn return_test() {
+        assert_eq!(
+            parse("$if x {$return} $return $print 1").unwrap().stmts,
+            vec![
+                If {
+                    guard: var("x"),
+                    tt: vec![Return.into()],
+                    ff: vec![],
+                },
+                Return,
+                Print(vec![int(1)]),
+            ]
+        );
+        assert_eq!(
+            parse("$fn f() {$return}").unwrap().to_string(),
+            "$fn f() {\n    $return\n}\n"
+        );
+        assert!(parse("$return x").is_err());
+        assert!(parse(":= x $return").is_err());
+    }
+
+    #[test]
+    fn death_test_functions() {
+        let err = parse("$fn f() {}\n$fn f(x) {}").unwrap_err();
+        assert_eq!((err.line, err.col), (2, 1));
+        assert_eq!(err.kind, ParseErrorKind::DuplicateFunction(id("f")));
+
+        let err = parse("$while x {$fn f() {}}").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 11));
+        assert_eq!(err.kind, ParseErrorKind::NestedFunction);
+
+        assert!(parse("$fn").is_err());
+        assert!(parse("$fn f {}").is_err());
+        assert!(parse("$fn f() $print 1").is_err());
+        assert!(parse("$fn f(x y) {}").is_err());
+        assert!(parse("$fn f(x,) {}").is_err());
+        assert!(parse("$fn f(1) {}").is_err());
+        assert!(parse("$print f(1").is_err());
+        assert!(parse("$print f(1 2)").is_err());
+        assert!(parse("$print f(, 2)").is_err());
+        assert!(parse("f(1)").is_err());
+    }
+
+    #[test]
+    fn spans() {
+        // the text of the input a span covers
+        let text = |src: &'static str, span: Span| &src[span.start..span.end];
+
+        let src = ":= x 1\n$print  * + x 2 ~ f(x, 3) // done";
+        let program = parse(src).unwrap();
+        let [assign, print] = &program.stmts[..] else {
+            panic!("expected two statements, found {:?}", program.stmts);
+        };
+        assert_eq!(text(src, assign.span), ":= x 1");
+        assert_eq!(text(src, print.span), "$print  * + x 2 ~ f(x, 3)");
+
+        let StmtKind::Print(es) = &print.kind else {
+            panic!("expected a print, found {print:?}");
+        };
+        let mul = &es[0];
+        assert_eq!(mul.span, span(15, 32, 2, 9));
+        let ExprKind::BinOp { lhs, rhs, .. } = &mul.kind else {
+            panic!("expected a binary operation, found {mul:?}");
+        };
+        assert_eq!(text(src, lhs.span), "+ x 2");
+        assert_eq!(text(src, rhs.span), "~ f(x, 3)");
+        let ExprKind::Negate(call) = &rhs.kind else {
+            panic!("expected a negation, found {rhs:?}");
+        };
+        let ExprKind::Call { args, .. } = &call.kind else {
+            panic!("expected a call, found {call:?}");
+        };
+        assert_eq!(text(src, call.span), "f(x, 3)");
+        assert_eq!(text(src, args[1].span), "3");
+
+        // blocks and `$elif` branches
+        let src = "$if x {$print \"π\"} $elif ( y ) {$read y}";
+        let program = parse(src).unwrap();
+        let StmtKind::If { tt, ff, .. } = &program.stmts[0].kind else {
+            panic!("expected a conditional, found {:?}", program.stmts);
+        };
+        assert_eq!(text(src, program.stmts[0].span), src);
+        assert_eq!(text(src, tt[0].span), "$print \"π\"");
+        assert_eq!(text(src, ff[0].span), "$elif ( y ) {$read y}");
+        let StmtKind::If { guard, .. } = &ff[0].kind else {
+            panic!("expected a conditional, found {ff:?}");
+        };
+        assert_eq!(text(src, guard.span), "( y )");
+
+        // infix operators span both operands
+        let src = "$print ~x * (y + 1)";
+        let program = parse_infix(src).unwrap();
+        let StmtKind::Print(es) = &program.stmts[0].kind else {
+            panic!("expected a print, found {:?}", program.stmts);
+        };
+        let e = &es[0];
+        assert_eq!(text(src, e.span), "~x * (y + 1)");
+
+        // spans do not matter when comparing
+        assert_eq!(parse(":= x  1").unwrap(), parse(":= x 1").unwrap());
+    }
+
+    #[test]
+    fn grouping() {
+        let cases = [
+            ("$print ( + x ( * y z ) )", "$print + x * y z"),
+            ("$print ((x))", "$print x"),
+            ("$print - (~ 1) (f(2))", "$print - ~ 1 f(2)"),
+            (
+                ":= x (+ 1 2) $if (< x 3) {} {}",
+                ":= x + 1 2 $if < x 3 {} {}",
+            ),
+            ("$print + x f(1)", "$print + x f(1)"),
+        ];
+        for (grouped, plain) in cases {
+            assert_eq!(
+                parse(grouped).unwrap(),
+                parse(plain).unwrap(),
+                "{grouped:?}"
+            );
+        }
+
+        // a `(` after whitespace starts a group rather than a call
+        assert_eq!(
+            parse("$print + f (1)").unwrap().stmts,
+            vec![Print(vec![bop(Add, var("f"), int(1))])]
+        );
+        assert_eq!(
+            parse("$print + f\n(1)").unwrap().stmts,
+            vec![Print(vec![bop(Add, var("f"), int(1))])]
+        );
+    }
+
+    #[test]
+    fn render_errors() {
+        let src = "$read a\n:= 3 a\n$print + a";
+        let err = parse(src).unwrap_err();
+        assert_eq!(
+            render_error(src, &err),
+            format!("{err}\n  |\n2 | := 3 a\n  |    ^")
+        );
+
+        // the underline spans the whole token, and lines up with tabs
+        let src = "$print 1\n\t$print 99999999999999999999 # big";
+        let err = parse(src).unwrap_err();
+        let rendered = render_error(src, &err);
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(lines[2], "2 | \t$print 99999999999999999999 # big");
+        assert_eq!(lines[3], "  | \t       ^^^^^^^^^^^^^^^^^^^^");
+
+        // the margin is as wide as the line number
+        let src = format!("{}$print", "\n".repeat(11));
+        let err = parse(&src).unwrap_err();
+        assert_eq!(
+            render_error(&src, &err),
+            format!("{err}\n   |\n12 | $print\n   |       ^")
+        );
+    }
+
+    #[test]
+    fn death_test_grouping() {
+        let err = parse("$print ( + x ( * y z )").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 8));
+        assert_eq!(err.kind, ParseErrorKind::UnclosedParen);
+
+        let err = parse("$print (+ 1 2 3)").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 8));
+        assert_eq!(err.kind, ParseErrorKind::UnclosedParen);
+
+        let err = parse("$print + x 1)").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 13));
+        assert_eq!(err.kind, ParseErrorKind::UnopenedParen);
+
+        let err = parse("$print + ) 1").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 10));
+        assert_eq!(err.kind, ParseErrorKind::UnopenedParen);
+
+        assert!(parse("$print ()").is_err());
+        assert!(parse("$print (").is_err());
+        assert!(parse("$print (x, y)").is_err());
+        assert!(parse("$print f (1, 2)").is_err());
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_eq!(
+            parse("$print 1_000_000 $print 0.000_5").unwrap().stmts,
+            vec![
+                Print(vec![int(1_000_000)]),
+                Print(vec![Const(Number::Float(0.0005)).into()])
+            ]
+        );
+        assert_eq!(
+            parse("$print 9_223_372_036_854_775_807").unwrap().stmts,
+            vec![Print(vec![int(i64::MAX)])]
+        );
+        // the separators are not kept when unparsing
+        assert_eq!(parse(":= x 1_0").unwrap().to_string(), ":= x 10\n");
+        // `_5` is a variable, not a number
+        assert_eq!(
+            parse("$print _5").unwrap().stmts,
+            vec![Print(vec![var("_5")])]
+        );
+    }
+
+    #[test]
+    fn radixes() {
+        assert_eq!(
+            parse("$print 0xFF").unwrap().stmts,
+            vec![Print(vec![int(255)])]
+        );
+        assert_eq!(
+            parse("$print 0b1010").unwrap().stmts,
+            vec![Print(vec![int(10)])]
+        );
+        assert_eq!(
+            parse("$print + 0xdead_beef 0b1_0000").unwrap().stmts,
+            vec![Print(vec![bop(Add, int(0xdead_beef), int(16))])]
+        );
+        assert_eq!(
+            parse("$print 0x7fffffffffffffff").unwrap().stmts,
+            vec![Print(vec![int(i64::MAX)])]
+        );
+        // literals are unparsed in decimal
+        assert_eq!(parse(":= x 0x10").unwrap().to_string(), ":= x 16\n");
+
+        let err = parse("$print 0x8000000000000000").unwrap_err();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::NumberTooLarge("0x8000000000000000".to_string())
+        );
+        for literal in ["0x", "0b", "0b2", "0xZ"] {
+            let err = parse(&format!("$print {literal}")).unwrap_err();
+            assert_eq!((err.line, err.col), (1, 8));
+            assert_eq!(
+                err.kind,
+                ParseErrorKind::MalformedNumber(literal.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn num_limits() {
+        assert_eq!(
+            parse("$print 9223372036854775807").unwrap().stmts,
+            vec![Print(vec![int(i64::MAX)])]
+        );
+        assert_eq!(
+            parse("$print 0000000000000000000000001").unwrap().stmts,
+            vec![Print(vec![int(1)])]
+        );
+    }
+
+    #[test]
+    fn death_test_overflow() {
+        assert!(parse("$print 9223372036854775808").is_err());
+        assert!(parse("$print 99999999999999999999999").is_err());
+        assert!(parse(":= x + 1 99999999999999999999999").is_err());
+        let err = parse("$print 99999999999999999999999").unwrap_err();
+        assert!(err.to_string().contains("99999999999999999999999"));
+    }
+
+    #[test]
+    fn error_kinds() {
+        use ParseErrorKind::*;
+        let kind = |src| parse(src).unwrap_err().kind;
+        assert_eq!(kind("$print +"), UnexpectedEof);
+        assert_eq!(kind(":="), MissingToken(TokenKind::Id));
+        assert_eq!(
+            kind(":= 3 x"),
+            UnexpectedToken {
+                expected: TokenKind::Id,
+                found: TokenKind::Num,
+                text: "3".to_string()
+            }
+        );
+        assert_eq!(kind("x"), ExpectedStatement("x".to_string()));
+        assert_eq!(kind("$print {"), ExpectedExpression("{".to_string()));
+        assert_eq!(kind("$print ?"), UnexpectedEof);
+        assert_eq!(kind("$read x @"), UnexpectedChar('@'));
+
+        // the messages stay the same
+        let err = parse(":= 3 x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at 1:4: Expected a token with kind id, found a token with kind num and text `3`."
+        );
+        let err = parse("$read").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at 1:6: Expected a token with kind id but reached the end of input."
+        );
+        let err = parse("x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at 1:1: Expected start of a statement, found x"
+        );
+        let err = parse("$print {").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at 1:8: Expected start of a statement, found {"
+        );
+    }
+
+    #[test]
+    fn error_position() {
+        let err = parse("$print 0\n\n  $read + x").unwrap_err();
+        assert_eq!((err.line, err.col), (3, 9));
+        assert!(err.to_string().starts_with("Parse error at 3:9: "));
+
+        let err = parse("$print 0\n$if x {\n  $print 1\n").unwrap_err();
+        assert_eq!((err.line, err.col), (4, 1));
+
+        let err = parse(":= x 0 )").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 8));
+
+        let err = parse("  $print 99999999999999999999").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 10));
+    }
+
+    #[test]
+    fn parse_all_ok() {
+        let src = ":= x 3 $if < x 4 {$print x} {} $while x {:= x - x 1}";
+        assert_eq!(parse_all(src).unwrap().stmts, parse(src).unwrap().stmts);
+        assert!(parse_all("").unwrap().stmts.is_empty());
+    }
+
+    #[test]
+    fn parse_all_errors() {
+        let errors = parse_all("$print 1\n:= 3 x\n$print 2\n$print + 1\n").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].line, errors[0].col), (2, 4));
+        assert_eq!((errors[1].line, errors[1].col), (5, 1));
+
+        // the recovery skips garbage between statements
+        let errors = parse_all("$read x 7 8 $print x := 1 2 3 $print").unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].col, 9);
+        assert_eq!(errors[1].col, 25);
+        assert_eq!(errors[2].col, 37);
+    }
+
+    #[test]
+    fn parse_all_blocks() {
+        // the error in the true branch does not hide the false branch, nor the
+        // statements after the `$if`
+        let src = "$if x {\n:= 3 y\n$print x\n} {\n$print y\n}\n$print + 1";
+        let errors = parse_all(src).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].line, errors[0].col), (2, 4));
+        assert_eq!((errors[1].line, errors[1].col), (7, 11));
+        assert!(parse_all("$if x {:= 3 y} {$print y}").is_err());
+        let errors = parse_all("$if x {:= 3 y} {$print +}").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        // nested blocks in the skipped tokens are skipped as a whole
+        let errors =
+            parse_all("$while x {$if x y {$print +} {} $print x} $print 1 2 +").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].col, 17);
+        // so are the blocks the failed statement left open
+        let errors = parse_all("$while x {$case x {1 {} oops {}} $print 1}").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        // `parse` stops at the first one
+        assert_eq!(parse(src).unwrap_err().line, 2);
+        // an unclosed block is still one error
+        assert_eq!(parse_all("$while x {$print x $print").unwrap_err().len(), 1);
+    }
+
     #[test]
     fn death_test_expr() {
         assert!(parse("$print 3 + x").is_err());
@@ -354,11 +2544,199 @@ mod tests {
         assert!(parse("$print - x").is_err());
         assert!(parse("$print * x").is_err());
         assert!(parse("$print / x").is_err());
+        assert!(parse("$print % x").is_err());
         assert!(parse("$print < x").is_err());
+        assert!(parse("$print > x").is_err());
+        assert!(parse("$print > > x y").is_err());
+        assert!(parse("$print = x").is_err());
+        assert!(parse("$print <= x").is_err());
+        assert!(parse("$print >= x").is_err());
+        assert!(parse("$print != x").is_err());
+        assert!(parse("$print ! x y").is_err());
         assert!(parse("$print ~").is_err());
-        assert!(parse("$print ~ x y").is_err());
         assert!(parse("$print + + x y").is_err());
         assert!(parse("$print < y").is_err());
         assert!(parse("$print < - y z").is_err());
     }
+
+    #[test]
+    fn pow() {
+        assert_eq!(
+            parse("$print ^ 2 10").unwrap().stmts,
+            vec![Print(vec![bop(Pow, int(2), int(10))])]
+        );
+        assert_eq!(
+            parse("$print ^ ^ x 2 * y 3").unwrap().stmts,
+            vec![Print(vec![bop(
+                Pow,
+                bop(Pow, var("x"), int(2)),
+                bop(Mul, var("y"), int(3))
+            )])]
+        );
+        // `^` binds tightest in infix notation, and it is right-associative
+        assert_eq!(
+            parse_infix("$print 2 * x ^ 3 ^ y").unwrap(),
+            parse("$print * 2 ^ x ^ 3 y").unwrap()
+        );
+        assert!(parse("$print ^ 2").is_err());
+        assert!(parse("$print ^").is_err());
+    }
+
+    #[test]
+    fn select() {
+        let select = |guard, tt, ff| -> Expr {
+            Select {
+                guard: b(guard),
+                tt: b(tt),
+                ff: b(ff),
+            }
+            .into()
+        };
+        assert_eq!(
+            parse("$print ? < x 0 0 x").unwrap().stmts,
+            vec![Print(vec![select(
+                bop(Lt, var("x"), int(0)),
+                int(0),
+                var("x")
+            )])]
+        );
+        // selects nest like any other expression
+        assert_eq!(
+            parse(":= y ? a ? b 1 2 + 3 ? c 4 5").unwrap().stmts,
+            vec![Assign(
+                id("y"),
+                select(
+                    var("a"),
+                    select(var("b"), int(1), int(2)),
+                    bop(Add, int(3), select(var("c"), int(4), int(5)))
+                )
+            )]
+        );
+        assert_eq!(
+            parse_infix("$print ? x < 0 0 x").unwrap(),
+            parse("$print ? < x 0 0 x").unwrap()
+        );
+        assert!(parse("$print ?").is_err());
+        assert!(parse("$print ? x").is_err());
+        assert!(parse("$print ? x 1").is_err());
+        assert!(parse("$print ? x 1 \"no\"").is_err());
+    }
+
+    #[test]
+    fn min_max() {
+        assert_eq!(
+            parse("$print $max x y").unwrap().stmts,
+            vec![Print(vec![bop(Max, var("x"), var("y"))])]
+        );
+        let src = "$print $min $max x 0 + y 1";
+        assert_eq!(
+            parse(src).unwrap().stmts,
+            vec![Print(vec![bop(
+                Min,
+                bop(Max, var("x"), int(0)),
+                bop(Add, var("y"), int(1))
+            )])]
+        );
+        assert_eq!(parse(src).unwrap().to_string(), format!("{src}\n"));
+        assert_eq!((Min.mnemonic(), Max.mnemonic()), ("min", "max"));
+        // they stay in front of their operands in infix notation
+        assert_eq!(
+            parse_infix("$print $min $max x 0 y + 1").unwrap(),
+            parse(src).unwrap()
+        );
+        assert!(parse_infix("$print x $max y").is_err());
+        assert!(parse("$print $max x").is_err());
+        assert!(parse("$max x y").is_err());
+    }
+
+    #[test]
+    fn bitwise() {
+        let src = "$print $bor $band x 3 $bxor y >> z 1";
+        assert_eq!(
+            parse(src).unwrap().stmts,
+            vec![Print(vec![bop(
+                BitOr,
+                bop(BitAnd, var("x"), int(3)),
+                bop(BitXor, var("y"), bop(Shr, var("z"), int(1)))
+            )])]
+        );
+        assert_eq!(parse(src).unwrap().to_string(), format!("{src}\n"));
+        let ops = [(BitAnd, "band"), (BitOr, "bor"), (BitXor, "bxor")];
+        for (op, mnemonic) in ops {
+            assert_eq!(op.to_string(), format!("${mnemonic}"));
+            assert_eq!(op.mnemonic(), mnemonic);
+        }
+        // shifts bind tighter than comparisons and looser than sums
+        let cases = [
+            ("$print x << 1 + 2", "$print << x + 1 2"),
+            ("$print x >> 1 < y", "$print < >> x 1 y"),
+            ("$print x << y << z", "$print << << x y z"),
+            ("$print $band x y >> 2", "$print $band x >> y 2"),
+        ];
+        for (infix, prefix) in cases {
+            assert_eq!(
+                parse_infix(infix).unwrap(),
+                parse(prefix).unwrap(),
+                "{infix:?}"
+            );
+        }
+        assert!(parse_infix("$print x $bor y").is_err());
+        assert!(parse("$print $bxor x").is_err());
+        // `<<=` is a shift of an equality, not a comparison of a comparison
+        assert_eq!(
+            parse("$print <<= a b c").unwrap().stmts,
+            vec![Print(vec![bop(Shl, bop(Eq, var("a"), var("b")), var("c"))])]
+        );
+        assert_eq!(
+            parse("$print < <= a b c").unwrap().stmts,
+            vec![Print(vec![bop(Lt, bop(Le, var("a"), var("b")), var("c"))])]
+        );
+    }
+
+    #[test]
+    fn infix() {
+        let cases = [
+            ("$print x + 3 * y", "$print + x * 3 y"),
+            ("$print (x + 3) * y", "$print * + x 3 y"),
+            ("$print x - y - z", "$print - - x y z"),
+            ("$print x / y * z % w", "$print % * / x y z w"),
+            (
+                "$print x < y + 1 & y != 0 | z",
+                "$print | & < x + y 1 != y 0 z",
+            ),
+            ("$print ~ x * ~ (y + 1)", "$print * ~ x ~ + y 1"),
+            ("$print f(x + 1, g()) * 2", "$print * f(+ x 1, g()) 2"),
+            ("$print 1.5 * x <= 2", "$print <= * 1.5 x 2"),
+            (
+                "$fn f(n) {:= f 1 $while n > 1 {:= f f * n := n n - 1}} $print f(5)",
+                "$fn f(n) {:= f 1 $while > n 1 {:= f * f n := n - n 1}} $print f(5)",
+            ),
+            (
+                "$read x $if x % 2 = 0 {$print x / 2} $elif x > 10 {} $else {$print \"odd\"}",
+                "$read x $if = % x 2 0 {$print / x 2} $elif > x 10 {} $else {$print \"odd\"}",
+            ),
+        ];
+        for (infix, prefix) in cases {
+            let program = parse_infix(infix).unwrap();
+            assert_eq!(program, parse(prefix).unwrap(), "{infix:?}");
+            assert_eq!(
+                lower(program).to_string(),
+                lower(parse(prefix).unwrap()).to_string(),
+                "{infix:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn death_test_infix() {
+        let err = parse_infix("$print x + * y").unwrap_err();
+        assert_eq!((err.line, err.col), (1, 12));
+        assert_eq!(err.kind, ParseErrorKind::ExpectedOperand("*".to_string()));
+
+        assert!(parse_infix("$print + x y").is_err());
+        assert!(parse_infix("$print x +").is_err());
+        assert!(parse_infix("$print (x + y").is_err());
+        assert!(parse_infix("$print x + y)").is_err());
+        assert!(parse_infix("$print f(x +)").is_err());
+    }
 }
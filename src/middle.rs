@@ -1,5 +1,29 @@
+pub mod dom;
+pub mod dot;
+pub mod edges;
+pub mod interp;
+pub mod liveness;
+pub mod loops;
+pub mod parse;
+pub mod ssa;
+pub mod stats;
 pub mod tir;
+pub mod unused;
+pub mod verify;
+pub use dom::dominators;
+pub use dot::to_dot;
+pub use edges::split_critical_edges;
+pub use liveness::liveness;
+pub use loops::{find_loops, Loop};
+pub use parse::parse_tir;
+pub use ssa::to_ssa;
+pub use stats::{cfg_stats, CfgStats};
 pub use tir::*;
+pub use unused::unused_variables;
+pub use verify::verify;
 
 mod opt;
-pub use opt::optimize;
+pub use opt::{optimize, remove_nops};
+
+#[cfg(test)]
+mod test_util;
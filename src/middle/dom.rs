@@ -0,0 +1,253 @@
+//! Dominators: block `a` dominates block `b` if every path from `entry` to `b`
+//! goes through `a`.
+
+use super::*;
+use crate::common::*;
+
+/// Compute the immediate dominator of each block that is reachable from
+/// `entry`, where `entry` is its own immediate dominator.
+///
+/// This is the iterative algorithm from "A Simple, Fast Dominance Algorithm" by
+/// Cooper, Harvey, and Kennedy, which visits the blocks in reverse postorder
+/// until the dominators stop changing.
+pub fn dominators(program: &Program) -> Map<Id, Id> {
+    let order = reverse_postorder(program);
+    let Some(&entry) = order.first() else {
+        return Map::new();
+    };
+    let index: Map<Id, usize> = order.iter().enumerate().map(|(i, l)| (*l, i)).collect();
+
+    let mut preds: Map<Id, Vec<Id>> = Map::new();
+    for &lbl in &order {
+        for succ in program.block[&lbl].term.successors() {
+            if index.contains_key(&succ) {
+                preds.entry(succ).or_default().push(lbl);
+            }
+        }
+    }
+
+    let mut idom = Map::from([(entry, entry)]);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &lbl in &order[1..] {
+            // every block other than `entry` has a predecessor that comes
+            // before it in reverse postorder, so it already has a dominator
+            let new_idom = preds[&lbl]
+                .iter()
+                .filter(|p| idom.contains_key(*p))
+                .copied()
+                .reduce(|a, b| intersect(&idom, &index, a, b))
+                .expect("a reachable block should have a processed predecessor");
+            if idom.get(&lbl) != Some(&new_idom) {
+                idom.insert(lbl, new_idom);
+                changed = true;
+            }
+        }
+    }
+
+    idom
+}
+
+// Find the closest common dominator of two blocks by walking up the dominator
+// tree from the one that is later in reverse postorder
+fn intersect(idom: &Map<Id, Id>, index: &Map<Id, usize>, mut a: Id, mut b: Id) -> Id {
+    while a != b {
+        while index[&a] > index[&b] {
+            a = idom[&a];
+        }
+        while index[&b] > index[&a] {
+            b = idom[&b];
+        }
+    }
+    a
+}
+
+/// Compute the dominance frontier of each block that is reachable from
+/// `entry`, given the immediate dominators: the blocks where the dominance of
+/// the block ends.  These are the blocks where SSA form needs phi nodes.
+pub fn dominance_frontiers(program: &Program, idom: &Map<Id, Id>) -> Map<Id, Set<Id>> {
+    let mut preds: Map<Id, Vec<Id>> = Map::new();
+    for &lbl in idom.keys() {
+        for succ in program.block[&lbl].term.successors() {
+            if idom.contains_key(&succ) {
+                preds.entry(succ).or_default().push(lbl);
+            }
+        }
+    }
+
+    let mut df: Map<Id, Set<Id>> = idom.keys().map(|l| (*l, Set::new())).collect();
+    for (lbl, preds) in preds {
+        if preds.len() < 2 {
+            continue;
+        }
+        // walk up from each predecessor until the dominator of the join
+        for mut runner in preds {
+            while runner != idom[&lbl] {
+                df.get_mut(&runner).unwrap().insert(lbl);
+                runner = idom[&runner];
+            }
+        }
+    }
+    df
+}
+
+/// Does `a` dominate `b`, given the immediate dominators?  Every block
+/// dominates itself.
+pub fn dominates(idom: &Map<Id, Id>, a: Id, mut b: Id) -> bool {
+    loop {
+        if a == b {
+            return true;
+        }
+        match idom.get(&b) {
+            Some(&parent) if parent != b => b = parent,
+            _ => return false,
+        }
+    }
+}
+
+/// The blocks that are reachable from `entry`, in reverse postorder of a
+/// depth-first search.  Each block comes before its successors, except for
+/// the targets of back edges.
+pub fn reverse_postorder(program: &Program) -> Vec<Id> {
+    search(program, false)
+}
+
+// The reverse postorder of a depth-first search from `entry`, which visits the
+// successors of each block first to last, or last to first if `backwards`
+pub(super) fn search(program: &Program, backwards: bool) -> Vec<Id> {
+    let entry = id(ENTRY_LABEL);
+    if !program.block.contains_key(&entry) {
+        return vec![];
+    }
+
+    let mut order = vec![];
+    let mut visited = Set::from([entry]);
+    // the blocks on the current path, and the successors left to visit
+    let successors = |lbl| {
+        let mut succs = successors(program, lbl);
+        if backwards {
+            succs.reverse();
+        }
+        succs
+    };
+    let mut stack = vec![(entry, successors(entry))];
+    while let Some((lbl, succs)) = stack.last_mut() {
+        match succs.pop() {
+            Some(succ) => {
+                if visited.insert(succ) {
+                    stack.push((succ, successors(succ)));
+                }
+            }
+            None => {
+                order.push(*lbl);
+                stack.pop();
+            }
+        }
+    }
+
+    order.reverse();
+    order
+}
+
+// The successors of the block that exist, in reverse so that popping them
+// visits them in order
+fn successors(program: &Program, lbl: Id) -> Vec<Id> {
+    let mut succs = program.block[&lbl].term.successors();
+    succs.retain(|s| program.block.contains_key(s));
+    succs.reverse();
+    succs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::test_util::lower_src;
+
+    // SECTION: tests
+
+    #[test]
+    fn diamond() {
+        // entry branches to lbl1 and lbl2, which both jump to lbl3
+        let program = lower_src("$read x $if x {$print 1} {$print 2} $print 3");
+        let idom = dominators(&program);
+        let (entry, tt, ff, join) = (id("entry"), id("lbl1"), id("lbl2"), id("lbl3"));
+        assert_eq!(
+            idom,
+            Map::from([(entry, entry), (tt, entry), (ff, entry), (join, entry)])
+        );
+        assert!(dominates(&idom, entry, join));
+        assert!(!dominates(&idom, tt, join));
+        assert!(!dominates(&idom, ff, join));
+        assert!(dominates(&idom, join, join));
+        assert!(!dominates(&idom, join, entry));
+    }
+
+    #[test]
+    fn loops() {
+        let program = lower_src("$read n $while n {$if n {:= n 0} {} $print n} $print 1");
+        let idom = dominators(&program);
+        let (header, body, exit) = (id("lbl1"), id("lbl2"), id("lbl3"));
+        assert_eq!(idom[&header], id("entry"));
+        assert_eq!(idom[&body], header);
+        assert_eq!(idom[&exit], header);
+        // the join block of the `$if` in the loop body
+        assert_eq!(idom[&id("lbl6")], body);
+        assert!(dominates(&idom, header, id("lbl6")));
+    }
+
+    #[test]
+    fn frontiers() {
+        let program = lower_src("$read x $if x {$print 1} {$print 2} $print 3");
+        let df = dominance_frontiers(&program, &dominators(&program));
+        let (entry, tt, ff, join) = (id("entry"), id("lbl1"), id("lbl2"), id("lbl3"));
+        assert_eq!(
+            df,
+            Map::from([
+                (entry, Set::new()),
+                (tt, Set::from([join])),
+                (ff, Set::from([join])),
+                (join, Set::new()),
+            ])
+        );
+
+        // the loop header is in the frontier of the blocks in the loop,
+        // including itself
+        let program = lower_src("$read n $while n {$if n {:= n 0} {} $print n} $print 1");
+        let df = dominance_frontiers(&program, &dominators(&program));
+        let header = id("lbl1");
+        assert_eq!(df[&header], Set::from([header]));
+        assert_eq!(df[&id("lbl2")], Set::from([header]));
+        assert_eq!(df[&id("lbl4")], Set::from([id("lbl6")]));
+        assert_eq!(df[&id("lbl6")], Set::from([header]));
+        assert_eq!(df[&id("lbl3")], Set::new());
+    }
+
+    #[test]
+    fn unreachable_blocks() {
+        let program = lower_src("$print 1 $return $while x {}");
+        assert_eq!(
+            dominators(&program),
+            Map::from([(id("entry"), id("entry"))])
+        );
+
+        let mut program = lower_src("");
+        program.block.clear();
+        assert!(dominators(&program).is_empty());
+    }
+
+    #[test]
+    fn reverse_postorder_test() {
+        let program = lower_src("$while x {$print x} $if y {} {}");
+        let order: Vec<_> = reverse_postorder(&program)
+            .into_iter()
+            .map(|l| l.to_string())
+            .collect();
+        // the loop body is visited first, so it is finished first and comes
+        // last
+        assert_eq!(
+            order,
+            ["entry", "lbl1", "lbl3", "lbl5", "lbl4", "lbl6", "lbl2"]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Graphviz output for the control-flow graph, for debugging.
+
+use std::fmt::Write;
+
+use super::*;
+use crate::common::*;
+
+/// Render the CFG of the program in Graphviz DOT format.  Each block is a node
+/// listing its instructions and its terminator, and the entry block is drawn
+/// with a bold border.  Each function is drawn as a cluster of its own.
+pub fn to_dot(program: &Program) -> String {
+    let mut dot = String::new();
+    writeln!(dot, "digraph cfg {{").unwrap();
+    writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+    write_cfg(&mut dot, program, "", "    ");
+
+    for (name, func) in &program.funcs {
+        let params = func.params.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        writeln!(dot, "    subgraph \"cluster_{name}\" {{").unwrap();
+        writeln!(dot, "        label=\"$fn {name}({})\";", params.join(", ")).unwrap();
+        write_cfg(&mut dot, &func.body, &format!("{name}."), "        ");
+        writeln!(dot, "    }}").unwrap();
+    }
+
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+// Write the nodes and the edges of a CFG.  The node names are the labels with
+// given prefix, so that the blocks of different functions are kept apart.
+fn write_cfg(dot: &mut String, program: &Program, prefix: &str, indent: &str) {
+    for (lbl, block) in &program.block {
+        // `\l` ends a left-justified line in the label
+        let mut label = format!("{lbl}:\\l");
+        for insn in &block.insn {
+            write!(label, "    {}\\l", escape(&insn.to_string())).unwrap();
+        }
+        write!(label, "    {}\\l", block.term).unwrap();
+
+        let style = if *lbl == id(ENTRY_LABEL) {
+            ", style=bold"
+        } else {
+            ""
+        };
+        writeln!(dot, "{indent}\"{prefix}{lbl}\" [label=\"{label}\"{style}];").unwrap();
+    }
+
+    for (lbl, block) in &program.block {
+        match block.term {
+            Terminator::Exit | Terminator::Return(_) => {}
+            Terminator::Jump(target) => {
+                writeln!(dot, "{indent}\"{prefix}{lbl}\" -> \"{prefix}{target}\";").unwrap()
+            }
+            Terminator::Branch { tt, ff, .. } => {
+                writeln!(
+                    dot,
+                    "{indent}\"{prefix}{lbl}\" -> \"{prefix}{tt}\" [label=\"true\"];"
+                )
+                .unwrap();
+                writeln!(
+                    dot,
+                    "{indent}\"{prefix}{lbl}\" -> \"{prefix}{ff}\" [label=\"false\"];"
+                )
+                .unwrap();
+            }
+            Terminator::Switch {
+                ref cases, default, ..
+            } => {
+                for (n, target) in cases {
+                    writeln!(
+                        dot,
+                        "{indent}\"{prefix}{lbl}\" -> \"{prefix}{target}\" [label=\"{n}\"];"
+                    )
+                    .unwrap();
+                }
+                writeln!(
+                    dot,
+                    "{indent}\"{prefix}{lbl}\" -> \"{prefix}{default}\" [label=\"default\"];"
+                )
+                .unwrap();
+            }
+        }
+    }
+}
+
+// Escape the characters that are special inside a quoted DOT string
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{lower, parse};
+
+    // SECTION: helpers
+
+    // Count the nodes and the edges in the DOT output
+    fn count(dot: &str) -> (usize, usize) {
+        let nodes = dot
+            .lines()
+            .filter(|l| l.contains("[label=\"") && !l.contains("->"));
+        let edges = dot.lines().filter(|l| l.contains("->"));
+        (nodes.count(), edges.count())
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn if_diamond() {
+        let dot = to_dot(&lower(parse("$if x {$print 0} {$print 1}").unwrap()));
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert_eq!(count(&dot), (4, 4));
+        assert!(dot.contains("\"entry\" -> \"lbl1\" [label=\"true\"];"));
+        assert!(dot.contains("\"entry\" -> \"lbl2\" [label=\"false\"];"));
+        assert!(dot.contains("\"lbl1\" -> \"lbl3\";"));
+        assert!(dot.contains("\"lbl2\" -> \"lbl3\";"));
+        assert!(dot.contains("    $branch x lbl1 lbl2\\l\"") && dot.contains(", style=bold];"));
+        assert_eq!(dot.matches("style=bold").count(), 1);
+    }
+
+    #[test]
+    fn empty() {
+        let dot = to_dot(&lower(parse("").unwrap()));
+        assert_eq!(
+            dot,
+            "digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n    \
+             \"entry\" [label=\"entry:\\l    $exit\\l\", style=bold];\n}\n"
+        );
+        assert_eq!(count(&dot), (1, 0));
+    }
+
+    #[test]
+    fn while_loop() {
+        let dot = to_dot(&lower(parse("$while x {$read x}").unwrap()));
+        assert_eq!(count(&dot), (4, 4));
+        assert!(dot.contains("\"lbl2\" -> \"lbl1\";"));
+    }
+
+    #[test]
+    fn strings() {
+        let dot = to_dot(&lower(parse(r#"$print "say \"hi\"\n""#).unwrap()));
+        assert!(dot.contains(r#"    $print \"say \\\"hi\\\"\\n\"\l"#));
+    }
+
+    #[test]
+    fn functions() {
+        let src = "$fn f(a, b) {$if a {:= f b} {}} $print f(1, 2)";
+        let dot = to_dot(&lower(parse(src).unwrap()));
+        assert_eq!(count(&dot), (5, 4));
+        assert!(dot.contains("    subgraph \"cluster_f\" {\n        label=\"$fn f(a, b)\";\n"));
+        assert!(dot.contains("        \"f.entry\" -> \"f.lbl1\" [label=\"true\"];"));
+        assert!(dot.contains("        \"f.lbl3\" [label=\"lbl3:\\l    $return f\\l\"];"));
+        assert_eq!(dot.matches("style=bold").count(), 2);
+    }
+}
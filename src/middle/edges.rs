@@ -0,0 +1,166 @@
+//! Critical edges: an edge is critical if it goes from a block with several
+//! successors to a block with several predecessors.  Code that has to run on
+//! such an edge, like the copies that resolve phi nodes, can go neither at the
+//! end of its source nor at the start of its target.
+
+use super::*;
+use crate::common::*;
+
+/// Split the critical edges of the main program and the functions by putting
+/// a new empty block that jumps to the target on each of them.
+pub fn split_critical_edges(program: &mut Program) {
+    split_body(program);
+    for func in program.funcs.values_mut() {
+        split_body(&mut func.body);
+    }
+}
+
+fn split_body(program: &mut Program) {
+    let mut preds: Map<Id, Set<Id>> = Map::new();
+    for (lbl, block) in &program.block {
+        for succ in block.term.successors() {
+            preds.entry(succ).or_default().insert(*lbl);
+        }
+    }
+
+    let labels: Vec<Id> = program.block.keys().copied().collect();
+    for lbl in labels {
+        let succs: Set<Id> = program.block[&lbl].term.successors().into_iter().collect();
+        if succs.len() < 2 {
+            continue;
+        }
+        for succ in succs {
+            if preds[&succ].len() < 2 {
+                continue;
+            }
+
+            let split = program.fresh_label();
+            program.block.insert(
+                split,
+                Block {
+                    insn: vec![],
+                    term: Terminator::Jump(succ),
+                },
+            );
+            program
+                .block
+                .get_mut(&lbl)
+                .unwrap()
+                .term
+                .map_targets(|target| if target == succ { split } else { target });
+            // control now comes from the new block
+            for i in program
+                .block
+                .get_mut(&succ)
+                .into_iter()
+                .flat_map(|b| &mut b.insn)
+            {
+                if let Instruction::Phi { srcs, .. } = i {
+                    for (pred, _) in srcs {
+                        if *pred == lbl {
+                            *pred = split;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::test_util::{lower_src, run_program};
+
+    // SECTION: helpers
+
+    // The critical edges of the program
+    fn critical_edges(program: &Program) -> Vec<(Id, Id)> {
+        let mut preds: Map<Id, Set<Id>> = Map::new();
+        for (lbl, block) in &program.block {
+            for succ in block.term.successors() {
+                preds.entry(succ).or_default().insert(*lbl);
+            }
+        }
+        let mut edges = vec![];
+        for (lbl, block) in &program.block {
+            let succs: Set<Id> = block.term.successors().into_iter().collect();
+            if succs.len() > 1 {
+                edges.extend(
+                    succs
+                        .into_iter()
+                        .filter(|succ| preds[succ].len() > 1)
+                        .map(|succ| (*lbl, succ)),
+                );
+            }
+        }
+        edges
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn split() {
+        // the edge from `entry` to `join` is critical
+        let mut program = parse_tir(
+            "let c, x;
+             entry: $read c x = $const 1 $branch c a join
+             a: x = $const 2 $jump join
+             join: $print x $exit",
+        )
+        .unwrap();
+        assert_eq!(critical_edges(&program), vec![(id("entry"), id("join"))]);
+
+        split_critical_edges(&mut program);
+        assert_eq!(critical_edges(&program), vec![]);
+        let split = &program.block[&id("lbl1")];
+        assert!(split.insn.is_empty());
+        assert!(matches!(split.term, Terminator::Jump(target) if target == id("join")));
+        assert!(matches!(
+            program.block[&id("entry")].term,
+            Terminator::Branch { tt, ff, .. } if tt == id("a") && ff == id("lbl1")
+        ));
+        assert_eq!(run_program(&program, "1\n"), "2\n");
+        assert_eq!(run_program(&program, "0\n"), "1\n");
+    }
+
+    #[test]
+    fn split_phi_sources() {
+        let mut program = parse_tir(
+            "let c, x_1, x_2, x_3;
+             entry: $read c x_1 = $const 1 $branch c a join
+             a: x_2 = $const 2 $jump join
+             join: x_3 = $phi(a: x_2, entry: x_1) $print x_3 $exit",
+        )
+        .unwrap();
+        split_critical_edges(&mut program);
+        assert_eq!(verify(&program), Ok(()), "ill-formed program:\n{program}");
+        assert!(matches!(
+            &program.block[&id("join")].insn[0],
+            Instruction::Phi { srcs, .. } if *srcs == vec![(id("a"), id("x_2")), (id("lbl1"), id("x_1"))]
+        ));
+        assert_eq!(run_program(&program, "1\n"), "2\n");
+        assert_eq!(run_program(&program, "0\n"), "1\n");
+    }
+
+    #[test]
+    fn nothing_to_split() {
+        // lowering puts a block on each arm of an `$if`, and a `$while` loop's
+        // header is the only block that branches
+        let src = "$read n $if n {$print 1} $while n {:= n - n 1}";
+        let mut program = lower_src(src);
+        let before = program.to_string();
+        split_critical_edges(&mut program);
+        assert_eq!(program.to_string(), before);
+
+        // both arms of a branch going to the same block is a single edge
+        let mut program = parse_tir(
+            "let c;
+             entry: $read c $branch c a a
+             a: $exit",
+        )
+        .unwrap();
+        split_critical_edges(&mut program);
+        assert_eq!(program.block.len(), 2);
+    }
+}
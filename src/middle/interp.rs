@@ -0,0 +1,1340 @@
+//! An interpreter for the tiny IR.
+//!
+//! This lets us run programs without a back-end, which is handy for testing
+//! the front-end and the optimizations.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use derive_more::Display;
+
+use super::*;
+use crate::common::*;
+use crate::front::ast::{BOp, Number};
+
+/// Errors that stop the execution of a program.
+#[derive(Debug, Display)]
+pub enum RuntimeError {
+    /// The location of the division, for the error message.
+    #[display("Runtime error: division by zero at {_0}.")]
+    DivisionByZero(Location),
+    /// The location of the power, for the error message.
+    #[display("Runtime error: negative exponent at {_0}.")]
+    NegativeExponent(Location),
+    /// The location of the operation, for the error message.  Apart from
+    /// integer powers, this only happens with [run_checked].
+    #[display("Runtime error: integer overflow at {_0}.")]
+    Overflow(Location),
+    #[display("Runtime error: jump to the missing block `{_0}`.")]
+    MissingBlock(Id),
+    #[display("Runtime error: use of the undeclared variable `{_0}`.")]
+    UndeclaredVariable(Id),
+    #[display("Runtime error: call to the undefined function `{_0}`.")]
+    MissingFunction(Id),
+    #[display(
+        "Runtime error: the function `{func}` takes {expected} arguments, but it is called with {found}."
+    )]
+    WrongArgumentCount {
+        func: Id,
+        expected: usize,
+        found: usize,
+    },
+    #[display(
+        "Runtime error: a phi node in block `{_0}` has no value for the block control came from."
+    )]
+    MissingPhiSource(Id),
+    #[display("Runtime error: expected a number in the input, found `{_0}`.")]
+    InvalidInput(String),
+    #[display("Runtime error: reached the end of the input while reading a number.")]
+    EndOfInput,
+    #[display("Runtime error: I/O failed: {_0}")]
+    Io(std::io::Error),
+    /// The assertion, with where it is in the source code if that is known.
+    #[display("Runtime error: assertion failed at {_0}.")]
+    AssertionFailed(String),
+    /// The maximum number of steps, which the program tried to exceed.
+    #[display("Runtime error: the program ran for more than {_0} steps.")]
+    BudgetExceeded(u64),
+    /// A string literal, quoted, that is used as a number.  Only the AST
+    /// interpreter can run into this, on programs that do not pass
+    /// [crate::front::check_strings].
+    #[display("Runtime error: the string literal {_0} is used as a number.")]
+    StringOperand(String),
+    /// The function whose call would have nested deeper than
+    /// [MAX_CALL_DEPTH].
+    #[display(
+        "Runtime error: calling the function `{_0}` nests calls more than {MAX_CALL_DEPTH} deep."
+    )]
+    CallTooDeep(Id),
+}
+
+/// Where an arithmetic error happened.  Each interpreter runs a different form
+/// of the program, so each one has its own kind of location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// An expression of the source program, written as source code.
+    Expr(String),
+    /// An instruction of the tiny IR, in a block of the main program or of a
+    /// function.
+    Insn {
+        insn: String,
+        block: Id,
+        func: Option<Id>,
+    },
+    /// An operation of the bytecode, and its address.
+    Address { op: String, addr: usize },
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Expr(e) => write!(f, "`{e}`"),
+            Location::Insn { insn, block, func } => {
+                write!(f, "`{insn}` in the block `{block}`")?;
+                match func {
+                    Some(func) => write!(f, " of the function `{func}`"),
+                    None => Ok(()),
+                }
+            }
+            Location::Address { op, addr } => write!(f, "`{op}` at the address {addr}"),
+        }
+    }
+}
+
+/// How deeply function calls can nest before the interpreters stop the program
+/// with [RuntimeError::CallTooDeep].  The interpreters recurse on each call,
+/// but they move to a fresh stack when theirs runs low, so this only bounds how
+/// much memory a runaway recursion takes.
+pub const MAX_CALL_DEPTH: usize = 10_000;
+
+/// Run `f`, which makes a nested call in an interpreter, on a new stack
+/// segment if the current one is running low.
+pub(crate) fn grow_stack<T>(f: impl FnOnce() -> T) -> T {
+    // the red zone must hold all the frames between two nested calls, which
+    // are large in debug builds
+    stacker::maybe_grow(256 << 10, 8 << 20, f)
+}
+
+impl RuntimeError {
+    /// The error for an operation at given location whose result is undefined
+    /// (see [BOp::eval]), given its right operand.
+    pub fn undefined(op: BOp, rhs: Number, location: Location) -> Self {
+        match op {
+            BOp::Pow if matches!(rhs, Number::Int(n) if n < 0) => {
+                RuntimeError::NegativeExponent(location)
+            }
+            BOp::Pow => RuntimeError::Overflow(location),
+            _ => RuntimeError::DivisionByZero(location),
+        }
+    }
+
+    /// Where the error happened, for the arithmetic errors.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            RuntimeError::DivisionByZero(at)
+            | RuntimeError::NegativeExponent(at)
+            | RuntimeError::Overflow(at) => Some(at),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RuntimeError {
+    fn from(e: std::io::Error) -> Self {
+        RuntimeError::Io(e)
+    }
+}
+
+/// The result of running a program, or a part of it, in an interpreter.
+pub type RunResult<T> = Result<T, RuntimeError>;
+
+/// Run the program starting from the `entry` block, reading numbers from
+/// `input` (one per line) and printing numbers to `output` (one per line).
+pub fn run(program: &Program, input: impl Read, output: impl Write) -> RunResult<()> {
+    run_io(program, Streams::new(input, output))
+}
+
+/// Run the program like [run], but do its I/O through `io`.
+pub fn run_io(program: &Program, io: impl Io) -> RunResult<()> {
+    Machine::new(program, io, NoTrace).run()
+}
+
+/// Run the program like [run], but stop it with
+/// [RuntimeError::BudgetExceeded] if it tries to take more than `max_steps`
+/// steps.  Each instruction and each terminator that is executed is a step,
+/// including the ones in called functions.
+pub fn run_with_budget(
+    program: &Program,
+    input: impl Read,
+    output: impl Write,
+    max_steps: u64,
+) -> RunResult<()> {
+    let mut machine = Machine::new(program, Streams::new(input, output), NoTrace);
+    machine.max_steps = Some(max_steps);
+    machine.run()
+}
+
+/// Run the program like [run], but stop it with [RuntimeError::Overflow] if
+/// an integer addition, subtraction, multiplication, division, remainder,
+/// left shift, or negation overflows, instead of letting the result wrap
+/// around.
+pub fn run_checked(program: &Program, input: impl Read, output: impl Write) -> RunResult<()> {
+    let mut machine = Machine::new(program, Streams::new(input, output), NoTrace);
+    machine.checked = true;
+    machine.run()
+}
+
+/// Run the program like [run], and also write a trace of the execution to
+/// `trace`: each block that is entered, each instruction that is executed with
+/// the value it writes, and the block that each terminator goes to.  The
+/// trace is in the textual format of tiny IR, with the values in comments.
+pub fn run_traced(
+    program: &Program,
+    input: impl Read,
+    output: impl Write,
+    trace: impl Write,
+) -> RunResult<()> {
+    Machine::new(program, Streams::new(input, output), Tracer(trace)).run()
+}
+
+/// Where `$read` reads numbers from, and where `$print` and `$printc` write
+/// to.
+pub trait Io {
+    /// Read the next number.
+    fn read_num(&mut self) -> RunResult<Number>;
+    /// Write a number, without a newline.
+    fn write_num(&mut self, v: Number) -> RunResult<()>;
+    /// Write a string, without a newline.
+    fn write_str(&mut self, s: &str) -> RunResult<()>;
+    /// End the current line.
+    fn write_line(&mut self) -> RunResult<()>;
+    /// Make sure everything written so far is out, at the end of the program.
+    fn flush(&mut self) -> RunResult<()> {
+        Ok(())
+    }
+}
+
+// so that the caller can look at the I/O after running a program with it
+impl<I: Io + ?Sized> Io for &mut I {
+    fn read_num(&mut self) -> RunResult<Number> {
+        (**self).read_num()
+    }
+
+    fn write_num(&mut self, v: Number) -> RunResult<()> {
+        (**self).write_num(v)
+    }
+
+    fn write_str(&mut self, s: &str) -> RunResult<()> {
+        (**self).write_str(s)
+    }
+
+    fn write_line(&mut self) -> RunResult<()> {
+        (**self).write_line()
+    }
+
+    fn flush(&mut self) -> RunResult<()> {
+        (**self).flush()
+    }
+}
+
+/// I/O on byte streams, such as the standard input and output.  The numbers in
+/// the input are separated by whitespace.
+pub struct Streams<R, W> {
+    input: BufReader<R>,
+    output: W,
+}
+
+impl<R: Read, W: Write> Streams<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Streams {
+            input: BufReader::new(input),
+            output,
+        }
+    }
+}
+
+impl<R: Read, W: Write> Io for Streams<R, W> {
+    fn read_num(&mut self) -> RunResult<Number> {
+        read_num(&mut self.input)
+    }
+
+    fn write_num(&mut self, v: Number) -> RunResult<()> {
+        Ok(write!(self.output, "{v}")?)
+    }
+
+    fn write_str(&mut self, s: &str) -> RunResult<()> {
+        Ok(write!(self.output, "{s}")?)
+    }
+
+    fn write_line(&mut self) -> RunResult<()> {
+        Ok(writeln!(self.output)?)
+    }
+
+    fn flush(&mut self) -> RunResult<()> {
+        Ok(self.output.flush()?)
+    }
+}
+
+/// I/O in memory: `$read` takes the numbers from `input` in order, and the
+/// output is collected in `output`.
+#[derive(Debug, Default)]
+pub struct MemoryIo {
+    pub input: VecDeque<Number>,
+    pub output: String,
+}
+
+impl MemoryIo {
+    pub fn new(input: impl IntoIterator<Item = Number>) -> Self {
+        MemoryIo {
+            input: input.into_iter().collect(),
+            output: String::new(),
+        }
+    }
+}
+
+impl Io for MemoryIo {
+    fn read_num(&mut self) -> RunResult<Number> {
+        self.input.pop_front().ok_or(RuntimeError::EndOfInput)
+    }
+
+    fn write_num(&mut self, v: Number) -> RunResult<()> {
+        self.output.push_str(&v.to_string());
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &str) -> RunResult<()> {
+        self.output.push_str(s);
+        Ok(())
+    }
+
+    fn write_line(&mut self) -> RunResult<()> {
+        self.output.push('\n');
+        Ok(())
+    }
+}
+
+// Where the trace goes.  This is a type parameter of the interpreter rather
+// than a run-time flag, so the tracing code is compiled away when it is off.
+trait Trace {
+    const ON: bool;
+    fn line(&mut self, line: std::fmt::Arguments) -> std::io::Result<()>;
+}
+
+struct NoTrace;
+
+impl Trace for NoTrace {
+    const ON: bool = false;
+    fn line(&mut self, _: std::fmt::Arguments) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Tracer<W>(W);
+
+impl<W: Write> Trace for Tracer<W> {
+    const ON: bool = true;
+    fn line(&mut self, line: std::fmt::Arguments) -> std::io::Result<()> {
+        writeln!(self.0, "{line}")
+    }
+}
+
+// The state of the interpreter
+struct Machine<'a, I, T> {
+    // the main program, which holds the functions
+    program: &'a Program,
+    io: I,
+    trace: T,
+    // the number of steps taken so far, and how many may be taken at most
+    steps: u64,
+    max_steps: Option<u64>,
+    // whether integer overflow is an error rather than wrapping around
+    checked: bool,
+    // the number of calls being run
+    depth: usize,
+}
+
+// The values of the variables of the main program or of a function call
+type Env = Map<Id, Number>;
+
+// How running a CFG ended
+enum Outcome {
+    Exit,
+    Return(Number),
+}
+
+impl<'a, I: Io, T: Trace> Machine<'a, I, T> {
+    fn new(program: &'a Program, io: I, trace: T) -> Self {
+        Machine {
+            program,
+            io,
+            trace,
+            steps: 0,
+            max_steps: None,
+            checked: false,
+            depth: 0,
+        }
+    }
+
+    fn run(mut self) -> RunResult<()> {
+        let program = self.program;
+        self.run_body(program, None, new_env(program))?;
+        self.io.flush()?;
+        Ok(())
+    }
+
+    // Run the CFG of the main program or a function starting from its `entry`
+    // block until it exits or returns.  In the trace, the labels of a function
+    // are prefixed with its name.
+    fn run_body(
+        &mut self,
+        body: &'a Program,
+        func: Option<Id>,
+        mut env: Env,
+    ) -> RunResult<Outcome> {
+        let mut lbl = id(ENTRY_LABEL);
+        // the block control came from
+        let mut prev = None;
+        loop {
+            let block = body
+                .block
+                .get(&lbl)
+                .ok_or(RuntimeError::MissingBlock(lbl))?;
+            match func {
+                Some(f) => self.trace(format_args!("{f}.{lbl}:"))?,
+                None => self.trace(format_args!("{lbl}:"))?,
+            }
+
+            // the phi nodes at the start of the block read their sources
+            // before any of them is written to
+            let phis = block
+                .insn
+                .iter()
+                .take_while(|i| matches!(i, Instruction::Phi { .. }))
+                .count();
+            let values = block.insn[..phis]
+                .iter()
+                .map(|i| phi(&env, lbl, prev, i))
+                .collect::<RunResult<Vec<_>>>()?;
+            for (insn, v) in block.insn[..phis].iter().zip(values) {
+                self.step()?;
+                set(&mut env, insn.def().unwrap(), v)?;
+                self.trace_insn(&env, insn)?;
+            }
+
+            for insn in &block.insn[phis..] {
+                self.step()?;
+                match insn {
+                    Instruction::Call { dst, func, args } => {
+                        // exiting from a function exits the whole program
+                        match self.call(&env, *func, args)? {
+                            Outcome::Return(v) => set(&mut env, *dst, v)?,
+                            Outcome::Exit => return Ok(Outcome::Exit),
+                        }
+                    }
+                    Instruction::Phi { dst, .. } => {
+                        let v = phi(&env, lbl, prev, insn)?;
+                        set(&mut env, *dst, v)?;
+                    }
+                    _ => self.exec(&mut env, func, lbl, insn)?,
+                }
+                self.trace_insn(&env, insn)?;
+            }
+
+            prev = Some(lbl);
+
+            self.step()?;
+            lbl = match block.term {
+                Terminator::Exit => {
+                    self.trace(format_args!("    {}", block.term))?;
+                    return Ok(Outcome::Exit);
+                }
+                Terminator::Return(x) => {
+                    let v = get(&env, x)?;
+                    self.trace(format_args!("    {}  // returns {v}", block.term))?;
+                    return Ok(Outcome::Return(v));
+                }
+                Terminator::Jump(target) => {
+                    self.trace(format_args!("    {}", block.term))?;
+                    target
+                }
+                Terminator::Branch { guard: x, .. } | Terminator::Switch { scrutinee: x, .. } => {
+                    let target = block.term.target(get(&env, x)?).unwrap();
+                    self.trace(format_args!("    {}  // to {target}", block.term))?;
+                    target
+                }
+            };
+        }
+    }
+
+    // Count a step, and fail if there are too many
+    fn step(&mut self) -> RunResult<()> {
+        self.steps += 1;
+        match self.max_steps {
+            Some(max) if self.steps > max => Err(RuntimeError::BudgetExceeded(max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn trace(&mut self, line: std::fmt::Arguments) -> RunResult<()> {
+        if T::ON {
+            self.trace.line(line)?;
+        }
+        Ok(())
+    }
+
+    // Trace an instruction after it is executed, with the value it wrote
+    fn trace_insn(&mut self, env: &Env, insn: &Instruction) -> RunResult<()> {
+        if T::ON {
+            match insn.def() {
+                Some(x) => self.trace(format_args!("    {insn}  // {x} = {}", get(env, x)?))?,
+                None => self.trace(format_args!("    {insn}"))?,
+            }
+        }
+        Ok(())
+    }
+
+    // Call the function with the values of given arguments in `env`
+    fn call(&mut self, env: &Env, name: Id, args: &[Id]) -> RunResult<Outcome> {
+        let program = self.program;
+        let func = program
+            .funcs
+            .get(&name)
+            .ok_or(RuntimeError::MissingFunction(name))?;
+        if func.params.len() != args.len() {
+            return Err(RuntimeError::WrongArgumentCount {
+                func: name,
+                expected: func.params.len(),
+                found: args.len(),
+            });
+        }
+
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(RuntimeError::CallTooDeep(name));
+        }
+
+        let mut callee_env = new_env(&func.body);
+        for (&param, &arg) in func.params.iter().zip(args) {
+            set(&mut callee_env, param, get(env, arg)?)?;
+        }
+        self.depth += 1;
+        let result = grow_stack(|| self.run_body(&func.body, Some(name), callee_env));
+        self.depth -= 1;
+        result
+    }
+
+    // Execute a single instruction other than a call, which is in the block
+    // `lbl` of the main program or a function
+    fn exec(
+        &mut self,
+        env: &mut Env,
+        func: Option<Id>,
+        lbl: Id,
+        insn: &Instruction,
+    ) -> RunResult<()> {
+        use Instruction::*;
+        match *insn {
+            Copy { dst, src } => {
+                let v = get(env, src)?;
+                set(env, dst, v)
+            }
+            Const { dst, src } => set(env, dst, src),
+            Arith { op, dst, lhs, rhs } => {
+                let (lhs, rhs) = (get(env, lhs)?, get(env, rhs)?);
+                if self.checked && op.overflows(lhs, rhs) {
+                    return Err(RuntimeError::Overflow(location(func, lbl, insn)));
+                }
+                let v = op
+                    .eval(lhs, rhs)
+                    .ok_or_else(|| RuntimeError::undefined(op, rhs, location(func, lbl, insn)))?;
+                set(env, dst, v)
+            }
+            Neg { dst, src } => {
+                let v = get(env, src)?;
+                if self.checked && v.checked_neg().is_none() {
+                    return Err(RuntimeError::Overflow(location(func, lbl, insn)));
+                }
+                set(env, dst, -v)
+            }
+            Read(dst) => {
+                let v = self.io.read_num()?;
+                set(env, dst, v)
+            }
+            Print(src) => {
+                let v = get(env, src)?;
+                self.io.write_num(v)?;
+                self.io.write_line()
+            }
+            PrintStr(ref s) => {
+                self.io.write_str(s)?;
+                self.io.write_line()
+            }
+            PrintNoNl(src) => {
+                let v = get(env, src)?;
+                self.io.write_num(v)
+            }
+            PrintStrNoNl(ref s) => self.io.write_str(s),
+            Assert(src, ref assertion) => {
+                if !get(env, src)?.is_true() {
+                    return Err(RuntimeError::AssertionFailed(assertion.clone()));
+                }
+                Ok(())
+            }
+            Nop => Ok(()),
+            Call { .. } | Phi { .. } => unreachable!("`run_body` handles calls and phi nodes"),
+        }
+    }
+}
+
+// Where an instruction is, for error messages
+fn location(func: Option<Id>, lbl: Id, insn: &Instruction) -> Location {
+    Location::Insn {
+        insn: insn.to_string(),
+        block: lbl,
+        func,
+    }
+}
+
+// The variables of the program or function body, all set to 0
+fn new_env(body: &Program) -> Env {
+    body.decl.iter().map(|x| (*x, Number::Int(0))).collect()
+}
+
+// The value of the source of the phi node in block `lbl` that goes with the
+// previous block
+fn phi(env: &Env, lbl: Id, prev: Option<Id>, insn: &Instruction) -> RunResult<Number> {
+    let Instruction::Phi { srcs, .. } = insn else {
+        unreachable!("{insn} is not a phi node");
+    };
+    let (_, x) = srcs
+        .iter()
+        .find(|(pred, _)| Some(*pred) == prev)
+        .ok_or(RuntimeError::MissingPhiSource(lbl))?;
+    get(env, *x)
+}
+
+fn get(env: &Env, x: Id) -> RunResult<Number> {
+    env.get(&x)
+        .copied()
+        .ok_or(RuntimeError::UndeclaredVariable(x))
+}
+
+fn set(env: &mut Env, x: Id, v: Number) -> RunResult<()> {
+    let slot = env.get_mut(&x).ok_or(RuntimeError::UndeclaredVariable(x))?;
+    *slot = v;
+    Ok(())
+}
+
+/// Read the next whitespace-separated word from the input, and parse it as an
+/// integer or a float.
+pub(crate) fn read_num(input: &mut impl BufRead) -> RunResult<Number> {
+    let mut token = vec![];
+    loop {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        // skip the whitespace before the number, and stop at the whitespace
+        // after it
+        let skip = if token.is_empty() {
+            buf.iter().take_while(|b| b.is_ascii_whitespace()).count()
+        } else {
+            0
+        };
+        let len = buf[skip..]
+            .iter()
+            .take_while(|b| !b.is_ascii_whitespace())
+            .count();
+        token.extend_from_slice(&buf[skip..skip + len]);
+        let done = skip + len < buf.len();
+        input.consume(skip + len);
+        if done {
+            break;
+        }
+    }
+    if token.is_empty() {
+        return Err(RuntimeError::EndOfInput);
+    }
+    let token = String::from_utf8_lossy(&token);
+    token
+        .parse()
+        .map_err(|_| RuntimeError::InvalidInput(token.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{lower, parse};
+
+    // SECTION: helpers
+
+    // Compile and run the given program with the given input, and return the
+    // output.
+    fn run_src(src: &str, input: &str) -> RunResult<String> {
+        let program = lower(parse(src).unwrap());
+        let mut output = vec![];
+        run(&program, input.as_bytes(), &mut output)?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn memory_io() {
+        let program = lower(
+            parse("$read n := s 0 $while n {$read x := s + s x := n - n 1} $print \"sum\" s")
+                .unwrap(),
+        );
+        let input = [3, 10, -4, 7].map(Number::Int);
+        let mut io = MemoryIo::new(input);
+        run_io(&program, &mut io).unwrap();
+        assert_eq!(io.output, "sum 13\n");
+        assert!(io.input.is_empty());
+
+        // the same as with streams
+        let mut output = vec![];
+        run(&program, "3 10 -4 7".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), io.output);
+
+        let mut io = MemoryIo::new([Number::Int(2), Number::Float(0.5)]);
+        assert!(matches!(
+            run_io(&program, &mut io),
+            Err(RuntimeError::EndOfInput)
+        ));
+        assert_eq!(io.output, "");
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(run_src("", "").unwrap(), "");
+        // the input is not read
+        assert_eq!(run_src("// nothing", "1 2 3").unwrap(), "");
+
+        let program = lower(parse("").unwrap());
+        let mut output = vec![];
+        run_checked(&program, "".as_bytes(), &mut output).unwrap();
+        assert!(output.is_empty());
+        let mut io = MemoryIo::new([]);
+        run_io(&crate::middle::optimize(program), &mut io).unwrap();
+        assert_eq!(io.output, "");
+    }
+
+    #[test]
+    fn print() {
+        assert_eq!(run_src("$print 42", "").unwrap(), "42\n");
+        assert_eq!(run_src("$print x", "").unwrap(), "0\n");
+        assert_eq!(run_src("$print ~ 7 $print 8", "").unwrap(), "-7\n8\n");
+    }
+
+    #[test]
+    fn case() {
+        let src = "$read x $case x {1 {$print 10} -2 {$print 20 $return} $default {$print 0}} \
+                   $print x";
+        assert_eq!(run_src(src, "1\n").unwrap(), "10\n1\n");
+        assert_eq!(run_src(src, "-2\n").unwrap(), "20\n");
+        assert_eq!(run_src(src, "3\n").unwrap(), "0\n3\n");
+        assert_eq!(
+            run_src("$read x $case x {1 {$print 1}} $print x", "2\n").unwrap(),
+            "2\n"
+        );
+    }
+
+    #[test]
+    fn read() {
+        assert_eq!(run_src("$read x $print x", "12\n").unwrap(), "12\n");
+        assert_eq!(
+            run_src("$read x $read y $print - x y", "  5 \n-3").unwrap(),
+            "8\n"
+        );
+        // the numbers can be on the same line or on separate lines
+        let src = "$read x y z $print - x * y z";
+        assert_eq!(run_src(src, "10 2 3\n").unwrap(), "4\n");
+        assert_eq!(run_src(src, "10\n2\t3").unwrap(), "4\n");
+        assert!(matches!(
+            run_src(src, "10 2\n"),
+            Err(RuntimeError::EndOfInput)
+        ));
+        // floats can be read back the way they are printed, and with exponents
+        let src = "$read a b c d $print a b c d";
+        assert_eq!(
+            run_src(src, "inf -inf NaN 1e3").unwrap(),
+            "inf -inf NaN 1000.0\n"
+        );
+        assert_eq!(
+            run_src(src, "Infinity nan -INF 2.5E-1").unwrap(),
+            "inf NaN -inf 0.25\n"
+        );
+        assert!(matches!(
+            run_src("$read x", "infinite"),
+            Err(RuntimeError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            run_src(r#"$print "hello" $print 1 $print """#, "").unwrap(),
+            "hello\n1\n\n"
+        );
+        assert_eq!(
+            run_src(r#"$print "a\nb \"c\" \\""#, "").unwrap(),
+            "a\nb \"c\" \\\n"
+        );
+    }
+
+    #[test]
+    fn print_without_newline() {
+        let src = r#"$printc "x = " $printc 1 $printc " " $print 2 $print 3"#;
+        assert_eq!(run_src(src, "").unwrap(), "x = 1 2\n3\n");
+        assert_eq!(run_src("$printc 1 $printc 2", "").unwrap(), "12");
+        let program = parse_tir(r#"let x; entry: $printc "a" $printc x $print x $exit"#).unwrap();
+        let mut output = vec![];
+        run(&program, "".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "a00\n");
+    }
+
+    #[test]
+    fn nop() {
+        let program =
+            parse_tir("let x; entry: $nop x = $const 4 $nop $print x $nop $exit").unwrap();
+        let mut output = vec![];
+        run(&program, "".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "4\n");
+    }
+
+    #[test]
+    fn switch() {
+        let src = "
+            let x;
+            entry: $read x $switch x (1: one, -2: two, 2: two) other
+            one: $print \"one\" $exit
+            two: $print \"two\" $exit
+            other: $print \"other\" $exit
+        ";
+        let program = parse_tir(src).unwrap();
+        for (input, expected) in [
+            ("1", "one\n"),
+            ("-2", "two\n"),
+            ("2", "two\n"),
+            ("3", "other\n"),
+            ("1.0", "one\n"),
+        ] {
+            let mut output = vec![];
+            run(&program, input.as_bytes(), &mut output).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                expected,
+                "input {input}"
+            );
+        }
+    }
+
+    #[test]
+    fn print_many() {
+        let src = r#"$read x $print "x =" x + x 1 $print x"#;
+        assert_eq!(run_src(src, "4\n").unwrap(), "x = 4 5\n4\n");
+    }
+
+    #[test]
+    fn arith() {
+        let src = ":= x 7 := y 2 $print + x y $print - x y $print * x y $print / x y \
+                   $print % x y $print < x y $print > x y $print = x y $print ~ x";
+        assert_eq!(run_src(src, "").unwrap(), "9\n5\n14\n3\n1\n0\n1\n0\n-7\n");
+    }
+
+    #[test]
+    fn conditionals() {
+        let src = "$read a $read b $if < a b {$print a} {$print b}";
+        assert_eq!(run_src(src, "3\n5\n").unwrap(), "3\n");
+        assert_eq!(run_src(src, "5\n3\n").unwrap(), "3\n");
+        let src = "$read x $if x {$if - x 1 {$print 2} {$print 1}} {$print 0}";
+        assert_eq!(run_src(src, "0").unwrap(), "0\n");
+        assert_eq!(run_src(src, "1").unwrap(), "1\n");
+        assert_eq!(run_src(src, "9").unwrap(), "2\n");
+    }
+
+    #[test]
+    fn loops() {
+        let src = "$read n := i 0 := sum 0 $while < i n {:= i + i 1 := sum + sum i} $print sum";
+        assert_eq!(run_src(src, "10").unwrap(), "55\n");
+        assert_eq!(run_src(src, "0").unwrap(), "0\n");
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert!(matches!(
+            run_src("$read x $print / 1 x", "0"),
+            Err(RuntimeError::DivisionByZero(Location::Insn { insn, block, func: None }))
+                if insn == "_t_2 = $arith div _const_1 x" && block == id("entry")
+        ));
+        assert!(matches!(
+            run_src("$fn f(x) {$if x {:= f / 1 - x 1}} $read x $print f(x)", "1"),
+            Err(RuntimeError::DivisionByZero(Location::Insn { insn, block, func: Some(f) }))
+                if insn == "_t_3 = $arith div _const_1 _t_2" && block == id("lbl1") && f == id("f")
+        ));
+        assert!(matches!(
+            run_src("$print % 1 x", ""),
+            Err(RuntimeError::DivisionByZero(_))
+        ));
+    }
+
+    #[test]
+    fn select() {
+        let src = "$read x $print ? < x 0 0 x";
+        assert_eq!(run_src(src, "-5\n").unwrap(), "0\n");
+        assert_eq!(run_src(src, "0\n").unwrap(), "0\n");
+        assert_eq!(run_src(src, "7\n").unwrap(), "7\n");
+
+        // only the chosen operand is evaluated
+        let src = "$read x $print ? x / 10 x 42";
+        assert_eq!(run_src(src, "0\n").unwrap(), "42\n");
+        assert_eq!(run_src(src, "5\n").unwrap(), "2\n");
+    }
+
+    #[test]
+    fn pow() {
+        assert_eq!(run_src("$print ^ 2 10", "").unwrap(), "1024\n");
+        let src = "$read x $read y $print ^ x y";
+        assert_eq!(run_src(src, "3\n0\n").unwrap(), "1\n");
+        assert_eq!(run_src(src, "-2\n3\n").unwrap(), "-8\n");
+        assert_eq!(run_src(src, "0\n0\n").unwrap(), "1\n");
+        // integer powers do not wrap around, even in unchecked mode
+        assert_eq!(run_src(src, "2\n62\n").unwrap(), "4611686018427387904\n");
+        assert_eq!(run_src(src, "-2\n63\n").unwrap(), "-9223372036854775808\n");
+        assert_eq!(run_src(src, "-1\n9223372036854775807\n").unwrap(), "-1\n");
+        for input in ["2\n63\n", "2\n64\n", "3\n41\n", "-3\n9223372036854775807\n"] {
+            assert!(
+                matches!(
+                    run_src(src, input),
+                    Err(RuntimeError::Overflow(at))
+                        if at.to_string() == "`_t_1 = $arith pow x y` in the block `entry`"
+                ),
+                "{input:?}"
+            );
+        }
+        // floats can have negative and fractional exponents
+        assert_eq!(run_src(src, "2.0\n-1\n").unwrap(), "0.5\n");
+        assert_eq!(run_src(src, "9\n0.5\n").unwrap(), "3.0\n");
+
+        assert!(matches!(
+            run_src(src, "2\n-1\n"),
+            Err(RuntimeError::NegativeExponent(at))
+                if at.to_string() == "`_t_1 = $arith pow x y` in the block `entry`"
+        ));
+        assert_eq!(
+            run_src("$print ^ 2 ~ 1", "").unwrap_err().to_string(),
+            "Runtime error: negative exponent at `_t_3 = $arith pow _const_1 _const_2` in the block `entry`."
+        );
+    }
+
+    #[test]
+    fn bad_input() {
+        assert!(matches!(
+            run_src("$read x", ""),
+            Err(RuntimeError::EndOfInput)
+        ));
+        assert!(matches!(
+            run_src("$read x", "abc\n"),
+            Err(RuntimeError::InvalidInput(s)) if s == "abc"
+        ));
+    }
+
+    #[test]
+    fn functions() {
+        let src = "$fn square(x) {:= square * x x} $read n $print square(+ n 1)";
+        assert_eq!(run_src(src, "4\n").unwrap(), "25\n");
+
+        let src = "$fn sum(n) {$if n {:= sum + n sum(- n 1)}} $print sum(100)";
+        assert_eq!(run_src(src, "").unwrap(), "5050\n");
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let src = "$read n $fn f(n) {$if n {:= f + 1 f(- n 1)} {:= f 0}} $print f(n)";
+        let max = MAX_CALL_DEPTH - 1;
+        assert_eq!(run_src(src, &max.to_string()).unwrap(), format!("{max}\n"));
+        let err = run_src(src, &MAX_CALL_DEPTH.to_string()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: calling the function `f` nests calls more than 10000 deep."
+        );
+    }
+
+    #[test]
+    fn missing_function() {
+        let mut program = lower(parse("$fn f(x) {} $print f(1)").unwrap());
+        program.funcs.get_mut(&id("f")).unwrap().params.clear();
+        assert!(matches!(
+            run(&program, &b""[..], vec![]),
+            Err(RuntimeError::WrongArgumentCount {
+                expected: 0,
+                found: 1,
+                ..
+            })
+        ));
+
+        program.funcs.clear();
+        assert!(matches!(
+            run(&program, &b""[..], vec![]),
+            Err(RuntimeError::MissingFunction(f)) if f == id("f")
+        ));
+    }
+
+    #[test]
+    fn phi_nodes() {
+        // the phi nodes swap `a` and `b` in each iteration
+        let src = "
+            let a, b, a1, b1, n, one;
+            entry: a = $const 1 b = $const 2 n = $const 3 one = $const 1 $jump loop
+            loop:
+                a1 = $phi(entry: a, loop: b1)
+                b1 = $phi(entry: b, loop: a1)
+                n = $arith sub n one
+                $branch n loop end
+            end: $print a1 $print b1 $exit
+        ";
+        let program = crate::middle::parse_tir(src).unwrap();
+        let mut output = vec![];
+        run(&program, &b""[..], &mut output).unwrap();
+        assert_eq!(output, b"1\n2\n");
+
+        let program = crate::middle::parse_tir("let x; entry: x = $phi(a: x) $exit").unwrap();
+        assert!(matches!(
+            run(&program, &b""[..], vec![]),
+            Err(RuntimeError::MissingPhiSource(l)) if l == id("entry")
+        ));
+    }
+
+    #[test]
+    fn trace() {
+        let src = "$fn f(x) {:= f + x 1} $read c $if < c 2 {$print f(c)} {$print \"big\"}";
+        let program = lower(parse(src).unwrap());
+        let (mut output, mut trace) = (vec![], vec![]);
+        run_traced(&program, &b"1\n"[..], &mut output, &mut trace).unwrap();
+        assert_eq!(output, b"2\n");
+        let trace = String::from_utf8(trace).unwrap();
+        let lines: Vec<_> = trace.lines().collect();
+        for line in [
+            "entry:",
+            "    $read c  // c = 1",
+            "    $branch _t_2 lbl1 lbl2  // to lbl1",
+            "lbl1:",
+            "f.entry:",
+            "    $return f  // returns 2",
+            "    _t_3 = $call f(c)  // _t_3 = 2",
+            "lbl3:",
+            "    $exit",
+        ] {
+            assert!(lines.contains(&line), "{line:?} is missing in\n{trace}");
+        }
+        assert!(
+            !trace.contains("lbl2:"),
+            "the untaken branch is traced:\n{trace}"
+        );
+
+        // the trace does not change what the program does
+        let mut untraced = vec![];
+        run(&program, &b"1\n"[..], &mut untraced).unwrap();
+        assert_eq!(untraced, output);
+    }
+
+    #[test]
+    fn missing_block() {
+        let mut program = lower(parse("$if x {} {}").unwrap());
+        program.block.remove(&id("lbl2"));
+        assert!(matches!(
+            run(&program, &b""[..], vec![]),
+            Err(RuntimeError::MissingBlock(l)) if l == id("lbl2")
+        ));
+
+        program.block.clear();
+        assert!(matches!(
+            run(&program, &b""[..], vec![]),
+            Err(RuntimeError::MissingBlock(l)) if l == id("entry")
+        ));
+    }
+
+    #[test]
+    fn temporaries_do_not_clash() {
+        let src = ":= _const_1 7 $print + _const_1 1 := _t_2 3 $print * _t_2 2";
+        assert_eq!(run_src(src, "").unwrap(), "8\n6\n");
+    }
+
+    #[test]
+    fn budget() {
+        let run_budget = |src: &str, max_steps| {
+            let program = lower(parse(src).unwrap());
+            let mut output = vec![];
+            run_with_budget(&program, "".as_bytes(), &mut output, max_steps)
+                .map(|()| String::from_utf8(output).unwrap())
+        };
+        assert!(matches!(
+            run_budget("$while 1 {}", 1000),
+            Err(RuntimeError::BudgetExceeded(1000))
+        ));
+        assert!(matches!(
+            run_budget(":= i 0 $while 1 {:= i + i 1}", 50),
+            Err(RuntimeError::BudgetExceeded(50))
+        ));
+        // steps in functions count too
+        assert!(matches!(
+            run_budget("$fn f() {$while 1 {}} $print f()", 100),
+            Err(RuntimeError::BudgetExceeded(100))
+        ));
+        // `$const`, `$print`, and `$exit`
+        assert_eq!(run_budget("$print 1", 3).unwrap(), "1\n");
+        assert!(run_budget("$print 1", 2).is_err());
+        let err = run_budget("$while 1 {}", 10).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: the program ran for more than 10 steps."
+        );
+    }
+
+    #[test]
+    fn checked() {
+        let run_checked_src = |src: &str, input: &str| {
+            let program = lower(parse(src).unwrap());
+            let mut output = vec![];
+            run_checked(&program, input.as_bytes(), &mut output)
+                .map(|()| String::from_utf8(output).unwrap())
+        };
+        let max = i64::MAX.to_string();
+        let min = i64::MIN.to_string();
+        let cases = [
+            ("$read x $print + x 1", max.as_str(), min.as_str()),
+            ("$read x $print - x 1", &min, &max),
+            ("$read x $print * x 2", &max, "-2"),
+            ("$read x $print ~ x", &min, &min),
+            ("$read x $print / x ~ 1", &min, &min),
+            ("$read x $print % x ~ 1", &min, "0"),
+        ];
+        for (src, input, wrapped) in cases {
+            assert_eq!(
+                run_src(src, input).unwrap(),
+                format!("{wrapped}\n"),
+                "{src}"
+            );
+            assert!(
+                matches!(run_checked_src(src, input), Err(RuntimeError::Overflow(_))),
+                "{src}"
+            );
+        }
+
+        // so do operations on constants, which are not folded away, even if
+        // their results are not used
+        let srcs = [
+            "$print + 9223372036854775807 1",
+            "$print - -9223372036854775808 1",
+            "$print * 4294967296 4294967296",
+            "$print ~ -9223372036854775808",
+            ":= x + 9223372036854775807 1 $print 0",
+            "$while 0 {:= x 1} := y << 1 63 $print 1",
+        ];
+        for src in srcs {
+            let program = lower(parse(src).unwrap());
+            for program in [program.clone(), crate::middle::optimize(program)] {
+                let mut output = vec![];
+                assert!(
+                    matches!(
+                        run_checked(&program, "".as_bytes(), &mut output),
+                        Err(RuntimeError::Overflow(_))
+                    ),
+                    "{src}\n{program}"
+                );
+            }
+        }
+        assert_eq!(
+            run_src("$print + 9223372036854775807 1", "").unwrap(),
+            format!("{min}\n")
+        );
+
+        // powers overflow in either mode
+        assert!(matches!(
+            run_checked_src("$read x $print ^ x 2", "4294967296"),
+            Err(RuntimeError::Overflow(_))
+        ));
+        // dividing by zero is not an overflow
+        assert!(matches!(
+            run_checked_src("$read x $print % x 0", &min),
+            Err(RuntimeError::DivisionByZero(_))
+        ));
+
+        // the results that fit are the same either way
+        let src = "$read x $print + x 0 $print - x 1 $print * x 1 $print / x -1 $print + x 0.5";
+        assert_eq!(
+            run_checked_src(src, &max).unwrap(),
+            run_src(src, &max).unwrap()
+        );
+        let err = run_checked_src("$fn f(x) {:= f * x x} $print f(4294967296)", "").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: integer overflow at `_t_1 = $arith mul x x` in the block `entry` of the function `f`."
+        );
+    }
+
+    #[test]
+    fn min_max() {
+        let src = "$read x y $print $max x y $print $min x y";
+        assert_eq!(run_src(src, "3 -5").unwrap(), "3\n-5\n");
+        assert_eq!(run_src(src, "-5 3").unwrap(), "3\n-5\n");
+        assert_eq!(run_src(src, "2 2.5").unwrap(), "2.5\n2.0\n");
+        let program = parse_tir(
+            "let x, y; entry: x = $const 4 y = $const 7 x = $arith max x y $print x $exit",
+        )
+        .unwrap();
+        let mut output = vec![];
+        run(&program, "".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "7\n");
+    }
+
+    #[test]
+    fn negate_min() {
+        // both the `$neg` instruction and subtracting from zero overflow
+        let srcs = [
+            "let x, y; entry: $read x y = $neg x $print y $exit",
+            "let x, y, z; entry: $read x z = $const 0 y = $arith sub z x $print y $exit",
+        ];
+        let min = i64::MIN.to_string();
+        for src in srcs {
+            let program = parse_tir(src).unwrap();
+            let mut output = vec![];
+            run(&program, min.as_bytes(), &mut output).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!("{min}\n"),
+                "{src}"
+            );
+            let mut output = vec![];
+            assert!(
+                matches!(
+                    run_checked(&program, min.as_bytes(), &mut output),
+                    Err(RuntimeError::Overflow(_))
+                ),
+                "{src}"
+            );
+            // one more than the minimum negates fine either way
+            let input = (i64::MIN + 1).to_string();
+            let mut output = vec![];
+            run_checked(&program, input.as_bytes(), &mut output).unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                format!("{}\n", i64::MAX)
+            );
+        }
+
+        // the same from source, where the constant is not folded away
+        for src in [
+            "$print ~ -9223372036854775808",
+            "$print - 0 -9223372036854775808",
+        ] {
+            assert_eq!(run_src(src, "").unwrap(), format!("{min}\n"), "{src}");
+            let program = lower(parse(src).unwrap());
+            let mut output = vec![];
+            assert!(
+                matches!(
+                    run_checked(&program, "".as_bytes(), &mut output),
+                    Err(RuntimeError::Overflow(_))
+                ),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn for_loop() {
+        let src = ":= sum 0 $for i 1 11 {:= sum + sum i} $print sum i";
+        assert_eq!(run_src(src, "").unwrap(), "55 11\n");
+        // the end is evaluated again before each iteration, and the body may
+        // change the variable
+        let src = "$read n $for i 0 n {:= n - n 1 $print i} $for i 0 5 {:= i + i 1 $print i}";
+        assert_eq!(run_src(src, "5").unwrap(), "0\n1\n2\n1\n3\n5\n");
+        assert_eq!(
+            run_src("$for i 3 0 {$print i} $print i", "").unwrap(),
+            "3\n"
+        );
+    }
+
+    #[test]
+    fn bitwise() {
+        let src = "$read x y $print $band x y $print $bor x y $print $bxor x y";
+        assert_eq!(run_src(src, "12 10").unwrap(), "8\n14\n6\n");
+        assert_eq!(run_src(src, "-1 5").unwrap(), "5\n-1\n-6\n");
+        // floats are truncated to integers first
+        assert_eq!(run_src(src, "12.9 -10.5").unwrap(), "4\n-2\n-6\n");
+        assert_eq!(
+            run_src("$read x $print << x 3 >> x 1", "5").unwrap(),
+            "40 2\n"
+        );
+    }
+
+    #[test]
+    fn shifts() {
+        let src = "
+            let x, k, y;
+            entry:
+                $read x
+                $read k
+                y = $arith shl x k
+                $print y
+                y = $arith shr x k
+                $print y
+                $exit
+        ";
+        let program = parse_tir(src).unwrap();
+        let run_shifts = |input: &str| {
+            let mut output = vec![];
+            run(&program, input.as_bytes(), &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(run_shifts("5 2"), "20\n1\n");
+        // the sign is kept, and only the low 6 bits of the amount count
+        assert_eq!(run_shifts("-5 1"), "-10\n-3\n");
+        assert_eq!(run_shifts("3 65"), "6\n1\n");
+        assert_eq!(run_shifts("1 -1"), format!("{}\n0\n", i64::MIN));
+        // floats are scaled by powers of two
+        assert_eq!(run_shifts("1.5 2"), "6.0\n0.375\n");
+
+        // shifting bits other than copies of the sign out is an overflow
+        let mut output = vec![];
+        assert!(matches!(
+            run_checked(&program, "4611686018427387904 1".as_bytes(), &mut output),
+            Err(RuntimeError::Overflow(_))
+        ));
+        let mut output = vec![];
+        run_checked(&program, "-4611686018427387904 1".as_bytes(), &mut output).unwrap();
+    }
+
+    #[test]
+    fn assertions() {
+        let src = "$read x $assert < x 10 $print x";
+        assert_eq!(run_src(src, "3").unwrap(), "3\n");
+        let err = run_src(src, "12").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: assertion failed at 1:9: `$assert < x 10`."
+        );
+
+        // the output before the failure is kept
+        let program = lower(parse("$print 1 $assert 0 $print 2").unwrap());
+        let mut output = vec![];
+        let result = run(&program, "".as_bytes(), &mut output);
+        assert!(matches!(result, Err(RuntimeError::AssertionFailed(_))));
+        assert_eq!(String::from_utf8(output).unwrap(), "1\n");
+
+        let err = run_src("$fn f(x) {$assert x := f x} $print f(0)", "").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: assertion failed at 1:11: `$assert x`."
+        );
+
+        // in the IR, any non-zero value holds
+        let program =
+            parse_tir(r#"let x; entry: $read x $assert x "`$assert x`" $print x $exit"#).unwrap();
+        for (input, holds) in [("1", true), ("-2.5", true), ("0", false), ("0.0", false)] {
+            let mut output = vec![];
+            let result = run(&program, input.as_bytes(), &mut output);
+            assert_eq!(result.is_ok(), holds, "{input}");
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Liveness analysis: which variables may be read later at each block
+//! boundary.
+
+use super::*;
+use crate::common::*;
+
+/// The live variables at the start and at the end of a block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LiveSets {
+    pub live_in: Set<Id>,
+    pub live_out: Set<Id>,
+}
+
+/// Compute the live variables at the boundaries of each block, using the
+/// standard backwards dataflow analysis.  A jump to a missing block does not
+/// make any variables live.
+pub fn liveness(program: &Program) -> Map<Id, LiveSets> {
+    // variables used before being defined, and variables defined in each block
+    let mut uses: Map<Id, Set<Id>> = Map::new();
+    let mut defs: Map<Id, Set<Id>> = Map::new();
+    for (lbl, block) in &program.block {
+        let (mut u, mut d) = (Set::new(), Set::new());
+        for i in &block.insn {
+            u.extend(i.uses().into_iter().filter(|x| !d.contains(x)));
+            d.extend(i.def());
+        }
+        u.extend(block.term.uses().into_iter().filter(|x| !d.contains(x)));
+        uses.insert(*lbl, u);
+        defs.insert(*lbl, d);
+    }
+
+    let mut live: Map<Id, LiveSets> = program
+        .block
+        .keys()
+        .map(|l| (*l, LiveSets::default()))
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (lbl, block) in program.block.iter().rev() {
+            let live_out: Set<Id> = block
+                .term
+                .successors()
+                .iter()
+                .filter_map(|succ| live.get(succ))
+                .flat_map(|sets| &sets.live_in)
+                .copied()
+                .collect();
+            let mut live_in = uses[lbl].clone();
+            live_in.extend(live_out.difference(&defs[lbl]));
+
+            let sets = LiveSets { live_in, live_out };
+            if sets != live[lbl] {
+                changed = true;
+                live.insert(*lbl, sets);
+            }
+        }
+    }
+
+    live
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{lower, parse};
+
+    // SECTION: helpers
+
+    // Build a set of variables
+    fn vars(names: &[&str]) -> Set<Id> {
+        names.iter().map(|x| id(x)).collect()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn straight_line() {
+        let program = lower(parse(":= x 1 $print x").unwrap());
+        let live = liveness(&program);
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[&id("entry")], LiveSets::default());
+    }
+
+    #[test]
+    fn branches() {
+        let program = parse_tir(
+            "
+            let a, b, c, x;
+            entry:
+                $read a
+                $read b
+                $branch a left right
+            left:
+                x = $copy b
+                $jump join
+            right:
+                x = $copy c
+                $jump join
+            join:
+                $print x
+                $print a
+                $exit
+            ",
+        )
+        .unwrap();
+        let live = liveness(&program);
+        let sets = |lbl: &str| {
+            let sets = &live[&id(lbl)];
+            (sets.live_in.clone(), sets.live_out.clone())
+        };
+        assert_eq!(sets("entry"), (vars(&["c"]), vars(&["a", "b", "c"])));
+        assert_eq!(sets("left"), (vars(&["a", "b"]), vars(&["a", "x"])));
+        assert_eq!(sets("right"), (vars(&["a", "c"]), vars(&["a", "x"])));
+        assert_eq!(sets("join"), (vars(&["a", "x"]), vars(&[])));
+    }
+
+    #[test]
+    fn loops() {
+        let program = parse_tir(
+            "
+            let i, n, s;
+            entry: $read n $jump header
+            header: $branch n body end
+            body:
+                s = $arith add s n
+                n = $arith sub n i
+                $jump header
+            end: $return s
+            ",
+        )
+        .unwrap();
+        let live = liveness(&program);
+        // the back edge keeps `i` and `s` live throughout the loop
+        assert_eq!(live[&id("entry")].live_in, vars(&["i", "s"]));
+        assert_eq!(live[&id("header")].live_in, vars(&["i", "n", "s"]));
+        assert_eq!(live[&id("body")].live_out, vars(&["i", "n", "s"]));
+        assert_eq!(live[&id("end")].live_in, vars(&["s"]));
+        assert_eq!(live[&id("end")].live_out, vars(&[]));
+    }
+}
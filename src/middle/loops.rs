@@ -0,0 +1,145 @@
+//! Natural loops: a back edge goes from a block to one of its dominators, the
+//! loop's header, and the loop is made up of the blocks that can reach the
+//! back edge without going through the header.
+
+use super::dom::{dominates, dominators, reverse_postorder};
+use super::*;
+use crate::common::*;
+
+/// A natural loop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// The only block of the loop that is entered from outside the loop.  It
+    /// dominates all the blocks of the loop.
+    pub header: Id,
+    /// All the blocks of the loop, including the header and the blocks of the
+    /// loops nested in it.
+    pub blocks: Set<Id>,
+}
+
+/// Find the natural loops of the blocks that are reachable from `entry`.  The
+/// back edges to the same header make up a single loop, and outer loops come
+/// before the loops nested in them.
+pub fn find_loops(program: &Program) -> Vec<Loop> {
+    let idom = dominators(program);
+
+    let mut preds: Map<Id, Vec<Id>> = Map::new();
+    for &lbl in idom.keys() {
+        for succ in program.block[&lbl].term.successors() {
+            if idom.contains_key(&succ) {
+                preds.entry(succ).or_default().push(lbl);
+            }
+        }
+    }
+
+    // headers are visited in reverse postorder, which puts each outer loop's
+    // header before the headers of the loops nested in it
+    let mut loops = vec![];
+    for header in reverse_postorder(program) {
+        let latches: Vec<Id> = preds
+            .get(&header)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|&pred| dominates(&idom, header, pred))
+            .collect();
+        if latches.is_empty() {
+            continue;
+        }
+
+        // walk backwards from the back edges, stopping at the header
+        let mut blocks = Set::from([header]);
+        let mut worklist = latches;
+        while let Some(lbl) = worklist.pop() {
+            if blocks.insert(lbl) {
+                worklist.extend(preds.get(&lbl).into_iter().flatten().copied());
+            }
+        }
+        loops.push(Loop { header, blocks });
+    }
+    loops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::test_util::lower_src;
+
+    // SECTION: helpers
+
+    // Make a set of labels
+    fn labels<const N: usize>(names: [&str; N]) -> Set<Id> {
+        names.into_iter().map(id).collect()
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn while_loop() {
+        // lbl1 is the header, lbl2 the body, and lbl3 the exit
+        let program = lower_src("$read n $while n {:= n - n 1 $print n} $print 0");
+        let loops = find_loops(&program);
+        assert_eq!(
+            loops,
+            vec![Loop {
+                header: id("lbl1"),
+                blocks: labels(["lbl1", "lbl2"]),
+            }]
+        );
+    }
+
+    #[test]
+    fn branches_in_the_body() {
+        let program = lower_src("$read n $while n {$if n {:= n 0} {} $print n} $print 1");
+        let loops = find_loops(&program);
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].header, id("lbl1"));
+        // the body, both branches of the `$if`, and its join
+        assert_eq!(
+            loops[0].blocks,
+            labels(["lbl1", "lbl2", "lbl4", "lbl5", "lbl6"])
+        );
+    }
+
+    #[test]
+    fn nested_loops() {
+        let program = lower_src("$read n $while n {:= i n $while i {:= i - i 1} := n - n 1}");
+        let loops = find_loops(&program);
+        assert_eq!(loops.len(), 2);
+        let (outer, inner) = (&loops[0], &loops[1]);
+        assert_eq!(outer.header, id("lbl1"));
+        assert_eq!(inner.header, id("lbl4"));
+        assert!(inner.blocks.is_subset(&outer.blocks));
+        assert!(!inner.blocks.contains(&outer.header));
+        assert_eq!(inner.blocks, labels(["lbl4", "lbl5"]));
+        assert_eq!(
+            outer.blocks,
+            labels(["lbl1", "lbl2", "lbl4", "lbl5", "lbl6"])
+        );
+    }
+
+    #[test]
+    fn no_loops() {
+        assert!(find_loops(&lower_src("$read x $if x {$print 1} {$print 2}")).is_empty());
+        // an unreachable loop is not found
+        assert!(find_loops(&lower_src("$return $while x {}")).is_empty());
+
+        let mut program = lower_src("");
+        program.block.clear();
+        assert!(find_loops(&program).is_empty());
+    }
+
+    #[test]
+    fn irreducible_cycle() {
+        // neither block of the cycle dominates the other, so there is no
+        // natural loop
+        let program = parse_tir(
+            "let x;
+             entry: $branch x a b
+             a: $jump b
+             b: $jump a",
+        )
+        .unwrap();
+        assert!(find_loops(&program).is_empty());
+    }
+}
@@ -1,7 +1,1477 @@
 //! Optimizations
 
 use super::*;
+use crate::common::*;
+use crate::front::ast::{BOp, Number};
 
-pub fn optimize(program: Program) -> Program {
+/// Run all the optimizations on the main program and the functions until they
+/// do not change anything.
+pub fn optimize(mut program: Program) -> Program {
+    optimize_body(&mut program);
+    for func in program.funcs.values_mut() {
+        optimize_body(&mut func.body);
+    }
     program
 }
+
+// Optimize the CFG of the main program or a function until it stops changing
+fn optimize_body(program: &mut Program) {
+    let mut before = program.to_string();
+    loop {
+        run_passes(program);
+        let after = program.to_string();
+        if after == before {
+            break;
+        }
+        before = after;
+    }
+}
+
+// Run each optimization once
+fn run_passes(program: &mut Program) {
+    propagate_constants(program);
+    peephole(program);
+    reduce_strength(program);
+    cse(program);
+    propagate_copies(program);
+    eliminate_dead_stores(program);
+    hoist_invariants(program);
+    remove_nops(program);
+    duplicate_tails(program);
+    remove_unreachable(program);
+    merge_blocks(program);
+}
+
+/// The largest block [duplicate_tails] copies, in instructions.
+pub const MAX_TAIL_LEN: usize = 4;
+
+/// Copy each small block that ends the program or a function into the blocks
+/// that jump to it, and remove it, if there are several of them and all of
+/// them jump to it.  This saves a jump on each path, e.g. at the end of a `$if`
+/// at the end of the program.
+pub fn duplicate_tails(program: &mut Program) {
+    let entry = id(ENTRY_LABEL);
+    // the blocks that go to each block, and whether all of them jump there
+    let mut preds: Map<Id, (Vec<Id>, bool)> = Map::new();
+    for (lbl, block) in &program.block {
+        for succ in block.term.successors() {
+            let (labels, all_jumps) = preds.entry(succ).or_insert((vec![], true));
+            labels.push(*lbl);
+            *all_jumps &= matches!(block.term, Terminator::Jump(_));
+        }
+    }
+
+    for (tail, (labels, all_jumps)) in preds {
+        let Some(block) = program.block.get(&tail) else {
+            continue;
+        };
+        let ends = matches!(block.term, Terminator::Exit | Terminator::Return(_));
+        if tail == entry
+            || !ends
+            || !all_jumps
+            || labels.len() < 2
+            || block.insn.len() > MAX_TAIL_LEN
+        {
+            continue;
+        }
+
+        let block = program.block.remove(&tail).unwrap();
+        for lbl in labels {
+            // the phi nodes of the copy have only one predecessor, so they are
+            // copies now
+            let insn = resolve_phis(&block.insn, lbl, &mut program.decl);
+            let pred = program.block.get_mut(&lbl).unwrap();
+            pred.insn.extend(insn);
+            pred.term = block.term.clone();
+        }
+    }
+}
+
+// The instructions of a block whose only predecessor is `pred`, with its phi
+// nodes replaced by copies of their sources from `pred`.  The phi nodes read
+// their sources before any of them writes, so if one of them reads the
+// destination of another, the sources are copied to fresh variables first.
+fn resolve_phis(insn: &[Instruction], pred: Id, decl: &mut Set<Id>) -> Vec<Instruction> {
+    let phis: Vec<(Id, Id)> = insn
+        .iter()
+        .filter_map(|i| match i {
+            Instruction::Phi { dst, srcs } => {
+                let (_, src) = srcs
+                    .iter()
+                    .find(|(p, _)| *p == pred)
+                    .expect("a phi node should have a value for each predecessor");
+                Some((*dst, *src))
+            }
+            _ => None,
+        })
+        .collect();
+    let dsts: Set<Id> = phis.iter().map(|(dst, _)| *dst).collect();
+    let clash = phis
+        .iter()
+        .any(|(dst, src)| src != dst && dsts.contains(src));
+
+    let mut resolved = vec![];
+    let mut moves = vec![];
+    for (dst, src) in phis {
+        if clash {
+            let tmp = fresh_var(decl, "_phi");
+            decl.insert(tmp);
+            resolved.push(Instruction::Copy { dst: tmp, src });
+            moves.push(Instruction::Copy { dst, src: tmp });
+        } else {
+            moves.push(Instruction::Copy { dst, src });
+        }
+    }
+    resolved.extend(moves);
+    resolved.extend(
+        insn.iter()
+            .filter(|i| !matches!(i, Instruction::Phi { .. }))
+            .cloned(),
+    );
+    resolved
+}
+
+/// Merge each block that ends with a jump into its target if it is the only
+/// way to reach the target.
+pub fn merge_blocks(program: &mut Program) {
+    let entry = id(ENTRY_LABEL);
+    loop {
+        // the number of edges into each block
+        let mut preds: Map<Id, usize> = Map::new();
+        for block in program.block.values() {
+            for succ in block.term.successors() {
+                *preds.entry(succ).or_default() += 1;
+            }
+        }
+
+        let mergeable = program
+            .block
+            .iter()
+            .find_map(|(lbl, block)| match block.term {
+                Terminator::Jump(target)
+                    if target != *lbl
+                        && target != entry
+                        && preds[&target] == 1
+                        && program.block.contains_key(&target) =>
+                {
+                    Some((*lbl, target))
+                }
+                _ => None,
+            });
+        let Some((lbl, target)) = mergeable else {
+            break;
+        };
+
+        let merged = program.block.remove(&target).unwrap();
+        // the phi nodes of the merged block have only one predecessor, so
+        // they are copies now
+        let insn = resolve_phis(&merged.insn, lbl, &mut program.decl);
+        let block = program.block.get_mut(&lbl).unwrap();
+        block.insn.extend(insn);
+        block.term = merged.term;
+
+        // control now comes from `lbl` instead of the merged block
+        for succ in block.term.successors() {
+            for i in program
+                .block
+                .get_mut(&succ)
+                .into_iter()
+                .flat_map(|b| &mut b.insn)
+            {
+                if let Instruction::Phi { srcs, .. } = i {
+                    for (pred, _) in srcs {
+                        if *pred == target {
+                            *pred = lbl;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Remove the blocks that cannot be reached from the entry block.
+pub fn remove_unreachable(program: &mut Program) {
+    let entry = id(ENTRY_LABEL);
+    let mut reachable = Set::new();
+    let mut stack = vec![entry];
+    while let Some(lbl) = stack.pop() {
+        if !reachable.insert(lbl) {
+            continue;
+        }
+        if let Some(block) = program.block.get(&lbl) {
+            stack.extend(block.term.successors());
+        }
+    }
+
+    program
+        .block
+        .retain(|lbl, _| *lbl == entry || reachable.contains(lbl));
+    for block in program.block.values_mut() {
+        for i in &mut block.insn {
+            if let Instruction::Phi { srcs, .. } = i {
+                srcs.retain(|(pred, _)| reachable.contains(pred));
+            }
+        }
+    }
+}
+
+/// Compute the instructions whose operands are known constants within each
+/// block, and turn branches on known constants into jumps.
+pub fn propagate_constants(program: &mut Program) {
+    // the edges that are removed by turning branches into jumps
+    let mut removed = vec![];
+    for (lbl, block) in program.block.iter_mut() {
+        // variables with known values
+        let mut consts: Map<Id, Number> = Map::new();
+        for i in block.insn.iter_mut() {
+            let value = match *i {
+                Instruction::Copy { src, .. } => consts.get(&src).copied(),
+                Instruction::Const { src, .. } => Some(src),
+                // operations that overflow stay, so that they fail in checked
+                // mode
+                Instruction::Arith { op, lhs, rhs, .. } => {
+                    match (consts.get(&lhs), consts.get(&rhs)) {
+                        (Some(&lhs), Some(&rhs)) if !op.overflows(lhs, rhs) => op.eval(lhs, rhs),
+                        _ => None,
+                    }
+                }
+                Instruction::Neg { src, .. } => consts.get(&src).and_then(|v| v.checked_neg()),
+                Instruction::Assert(x, _) => {
+                    // an assertion of a true constant always holds
+                    if consts.get(&x).is_some_and(|v| v.is_true()) {
+                        *i = Instruction::Nop;
+                    }
+                    None
+                }
+                Instruction::Read(_)
+                | Instruction::Print(_)
+                | Instruction::PrintStr(_)
+                | Instruction::PrintNoNl(_)
+                | Instruction::PrintStrNoNl(_)
+                | Instruction::Call { .. }
+                | Instruction::Phi { .. }
+                | Instruction::Nop => None,
+            };
+
+            if let Some(dst) = i.def() {
+                match value {
+                    Some(src) => {
+                        consts.insert(dst, src);
+                        *i = Instruction::Const { dst, src };
+                    }
+                    None => {
+                        consts.remove(&dst);
+                    }
+                }
+            }
+        }
+
+        // only branches and switches have a target for the value they test
+        let tested = block.term.uses().first().and_then(|x| consts.get(x));
+        if let Some(taken) = tested.and_then(|&v| block.term.target(v)) {
+            let untaken: Set<Id> = block.term.successors().into_iter().collect();
+            removed.extend(
+                untaken
+                    .into_iter()
+                    .filter(|&succ| succ != taken)
+                    .map(|succ| (*lbl, succ)),
+            );
+            block.term = Terminator::Jump(taken);
+        }
+    }
+
+    // the phi nodes no longer get values from the removed edges
+    for (pred, succ) in removed {
+        for i in program
+            .block
+            .get_mut(&succ)
+            .into_iter()
+            .flat_map(|b| &mut b.insn)
+        {
+            if let Instruction::Phi { srcs, .. } = i {
+                srcs.retain(|(p, _)| *p != pred);
+            }
+        }
+    }
+}
+
+/// Simplify arithmetic with an identity or an annihilator within each block:
+/// `+ x 0`, `- x 0`, `* x 1`, and `/ x 1` become copies of `x`, and `* x 0`
+/// becomes the constant 0.
+///
+/// Only integer constants count, and `+ x 0` and `* x 0` are rewritten only if
+/// `x` is known to be an integer, because the rewrites do not hold for floats
+/// (`-0.0 + 0` is `0.0`, and `* x 0` is a float).  A variable is known to be an
+/// integer if it is computed in the block from integers.
+pub fn peephole(program: &mut Program) {
+    for block in program.block.values_mut() {
+        let mut facts = Facts::default();
+        for i in block.insn.iter_mut() {
+            if let Instruction::Arith { op, dst, lhs, rhs } = *i {
+                let (l, r) = (
+                    facts.consts.get(&lhs).copied(),
+                    facts.consts.get(&rhs).copied(),
+                );
+                let is_int = |x| facts.ints.contains(&x);
+                let rewritten = match (op, l, r) {
+                    (BOp::Add, _, Some(0)) if is_int(lhs) => {
+                        Some(Instruction::Copy { dst, src: lhs })
+                    }
+                    (BOp::Add, Some(0), _) if is_int(rhs) => {
+                        Some(Instruction::Copy { dst, src: rhs })
+                    }
+                    (BOp::Sub, _, Some(0)) | (BOp::Mul | BOp::Div, _, Some(1)) => {
+                        Some(Instruction::Copy { dst, src: lhs })
+                    }
+                    (BOp::Mul, Some(1), _) => Some(Instruction::Copy { dst, src: rhs }),
+                    (BOp::Mul, _, Some(0)) if is_int(lhs) => Some(Instruction::Const {
+                        dst,
+                        src: Number::Int(0),
+                    }),
+                    (BOp::Mul, Some(0), _) if is_int(rhs) => Some(Instruction::Const {
+                        dst,
+                        src: Number::Int(0),
+                    }),
+                    _ => None,
+                };
+                if let Some(rewritten) = rewritten {
+                    *i = rewritten;
+                }
+            }
+            facts.learn(i);
+        }
+    }
+}
+
+/// Replace multiplications and divisions by powers of two with cheaper
+/// operations within each block: `* x 2` becomes `+ x x`, `* x 2^k` becomes a
+/// left shift by `k`, and `/ x 2^k` becomes a right shift by `k`.  The shift
+/// amounts go to new variables.
+///
+/// Doubling is exact for floats too, but shifts do not work on floats, so the
+/// shifts need `x` to be known to be an integer (see [peephole]).  A right
+/// shift rounds down where division rounds toward zero, so `/ x 2^k` is also
+/// rewritten only if `x` is known to be non-negative.
+pub fn reduce_strength(program: &mut Program) {
+    for block in program.block.values_mut() {
+        let mut facts = Facts::default();
+        let mut insn = Vec::with_capacity(block.insn.len());
+        for mut i in std::mem::take(&mut block.insn) {
+            if let Instruction::Arith { op, dst, lhs, rhs } = i {
+                // the exponent if the variable is a known power of two
+                let log2 = |x| {
+                    let n = *facts.consts.get(&x)?;
+                    (n > 0 && n.count_ones() == 1).then(|| n.trailing_zeros() as i64)
+                };
+                let is_int = |x| facts.ints.contains(&x);
+                let shift = match (op, log2(lhs), log2(rhs)) {
+                    (BOp::Mul, _, Some(1)) => {
+                        i = Instruction::Arith {
+                            op: BOp::Add,
+                            dst,
+                            lhs,
+                            rhs: lhs,
+                        };
+                        None
+                    }
+                    (BOp::Mul, Some(1), _) => {
+                        i = Instruction::Arith {
+                            op: BOp::Add,
+                            dst,
+                            lhs: rhs,
+                            rhs,
+                        };
+                        None
+                    }
+                    (BOp::Mul, _, Some(k)) if k > 1 && is_int(lhs) => Some((BOp::Shl, lhs, k)),
+                    (BOp::Mul, Some(k), _) if k > 1 && is_int(rhs) => Some((BOp::Shl, rhs, k)),
+                    (BOp::Div, _, Some(k)) if k > 0 && facts.nonneg.contains(&lhs) => {
+                        Some((BOp::Shr, lhs, k))
+                    }
+                    _ => None,
+                };
+                if let Some((op, lhs, k)) = shift {
+                    let amount = fresh_var(&program.decl, "_shift");
+                    program.decl.insert(amount);
+                    let src = Number::Int(k);
+                    insn.push(Instruction::Const { dst: amount, src });
+                    facts.learn(&insn[insn.len() - 1]);
+                    i = Instruction::Arith {
+                        op,
+                        dst,
+                        lhs,
+                        rhs: amount,
+                    };
+                }
+            }
+            facts.learn(&i);
+            insn.push(i);
+        }
+        block.insn = insn;
+    }
+}
+
+// What is known about the variables at a point of a block, from the
+// instructions before it in the block
+#[derive(Default)]
+struct Facts {
+    // variables with known integer values
+    consts: Map<Id, i64>,
+    // variables known to be integers
+    ints: Set<Id>,
+    // variables known to be non-negative integers
+    nonneg: Set<Id>,
+}
+
+impl Facts {
+    // Update the facts after the instruction
+    fn learn(&mut self, i: &Instruction) {
+        let Some(dst) = i.def() else {
+            return;
+        };
+        let int = |x| self.ints.contains(&x);
+        let nonneg = |x| self.nonneg.contains(&x);
+        let (value, is_int, is_nonneg) = match *i {
+            Instruction::Copy { src, .. } => {
+                (self.consts.get(&src).copied(), int(src), nonneg(src))
+            }
+            Instruction::Const {
+                src: Number::Int(n),
+                ..
+            } => (Some(n), true, n >= 0),
+            Instruction::Arith { op, lhs, rhs, .. } => match op {
+                // comparisons and logical operators always produce 0 or 1
+                BOp::Lt | BOp::Gt | BOp::Eq | BOp::Le | BOp::Ge | BOp::Ne | BOp::And | BOp::Or => {
+                    (None, true, true)
+                }
+                // the bitwise operators always produce integers
+                BOp::BitAnd => (None, true, nonneg(lhs) || nonneg(rhs)),
+                BOp::BitOr | BOp::BitXor => (None, true, nonneg(lhs) && nonneg(rhs)),
+                _ => {
+                    let ints = int(lhs) && int(rhs);
+                    // adding or multiplying may wrap around to a negative
+                    // number, but these cannot
+                    let is_nonneg = match op {
+                        BOp::Mod | BOp::Shr => nonneg(lhs),
+                        BOp::Div | BOp::Min => nonneg(lhs) && nonneg(rhs),
+                        BOp::Max => nonneg(lhs) || nonneg(rhs),
+                        _ => false,
+                    };
+                    (None, ints, ints && is_nonneg)
+                }
+            },
+            Instruction::Neg { src, .. } => (None, int(src), false),
+            _ => (None, false, false),
+        };
+        match value {
+            Some(n) => self.consts.insert(dst, n),
+            None => self.consts.remove(&dst),
+        };
+        for (set, holds) in [(&mut self.ints, is_int), (&mut self.nonneg, is_nonneg)] {
+            if holds {
+                set.insert(dst);
+            } else {
+                set.remove(&dst);
+            }
+        }
+    }
+}
+
+// A variable of the form `prefix_N` that the program does not declare
+fn fresh_var(decl: &Set<Id>, prefix: &str) -> Id {
+    (1..)
+        .map(|n| id(&format!("{prefix}_{n}")))
+        .find(|x| !decl.contains(x))
+        .unwrap()
+}
+
+// A computation that common subexpression elimination can reuse
+#[derive(PartialEq, Eq)]
+enum Computation {
+    Arith(BOp, Id, Id),
+    Neg(Id),
+}
+
+impl Computation {
+    fn uses(&self, x: Id) -> bool {
+        match *self {
+            Computation::Arith(_, lhs, rhs) => lhs == x || rhs == x,
+            Computation::Neg(src) => src == x,
+        }
+    }
+}
+
+/// Eliminate common subexpressions within each block: an arithmetic operation
+/// or a negation that computes the same thing as an earlier one becomes a
+/// copy of the earlier result, as long as none of the variables involved is
+/// redefined in between.  The operands of commutative operators are matched
+/// in either order.
+pub fn cse(program: &mut Program) {
+    for block in program.block.values_mut() {
+        // the computations that are still available, and where their results
+        // are
+        let mut available: Vec<(Computation, Id)> = vec![];
+        for i in block.insn.iter_mut() {
+            let computation = match *i {
+                Instruction::Arith { op, lhs, rhs, .. } => {
+                    let commutative = matches!(
+                        op,
+                        BOp::Add
+                            | BOp::Mul
+                            | BOp::Eq
+                            | BOp::Ne
+                            | BOp::And
+                            | BOp::Or
+                            | BOp::BitAnd
+                            | BOp::BitOr
+                            | BOp::BitXor
+                    );
+                    if commutative && rhs < lhs {
+                        Some(Computation::Arith(op, rhs, lhs))
+                    } else {
+                        Some(Computation::Arith(op, lhs, rhs))
+                    }
+                }
+                Instruction::Neg { src, .. } => Some(Computation::Neg(src)),
+                _ => None,
+            };
+            let Some(dst) = i.def() else {
+                continue;
+            };
+
+            if let Some(c) = &computation {
+                if let Some((_, src)) = available.iter().find(|(a, _)| a == c) {
+                    *i = Instruction::Copy { dst, src: *src };
+                }
+            }
+
+            available.retain(|(c, result)| *result != dst && !c.uses(dst));
+            // `x = $arith add x y` does not compute `add x y` with the new `x`
+            if let Some(c) = computation.filter(|c| !c.uses(dst)) {
+                available.push((c, dst));
+            }
+        }
+    }
+}
+
+/// Replace the uses of copied variables with the originals within each block,
+/// as long as neither of them is redefined in between.  This leaves the copies
+/// themselves in place, so it is best followed by dead store elimination.
+pub fn propagate_copies(program: &mut Program) {
+    for block in program.block.values_mut() {
+        // copies that are still valid, from the destination to the source
+        let mut copies: Map<Id, Id> = Map::new();
+        for i in block.insn.iter_mut() {
+            i.map_uses(|x| copies.get(&x).copied().unwrap_or(x));
+            if let Some(dst) = i.def() {
+                copies.retain(|d, s| *d != dst && *s != dst);
+                if let Instruction::Copy { dst, src } = *i {
+                    if dst != src {
+                        copies.insert(dst, src);
+                    }
+                }
+            }
+        }
+        block
+            .term
+            .map_uses(|x| copies.get(&x).copied().unwrap_or(x));
+
+        // copying a variable to itself does nothing
+        block
+            .insn
+            .retain(|i| !matches!(i, Instruction::Copy { dst, src } if dst == src));
+    }
+}
+
+/// Remove the instructions whose results are never read.
+///
+/// Instructions with side effects are kept even if their results are dead:
+/// `$read` and `$print` do I/O, division and remainder may fail at runtime,
+/// and integer arithmetic that may overflow fails in checked mode.
+pub fn eliminate_dead_stores(program: &mut Program) {
+    let sets = liveness(program);
+
+    for (lbl, block) in program.block.iter_mut() {
+        // walk the block backwards, keeping track of the live variables
+        let mut live = sets[lbl].live_out.clone();
+        live.extend(block.term.uses());
+
+        let mut insn = vec![];
+        for i in block.insn.drain(..).rev() {
+            let dead = match i.def() {
+                Some(dst) => !live.contains(&dst) && is_pure(&i),
+                None => false,
+            };
+            if dead {
+                continue;
+            }
+            if let Some(dst) = i.def() {
+                live.remove(&dst);
+            }
+            live.extend(i.uses());
+            insn.push(i);
+        }
+        insn.reverse();
+        block.insn = insn;
+    }
+}
+
+/// Remove the [Instruction::Nop]s that other passes leave behind.
+pub fn remove_nops(program: &mut Program) {
+    for block in program.block.values_mut() {
+        block.insn.retain(|i| !matches!(i, Instruction::Nop));
+    }
+}
+
+/// Move the instructions that compute the same value in every iteration of a
+/// loop into a new preheader block that runs once before the loop.
+///
+/// Only constants and arithmetic that can neither fail nor overflow are moved,
+/// and only if their operands are not assigned in the loop, their destination
+/// is assigned only once in the loop, and the old value of the destination is
+/// not read at the loop header or after the loop.
+pub fn hoist_invariants(program: &mut Program) {
+    let mut loops = find_loops(program);
+    // inner loops first, so that what they hoist can be hoisted further out of
+    // the loops around them
+    for i in (0..loops.len()).rev() {
+        let Some(preheader) = hoist_loop(program, &loops[i]) else {
+            continue;
+        };
+        let header = loops[i].header;
+        for outer in &mut loops[..i] {
+            if outer.blocks.contains(&header) {
+                outer.blocks.insert(preheader);
+            }
+        }
+    }
+}
+
+// Hoist the invariant instructions of a loop, and return the label of the
+// preheader if there were any
+fn hoist_loop(program: &mut Program, lp: &Loop) -> Option<Id> {
+    let header = lp.header;
+    if header == id(ENTRY_LABEL) {
+        return None;
+    }
+    let outside: Vec<Id> = program
+        .block
+        .iter()
+        .filter(|(lbl, block)| {
+            !lp.blocks.contains(lbl) && block.term.successors().contains(&header)
+        })
+        .map(|(lbl, _)| *lbl)
+        .collect();
+    // the phi nodes would have to be split between the header and the
+    // preheader
+    let has_phis = program.block[&header]
+        .insn
+        .iter()
+        .any(|i| matches!(i, Instruction::Phi { .. }));
+    if has_phis && outside.len() != 1 {
+        return None;
+    }
+
+    // the variables whose values flow into the header or out of the loop
+    let live = liveness(program);
+    let mut live_across: Set<Id> = live[&header].live_in.clone();
+    for lbl in &lp.blocks {
+        for succ in program.block[lbl].term.successors() {
+            if !lp.blocks.contains(&succ) {
+                live_across.extend(live.get(&succ).into_iter().flat_map(|s| &s.live_in));
+            }
+        }
+    }
+
+    let mut hoisted = vec![];
+    loop {
+        let mut defs: Map<Id, usize> = Map::new();
+        for lbl in &lp.blocks {
+            for dst in program.block[lbl].insn.iter().filter_map(|i| i.def()) {
+                *defs.entry(dst).or_default() += 1;
+            }
+        }
+
+        let invariant = |i: &Instruction| {
+            matches!(
+                i,
+                Instruction::Const { .. } | Instruction::Neg { .. } | Instruction::Arith { .. }
+            ) && is_pure(i)
+                && i.uses().iter().all(|x| !defs.contains_key(x))
+                && i.def()
+                    .is_some_and(|dst| defs[&dst] == 1 && !live_across.contains(&dst))
+        };
+        let found = lp.blocks.iter().find_map(|lbl| {
+            let index = program.block[lbl].insn.iter().position(invariant)?;
+            Some((*lbl, index))
+        });
+        let Some((lbl, index)) = found else {
+            break;
+        };
+        hoisted.push(program.block.get_mut(&lbl).unwrap().insn.remove(index));
+    }
+    if hoisted.is_empty() {
+        return None;
+    }
+
+    let preheader = program.fresh_label();
+    for lbl in &outside {
+        let block = program.block.get_mut(lbl).unwrap();
+        block
+            .term
+            .map_targets(|target| if target == header { preheader } else { target });
+    }
+    for i in &mut program.block.get_mut(&header).unwrap().insn {
+        if let Instruction::Phi { srcs, .. } = i {
+            for (pred, _) in srcs {
+                if outside.contains(pred) {
+                    *pred = preheader;
+                }
+            }
+        }
+    }
+    program.block.insert(
+        preheader,
+        Block {
+            insn: hoisted,
+            term: Terminator::Jump(header),
+        },
+    );
+    Some(preheader)
+}
+
+// Can this instruction be removed when its result is not used?  Operations that
+// may overflow are not, since they fail in checked mode.
+fn is_pure(insn: &Instruction) -> bool {
+    match insn {
+        Instruction::Copy { .. }
+        | Instruction::Const { .. }
+        | Instruction::Phi { .. }
+        | Instruction::Nop => true,
+        Instruction::Arith { op, .. } => !matches!(
+            op,
+            BOp::Div | BOp::Mod | BOp::Pow | BOp::Add | BOp::Sub | BOp::Mul | BOp::Shl
+        ),
+        // calls may have side effects, and they may not return at all
+        Instruction::Read(_)
+        | Instruction::Print(_)
+        | Instruction::PrintStr(_)
+        | Instruction::PrintNoNl(_)
+        | Instruction::PrintStrNoNl(_)
+        | Instruction::Neg { .. }
+        | Instruction::Assert(..)
+        | Instruction::Call { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::interp::{run, RuntimeError};
+    use crate::middle::parse_tir;
+    use crate::middle::test_util::{lower_src, run_program};
+
+    // SECTION: helpers
+
+    // All the instructions in the program, as text
+    fn insns(program: &Program) -> Vec<String> {
+        program
+            .block
+            .values()
+            .flat_map(|b| b.insn.iter().map(|i| i.to_string()))
+            .collect()
+    }
+
+    // Check that all jump targets in the program exist
+    fn assert_targets_exist(program: &Program) {
+        for (lbl, block) in &program.block {
+            for target in block.term.successors() {
+                assert!(
+                    program.block.contains_key(&target),
+                    "block {lbl} jumps to missing block {target}"
+                );
+            }
+        }
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn constants() {
+        let mut program = lower_src(":= x 3 := y + x 1 := z ~ y $print * y z");
+        propagate_constants(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec![
+                "_const_1 = $const 3",
+                "x = $const 3",
+                "_const_2 = $const 1",
+                "_t_3 = $const 4",
+                "y = $const 4",
+                "_t_4 = $const -4",
+                "z = $const -4",
+                "_t_5 = $const -16",
+                "$print _t_5"
+            ]
+        );
+        eliminate_dead_stores(&mut program);
+        assert_eq!(insns(&program), vec!["_t_5 = $const -16", "$print _t_5"]);
+    }
+
+    #[test]
+    fn constant_assertions() {
+        let mut program =
+            lower_src(":= x 3 $assert < x 4 $assert x $read y $assert y $assert - x 3");
+        propagate_constants(&mut program);
+        remove_nops(&mut program);
+        // only the assertions that may fail are left
+        let asserts: Vec<_> = insns(&program)
+            .into_iter()
+            .filter(|i| i.starts_with("$assert"))
+            .collect();
+        assert_eq!(
+            asserts,
+            [
+                r#"$assert y "1:40: `$assert y`""#,
+                r#"$assert _t_5 "1:50: `$assert - x 3`""#
+            ]
+        );
+        assert!(optimize(lower_src(":= x 3 $assert < x 4 $print x"))
+            .to_string()
+            .find("$assert")
+            .is_none());
+        // a failing assertion is kept
+        let mut output = vec![];
+        let result = run(
+            &optimize(lower_src("$assert 0")),
+            "".as_bytes(),
+            &mut output,
+        );
+        assert!(matches!(result, Err(RuntimeError::AssertionFailed(_))));
+    }
+
+    #[test]
+    fn constants_invalidated() {
+        let mut program = lower_src(":= x 3 $read x $print + x 1");
+        propagate_constants(&mut program);
+        assert_eq!(insns(&program)[4], "_t_3 = $arith add x _const_2");
+
+        let mut program = lower_src(":= x 3 := x y $print + x 1");
+        propagate_constants(&mut program);
+        assert_eq!(insns(&program)[4], "_t_3 = $arith add x _const_2");
+    }
+
+    #[test]
+    fn constants_division_by_zero() {
+        let mut program = lower_src(":= x 0 $print / 1 x");
+        propagate_constants(&mut program);
+        assert_eq!(insns(&program)[3], "_t_3 = $arith div _const_2 x");
+    }
+
+    #[test]
+    fn constant_branches() {
+        let mut program = lower_src(":= x 3 $if < x 4 {$print 1} {$print 2}");
+        propagate_constants(&mut program);
+        assert!(matches!(
+            program.block[&id("entry")].term,
+            Terminator::Jump(l) if l == id("lbl1")
+        ));
+        assert_eq!(run_program(&program, ""), "1\n");
+
+        let mut program = lower_src("$if 0 {$print 1} {$print 2}");
+        propagate_constants(&mut program);
+        assert!(matches!(
+            program.block[&id("entry")].term,
+            Terminator::Jump(l) if l == id("lbl2")
+        ));
+
+        // constants are not tracked across blocks
+        let mut program = lower_src(":= x 1 $while x {:= x 0}");
+        propagate_constants(&mut program);
+        assert!(matches!(
+            program.block[&id("lbl1")].term,
+            Terminator::Branch { .. }
+        ));
+    }
+
+    #[test]
+    fn constant_switches() {
+        let src = "
+            let x;
+            entry: x = $const 2 $switch x (1: a, 2: b) a
+            a: $print x $jump b
+            b: x = $phi(entry: x, a: x) $print x $exit
+        ";
+        let mut program = parse_tir(src).unwrap();
+        propagate_constants(&mut program);
+        assert!(matches!(
+            program.block[&id("entry")].term,
+            Terminator::Jump(l) if l == id("b")
+        ));
+        // `a` is still a predecessor of `b`, but `entry` no longer goes to `a`
+        let b = &program.block[&id("b")];
+        assert_eq!(b.insn[0].to_string(), "x = $phi(entry: x, a: x)");
+        assert_eq!(run_program(&program, ""), "2\n");
+    }
+
+    #[test]
+    fn tails() {
+        let mut program = lower_src("$read x $if x {$print 1} {$print 2}");
+        duplicate_tails(&mut program);
+        assert!(!program.block.contains_key(&id("lbl3")));
+        for lbl in ["lbl1", "lbl2"] {
+            assert_eq!(program.block[&id(lbl)].term, Terminator::Exit);
+        }
+        assert_targets_exist(&program);
+        assert_eq!(run_program(&program, "0"), "2\n");
+
+        // the phi nodes become copies in each predecessor
+        let mut program = lower_src("$read x $if x {:= y 1} {:= y 2} $print y");
+        to_ssa(&mut program);
+        duplicate_tails(&mut program);
+        assert!(!program.block.contains_key(&id("lbl3")));
+        assert!(matches!(
+            program.block[&id("lbl1")].insn.last(),
+            Some(Instruction::Print(_))
+        ));
+        assert_eq!(run_program(&program, "1"), "1\n");
+        assert_eq!(run_program(&program, "0"), "2\n");
+
+        // the phi nodes read their sources before writing, so swapping two
+        // variables goes through temporaries
+        let src = "
+            let a, b, c;
+            entry: $read a $read b $read c $branch c l1 l2
+            l1: $jump t
+            l2: $jump t
+            t: a = $phi(l1: b, l2: a) b = $phi(l1: a, l2: b) $print a $print b $exit
+        ";
+        let program = parse_tir(src).unwrap();
+        assert_eq!(run_program(&program, "1 2 1"), "2\n1\n");
+        assert_eq!(run_program(&program, "1 2 0"), "1\n2\n");
+        for mut program in [program.clone(), optimize(program)] {
+            duplicate_tails(&mut program);
+            assert!(!program.block.contains_key(&id("t")));
+            assert_eq!(run_program(&program, "1 2 1"), "2\n1\n");
+            assert_eq!(run_program(&program, "1 2 0"), "1\n2\n");
+        }
+
+        // the tail is too long to copy
+        let long = "$print 3 ".repeat(MAX_TAIL_LEN);
+        let mut program = lower_src(&format!("$read x $if x {{$print 1}} {{$print 2}} {long}"));
+        duplicate_tails(&mut program);
+        assert!(program.block.contains_key(&id("lbl3")));
+
+        // a loop header does not end the program
+        let mut program = lower_src("$read x $while x {:= x - x 1}");
+        let before = program.to_string();
+        duplicate_tails(&mut program);
+        assert_eq!(program.to_string(), before);
+    }
+
+    #[test]
+    fn unreachable() {
+        let mut program = lower_src("$if 1 {$print 1} {$print 2} $print 3");
+        remove_unreachable(&mut program);
+        assert_eq!(program.block.len(), 4);
+
+        propagate_constants(&mut program);
+        remove_unreachable(&mut program);
+        assert_eq!(
+            program.block.keys().map(|l| l.as_str()).collect::<Vec<_>>(),
+            vec!["entry", "lbl1", "lbl3"]
+        );
+        assert_targets_exist(&program);
+        assert_eq!(run_program(&program, ""), "1\n3\n");
+    }
+
+    #[test]
+    fn unreachable_loop() {
+        let mut program = lower_src("$while 0 {$print 1} $while 1 {$print 2} $print 3");
+        propagate_constants(&mut program);
+        remove_unreachable(&mut program);
+        // the body of the first loop and the exit of the second loop are gone
+        assert_eq!(
+            program.block.keys().map(|l| l.as_str()).collect::<Vec<_>>(),
+            vec!["entry", "lbl1", "lbl3", "lbl4", "lbl5"]
+        );
+        assert_targets_exist(&program);
+    }
+
+    #[test]
+    fn unreachable_after_return() {
+        let mut program = lower_src("$print 1 $return $print 2 $while x {}");
+        assert_eq!(program.block.len(), 5);
+        remove_unreachable(&mut program);
+        assert_eq!(
+            program.block.keys().map(|l| l.as_str()).collect::<Vec<_>>(),
+            vec!["entry"]
+        );
+        assert!(matches!(program.block[&id("entry")].term, Terminator::Exit));
+        assert_eq!(run_program(&program, ""), "1\n");
+    }
+
+    #[test]
+    fn unreachable_keeps_entry() {
+        let mut program = lower_src("");
+        program.block.clear();
+        remove_unreachable(&mut program);
+        assert!(program.block.is_empty());
+
+        let mut program = lower_src("$print 1");
+        remove_unreachable(&mut program);
+        assert_eq!(program.block.len(), 1);
+    }
+
+    #[test]
+    fn merge() {
+        let mut program = lower_src("$if 1 {$print 1} {$print 2} $print 3");
+        merge_blocks(&mut program);
+        assert_eq!(program.block.len(), 4);
+
+        propagate_constants(&mut program);
+        remove_unreachable(&mut program);
+        assert_eq!(program.block.len(), 3);
+        merge_blocks(&mut program);
+        assert_eq!(program.block.len(), 1);
+        assert!(matches!(program.block[&id("entry")].term, Terminator::Exit));
+        assert_eq!(run_program(&program, ""), "1\n3\n");
+    }
+
+    #[test]
+    fn merge_chains() {
+        let mut program = lower_src("$if 1 {$if x {$print 1} {}} {} $print 2");
+        propagate_constants(&mut program);
+        remove_unreachable(&mut program);
+        merge_blocks(&mut program);
+        // the outer true branch is merged into the entry block, and the inner
+        // join block is merged with the outer join block
+        assert_eq!(
+            program.block.keys().map(|l| l.as_str()).collect::<Vec<_>>(),
+            vec!["entry", "lbl4", "lbl5", "lbl6"]
+        );
+        assert_eq!(program.block[&id("lbl6")].insn.len(), 2);
+        assert_targets_exist(&program);
+        assert_eq!(run_program(&program, ""), "2\n");
+    }
+
+    #[test]
+    fn merge_phi_swap() {
+        let src = "
+            let a, b;
+            entry: $read a $read b $jump t
+            t: a = $phi(entry: b) b = $phi(entry: a) $print a $print b $exit
+        ";
+        let mut program = parse_tir(src).unwrap();
+        merge_blocks(&mut program);
+        assert_eq!(program.block.len(), 1);
+        assert_eq!(run_program(&program, "1 2"), "2\n1\n");
+    }
+
+    #[test]
+    fn merge_keeps_loops() {
+        let mut program = lower_src("$read x $while x {$read x $print x}");
+        merge_blocks(&mut program);
+        assert_eq!(program.block.len(), 4);
+        assert_eq!(run_program(&program, "2\n1\n0\n"), "1\n0\n");
+    }
+
+    #[test]
+    fn peephole_identities() {
+        // the operand is read, so it may be a float
+        for (src, rewritten) in [
+            ("$read x $print - x 0", "= $copy x"),
+            ("$read x $print * x 1", "= $copy x"),
+            ("$read x $print * 1 x", "= $copy x"),
+            ("$read x $print / x 1", "= $copy x"),
+        ] {
+            let mut program = lower_src(src);
+            peephole(&mut program);
+            assert!(
+                insns(&program).iter().any(|i| i.ends_with(rewritten)),
+                "for {src:?}"
+            );
+            assert_eq!(run_program(&program, "2.5\n"), "2.5\n");
+        }
+    }
+
+    #[test]
+    fn peephole_integers() {
+        // `c` is a comparison, so it is an integer
+        for (src, rewritten) in [
+            ("$read x := c < x 5 $print + c 0", "= $copy c"),
+            ("$read x := c < x 5 $print + 0 c", "= $copy c"),
+            ("$read x := c < x 5 $print * c 0", "= $const 0"),
+            ("$read x := c < x 5 $print * 0 c", "= $const 0"),
+            ("$read x := c * ~ < x 5 2 $print * c 0", "= $const 0"),
+        ] {
+            let mut program = lower_src(src);
+            let before = run_program(&program, "3\n");
+            peephole(&mut program);
+            assert!(
+                insns(&program)
+                    .iter()
+                    .any(|i| i.starts_with("_t_") && i.ends_with(rewritten)),
+                "for {src:?}"
+            );
+            assert_eq!(run_program(&program, "3\n"), before);
+        }
+
+        // `+ x 0` and `* x 0` do not hold for floats
+        let src = "$read x $print + x 0 $print * x 0 := y x $print * y 0";
+        let mut program = lower_src(src);
+        let before = insns(&program);
+        peephole(&mut program);
+        assert_eq!(insns(&program), before);
+        assert_eq!(run_program(&program, "-0.0\n"), "0.0\n-0.0\n-0.0\n");
+
+        // float constants are not identities
+        let mut program = lower_src("$read x := c < x 5 $print * c 1.0");
+        let before = insns(&program);
+        peephole(&mut program);
+        assert_eq!(insns(&program), before);
+
+        // the facts are invalidated when the variables are redefined
+        let mut program = lower_src(":= z 0 $read x := c < x 5 $read c $read z $print * c z");
+        let before = insns(&program);
+        peephole(&mut program);
+        assert_eq!(insns(&program), before);
+    }
+
+    #[test]
+    fn strength_reduction() {
+        // doubling works for floats too
+        let mut program = lower_src("$read x $print * x 2 $print * 2 x");
+        reduce_strength(&mut program);
+        let doubled = insns(&program);
+        assert_eq!(
+            doubled
+                .iter()
+                .filter(|i| i.ends_with("= $arith add x x"))
+                .count(),
+            2
+        );
+        assert_eq!(run_program(&program, "2.5\n"), "5.0\n5.0\n");
+
+        // `n` is a non-negative integer, so multiplying and dividing it by
+        // powers of two are shifts
+        let src = "
+            let n, x, y, z, eight;
+            entry:
+                $read x
+                eight = $const 8
+                n = $const 100
+                y = $arith mul n eight
+                z = $arith div n eight
+                $print y
+                $print z
+                $exit
+        ";
+        let mut program = parse_tir(src).unwrap();
+        reduce_strength(&mut program);
+        let insn = &program.block[&id("entry")].insn;
+        assert_eq!(
+            insn[3..7].iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+            [
+                "_shift_1 = $const 3",
+                "y = $arith shl n _shift_1",
+                "_shift_2 = $const 3",
+                "z = $arith shr n _shift_2",
+            ]
+        );
+        assert!(program.decl.contains(&id("_shift_1")) && program.decl.contains(&id("_shift_2")));
+        assert_eq!(verify(&program), Ok(()));
+        assert_eq!(run_program(&program, "1.5\n"), "800\n12\n");
+
+        // what is not known to be an integer stays the same, and so does the
+        // division of an integer that may be negative
+        for src in [
+            "$read x $print * x 8 $print / x 8",
+            "$read x := c ~ < x 5 $print / c 2",
+            "$read x := c < x 5 $read c $print * c 4",
+        ] {
+            let mut program = lower_src(src);
+            let before = insns(&program);
+            reduce_strength(&mut program);
+            assert_eq!(insns(&program), before, "for {src:?}");
+        }
+    }
+
+    #[test]
+    fn strength_reduction_optimized() {
+        // the shifts compute the same as the multiplications and divisions
+        let src = "$read x := c + < x 0 < x 10 $print * c 8 $print * 16 ~ c \
+                   $print / $max c 0 4 $print * x 2";
+        let program = lower_src(src);
+        let optimized = optimize(program.clone());
+        let text = optimized.to_string();
+        for op in ["shl", "shr", "add x x"] {
+            assert!(text.contains(&format!("$arith {op}")), "no {op} in\n{text}");
+        }
+        for input in ["-3\n", "5\n", "20\n", "2.5\n"] {
+            assert_eq!(run_program(&optimized, input), run_program(&program, input));
+        }
+    }
+
+    #[test]
+    fn common_subexpressions() {
+        let mut program = lower_src("$read x $read y $print + + x y + x y");
+        cse(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec![
+                "$read x",
+                "$read y",
+                "_t_1 = $arith add x y",
+                "_t_2 = $copy _t_1",
+                "_t_3 = $arith add _t_1 _t_2",
+                "$print _t_3"
+            ]
+        );
+        assert_eq!(run_program(&program, "2\n3\n"), "10\n");
+
+        // commutative operators match in either order, others do not
+        let mut program = lower_src("$read x $read y $print * x y $print * y x $print - y x $print - x y $print ~ x $print ~ x");
+        cse(&mut program);
+        let copies = insns(&program)
+            .iter()
+            .filter(|i| i.contains("$copy"))
+            .count();
+        assert_eq!(copies, 2, "unexpected CSE:\n{program}");
+    }
+
+    #[test]
+    fn common_subexpressions_invalidated() {
+        // an operand is redefined
+        let mut program = lower_src("$read x $read y := a + x y $read x := b + x y $print - a b");
+        cse(&mut program);
+        let adds = insns(&program)
+            .iter()
+            .filter(|i| i.contains("$arith add"))
+            .count();
+        assert_eq!(adds, 2, "unexpected CSE:\n{program}");
+        assert_eq!(run_program(&program, "1\n2\n5\n"), "-4\n");
+
+        // the result is redefined
+        let mut program = lower_src("$read x := a ~ x := a 0 := b ~ x $print b");
+        cse(&mut program);
+        assert_eq!(run_program(&program, "3\n"), "-3\n");
+
+        // `x = x + 1` twice increments twice
+        let src = "$read x := x + x 1 := x + x 1 $print x";
+        let mut program = lower_src(src);
+        propagate_copies(&mut program);
+        cse(&mut program);
+        assert_eq!(run_program(&program, "3\n"), "5\n");
+    }
+
+    #[test]
+    fn copies() {
+        let mut program = lower_src(":= x 3 := y x $print y");
+        propagate_copies(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec![
+                "_const_1 = $const 3",
+                "x = $copy _const_1",
+                "y = $copy _const_1",
+                "$print _const_1"
+            ]
+        );
+        eliminate_dead_stores(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec!["_const_1 = $const 3", "$print _const_1"]
+        );
+    }
+
+    #[test]
+    fn copies_invalidated() {
+        // `x` is redefined after the copy, so `y` cannot be replaced
+        let mut program = lower_src("$read x := y x $read x $print y $print x");
+        propagate_copies(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec!["$read x", "y = $copy x", "$read x", "$print y", "$print x"]
+        );
+
+        // `y` is redefined after the copy
+        let mut program = lower_src("$read x := y x $read y $print y");
+        propagate_copies(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec!["$read x", "y = $copy x", "$read y", "$print y"]
+        );
+
+        // copies in a cycle
+        let mut program = lower_src("$read x := y x := x y $print x");
+        propagate_copies(&mut program);
+        assert_eq!(insns(&program), vec!["$read x", "y = $copy x", "$print x"]);
+    }
+
+    #[test]
+    fn copies_in_guards() {
+        let mut program = lower_src("$read x := y x $if y {$print y} {}");
+        propagate_copies(&mut program);
+        assert!(matches!(
+            program.block[&id("entry")].term,
+            Terminator::Branch { guard, .. } if guard == id("x")
+        ));
+        // copy propagation is local, so the uses in other blocks remain
+        assert_eq!(insns(&program)[2], "$print y");
+    }
+
+    #[test]
+    fn dead_stores_straight_line() {
+        let mut program = lower_src(":= x 3 := y 4 $print x");
+        assert_eq!(insns(&program).len(), 5);
+        eliminate_dead_stores(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec!["_const_1 = $const 3", "x = $copy _const_1", "$print x"]
+        );
+    }
+
+    #[test]
+    fn constants_that_overflow() {
+        // the operations stay, so that they fail in checked mode
+        let mut program = lower_src("$print + 9223372036854775807 1 $print ~ -9223372036854775808");
+        propagate_constants(&mut program);
+        let insns = insns(&program);
+        assert!(insns.contains(&"_t_3 = $arith add _const_1 _const_2".to_string()));
+        assert!(insns.contains(&"_t_5 = $neg _const_4".to_string()));
+    }
+
+    #[test]
+    fn dead_stores_overwritten() {
+        let mut program = lower_src(":= x 1 := x 2 $print x := x 3");
+        eliminate_dead_stores(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec!["_const_2 = $const 2", "x = $copy _const_2", "$print x"]
+        );
+    }
+
+    #[test]
+    fn dead_stores_keep_effects() {
+        let mut program = lower_src("$read x := y / 1 x := z + 1 x $print 5");
+        eliminate_dead_stores(&mut program);
+        assert_eq!(
+            insns(&program),
+            vec![
+                "$read x",
+                "_const_1 = $const 1",
+                "_t_2 = $arith div _const_1 x",
+                "_const_3 = $const 1",
+                "_t_4 = $arith add _const_3 x",
+                "_const_5 = $const 5",
+                "$print _const_5"
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_stores_across_blocks() {
+        let src = ":= x 1 := y 2 $read c $if c {$print x} {:= x 5 $print x}";
+        let mut program = lower_src(src);
+        eliminate_dead_stores(&mut program);
+        // `y` is dead, `x = 1` is live only through the true branch
+        assert!(!insns(&program).iter().any(|i| i.starts_with("y =")));
+        assert!(insns(&program).contains(&"x = $copy _const_1".to_string()));
+        assert_eq!(run_program(&program, "1"), "1\n");
+        assert_eq!(run_program(&program, "0"), "5\n");
+    }
+
+    #[test]
+    fn dead_stores_loop() {
+        let src =
+            "$read n := i 0 := s 0 := d 0 $while < i n {:= s + s i := d * i 2 := i + i 1} $print s";
+        let mut program = lower_src(src);
+        let before = run_program(&program, "5");
+        eliminate_dead_stores(&mut program);
+        assert!(!insns(&program).iter().any(|i| i.starts_with("d =")));
+        assert!(insns(&program).iter().any(|i| i.starts_with("s =")));
+        assert!(insns(&program).iter().any(|i| i.starts_with("i =")));
+        assert_eq!(run_program(&program, "5"), before);
+    }
+
+    #[test]
+    fn hoist_loop_invariants() {
+        let src = "$read a $read b $read n $while n {$print < a b := n - n 1}";
+        let mut program = lower_src(src);
+        let before = run_program(&program, "6\n7\n3\n");
+        hoist_invariants(&mut program);
+        assert_targets_exist(&program);
+
+        // the new preheader sits between the entry block and the header
+        let header = id("lbl1");
+        let preheader = match program.block[&id("entry")].term {
+            Terminator::Jump(target) => target,
+            ref term => panic!("unexpected terminator {term}"),
+        };
+        assert_ne!(preheader, header);
+        let block = &program.block[&preheader];
+        assert!(matches!(block.term, Terminator::Jump(target) if target == header));
+        let hoisted: Vec<String> = block.insn.iter().map(|i| i.to_string()).collect();
+        assert!(hoisted.contains(&"_t_1 = $arith lt a b".to_string()));
+        // `n` changes in each iteration
+        assert!(!hoisted.iter().any(|i| i.contains("sub")));
+        assert!(!program.block[&id("lbl2")]
+            .insn
+            .iter()
+            .any(|i| i.to_string().contains("lt")));
+        assert_eq!(run_program(&program, "6\n7\n3\n"), before);
+    }
+
+    #[test]
+    fn hoist_keeps_variants() {
+        // `x` is read at the header, and `y` after the loop
+        let src = "$read n := x 0 := y 0 $while n {$print x := x 5 := y 6 := n - n 1} $print y";
+        let mut program = lower_src(src);
+        let before = run_program(&program, "2");
+        hoist_invariants(&mut program);
+        let body = &program.block[&id("lbl2")].insn;
+        assert!(body.iter().any(|i| i.def() == Some(id("x"))));
+        assert!(body.iter().any(|i| i.def() == Some(id("y"))));
+        assert_eq!(run_program(&program, "2"), before);
+        assert_eq!(run_program(&program, "0"), "0\n");
+    }
+
+    #[test]
+    fn hoist_keeps_failing_arithmetic() {
+        // the loop may not run at all, so the division must stay in it, and so
+        // must the multiplication, which may overflow in checked mode
+        let src = "$read a $read b $read n $while n {$print / a b $print * a b := n - n 1}";
+        let mut program = lower_src(src);
+        hoist_invariants(&mut program);
+        for op in ["div", "mul"] {
+            assert!(
+                program.block[&id("lbl2")]
+                    .insn
+                    .iter()
+                    .any(|i| i.to_string().contains(op)),
+                "{op} was hoisted"
+            );
+        }
+        assert_eq!(run_program(&program, "1\n0\n0\n"), "");
+    }
+
+    #[test]
+    fn nops() {
+        let mut program = lower_src("$read x $print + x 1 $print x");
+        // delete the first print in place
+        let insn = &mut program.block.get_mut(&id("entry")).unwrap().insn;
+        let print = insn
+            .iter()
+            .position(|i| matches!(i, Instruction::Print(_)))
+            .unwrap();
+        insn[print] = Instruction::Nop;
+        assert_eq!(run_program(&program, "5\n"), "5\n");
+        remove_nops(&mut program);
+        let insn = &program.block[&id("entry")].insn;
+        assert!(!insn.iter().any(|i| matches!(i, Instruction::Nop)));
+        assert_eq!(insn.len(), 4);
+        assert_eq!(run_program(&program, "5\n"), "5\n");
+    }
+}
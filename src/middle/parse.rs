@@ -0,0 +1,652 @@
+//! A parser for the textual form of tiny IR (see `doc/ir.md`), which is the
+//! format the IR is printed in.  This allows writing IR programs by hand.
+
+use super::*;
+use crate::common::*;
+use crate::front::ast::{BOp, Number};
+
+/// Parse a tiny IR program.  The error message contains the line the error is
+/// at.
+pub fn parse_tir(input: &str) -> Result<Program, String> {
+    let mut tokens = tokenize(input);
+    tokens.reverse();
+    let mut parser = Parser { tokens };
+    parser.parse_program()
+}
+
+// A token and the line it is on
+type Token<'a> = (&'a str, usize);
+
+// Split the input into words, string literals, and punctuation, dropping
+// whitespace and comments
+fn tokenize(input: &str) -> Vec<Token<'_>> {
+    let is_punct = |c| matches!(c, ',' | ';' | ':' | '=' | '(' | ')');
+    let mut tokens = vec![];
+    for (i, line) in input.lines().enumerate() {
+        let mut rest = line.trim_start();
+        while !rest.is_empty() && !rest.starts_with("//") {
+            let len = if rest.starts_with('"') {
+                string_len(rest)
+            } else if rest.starts_with(is_punct) {
+                1
+            } else {
+                rest.char_indices()
+                    .find(|&(pos, c)| {
+                        c.is_whitespace()
+                            || is_punct(c)
+                            || c == '"'
+                            || rest[pos..].starts_with("//")
+                    })
+                    .map_or(rest.len(), |(pos, _)| pos)
+            };
+            tokens.push((&rest[..len], i + 1));
+            rest = rest[len..].trim_start();
+        }
+    }
+    tokens
+}
+
+// The length of the string literal at the start of `s`.  An unterminated
+// literal runs until the end of `s`.
+fn string_len(s: &str) -> usize {
+    let mut chars = s.char_indices().skip(1);
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '"' => return pos + 1,
+            '\\' => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+struct Parser<'a> {
+    // rest of the input, ordered in reverse
+    tokens: Vec<Token<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.last().map(|(t, _)| *t)
+    }
+
+    fn next(&mut self) -> Result<Token<'a>, String> {
+        self.tokens
+            .pop()
+            .ok_or_else(|| "Unexpected end of input.".to_string())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            (t, _) if t == expected => Ok(()),
+            (t, line) => Err(format!("line {line}: expected `{expected}`, found `{t}`.")),
+        }
+    }
+
+    fn parse_id(&mut self) -> Result<Id, String> {
+        let (t, line) = self.next()?;
+        let mut chars = t.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if valid {
+            Ok(id(t))
+        } else {
+            Err(format!("line {line}: expected an identifier, found `{t}`."))
+        }
+    }
+
+    fn parse_num(&mut self) -> Result<Number, String> {
+        let (t, line) = self.next()?;
+        t.parse()
+            .map_err(|_| format!("line {line}: expected a number, found `{t}`."))
+    }
+
+    fn parse_str(&mut self) -> Result<String, String> {
+        let (t, line) = self.next()?;
+        if !t.starts_with('"') {
+            return Err(format!("line {line}: expected a string, found `{t}`."));
+        }
+        unquote(t).map_err(|msg| format!("line {line}: {msg}"))
+    }
+
+    fn parse_bop(&mut self) -> Result<BOp, String> {
+        use BOp::*;
+        let (t, line) = self.next()?;
+        Ok(match t {
+            "mul" => Mul,
+            "div" => Div,
+            "mod" => Mod,
+            "pow" => Pow,
+            "add" => Add,
+            "sub" => Sub,
+            "lt" => Lt,
+            "gt" => Gt,
+            "eq" => Eq,
+            "le" => Le,
+            "ge" => Ge,
+            "ne" => Ne,
+            "and" => And,
+            "or" => Or,
+            "min" => Min,
+            "max" => Max,
+            "shl" => Shl,
+            "shr" => Shr,
+            "band" => BitAnd,
+            "bor" => BitOr,
+            "bxor" => BitXor,
+            _ => return Err(format!("line {line}: expected an operator, found `{t}`.")),
+        })
+    }
+
+    fn parse_program(&mut self) -> Result<Program, String> {
+        let mut program = self.parse_body()?;
+        while let Some(&(_, line)) = self.tokens.last() {
+            self.expect("$fn")?;
+            let name = self.parse_id()?;
+            let params = self.parse_list()?;
+            let body = self.parse_body()?;
+            if program
+                .funcs
+                .insert(name, Function { params, body })
+                .is_some()
+            {
+                return Err(format!(
+                    "line {line}: the function `{name}` is defined twice."
+                ));
+            }
+        }
+        Ok(program)
+    }
+
+    // Parse a parenthesized, comma-separated list of identifiers
+    fn parse_list(&mut self) -> Result<Vec<Id>, String> {
+        let mut items = vec![];
+        self.expect("(")?;
+        if self.peek() != Some(")") {
+            items.push(self.parse_id()?);
+            while self.peek() == Some(",") {
+                self.next()?;
+                items.push(self.parse_id()?);
+            }
+        }
+        self.expect(")")?;
+        Ok(items)
+    }
+
+    // Parse the `(lbl: x, ...)` part of a phi node
+    fn parse_phi_srcs(&mut self) -> Result<Vec<(Id, Id)>, String> {
+        let mut srcs = vec![];
+        self.expect("(")?;
+        while self.peek() != Some(")") {
+            if !srcs.is_empty() {
+                self.expect(",")?;
+            }
+            let lbl = self.parse_id()?;
+            self.expect(":")?;
+            srcs.push((lbl, self.parse_id()?));
+        }
+        self.expect(")")?;
+        Ok(srcs)
+    }
+
+    // Parse the `(n: lbl, ...)` part of a switch
+    fn parse_cases(&mut self) -> Result<Vec<(i64, Id)>, String> {
+        let mut cases = vec![];
+        self.expect("(")?;
+        while self.peek() != Some(")") {
+            if !cases.is_empty() {
+                self.expect(",")?;
+            }
+            let (t, line) = self.next()?;
+            let n = t
+                .parse()
+                .map_err(|_| format!("line {line}: expected an integer, found `{t}`."))?;
+            self.expect(":")?;
+            cases.push((n, self.parse_id()?));
+        }
+        self.expect(")")?;
+        Ok(cases)
+    }
+
+    // Parse the declarations and the blocks of the main program or a function
+    fn parse_body(&mut self) -> Result<Program, String> {
+        self.expect("let")?;
+        let mut decl = Set::new();
+        if self.peek() != Some(";") {
+            decl.insert(self.parse_id()?);
+            while self.peek() == Some(",") {
+                self.next()?;
+                decl.insert(self.parse_id()?);
+            }
+        }
+        self.expect(";")?;
+
+        let mut block = Map::new();
+        while let Some(&(t, line)) = self.tokens.last() {
+            if t == "$fn" {
+                break;
+            }
+            let lbl = self.parse_id()?;
+            self.expect(":")?;
+            if block.insert(lbl, self.parse_block()?).is_some() {
+                return Err(format!("line {line}: the block `{lbl}` is defined twice."));
+            }
+        }
+
+        Ok(Program {
+            decl,
+            block,
+            funcs: Map::new(),
+        })
+    }
+
+    fn parse_block(&mut self) -> Result<Block, String> {
+        let mut insn = vec![];
+        loop {
+            let term = match self.peek() {
+                Some("$exit") => {
+                    self.next()?;
+                    Terminator::Exit
+                }
+                Some("$jump") => {
+                    self.next()?;
+                    Terminator::Jump(self.parse_id()?)
+                }
+                Some("$return") => {
+                    self.next()?;
+                    Terminator::Return(self.parse_id()?)
+                }
+                Some("$branch") => {
+                    self.next()?;
+                    let guard = self.parse_id()?;
+                    let tt = self.parse_id()?;
+                    let ff = self.parse_id()?;
+                    Terminator::Branch { guard, tt, ff }
+                }
+                Some("$switch") => {
+                    self.next()?;
+                    let scrutinee = self.parse_id()?;
+                    let cases = self.parse_cases()?;
+                    let default = self.parse_id()?;
+                    Terminator::Switch {
+                        scrutinee,
+                        cases,
+                        default,
+                    }
+                }
+                _ => {
+                    insn.push(self.parse_insn()?);
+                    continue;
+                }
+            };
+            return Ok(Block { insn, term });
+        }
+    }
+
+    fn parse_insn(&mut self) -> Result<Instruction, String> {
+        match self.peek() {
+            Some("$nop") => {
+                self.next()?;
+                return Ok(Instruction::Nop);
+            }
+            Some("$read") => {
+                self.next()?;
+                return Ok(Instruction::Read(self.parse_id()?));
+            }
+            Some("$assert") => {
+                self.next()?;
+                let x = self.parse_id()?;
+                return Ok(Instruction::Assert(x, self.parse_str()?));
+            }
+            Some(cmd @ ("$print" | "$printc")) => {
+                let newline = cmd == "$print";
+                self.next()?;
+                if self.peek().is_some_and(|t| t.starts_with('"')) {
+                    let (t, line) = self.next()?;
+                    let s = unquote(t).map_err(|msg| format!("line {line}: {msg}"))?;
+                    return Ok(if newline {
+                        Instruction::PrintStr(s)
+                    } else {
+                        Instruction::PrintStrNoNl(s)
+                    });
+                }
+                let x = self.parse_id()?;
+                return Ok(if newline {
+                    Instruction::Print(x)
+                } else {
+                    Instruction::PrintNoNl(x)
+                });
+            }
+            _ => {}
+        }
+
+        let dst = self.parse_id()?;
+        self.expect("=")?;
+        match self.next()? {
+            ("$copy", _) => Ok(Instruction::Copy {
+                dst,
+                src: self.parse_id()?,
+            }),
+            ("$const", _) => Ok(Instruction::Const {
+                dst,
+                src: self.parse_num()?,
+            }),
+            ("$arith", _) => Ok(Instruction::Arith {
+                op: self.parse_bop()?,
+                dst,
+                lhs: self.parse_id()?,
+                rhs: self.parse_id()?,
+            }),
+            ("$neg", _) => Ok(Instruction::Neg {
+                dst,
+                src: self.parse_id()?,
+            }),
+            ("$call", _) => Ok(Instruction::Call {
+                dst,
+                func: self.parse_id()?,
+                args: self.parse_list()?,
+            }),
+            ("$phi", _) => Ok(Instruction::Phi {
+                dst,
+                srcs: self.parse_phi_srcs()?,
+            }),
+            (t, line) => Err(format!(
+                "line {line}: expected an instruction, found `{t}`."
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{lower, parse};
+
+    #[test]
+    fn round_trip() {
+        let programs = [
+            "",
+            "$print 0",
+            "$read x $print + x 3",
+            "$print ~ 5 $print % / * x y z - a b",
+            "$if < x y {$print x} {$print y}",
+            "$if >= x y {$if != x 3 {} {:= y = x 2}} {$print <= x 7}",
+            "$while x {$if y {$read x} {:= x - x 1}} $print > x 0",
+            "$print + x 2.5 $print / 1.0 0.0 $print ~ 0.0",
+            r#"$print "" $print "a, b = c; // d" $print "\"quoted\"\n\\""#,
+            "$fn f(x, y) {:= f + x y} $fn g() {$print 1} $print f(g(), 2)",
+        ];
+        for src in programs {
+            let program = lower(parse(src).unwrap());
+            let printed = program.to_string();
+            let reparsed = parse_tir(&printed).unwrap();
+            assert_eq!(
+                reparsed.to_string(),
+                printed,
+                "round trip failed for {src:?}"
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let src = "$fn f(x) {:= f * x 2.5} $read y $if < y 3 {$print \"small\"} {$print f(~ y)} \
+                   $print / 1.0 0.0 / 0.0 0.0";
+        let program = lower(parse(src).unwrap());
+        let json = serde_json::to_string(&program).unwrap();
+        let reparsed: Program = serde_json::from_str(&json).unwrap();
+        assert_eq!(reparsed.to_string(), program.to_string());
+        // the blocks are keyed by their labels
+        assert!(
+            json.contains(r#""block":{"entry":{"insn":["#),
+            "unexpected JSON: {json}"
+        );
+    }
+
+    #[test]
+    fn hand_written() {
+        let src = "
+            let i, n , one;
+            // comments are allowed
+            entry:
+                one = $const 1
+                $read n
+                $jump loop
+            loop: $branch n body end
+            body:
+                i=$arith add i one // so is no whitespace around `=`
+                n = $arith sub n one
+                $jump loop
+            end:
+                $print i $exit
+        ";
+        let program = parse_tir(src).unwrap();
+        assert_eq!(program.decl.len(), 3);
+        assert_eq!(program.block.len(), 4);
+        assert_eq!(program.block[&id("body")].insn.len(), 2);
+        assert!(matches!(
+            program.block[&id("loop")].term,
+            Terminator::Branch { guard, .. } if guard == id("n")
+        ));
+
+        let mut output = vec![];
+        crate::middle::interp::run(&program, &b"4\n"[..], &mut output).unwrap();
+        assert_eq!(output, b"4\n");
+    }
+
+    #[test]
+    fn functions() {
+        let src = "
+            let r, x, ten;
+            entry:
+                ten = $const 10
+                x = $call square(ten)
+                r = $call zero()
+                $print x
+                $exit
+            $fn square(x)
+            let square, x;
+            entry:
+                square = $arith mul x x
+                $return square
+            $fn zero() let z; entry: $return z
+        ";
+        let program = parse_tir(src).unwrap();
+        assert_eq!(program.funcs.len(), 2);
+        assert_eq!(program.funcs[&id("square")].params, vec![id("x")]);
+        assert!(matches!(
+            &program.block[&id("entry")].insn[1],
+            Instruction::Call { func, args, .. } if *func == id("square") && *args == vec![id("ten")]
+        ));
+
+        let mut output = vec![];
+        crate::middle::interp::run(&program, &b""[..], &mut output).unwrap();
+        assert_eq!(output, b"100\n");
+    }
+
+    #[test]
+    fn phi() {
+        let src = "
+            let c, x_1, x_2, x_3;
+            entry: $read c $branch c a b
+            a: x_1 = $const 1 $jump join
+            b: x_2 = $const 2 $jump join
+            join:
+                x_3 = $phi(a: x_1, b: x_2)
+                $print x_3
+                $exit
+        ";
+        let program = parse_tir(src).unwrap();
+        assert!(matches!(
+            &program.block[&id("join")].insn[0],
+            Instruction::Phi { srcs, .. } if *srcs == vec![(id("a"), id("x_1")), (id("b"), id("x_2"))]
+        ));
+        assert!(program
+            .to_string()
+            .contains("    x_3 = $phi(a: x_1, b: x_2)\n"));
+        assert_eq!(
+            parse_tir(&program.to_string()).unwrap().to_string(),
+            program.to_string()
+        );
+
+        for (input, output) in [("1\n", b"1\n"), ("0\n", b"2\n")] {
+            let mut out = vec![];
+            crate::middle::interp::run(&program, input.as_bytes(), &mut out).unwrap();
+            assert_eq!(out, output);
+        }
+    }
+
+    #[test]
+    fn switch() {
+        let src = "let x; entry: $read x $switch x (1: a, -2: entry) a a: $exit";
+        let program = parse_tir(src).unwrap();
+        assert!(matches!(
+            &program.block[&id("entry")].term,
+            Terminator::Switch { scrutinee, cases, default }
+                if *scrutinee == id("x") && *cases == vec![(1, id("a")), (-2, id("entry"))]
+                    && *default == id("a")
+        ));
+        assert!(program
+            .to_string()
+            .contains("    $switch x (1: a, -2: entry) a\n"));
+        assert_eq!(
+            parse_tir(&program.to_string()).unwrap().to_string(),
+            program.to_string()
+        );
+        assert!(parse_tir("let x; entry: $switch x (1.5: entry) entry").is_err());
+        assert!(parse_tir("let x; entry: $switch x () entry").is_ok());
+    }
+
+    #[test]
+    fn assert() {
+        let program = parse_tir(r#"let x; entry: $assert x "1:1: `$assert x`" $exit"#).unwrap();
+        assert_eq!(
+            program.block[&id("entry")].insn,
+            [Instruction::Assert(id("x"), "1:1: `$assert x`".to_string())]
+        );
+        assert_eq!(
+            parse_tir(&program.to_string()).unwrap().to_string(),
+            program.to_string()
+        );
+        assert!(parse_tir("let x; entry: $assert $exit").is_err());
+        assert!(parse_tir("let x; entry: $assert x $exit").is_err());
+    }
+
+    #[test]
+    fn negative_constants() {
+        let program = parse_tir("let x; entry: x = $const -42 $print x $exit").unwrap();
+        assert!(matches!(
+            program.block[&id("entry")].insn[0],
+            Instruction::Const {
+                src: Number::Int(-42),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn floats() {
+        let src =
+            "let x; entry: x = $const 2.5 x = $const -0.0 x = $const 3.0 x = $const inf $exit";
+        let program = parse_tir(src).unwrap();
+        let insn = &program.block[&id("entry")].insn;
+        let consts: Vec<_> = insn
+            .iter()
+            .map(|i| match i {
+                Instruction::Const { src, .. } => *src,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(consts, [2.5, -0.0, 3.0, f64::INFINITY].map(Number::Float));
+        assert_eq!(
+            parse_tir(&program.to_string()).unwrap().to_string(),
+            program.to_string()
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let src = r#"
+            let; entry:
+                $print "hi, there"// comment
+                $print "\"a\" // b"
+            $exit
+        "#;
+        let program = parse_tir(src).unwrap();
+        let insn = &program.block[&id("entry")].insn;
+        assert!(matches!(&insn[0], Instruction::PrintStr(s) if s == "hi, there"));
+        assert!(matches!(&insn[1], Instruction::PrintStr(s) if s == "\"a\" // b"));
+    }
+
+    #[test]
+    fn errors() {
+        let errors = [
+            ("", "Unexpected end of input."),
+            ("x;", "line 1: expected `let`, found `x`."),
+            ("let x y;", "line 1: expected `;`, found `y`."),
+            ("let x,;", "line 1: expected an identifier, found `;`."),
+            ("let;\nentry:\n$print x", "Unexpected end of input."),
+            ("let;\nentry $exit", "line 2: expected `:`, found `$exit`."),
+            (
+                "let;\nentry:\n  x = $foo y",
+                "line 3: expected an instruction, found `$foo`.",
+            ),
+            (
+                "let;\nentry:\n  x = $arith frob y z",
+                "line 3: expected an operator, found `frob`.",
+            ),
+            (
+                "let;\nentry:\n  x = $const 1.5.2",
+                "line 3: expected a number, found `1.5.2`.",
+            ),
+            (
+                "let;\n1abc: $exit",
+                "line 2: expected an identifier, found `1abc`.",
+            ),
+            (
+                "let;\nentry:\n  $print \"abc",
+                "line 3: Unterminated string literal.",
+            ),
+            (
+                "let;\nentry:\n  $print \"a\\tb\"",
+                "line 3: Unknown escape sequence `\\t` in a string.",
+            ),
+            (
+                "let;\na: $exit\na: $exit",
+                "line 3: the block `a` is defined twice.",
+            ),
+            (
+                "let; entry: $exit\n$fn f() let; entry: $exit\n$fn f() let; entry: $exit",
+                "line 3: the function `f` is defined twice.",
+            ),
+            (
+                "let x; entry: x = $phi(a x) $exit",
+                "line 1: expected `:`, found `x`.",
+            ),
+            (
+                "let x; entry: x = $phi(a: x b: x) $exit",
+                "line 1: expected `,`, found `b`.",
+            ),
+            (
+                "let x; entry: x = $call f(x y) $exit",
+                "line 1: expected `)`, found `y`.",
+            ),
+            (
+                "let; entry: $exit $fn f(1) let; entry: $exit",
+                "line 1: expected an identifier, found `1`.",
+            ),
+        ];
+        for (src, expected) in errors {
+            assert_eq!(
+                parse_tir(src).unwrap_err(),
+                expected,
+                "wrong error for {src:?}"
+            );
+        }
+    }
+}
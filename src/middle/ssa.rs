@@ -0,0 +1,352 @@
+//! Construction of static single assignment (SSA) form, where each variable
+//! is written to by exactly one instruction.
+
+use super::dom::{dominance_frontiers, dominators};
+use super::opt::remove_unreachable;
+use super::*;
+use crate::common::*;
+
+/// Convert the main program and the functions into SSA form.
+///
+/// Each variable `x` is split into the versions `x_0`, `x_1`, ..., where `x_0`
+/// is the initial value (0, or the argument for a parameter), and phi nodes
+/// merge the versions at the blocks where different versions meet.  Only live
+/// variables get phi nodes (this is pruned SSA).  The unreachable blocks are
+/// removed first.  If the `entry` block is a jump target, its code moves to a
+/// new block that a new, empty `entry` block jumps to.
+pub fn to_ssa(program: &mut Program) {
+    to_ssa_body(program);
+    for func in program.funcs.values_mut() {
+        to_ssa_body(&mut func.body);
+        for x in &mut func.params {
+            *x = version(*x, 0);
+        }
+    }
+}
+
+// The name of the given version of a variable.  Every variable gets a version
+// number, so these cannot clash: the number after the last `_` is the version.
+fn version(x: Id, n: usize) -> Id {
+    id(&format!("{x}_{n}"))
+}
+
+fn to_ssa_body(program: &mut Program) {
+    remove_unreachable(program);
+    let entry = id(ENTRY_LABEL);
+    if !program.block.contains_key(&entry) {
+        return;
+    }
+    // the entry block sets the initial versions, so nothing may jump back to
+    // it.  If something does, the entry block's code moves to a block of its
+    // own, and a new, empty entry block jumps to that.
+    if !program.predecessors(entry).is_empty() {
+        let start = program.fresh_label();
+        let block = program.block.remove(&entry).unwrap();
+        program.block.insert(start, block);
+        for block in program.block.values_mut() {
+            block
+                .term
+                .map_targets(|target| if target == entry { start } else { target });
+        }
+        program.block.insert(
+            entry,
+            Block {
+                insn: vec![],
+                term: Terminator::Jump(start),
+            },
+        );
+    }
+
+    let idom = dominators(program);
+    let df = dominance_frontiers(program, &idom);
+    let live = liveness(program);
+
+    let mut preds: Map<Id, Vec<Id>> = Map::new();
+    for (lbl, block) in &program.block {
+        for succ in block.term.successors() {
+            if !preds.get(&succ).is_some_and(|p| p.contains(lbl)) {
+                preds.entry(succ).or_default().push(*lbl);
+            }
+        }
+    }
+
+    // the blocks that write to each variable.  The entry block defines all of
+    // them, because it sets their initial values.
+    let mut defsites: Map<Id, Set<Id>> = program
+        .decl
+        .iter()
+        .map(|x| (*x, Set::from([entry])))
+        .collect();
+    for (lbl, block) in &program.block {
+        for x in block.insn.iter().filter_map(|i| i.def()) {
+            defsites.entry(x).or_default().insert(*lbl);
+        }
+    }
+
+    // insert the phi nodes, and remember which variable each of them is for
+    let mut phi_vars: Map<Id, Vec<Id>> = Map::new();
+    for (x, sites) in defsites {
+        let mut work: Vec<Id> = sites.iter().copied().collect();
+        let mut has_phi = Set::new();
+        while let Some(lbl) = work.pop() {
+            for &frontier in &df[&lbl] {
+                if !live[&frontier].live_in.contains(&x) || !has_phi.insert(frontier) {
+                    continue;
+                }
+                let srcs = preds[&frontier].iter().map(|pred| (*pred, x)).collect();
+                let block = program.block.get_mut(&frontier).unwrap();
+                block.insn.insert(0, Instruction::Phi { dst: x, srcs });
+                phi_vars.entry(frontier).or_default().insert(0, x);
+                if !sites.contains(&frontier) {
+                    work.push(frontier);
+                }
+            }
+        }
+    }
+
+    let mut children: Map<Id, Vec<Id>> = Map::new();
+    for (&lbl, &parent) in &idom {
+        if lbl != parent {
+            children.entry(parent).or_default().push(lbl);
+        }
+    }
+
+    let mut renamer = Renamer {
+        phi_vars,
+        children,
+        stacks: program
+            .decl
+            .iter()
+            .map(|x| (*x, vec![version(*x, 0)]))
+            .collect(),
+        counts: program.decl.iter().map(|x| (*x, 1)).collect(),
+    };
+    renamer.rename(program, entry);
+
+    program.decl = program
+        .block
+        .values()
+        .flat_map(|b| {
+            b.insn
+                .iter()
+                .flat_map(|i| i.uses().into_iter().chain(i.def()))
+                .chain(b.term.uses())
+        })
+        .chain(program.decl.iter().map(|x| version(*x, 0)))
+        .collect();
+}
+
+// The state of renaming the variables to their versions
+struct Renamer {
+    // the original variable of each phi node in each block
+    phi_vars: Map<Id, Vec<Id>>,
+    // the children of each block in the dominator tree
+    children: Map<Id, Vec<Id>>,
+    // the versions of each variable that are visible, the current one is on
+    // top
+    stacks: Map<Id, Vec<Id>>,
+    // the number of versions of each variable so far
+    counts: Map<Id, usize>,
+}
+
+impl Renamer {
+    // Rename the variables in the block and the blocks it dominates
+    fn rename(&mut self, program: &mut Program, lbl: Id) {
+        let mut defined = vec![];
+        let block = program.block.get_mut(&lbl).unwrap();
+        for i in &mut block.insn {
+            if !matches!(i, Instruction::Phi { .. }) {
+                i.map_uses(|x| self.current(x));
+            }
+            if let Some(dst) = i.def() {
+                let new = self.fresh(dst);
+                set_def(i, new);
+                defined.push(dst);
+            }
+        }
+        block.term.map_uses(|x| self.current(x));
+
+        // the phi nodes in the successors read the current versions
+        for succ in block.term.successors() {
+            let vars = self.phi_vars.get(&succ).cloned().unwrap_or_default();
+            let succ = program.block.get_mut(&succ).unwrap();
+            for (i, x) in succ.insn.iter_mut().zip(vars) {
+                let Instruction::Phi { srcs, .. } = i else {
+                    unreachable!("the phi nodes come first");
+                };
+                for (pred, src) in srcs {
+                    if *pred == lbl {
+                        *src = self.current(x);
+                    }
+                }
+            }
+        }
+
+        for child in self.children.get(&lbl).cloned().unwrap_or_default() {
+            self.rename(program, child);
+        }
+        for x in defined {
+            self.stacks.get_mut(&x).unwrap().pop();
+        }
+    }
+
+    // The current version of the variable
+    fn current(&self, x: Id) -> Id {
+        self.stacks
+            .get(&x)
+            .and_then(|s| s.last())
+            .copied()
+            .unwrap_or_else(|| version(x, 0))
+    }
+
+    // Create the next version of the variable, and make it current
+    fn fresh(&mut self, x: Id) -> Id {
+        let count = self.counts.entry(x).or_default();
+        let new = version(x, *count);
+        *count += 1;
+        self.stacks.entry(x).or_default().push(new);
+        new
+    }
+}
+
+// Change the variable the instruction writes to
+fn set_def(insn: &mut Instruction, new: Id) {
+    use Instruction::*;
+    match insn {
+        Copy { dst, .. }
+        | Const { dst, .. }
+        | Arith { dst, .. }
+        | Neg { dst, .. }
+        | Read(dst)
+        | Call { dst, .. }
+        | Phi { dst, .. } => *dst = new,
+        Print(_) | PrintStr(_) | PrintNoNl(_) | PrintStrNoNl(_) | Assert(..) | Nop => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::parse_tir;
+    use crate::middle::test_util::{lower_src, run_program};
+
+    // SECTION: helpers
+
+    // Parse and lower a program, and convert it to SSA form
+    fn ssa_src(src: &str) -> Program {
+        let mut program = lower_src(src);
+        to_ssa(&mut program);
+        assert_eq!(verify(&program), Ok(()), "ill-formed SSA:\n{program}");
+        program
+    }
+
+    // Check that each variable is written to at most once
+    fn assert_single_assignment(program: &Program) {
+        let mut defined = Set::new();
+        for insn in program.block.values().flat_map(|b| &b.insn) {
+            if let Some(x) = insn.def() {
+                assert!(defined.insert(x), "{x} is written to twice in\n{program}");
+            }
+        }
+    }
+
+    // SECTION: tests
+
+    #[test]
+    fn if_join() {
+        let program = ssa_src("$read c $if c {:= x 1} {:= x 2} $print x");
+        assert_single_assignment(&program);
+        let join = &program.block[&id("lbl3")];
+        assert!(
+            matches!(
+                &join.insn[0],
+                Instruction::Phi { dst, srcs } if *dst == id("x_3")
+                    && *srcs == vec![(id("lbl1"), id("x_1")), (id("lbl2"), id("x_2"))]
+            ),
+            "unexpected join block:\n{join}"
+        );
+        assert!(matches!(join.insn[1], Instruction::Print(x) if x == id("x_3")));
+        assert_eq!(run_program(&program, "1\n"), "1\n");
+        assert_eq!(run_program(&program, "0\n"), "2\n");
+    }
+
+    #[test]
+    fn no_phi_for_dead_variables() {
+        // `x` is not read after the `$if`, and `c` is not written to in it
+        let program = ssa_src("$read c $if c {:= x 1} {:= x 2} $print c");
+        assert!(program
+            .block
+            .values()
+            .flat_map(|b| &b.insn)
+            .all(|i| !matches!(i, Instruction::Phi { .. })));
+    }
+
+    #[test]
+    fn one_armed_if() {
+        // the initial value comes from the entry block
+        let program = ssa_src("$read c $if c {:= x 1} $print x");
+        assert_single_assignment(&program);
+        assert_eq!(run_program(&program, "1\n"), "1\n");
+        assert_eq!(run_program(&program, "0\n"), "0\n");
+    }
+
+    #[test]
+    fn loops() {
+        let src = "$read n := i 0 := sum 0 $while < i n {:= i + i 1 := sum + sum i} $print sum";
+        let program = ssa_src(src);
+        assert_single_assignment(&program);
+        let header = &program.block[&id("lbl1")];
+        let phis = header
+            .insn
+            .iter()
+            .filter(|i| matches!(i, Instruction::Phi { .. }))
+            .count();
+        // `i` and `sum` change in the loop, `n` does not
+        assert_eq!(phis, 2, "unexpected loop header:\n{header}");
+        assert_eq!(run_program(&program, "10\n"), "55\n");
+        assert_eq!(run_program(&program, "0\n"), "0\n");
+    }
+
+    #[test]
+    fn swap_in_loop() {
+        // the phi nodes read their sources at the same time
+        let src =
+            ":= a 1 := b 2 := n 3 $while n {:= t a := a b := b t := n - n 1} $print a $print b";
+        let program = ssa_src(src);
+        assert_single_assignment(&program);
+        assert_eq!(run_program(&program, ""), "2\n1\n");
+    }
+
+    #[test]
+    fn functions() {
+        let src = "$fn f(x) {$if x {:= f * x f(- x 1)} {:= f 1}} $read n $print f(n)";
+        let program = ssa_src(src);
+        let f = &program.funcs[&id("f")];
+        assert_eq!(f.params, vec![id("x_0")]);
+        assert_single_assignment(&f.body);
+        assert_eq!(run_program(&program, "5\n"), "120\n");
+    }
+
+    #[test]
+    fn optimize_after_ssa() {
+        let src = "$read c $if 1 {:= x c} {:= x 2} $while c {:= c - c 1 $print x} $print x";
+        let program = optimize(ssa_src(src));
+        assert_eq!(verify(&program), Ok(()), "ill-formed program:\n{program}");
+        assert_eq!(run_program(&program, "2\n"), "2\n2\n2\n");
+    }
+
+    #[test]
+    fn loop_to_entry() {
+        // the entry block counts, and it loops back to itself
+        let src = "let x, one, three, c; \
+                   entry: one = $const 1 three = $const 3 x = $arith add x one $print x \
+                   c = $arith lt x three $branch c entry done \
+                   done: $exit";
+        let mut program = parse_tir(src).unwrap();
+        to_ssa(&mut program);
+        assert_eq!(verify(&program), Ok(()), "ill-formed SSA:\n{program}");
+        assert_single_assignment(&program);
+        assert_eq!(program.predecessors(id(ENTRY_LABEL)), []);
+        assert_eq!(run_program(&program, ""), "1\n2\n3\n");
+    }
+}
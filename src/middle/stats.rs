@@ -0,0 +1,103 @@
+//! Statistics about the shape of a CFG, for getting a feel for how complex a
+//! program is.
+
+use std::fmt;
+
+use super::loops::find_loops;
+use super::*;
+use crate::common::*;
+
+/// Statistics about the CFG of the main program, and those of its functions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfgStats {
+    pub blocks: usize,
+    pub edges: usize,
+    /// The cyclomatic complexity `E - N + 2`, the number of independent paths
+    /// through the CFG.  Unreachable blocks make it smaller, even negative.
+    pub complexity: i64,
+    /// The number of natural loops (see [find_loops]).
+    pub loops: usize,
+    /// The block with the most instructions and their number, the first one
+    /// in the order of [Program::block_order] if there are several.
+    pub longest_block: (Id, usize),
+    pub funcs: Map<Id, CfgStats>,
+}
+
+/// Compute the statistics of the program's CFG and the CFGs of its functions.
+pub fn cfg_stats(program: &Program) -> CfgStats {
+    let blocks = program.block.len();
+    let edges = program.edges().len();
+    let mut longest_block = (id(ENTRY_LABEL), 0);
+    for lbl in program.block_order() {
+        let len = program.block[&lbl].insn.len();
+        if len > longest_block.1 {
+            longest_block = (lbl, len);
+        }
+    }
+    CfgStats {
+        blocks,
+        edges,
+        complexity: edges as i64 - blocks as i64 + 2,
+        loops: find_loops(program).len(),
+        longest_block,
+        funcs: program
+            .funcs
+            .iter()
+            .map(|(name, func)| (*name, cfg_stats(&func.body)))
+            .collect(),
+    }
+}
+
+impl fmt::Display for CfgStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "blocks: {}", self.blocks)?;
+        writeln!(f, "edges: {}", self.edges)?;
+        writeln!(f, "cyclomatic complexity: {}", self.complexity)?;
+        writeln!(f, "loops: {}", self.loops)?;
+        let (lbl, len) = self.longest_block;
+        writeln!(f, "longest block: {lbl} ({len} instructions)")?;
+
+        for (name, stats) in &self.funcs {
+            write!(f, "\n$fn {name}\n{stats}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{lower, parse};
+
+    // SECTION: tests
+
+    #[test]
+    fn straight_line() {
+        let stats = cfg_stats(&lower(parse("$read x $print + x 1").unwrap()));
+        assert_eq!(
+            stats,
+            CfgStats {
+                blocks: 1,
+                edges: 0,
+                complexity: 1,
+                loops: 0,
+                longest_block: (id("entry"), 4),
+                funcs: Map::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn branches_and_loops() {
+        let src = "$read x $while x {$if < x 0 {:= x + x 1} {:= x - x 1}} \
+                   $fn f(a) {$while a {$while a {:= a 0}}}";
+        let stats = cfg_stats(&lower(parse(src).unwrap()));
+        // each `$while` and `$if` adds a path
+        assert_eq!((stats.blocks, stats.edges), (7, 8));
+        assert_eq!((stats.complexity, stats.loops), (3, 1));
+        let f = &stats.funcs[&id("f")];
+        assert_eq!((f.complexity, f.loops), (3, 2));
+        assert!(stats.to_string().contains("\n$fn f\nblocks: "));
+    }
+}
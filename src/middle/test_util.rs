@@ -0,0 +1,17 @@
+//! Helpers shared by the tests of the middle end.
+
+use super::interp::run;
+use super::Program;
+use crate::front::{lower, parse};
+
+/// Parse and lower a program.
+pub fn lower_src(src: &str) -> Program {
+    lower(parse(src).unwrap())
+}
+
+/// Run a program with the given input and return its output.
+pub fn run_program(program: &Program, input: &str) -> String {
+    let mut output = vec![];
+    run(program, input.as_bytes(), &mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
@@ -3,27 +3,137 @@
 use std::fmt::Display;
 
 use crate::common::*;
-use crate::front::ast::BOp;
+use crate::front::ast::{BOp, Number};
 
-#[derive(Debug)]
+/// The label of the block where the main program and each function start.
+pub const ENTRY_LABEL: &str = "entry";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Program {
     pub decl: Set<Id>,
     pub block: Map<Id, Block>,
+    /// The functions the program defines.  These are empty for the bodies of
+    /// the functions themselves.
+    pub funcs: Map<Id, Function>,
+}
+
+/// A function.  Its body is a CFG of its own, starting at its own `entry`
+/// block, and its variables are local to each call.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Function {
+    pub params: Vec<Id>,
+    pub body: Program,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Block {
     pub insn: Vec<Instruction>,
     pub term: Terminator,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
-    Copy { dst: Id, src: Id },
-    Const { dst: Id, src: i64 },
-    Arith { op: BOp, dst: Id, lhs: Id, rhs: Id },
+    Copy {
+        dst: Id,
+        src: Id,
+    },
+    Const {
+        dst: Id,
+        src: Number,
+    },
+    Arith {
+        op: BOp,
+        dst: Id,
+        lhs: Id,
+        rhs: Id,
+    },
+    Neg {
+        dst: Id,
+        src: Id,
+    },
     Read(Id),
     Print(Id),
+    /// Print a string literal.
+    PrintStr(String),
+    /// Like [Instruction::Print] and [Instruction::PrintStr], but without a
+    /// newline after the value.
+    PrintNoNl(Id),
+    PrintStrNoNl(String),
+    /// Stop the program with an error if the value is zero, saying that the
+    /// assertion described by the string failed.
+    Assert(Id, String),
+    /// Call `func` with the values of `args`, and store its result in `dst`.
+    Call {
+        dst: Id,
+        func: Id,
+        args: Vec<Id>,
+    },
+    /// Copy to `dst` the variable that goes with the block control came from.
+    /// Each source is a predecessor's label and a variable.  Phi nodes come
+    /// before the other instructions of a block, and they all read their
+    /// sources before any of them writes to its destination.
+    Phi {
+        dst: Id,
+        srcs: Vec<(Id, Id)>,
+    },
+    /// Do nothing.  Passes can delete an instruction in place by replacing it
+    /// with this, and [crate::middle::remove_nops] cleans up afterwards.
+    Nop,
+}
+
+impl Instruction {
+    /// The variable this instruction writes to, if any.
+    pub fn def(&self) -> Option<Id> {
+        use Instruction::*;
+        match self {
+            Copy { dst, .. } | Const { dst, .. } | Arith { dst, .. } | Neg { dst, .. } => {
+                Some(*dst)
+            }
+            Read(dst) | Call { dst, .. } | Phi { dst, .. } => Some(*dst),
+            Print(_) | PrintStr(_) | PrintNoNl(_) | PrintStrNoNl(_) | Assert(..) | Nop => None,
+        }
+    }
+
+    /// The variables this instruction reads.
+    pub fn uses(&self) -> Vec<Id> {
+        use Instruction::*;
+        match self {
+            Copy { src, .. } | Neg { src, .. } => vec![*src],
+            Const { .. } | Read(_) | PrintStr(_) | PrintStrNoNl(_) | Nop => vec![],
+            Arith { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Print(src) | PrintNoNl(src) | Assert(src, _) => vec![*src],
+            Call { args, .. } => args.clone(),
+            Phi { srcs, .. } => srcs.iter().map(|(_, x)| *x).collect(),
+        }
+    }
+
+    /// Replace each variable this instruction reads with `f` applied to it.
+    pub fn map_uses(&mut self, mut f: impl FnMut(Id) -> Id) {
+        use Instruction::*;
+        match self {
+            Copy { src, .. } | Neg { src, .. } => *src = f(*src),
+            Const { .. } | Read(_) | PrintStr(_) | PrintStrNoNl(_) | Nop => {}
+            Arith { lhs, rhs, .. } => {
+                *lhs = f(*lhs);
+                *rhs = f(*rhs);
+            }
+            Print(src) | PrintNoNl(src) | Assert(src, _) => *src = f(*src),
+            Call { args, .. } => {
+                for arg in args {
+                    *arg = f(*arg);
+                }
+            }
+            Phi { srcs, .. } => {
+                for (_, x) in srcs {
+                    *x = f(*x);
+                }
+            }
+        }
+    }
 }
 
 impl Display for Instruction {
@@ -32,18 +142,129 @@ impl Display for Instruction {
         match self {
             Copy { dst, src } => write!(f, "{dst} = $copy {src}"),
             Const { dst, src } => write!(f, "{dst} = $const {src}"),
-            Arith { op, dst, lhs, rhs } => write!(f, "{dst} = $arith {op} {lhs} {rhs}"),
+            Arith { op, dst, lhs, rhs } => {
+                write!(f, "{dst} = $arith {} {lhs} {rhs}", op.mnemonic())
+            }
+            Neg { dst, src } => write!(f, "{dst} = $neg {src}"),
             Read(x) => write!(f, "$read {x}"),
             Print(x) => write!(f, "$print {x}"),
+            PrintStr(s) => write!(f, "$print {}", quote(s)),
+            PrintNoNl(x) => write!(f, "$printc {x}"),
+            PrintStrNoNl(s) => write!(f, "$printc {}", quote(s)),
+            Assert(x, s) => write!(f, "$assert {x} {}", quote(s)),
+            Nop => write!(f, "$nop"),
+            Call { dst, func, args } => {
+                let args = args.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+                write!(f, "{dst} = $call {func}({})", args.join(", "))
+            }
+            Phi { dst, srcs } => {
+                let srcs = srcs
+                    .iter()
+                    .map(|(lbl, x)| format!("{lbl}: {x}"))
+                    .collect::<Vec<_>>();
+                write!(f, "{dst} = $phi({})", srcs.join(", "))
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Terminator {
     Exit,
     Jump(Id),
-    Branch { guard: Id, tt: Id, ff: Id },
+    Branch {
+        guard: Id,
+        tt: Id,
+        ff: Id,
+    },
+    /// Return the value of given variable from a function.
+    Return(Id),
+    /// Jump to the block of the case whose value is equal to the value of
+    /// `scrutinee`, or to `default` if there is no such case.  The values of
+    /// the cases are different.
+    Switch {
+        scrutinee: Id,
+        cases: Vec<(i64, Id)>,
+        default: Id,
+    },
+}
+
+impl Terminator {
+    /// The variables this terminator reads.
+    pub fn uses(&self) -> Vec<Id> {
+        match self {
+            Terminator::Branch { guard, .. }
+            | Terminator::Return(guard)
+            | Terminator::Switch {
+                scrutinee: guard, ..
+            } => vec![*guard],
+            Terminator::Exit | Terminator::Jump(_) => vec![],
+        }
+    }
+
+    /// Replace each variable this terminator reads with `f` applied to it.
+    pub fn map_uses(&mut self, mut f: impl FnMut(Id) -> Id) {
+        if let Terminator::Branch { guard, .. }
+        | Terminator::Return(guard)
+        | Terminator::Switch {
+            scrutinee: guard, ..
+        } = self
+        {
+            *guard = f(*guard);
+        }
+    }
+
+    /// The blocks this terminator may jump to.
+    pub fn successors(&self) -> Vec<Id> {
+        match self {
+            Terminator::Exit | Terminator::Return(_) => vec![],
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { tt, ff, .. } => vec![*tt, *ff],
+            Terminator::Switch { cases, default, .. } => cases
+                .iter()
+                .map(|(_, target)| *target)
+                .chain([*default])
+                .collect(),
+        }
+    }
+
+    /// The block this terminator jumps to when the variable it tests has the
+    /// value `v`.  This is `None` for terminators that do not test a variable.
+    pub fn target(&self, v: Number) -> Option<Id> {
+        match self {
+            Terminator::Branch { tt, ff, .. } => Some(if v.is_true() { *tt } else { *ff }),
+            Terminator::Switch { cases, default, .. } => Some(
+                cases
+                    .iter()
+                    .find(|(n, _)| {
+                        BOp::Eq
+                            .eval(v, Number::Int(*n))
+                            .is_some_and(Number::is_true)
+                    })
+                    .map_or(*default, |(_, target)| *target),
+            ),
+            Terminator::Exit | Terminator::Jump(_) | Terminator::Return(_) => None,
+        }
+    }
+
+    /// Replace each block this terminator may jump to with `f` applied to it.
+    pub fn map_targets(&mut self, mut f: impl FnMut(Id) -> Id) {
+        match self {
+            Terminator::Exit | Terminator::Return(_) => {}
+            Terminator::Jump(target) => *target = f(*target),
+            Terminator::Branch { tt, ff, .. } => {
+                *tt = f(*tt);
+                *ff = f(*ff);
+            }
+            Terminator::Switch { cases, default, .. } => {
+                for (_, target) in cases {
+                    *target = f(*target);
+                }
+                *default = f(*default);
+            }
+        }
+    }
 }
 
 impl Display for Terminator {
@@ -53,26 +274,260 @@ impl Display for Terminator {
             Exit => write!(f, "$exit"),
             Jump(lbl) => write!(f, "$jump {lbl}"),
             Branch { guard, tt, ff } => write!(f, "$branch {guard} {tt} {ff}"),
+            Return(x) => write!(f, "$return {x}"),
+            Switch {
+                scrutinee,
+                cases,
+                default,
+            } => {
+                let cases: Vec<_> = cases.iter().map(|(n, lbl)| format!("{n}: {lbl}")).collect();
+                write!(f, "$switch {scrutinee} ({}) {default}", cases.join(", "))
+            }
         }
     }
 }
 
+impl Program {
+    /// The labels of the blocks in the order control flows through them: the
+    /// blocks that are reachable from `entry` in reverse postorder, where each
+    /// block comes before its successors except for the targets of back
+    /// edges, and the true branch comes before the false branch.  The
+    /// unreachable blocks come last, in the order of their labels.
+    pub fn block_order(&self) -> Vec<Id> {
+        let mut order = super::dom::search(self, true);
+        let reachable: Set<Id> = order.iter().copied().collect();
+        order.extend(self.block.keys().filter(|lbl| !reachable.contains(lbl)));
+        order
+    }
+
+    /// The edges of the CFG as (source, target) pairs, ordered by the source
+    /// label and then in the order of [Terminator::successors].
+    pub fn edges(&self) -> Vec<(Id, Id)> {
+        self.block
+            .iter()
+            .flat_map(|(&lbl, block)| block.term.successors().into_iter().map(move |s| (lbl, s)))
+            .collect()
+    }
+
+    /// The blocks the block `label` may jump to, or none if there is no such
+    /// block.
+    pub fn successors(&self, label: Id) -> Vec<Id> {
+        self.block
+            .get(&label)
+            .map_or(vec![], |block| block.term.successors())
+    }
+
+    /// The blocks that may jump to the block `label`, in label order.
+    pub fn predecessors(&self, label: Id) -> Vec<Id> {
+        self.block
+            .iter()
+            .filter(|(_, block)| block.term.successors().contains(&label))
+            .map(|(&lbl, _)| lbl)
+            .collect()
+    }
+
+    /// A block label of the form `lblN` that no block of the program has.
+    pub fn fresh_label(&self) -> Id {
+        (1..)
+            .map(|n| id(&format!("lbl{n}")))
+            .find(|lbl| !self.block.contains_key(lbl))
+            .unwrap()
+    }
+}
+
+/// The number of instructions in the program and its functions, not counting
+/// the terminators.
+pub fn instruction_count(program: &Program) -> usize {
+    let own: usize = program.block.values().map(|block| block.insn.len()).sum();
+    own + program
+        .funcs
+        .values()
+        .map(|func| instruction_count(&func.body))
+        .sum::<usize>()
+}
+
+/// The number of basic blocks in the program and its functions.
+pub fn block_count(program: &Program) -> usize {
+    program.block.len()
+        + program
+            .funcs
+            .values()
+            .map(|func| block_count(&func.body))
+            .sum::<usize>()
+}
+
+impl Display for Block {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for insn in &self.insn {
+            writeln!(f, "    {insn}")?;
+        }
+        writeln!(f, "    {}", self.term)
+    }
+}
+
 impl Display for Program {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "let ")?;
-        for x in &self.decl {
-            write!(f, "{x}, ")?;
+        write!(f, "let")?;
+        for (i, x) in self.decl.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{sep}{x}")?;
         }
-        writeln!(f)?;
+        writeln!(f, ";")?;
 
         for (lbl, block) in &self.block {
-            writeln!(f, "{lbl}:")?;
-            for insn in &block.insn {
-                writeln!(f, "    {insn}")?;
-            }
-            writeln!(f, "    {}", block.term)?;
+            write!(f, "{lbl}:\n{block}")?;
+        }
+
+        for (name, func) in &self.funcs {
+            let params = func.params.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+            write!(f, "\n$fn {name}({})\n{}", params.join(", "), func.body)?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::front::{lower, parse};
+
+    #[test]
+    fn display() {
+        let program = lower(parse("$read x $if < x 10 {$print ~ x} {:= y x} $print y").unwrap());
+        assert_eq!(
+            program.to_string(),
+            "\
+let _const_1, _t_2, _t_3, x, y;
+entry:
+    $read x
+    _const_1 = $const 10
+    _t_2 = $arith lt x _const_1
+    $branch _t_2 lbl1 lbl2
+lbl1:
+    _t_3 = $neg x
+    $print _t_3
+    $jump lbl3
+lbl2:
+    y = $copy x
+    $jump lbl3
+lbl3:
+    $print y
+    $exit
+"
+        );
+    }
+
+    #[test]
+    fn edges() {
+        let program = lower(parse("$read x $if < x 10 {$print ~ x} {:= y x} $print y").unwrap());
+        let [entry, lbl1, lbl2, lbl3] = ["entry", "lbl1", "lbl2", "lbl3"].map(id);
+        assert_eq!(
+            program.edges(),
+            vec![(entry, lbl1), (entry, lbl2), (lbl1, lbl3), (lbl2, lbl3)]
+        );
+        assert_eq!(program.successors(entry), vec![lbl1, lbl2]);
+        assert_eq!(program.successors(lbl1), vec![lbl3]);
+        assert_eq!(program.successors(lbl3), vec![]);
+        assert_eq!(program.successors(id("nowhere")), vec![]);
+        assert_eq!(program.predecessors(entry), vec![]);
+        assert_eq!(program.predecessors(lbl2), vec![entry]);
+        assert_eq!(program.predecessors(lbl3), vec![lbl1, lbl2]);
+    }
+
+    #[test]
+    fn equality() {
+        let src = "$fn f(a) {:= f * a a} $read x $while < x 10 {:= x f(+ x 1)} $print x";
+        let program = lower(parse(src).unwrap());
+        assert_eq!(program, lower(parse(src).unwrap()));
+        assert_eq!(program, program.clone());
+        let other = lower(parse("$read x $print x").unwrap());
+        assert_ne!(program, other);
+
+        // equal programs hash the same, so they can be deduplicated
+        let programs: std::collections::HashSet<Program> =
+            [program.clone(), other, program].into_iter().collect();
+        assert_eq!(programs.len(), 2);
+    }
+
+    #[test]
+    fn display_empty() {
+        assert_eq!(
+            lower(parse("").unwrap()).to_string(),
+            "let;\nentry:\n    $exit\n"
+        );
+        let program = lower(parse("").unwrap());
+        assert_eq!(
+            crate::middle::parse_tir(&program.to_string()).unwrap(),
+            program
+        );
+        assert_eq!(
+            Block {
+                insn: vec![Instruction::Print(id("x"))],
+                term: Terminator::Exit
+            }
+            .to_string(),
+            "    $print x\n    $exit\n"
+        );
+    }
+
+    #[test]
+    fn block_order() {
+        let order = |src| {
+            let program = lower(parse(src).unwrap());
+            program
+                .block_order()
+                .into_iter()
+                .map(|l| l.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            order("$read x $if x {$print 1} {$print 2} $print 3"),
+            ["entry", "lbl1", "lbl2", "lbl3"]
+        );
+        assert_eq!(
+            order("$read x $while x {:= x - x 1} $print x"),
+            ["entry", "lbl1", "lbl2", "lbl3"]
+        );
+        // the unreachable block after `$return` comes last
+        assert_eq!(
+            order("$read x $if x {$return} {} $print x"),
+            ["entry", "lbl1", "lbl2", "lbl3", "lbl4"]
+        );
+
+        // the labels sort `lbl10` before `lbl2`, but the order follows the
+        // control flow
+        let program =
+            lower(parse("$read x $if x {$if x {$if x {$print 1} {}} {}} {} $while x {}").unwrap());
+        let order = program.block_order();
+        assert_eq!(order.len(), program.block.len());
+        assert_eq!(
+            order[..4],
+            [id("entry"), id("lbl1"), id("lbl4"), id("lbl7")]
+        );
+        let index = |lbl| order.iter().position(|l| *l == lbl).unwrap();
+        assert!(index(id("lbl2")) < index(id("lbl10")));
+    }
+
+    #[test]
+    fn counts() {
+        let src = "
+            let x;
+            entry: $read x $branch x a b
+            a: x = $call f(x) $jump b
+            b: $print x $exit
+            $fn f(n)
+            let n, f;
+            entry: f = $const 1 f = $arith add f n $return f
+        ";
+        let program = crate::middle::parse_tir(src).unwrap();
+        assert_eq!(instruction_count(&program), 5);
+        assert_eq!(block_count(&program), 4);
+
+        // optimizing the program shrinks it
+        let program = lower(parse(":= x 2 := y * x 3 $if < y 10 {$print 1} {$print y}").unwrap());
+        let optimized = crate::middle::optimize(program.clone());
+        assert!(instruction_count(&optimized) < instruction_count(&program));
+        assert!(block_count(&optimized) < block_count(&program));
+    }
+}
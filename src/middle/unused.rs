@@ -0,0 +1,61 @@
+//! Warnings about variables whose values are never read.
+
+use super::*;
+use crate::common::*;
+
+/// The user variables of the CFG of the main program or a function that are
+/// declared but never read by any instruction or terminator, in order.  The
+/// temporaries that lowering creates start with `_`, so they are left out, and
+/// so are the user variables that start with `_` to say they are unused on
+/// purpose.
+pub fn unused_variables(program: &Program) -> Vec<Id> {
+    let mut read = Set::new();
+    for block in program.block.values() {
+        for insn in &block.insn {
+            read.extend(insn.uses());
+        }
+        read.extend(block.term.uses());
+    }
+    program
+        .decl
+        .iter()
+        .filter(|x| !x.starts_with('_') && !read.contains(x))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::test_util::lower_src;
+
+    // SECTION: tests
+
+    #[test]
+    fn unused() {
+        let program = lower_src(":= x 1 := y + x 2 $read z := w 3 $print y");
+        assert_eq!(unused_variables(&program), vec![id("w"), id("z")]);
+    }
+
+    #[test]
+    fn all_used() {
+        let program = lower_src("$read x $while x {:= x - x 1} $print x");
+        assert_eq!(unused_variables(&program), vec![]);
+        assert_eq!(unused_variables(&lower_src("")), vec![]);
+    }
+
+    #[test]
+    fn temporaries() {
+        // the temporaries are never reported, and neither is `_x`
+        let program = lower_src("$print + 1 * 2 3 := _x 4 := a 5");
+        assert_eq!(unused_variables(&program), vec![id("a")]);
+    }
+
+    #[test]
+    fn functions() {
+        let program = lower_src("$fn f(a, b) {:= c a := f 1} $print f(1, 2)");
+        assert_eq!(unused_variables(&program), vec![]);
+        let body = &program.funcs[&id("f")].body;
+        assert_eq!(unused_variables(body), vec![id("b"), id("c")]);
+    }
+}
@@ -0,0 +1,313 @@
+//! A checker for the well-formedness constraints of tiny IR programs (see
+//! `doc/ir.md`).
+
+use super::*;
+use crate::common::*;
+
+/// Check that the program is well-formed, and return all the violations if it
+/// is not.
+pub fn verify(program: &Program) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    verify_body(program, program, false, &mut errors);
+
+    for (name, func) in &program.funcs {
+        let mut func_errors = vec![];
+        for x in &func.params {
+            if !func.body.decl.contains(x) {
+                func_errors.push(format!("The parameter `{x}` is not declared."));
+            }
+        }
+        if !func.body.funcs.is_empty() {
+            func_errors.push("Functions cannot be nested.".to_string());
+        }
+        verify_body(program, &func.body, true, &mut func_errors);
+        errors.extend(
+            func_errors
+                .into_iter()
+                .map(|e| format!("In the function `{name}`: {e}")),
+        );
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// Check the CFG of the main program or a function.  Calls are resolved using
+// the functions of the main program.
+fn verify_body(main: &Program, program: &Program, in_func: bool, errors: &mut Vec<String>) {
+    if !program.block.contains_key(&id(ENTRY_LABEL)) {
+        errors.push(format!("There is no `{ENTRY_LABEL}` block."));
+    }
+
+    let mut preds: Map<Id, Set<Id>> = Map::new();
+    for (lbl, block) in &program.block {
+        for succ in block.term.successors() {
+            preds.entry(succ).or_default().insert(*lbl);
+        }
+    }
+
+    for (lbl, block) in &program.block {
+        let mut after_phis = false;
+        for insn in &block.insn {
+            if let Instruction::Phi { srcs, .. } = insn {
+                if after_phis {
+                    errors.push(format!(
+                        "The phi node `{insn}` in block `{lbl}` comes after other instructions."
+                    ));
+                }
+                let labels: Set<Id> = srcs.iter().map(|(pred, _)| *pred).collect();
+                if labels.len() != srcs.len() || Some(&labels) != preds.get(lbl) {
+                    errors.push(format!(
+                        "The phi node `{insn}` in block `{lbl}` does not have exactly one value for each predecessor."
+                    ));
+                }
+            } else {
+                after_phis = true;
+            }
+            if let Instruction::Call { func, args, .. } = insn {
+                match main.funcs.get(func) {
+                    None => errors.push(format!(
+                        "The instruction `{insn}` in block `{lbl}` calls the missing function `{func}`."
+                    )),
+                    Some(f) if f.params.len() != args.len() => errors.push(format!(
+                        "The instruction `{insn}` in block `{lbl}` passes {} arguments to `{func}`, which takes {}.",
+                        args.len(),
+                        f.params.len()
+                    )),
+                    Some(_) => {}
+                }
+            }
+            for x in insn.uses() {
+                if !program.decl.contains(&x) {
+                    errors.push(format!(
+                        "The instruction `{insn}` in block `{lbl}` reads the undeclared variable `{x}`."
+                    ));
+                }
+            }
+            if let Some(x) = insn.def() {
+                if !program.decl.contains(&x) {
+                    errors.push(format!(
+                        "The instruction `{insn}` in block `{lbl}` writes to the undeclared variable `{x}`."
+                    ));
+                }
+            }
+        }
+
+        let term = &block.term;
+        for x in term.uses() {
+            if !program.decl.contains(&x) {
+                errors.push(format!(
+                    "The terminator `{term}` of block `{lbl}` reads the undeclared variable `{x}`."
+                ));
+            }
+        }
+        for target in term.successors() {
+            if !program.block.contains_key(&target) {
+                errors.push(format!(
+                    "The terminator `{term}` of block `{lbl}` jumps to the missing block `{target}`."
+                ));
+            }
+        }
+        if let Terminator::Switch { cases, .. } = term {
+            let mut seen = Set::new();
+            for (n, _) in cases {
+                if !seen.insert(n) {
+                    errors.push(format!(
+                        "The terminator `{term}` of block `{lbl}` has the case `{n}` more than once."
+                    ));
+                }
+            }
+        }
+        if !in_func && matches!(term, Terminator::Return(_)) {
+            errors.push(format!(
+                "The terminator `{term}` of block `{lbl}` returns from the main program."
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middle::test_util::lower_src;
+
+    // SECTION: tests
+
+    #[test]
+    fn lowered_programs() {
+        let programs = [
+            "",
+            "$print 0",
+            "$read x $print + x 3",
+            "$if < x y {$print x} {$print y}",
+            "$while x {$if y {$read x} {:= x - x 1}}",
+            "$fn f(a, b) {:= f + a b} $fn g() {} $print f(g(), 1)",
+            "$read x $if x {$return} $print x $return $print y",
+            "$fn f(x) {$while x {$return := x 0}} $print f(1) $return",
+        ];
+        for src in programs {
+            assert_eq!(verify(&lower_src(src)), Ok(()), "{src:?} is ill-formed");
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let program = lower_src("");
+        assert_eq!(verify(&program), Ok(()));
+        assert_eq!(verify(&crate::middle::optimize(program.clone())), Ok(()));
+        let mut ssa = program;
+        crate::middle::to_ssa(&mut ssa);
+        assert_eq!(verify(&ssa), Ok(()));
+        // a function with an empty body only returns
+        assert_eq!(verify(&lower_src("$fn f() {}")), Ok(()));
+    }
+
+    #[test]
+    fn missing_entry() {
+        let mut program = lower_src("$print 0");
+        let block = program.block.remove(&id(ENTRY_LABEL)).unwrap();
+        program.block.insert(id("start"), block);
+        assert_eq!(
+            verify(&program),
+            Err(vec!["There is no `entry` block.".to_string()])
+        );
+
+        // each function needs one too
+        let mut program = lower_src("$fn f() {} $print f()");
+        let body = &mut program.funcs.get_mut(&id("f")).unwrap().body;
+        body.block.remove(&id(ENTRY_LABEL));
+        assert_eq!(
+            verify(&program),
+            Err(vec![
+                "In the function `f`: There is no `entry` block.".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn missing_targets() {
+        let mut program = lower_src("$if x {$print 0} {$print 1}");
+        program.block.remove(&id("lbl2"));
+        program.block.remove(&id("lbl3"));
+        let errors = verify(&program).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "The terminator `$branch x lbl1 lbl2` of block `entry` jumps to the missing block `lbl2`.",
+                "The terminator `$jump lbl3` of block `lbl1` jumps to the missing block `lbl3`.",
+            ]
+        );
+    }
+
+    #[test]
+    fn functions() {
+        let mut program = lower_src("$fn f(a) {:= f a} $print f(1) $print g(2)");
+        let f = program.funcs.get_mut(&id("f")).unwrap();
+        f.params.push(id("b"));
+        f.body.decl.remove(&id("a"));
+        program.block.get_mut(&id("entry")).unwrap().term = Terminator::Return(id("_t_2"));
+        let errors = verify(&program).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "The instruction `_t_2 = $call f(_const_1)` in block `entry` passes 1 arguments to `f`, which takes 2.",
+                "The instruction `_t_4 = $call g(_const_3)` in block `entry` calls the missing function `g`.",
+                "The terminator `$return _t_2` of block `entry` returns from the main program.",
+                "In the function `f`: The parameter `a` is not declared.",
+                "In the function `f`: The parameter `b` is not declared.",
+                "In the function `f`: The instruction `f = $copy a` in block `entry` reads the undeclared variable `a`.",
+            ]
+        );
+    }
+
+    #[test]
+    fn phi_nodes() {
+        let src = "
+            let c, x;
+            entry: $read c $branch c a b
+            a: x = $phi(entry: c) $print x $jump join
+            b: $jump join
+            join:
+                x = $phi(a: c, a: x)
+                $print x
+                x = $phi(a: c, b: c, entry: c)
+                $exit
+        ";
+        let errors = verify(&parse_tir(src).unwrap()).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "The phi node `x = $phi(a: c, a: x)` in block `join` does not have exactly one value for each predecessor.",
+                "The phi node `x = $phi(a: c, b: c, entry: c)` in block `join` comes after other instructions.",
+                "The phi node `x = $phi(a: c, b: c, entry: c)` in block `join` does not have exactly one value for each predecessor.",
+            ]
+        );
+    }
+
+    #[test]
+    fn switch() {
+        let src = "
+            let x;
+            entry: $switch x (1: a, 2: b, 1: a) c
+            a: $exit
+            b: $exit
+        ";
+        let errors = verify(&parse_tir(src).unwrap()).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "The terminator `$switch x (1: a, 2: b, 1: a) c` of block `entry` jumps to the missing block `c`.",
+                "The terminator `$switch x (1: a, 2: b, 1: a) c` of block `entry` has the case `1` more than once.",
+            ]
+        );
+    }
+
+    #[test]
+    fn undeclared_variables() {
+        let (x, y, z) = (id("x"), id("y"), id("z"));
+        let mut block = Map::new();
+        block.insert(
+            id("entry"),
+            Block {
+                insn: vec![
+                    Instruction::Copy { dst: x, src: y },
+                    Instruction::Arith {
+                        op: crate::front::BOp::Add,
+                        dst: x,
+                        lhs: x,
+                        rhs: z,
+                    },
+                    Instruction::Print(z),
+                ],
+                term: Terminator::Branch {
+                    guard: y,
+                    tt: id("entry"),
+                    ff: id("entry"),
+                },
+            },
+        );
+        let program = Program {
+            decl: Set::from([x]),
+            block,
+            funcs: Map::new(),
+        };
+        let errors = verify(&program).unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].contains("reads the undeclared variable `y`"));
+        assert!(errors[1].contains("reads the undeclared variable `z`"));
+        assert!(errors[2].contains("`$print z`"));
+        assert!(errors[3].starts_with("The terminator `$branch y entry entry`"));
+
+        let program = Program {
+            decl: Set::new(),
+            block: program.block,
+            funcs: Map::new(),
+        };
+        let errors = verify(&program).unwrap_err();
+        assert_eq!(errors.len(), 7);
+        assert!(errors[1].contains("writes to the undeclared variable `x`"));
+    }
+}
@@ -0,0 +1,435 @@
+//! Integration tests for the `smol` driver, the `smolc` compiler, and the `vm`.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// SECTION: helpers
+
+// Run `smol` with given arguments and standard input
+fn smol(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_smol"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("smol should start");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Run `smolc` with given arguments
+fn smolc(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_smolc"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("smolc should start")
+}
+
+// Run `vm` with given arguments
+fn vm(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_vm"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("vm should start")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+// SECTION: tests
+
+#[test]
+fn parse() {
+    let output = smol(&["parse", "tests/fixtures/max.smol"], "");
+    assert!(output.status.success());
+    assert!(stdout(&output).starts_with("Program { stmts: [Read("));
+}
+
+#[test]
+fn lower() {
+    let output = smol(&["lower", "tests/fixtures/max.smol"], "");
+    assert!(output.status.success());
+    let tir = stdout(&output);
+    assert!(tir.starts_with("let _t_1, a, b;\nentry:\n    $read a\n"));
+    assert_eq!(tir.matches("$print").count(), 2);
+}
+
+#[test]
+fn run() {
+    let output = smol(&["run", "tests/fixtures/max.smol"], "3\n8\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "8\n");
+
+    let output = smol(&["run", "tests/fixtures/sum.smol"], "100\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "5050\n");
+}
+
+#[test]
+fn optimize() {
+    // the sum has constants to propagate, the maximum has a join block that
+    // only exits
+    for (file, input) in [
+        ("tests/fixtures/sum.smol", "100\n"),
+        ("tests/fixtures/max.smol", "3\n7\n"),
+    ] {
+        let plain = smol(&["lower", file], "");
+        let optimized = smol(&["lower", "--optimize", file], "");
+        assert!(plain.status.success() && optimized.status.success());
+        let (plain, optimized) = (stdout(&plain), stdout(&optimized));
+        assert!(
+            optimized.lines().count() < plain.lines().count(),
+            "the optimized IR is not smaller:\n{optimized}"
+        );
+
+        let plain = smol(&["run", file], input);
+        let optimized = smol(&["run", "--optimize", file], input);
+        assert!(optimized.status.success());
+        assert_eq!(stdout(&optimized), stdout(&plain));
+    }
+}
+
+#[test]
+fn empty_program() {
+    let commands: [&[&str]; 6] = [
+        &["lower"],
+        &["lower", "--optimize"],
+        &["run"],
+        &["run", "--optimize"],
+        &["fmt"],
+        &["repl"],
+    ];
+    for args in commands {
+        let output = smol(args, "");
+        assert!(output.status.success(), "{args:?}: {}", stderr(&output));
+        assert_eq!(stderr(&output), "", "{args:?}");
+    }
+    assert_eq!(stdout(&smol(&["lower"], "")), "let;\nentry:\n    $exit\n");
+    assert_eq!(stdout(&smol(&["run"], "")), "");
+    assert_eq!(stdout(&smol(&["fmt"], "// only a comment\n")), "");
+}
+
+#[test]
+fn stdin() {
+    for args in [&["run"][..], &["run", "-"]] {
+        let output = smol(args, "$print + 2 3");
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), "5\n");
+    }
+
+    let output = smol(&["parse"], "$print + 2 3");
+    assert!(output.status.success());
+    assert!(stdout(&output).starts_with("Program { stmts: [Print([BinOp {"));
+
+    let output = smol(&["lower", "-"], "$print + 2 3");
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("$print _const_1"));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn parse_json() {
+    let output = smol(&["parse", "--emit=json"], ":= x 1 $print + x 2");
+    assert!(output.status.success());
+    let json = stdout(&output);
+    assert!(
+        json.starts_with("{\n  \"stmts\": ["),
+        "unexpected JSON: {json}"
+    );
+    assert!(
+        json.contains("\"Assign\": [\n        \"x\","),
+        "unexpected JSON: {json}"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn lower_json() {
+    let output = smol(&["lower", "--emit=json"], "$read x $print x");
+    assert!(output.status.success());
+    let json = stdout(&output);
+    assert!(
+        json.contains("\"decl\": [\n    \"x\"\n  ],"),
+        "unexpected JSON: {json}"
+    );
+    assert!(json.contains("\"entry\": {"), "unexpected JSON: {json}");
+    assert!(
+        json.contains("\"term\": \"Exit\""),
+        "unexpected JSON: {json}"
+    );
+}
+
+#[test]
+fn repl() {
+    let session = "\
+:= x 5
+$print x
+:= 3 x
+$print + x 1
+$fn sq(a) {:= sq * a a}
+$read y
+7
+$print sq(y)
+$print 1 $print / 1 0 $print 2
+$if < x y {
+    $print x
+} {
+    $print y
+}
+$print \"done\"
+";
+    let output = smol(&["repl"], session);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "5\n6\n49\n1\n5\ndone\n");
+    let errors = stderr(&output);
+    assert_eq!(errors.lines().count(), 5, "unexpected errors: {errors}");
+    assert!(errors.starts_with("Parse error at 1:4: "));
+    assert!(errors.contains("\n1 | := 3 x\n  |    ^\n"));
+    assert!(errors.ends_with("Runtime error: division by zero at `/ 1 0`.\n"));
+}
+
+#[test]
+fn parse_errors() {
+    for cmd in ["parse", "lower", "run"] {
+        let output = smol(&[cmd, "tests/fixtures/syntax_error.smol"], "");
+        assert!(!output.status.success());
+        assert_eq!(stdout(&output), "");
+        let errors = stderr(&output);
+        let count = errors.matches("Parse error").count();
+        assert_eq!(count, 2, "unexpected errors: {errors}");
+        assert!(errors.starts_with("Parse error at 2:4: "));
+        assert!(errors.contains("\n2 | := 3 a\n  |    ^\n"));
+        assert!(errors.contains("Parse error at 4:1: "));
+    }
+
+    // smolc reports them the same way
+    let expected = stderr(&smol(&["parse", "tests/fixtures/syntax_error.smol"], ""));
+    for out in ["ast", "tir", "asm"] {
+        let output = smolc(&["-o", out, "tests/fixtures/syntax_error.smol"]);
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(stdout(&output), "");
+        assert_eq!(stderr(&output), expected);
+    }
+}
+
+#[test]
+fn undefined_variables() {
+    for cmd in ["lower", "run"] {
+        let output = smol(&[cmd], "$read c $if c {:= x 1} {} $print + x y");
+        assert!(!output.status.success());
+        assert_eq!(stdout(&output), "");
+        assert_eq!(
+            stderr(&output),
+            "Error: The variable `x` may be used before it is defined.\n\
+             Error: The variable `y` may be used before it is defined.\n"
+        );
+    }
+
+    // parsing does not check the variables
+    let output = smol(&["parse"], "$print x");
+    assert!(output.status.success());
+}
+
+#[test]
+fn unused_variables() {
+    let src = "$fn f(a, b) {:= f a} := x 1 := y 2 $print f(x, 0)";
+    for cmd in ["lower", "run"] {
+        let output = smol(&[cmd], src);
+        assert!(output.status.success());
+        assert_eq!(
+            stderr(&output),
+            "Warning: The variable `y` is never read.\n\
+             Warning: In the function `f`: The variable `b` is never read.\n"
+        );
+    }
+    let output = smol(&["run"], src);
+    assert_eq!(stdout(&output), "1\n");
+}
+
+#[test]
+fn runtime_errors() {
+    let output = smol(&["run", "tests/fixtures/div_zero.smol"], "0\n");
+    assert!(!output.status.success());
+    assert_eq!(
+        stderr(&output),
+        "Runtime error: division by zero at `_t_2 = $arith div _const_1 a` in the block `entry`.\n"
+    );
+}
+
+#[test]
+fn missing_file() {
+    let output = smol(&["run", "tests/fixtures/does_not_exist.smol"], "");
+    assert!(!output.status.success());
+    assert!(stderr(&output).starts_with("Cannot read tests/fixtures/does_not_exist.smol"));
+
+    for output in [
+        smolc(&["tests/fixtures/does_not_exist.smol"]),
+        vm(&["tests/fixtures/does_not_exist.tir"]),
+    ] {
+        assert!(!output.status.success());
+        assert!(stderr(&output).starts_with("Cannot read tests/fixtures/does_not_exist."));
+    }
+    // the programs are text
+    let binary = std::env::temp_dir().join("smol_cli_not_utf8.tir");
+    std::fs::write(&binary, [0xff, 0xfe]).unwrap();
+    let binary = binary.to_str().unwrap();
+    for output in [smolc(&[binary]), vm(&[binary])] {
+        assert!(!output.status.success());
+        assert!(stderr(&output).starts_with(&format!("Cannot read {binary}: ")));
+    }
+}
+
+#[test]
+fn fmt() {
+    let messy = [
+        ":= x   3 $print x",
+        "$read n\n\n  := s 0 $while n {:= s + s n := n - n 1} // sum\n$print s",
+        "$fn f(a,b){:= f + a b}\n# comment\n$if < x 3 {$print f(x,1)} $elif x {$read y} $else {$print \"hi\"}",
+        "$print ( * 2 (+ 1 2) )$return",
+    ];
+    for src in messy {
+        let output = smol(&["fmt"], src);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let formatted = stdout(&output);
+        assert!(formatted.ends_with('\n'));
+
+        // formatting keeps the program the same, and formatting twice is a
+        // no-op
+        let parsed = |src: &str| stdout(&smol(&["parse"], src));
+        assert_eq!(parsed(&formatted), parsed(src), "{formatted}");
+        assert_eq!(stdout(&smol(&["fmt"], &formatted)), formatted);
+    }
+
+    let output = smol(&["fmt"], "$while x {:= x - x 1 $print   x}");
+    assert_eq!(
+        stdout(&output),
+        "$while x {\n    := x - x 1\n    $print x\n}\n"
+    );
+
+    // literals too large for a float are infinite, which has to be written in
+    // a way that does not read back as a variable
+    let huge = format!("1{}.0", "0".repeat(400));
+    let src = format!(":= inf 7 := NaN 8 $print {huge} -{huge} - {huge} {huge}");
+    let formatted = stdout(&smol(&["fmt"], &src));
+    assert!(
+        formatted.contains("$print / 1.0 0.0 / -1.0 0.0"),
+        "{formatted}"
+    );
+    let run = |src: &str| stdout(&smol(&["run"], src));
+    assert_eq!(run(&src), "inf -inf NaN\n");
+    assert_eq!(run(&formatted), run(&src));
+
+    let output = smol(&["fmt"], "$print + x");
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn fmt_write() {
+    let path = std::env::temp_dir().join(format!("smol-fmt-{}.smol", std::process::id()));
+    std::fs::write(&path, ":= x 1 $print   x").unwrap();
+    let output = smol(&["fmt", "--write", path.to_str().unwrap()], "");
+    let formatted = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(formatted, ":= x 1\n$print x\n");
+
+    // there is no file to write to
+    let output = smol(&["fmt", "--write"], ":= x 1");
+    assert!(!output.status.success());
+
+    // the comments would be lost, so the file stays as it is
+    for src in ["# one\n:= x 1", ":= x 1 // two\n$print   x"] {
+        std::fs::write(&path, src).unwrap();
+        let output = smol(&["fmt", "--write", path.to_str().unwrap()], "");
+        let after = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(after, src);
+        assert!(
+            stderr(&output).ends_with("since it has comments.\n"),
+            "{}",
+            stderr(&output)
+        );
+    }
+}
+
+#[test]
+fn cfg_stats() {
+    // the loop adds one path to the straight-line program
+    let output = smolc(&["-o", "cfg-stats", "tests/fixtures/sum.smol"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "blocks: 4\nedges: 4\ncyclomatic complexity: 2\nloops: 1\nlongest block: lbl2 (5 instructions)\n"
+    );
+
+    let output = smolc(&["-o", "cfg-stats", "tests/fixtures/max.smol"]);
+    assert!(stdout(&output).contains("cyclomatic complexity: 2\nloops: 0\n"));
+}
+
+#[test]
+fn llvm_floats() {
+    let output = smolc(&["-o", "llvm", "tests/fixtures/max.smol"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("define i32 @main()"));
+
+    // the LLVM back-end only handles integers, which is an error, not a crash
+    let output = smolc(&["-o", "llvm", "tests/fixtures/circle.smol"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "Error: the LLVM back-end does not support floats, found `_const_1 = $const 3.14159`.\n"
+    );
+}
+
+#[test]
+fn smolc_tokens() {
+    let output = smolc(&["-o", "tokens", "tests/fixtures/sum.smol"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stdout(&output).is_empty());
+
+    // a character that starts no token is an error
+    let path = std::env::temp_dir().join(format!("smol-lex-{}.smol", std::process::id()));
+    std::fs::write(&path, "$read x\n:= x @").unwrap();
+    let output = smolc(&["-o", "tokens", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "Lex error at 2:6: Unexpected character `@`.\n"
+    );
+}
+
+#[test]
+fn smolc_undefined() {
+    // the errors are reported the way `smol lower` reports them
+    let output = smolc(&["-o", "tir", "tests/fixtures/undefined.smol"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    let lower = smol(&["lower", "tests/fixtures/undefined.smol"], "");
+    assert!(!lower.status.success());
+    assert_eq!(stderr(&output), stderr(&lower));
+    assert!(
+        stderr(&output).starts_with("Error: "),
+        "{}",
+        stderr(&output)
+    );
+}